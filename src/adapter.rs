@@ -0,0 +1,268 @@
+//! Bluetooth adapter setup. On boot the daemon may start before bluetoothd, or before the
+//! adapter has been powered on, so both are retried with backoff for up to
+//! [adapter.wait_seconds].
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use btleplug::api::{Central as _, CentralState, ScanFilter};
+use log::{info, warn};
+
+use crate::config::{self, AdapterConfig, BleDevice};
+
+#[cfg(all(feature = "mock", target_os = "linux"))]
+pub use crate::mock::Adapter;
+#[cfg(not(all(feature = "mock", target_os = "linux")))]
+pub use btleplug::platform::Adapter;
+
+struct Backoff {
+    started: Instant,
+    wait: Duration,
+    interval: Duration,
+    max_interval: Duration,
+}
+
+impl Backoff {
+    fn new(cfg: &AdapterConfig) -> Self {
+        Backoff {
+            started: Instant::now(),
+            wait: Duration::from_secs(
+                cfg.wait_seconds
+                    .unwrap_or(config::DEFAULT_ADAPTER_WAIT_SECONDS),
+            ),
+            interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(
+                cfg.max_retry_interval_seconds
+                    .unwrap_or(config::DEFAULT_ADAPTER_MAX_RETRY_INTERVAL_SECONDS),
+            ),
+        }
+    }
+
+    /// Sleep before the next attempt, or return the error once we've waited long enough.
+    async fn retry(&mut self, error: String) -> anyhow::Result<()> {
+        let elapsed = self.started.elapsed();
+        if elapsed >= self.wait {
+            anyhow::bail!(error);
+        }
+        let delay = self.interval.min(self.wait - elapsed);
+        warn!("{error}, retrying in {delay:?}");
+        tokio::time::sleep(delay).await;
+        self.interval = (self.interval * 2).min(self.max_interval);
+        Ok(())
+    }
+}
+
+/// Find the configured adapters, in the order they're listed, or the first adapter found if none
+/// are configured.
+pub async fn wait_for_adapters(cfg: &AdapterConfig) -> anyhow::Result<Vec<(String, Adapter)>> {
+    let mut backoff = Backoff::new(cfg);
+    loop {
+        let error = match find_adapters(cfg).await {
+            Ok(adapters) => return Ok(adapters),
+            Err(err) => format!("{err:#}"),
+        };
+        backoff.retry(error).await?;
+    }
+}
+
+async fn find_adapters(cfg: &AdapterConfig) -> anyhow::Result<Vec<(String, Adapter)>> {
+    let mut found = Vec::new();
+    for adapter in list_adapters(cfg).await? {
+        found.push((adapter_id(&adapter).await?, adapter));
+    }
+
+    let Some(ids) = &cfg.ids else {
+        return found
+            .into_iter()
+            .next()
+            .map(|adapter| vec![adapter])
+            .context("No Bluetooth adapter found");
+    };
+    ids.iter()
+        .map(|id| {
+            found
+                .iter()
+                .find(|(found_id, _)| found_id == id)
+                .cloned()
+                .with_context(|| format!("Bluetooth adapter {id} not found"))
+        })
+        .collect()
+}
+
+#[cfg(not(all(feature = "mock", target_os = "linux")))]
+async fn list_adapters(_cfg: &AdapterConfig) -> anyhow::Result<Vec<Adapter>> {
+    use btleplug::api::Manager as _;
+    btleplug::platform::Manager::new()
+        .await?
+        .adapters()
+        .await
+        .context("Error listing Bluetooth adapters")
+}
+
+#[cfg(all(feature = "mock", target_os = "linux"))]
+async fn list_adapters(cfg: &AdapterConfig) -> anyhow::Result<Vec<Adapter>> {
+    let path = cfg
+        .mock_scenario
+        .as_ref()
+        .context("adapter.mock_scenario is required when built with the mock feature")?;
+    crate::mock::load(path)
+}
+
+/// Short name for the adapter, e.g. `hci0` on Linux.
+async fn adapter_id(adapter: &Adapter) -> anyhow::Result<String> {
+    // btleplug describes BlueZ adapters as "hci0 (usb:...)"
+    Ok(adapter
+        .adapter_info()
+        .await?
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Split devices between adapters: a device's own `adapter` wins, then its room's adapter, and
+/// everything else goes to the first adapter. Returns one list per entry in `adapter_ids`.
+pub fn assign_devices(
+    devices: &[BleDevice],
+    adapter_ids: &[String],
+    cfg: &AdapterConfig,
+) -> Vec<Vec<BleDevice>> {
+    let mut assigned = vec![Vec::new(); adapter_ids.len()];
+    for device in devices {
+        let wanted = device.adapter.as_ref().or_else(|| {
+            let rooms = cfg.rooms.as_ref()?;
+            rooms.get(device.room.as_ref()?)
+        });
+        let index = match wanted {
+            Some(id) => adapter_ids
+                .iter()
+                .position(|adapter_id| adapter_id == id)
+                .unwrap_or_else(|| {
+                    warn!(
+                        "Device {} assigned to unknown adapter {id}, using {}",
+                        device.name, adapter_ids[0]
+                    );
+                    0
+                }),
+            None => 0,
+        };
+        assigned[index].push(device.clone());
+    }
+    assigned
+}
+
+/// Make sure the adapter is powered on before scanning, powering it on where the platform
+/// allows it.
+pub async fn ensure_powered(
+    id: &str,
+    adapter: &Adapter,
+    cfg: &AdapterConfig,
+) -> anyhow::Result<()> {
+    let mut backoff = Backoff::new(cfg);
+    loop {
+        let state = adapter.adapter_state().await?;
+        if state != CentralState::PoweredOff {
+            return Ok(());
+        }
+
+        info!("Bluetooth adapter {id} is powered off, powering on");
+        let error = match set_powered(id, true).await {
+            Ok(()) => continue,
+            Err(err) => format!("Unable to power on Bluetooth adapter {id}: {err:#}"),
+        };
+        backoff.retry(error).await?;
+    }
+}
+
+/// Power the adapter off and back on, for when its Bluetooth stack stopped answering, and start
+/// scanning again since powering off stops it.
+pub async fn restart(id: &str, adapter: &Adapter) -> anyhow::Result<()> {
+    info!("Restarting Bluetooth adapter {id}");
+    set_powered(id, false).await?;
+    set_powered(id, true).await?;
+    adapter
+        .start_scan(ScanFilter::default())
+        .await
+        .with_context(|| format!("start scan on adapter {id}"))
+}
+
+#[cfg(target_os = "linux")]
+async fn set_powered(id: &str, powered: bool) -> anyhow::Result<()> {
+    let (_, session) = bluez_async::BluetoothSession::new()
+        .await
+        .context("connect to BlueZ")?;
+    let adapter = session
+        .get_adapters()
+        .await
+        .context("list BlueZ adapters")?
+        .into_iter()
+        .find(|adapter| adapter.id.to_string() == id)
+        .with_context(|| format!("BlueZ adapter {id} not found"))?;
+    session
+        .set_powered(&adapter.id, powered)
+        .await
+        .with_context(|| {
+            if powered {
+                "power on adapter (is it blocked by rfkill?)"
+            } else {
+                "power off adapter"
+            }
+        })?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn set_powered(_id: &str, _powered: bool) -> anyhow::Result<()> {
+    anyhow::bail!("powering adapters on and off is not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign_devices() {
+        let config_str = r#"
+            [mqtt]
+            host = "localhost"
+
+            [adapter]
+            ids = ["hci0", "hci1"]
+            rooms = { Garage = "hci1" }
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Phone"
+
+            [[devices]]
+            address = "00:11:22:33:44:56"
+            name = "Car keys"
+            room = "Garage"
+
+            [[devices]]
+            address = "00:11:22:33:44:57"
+            name = "Watch"
+            room = "Garage"
+            adapter = "hci0"
+
+            [[devices]]
+            address = "00:11:22:33:44:58"
+            name = "Tablet"
+            adapter = "hci7"
+        "#;
+        let config: crate::config::AppConfig = toml::de::from_str(config_str).unwrap();
+        let adapter = config.adapter.unwrap();
+        let assigned = assign_devices(
+            &config.devices.unwrap(),
+            adapter.ids.as_ref().unwrap(),
+            &adapter,
+        );
+        let names = |devices: &[BleDevice]| {
+            devices
+                .iter()
+                .map(|device| device.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&assigned[0]), vec!["Phone", "Watch", "Tablet"]);
+        assert_eq!(names(&assigned[1]), vec!["Car keys"]);
+    }
+}
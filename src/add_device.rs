@@ -0,0 +1,90 @@
+//! `monitor-rs add-device`: append a device to an existing config file.
+use std::path::Path;
+
+use anyhow::Context as _;
+use mac_address::MacAddress;
+
+use crate::config::AppConfig;
+
+/// Append a `[[devices]]` table to the config at `path`, leaving the rest of the file (and its
+/// comments) untouched.
+pub fn run(
+    path: &Path,
+    name: &str,
+    address: MacAddress,
+    manufacturer: Option<&str>,
+) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let updated = append_device(&contents, name, address, manufacturer)?;
+    std::fs::write(path, updated).with_context(|| format!("Failed to write {}", path.display()))?;
+    println!(
+        "Added {name} ({address}) to {}, restart monitor-rs to start tracking it",
+        path.display()
+    );
+    Ok(())
+}
+
+fn append_device(
+    contents: &str,
+    name: &str,
+    address: MacAddress,
+    manufacturer: Option<&str>,
+) -> anyhow::Result<String> {
+    let config: AppConfig = toml::de::from_str(contents).context("Existing config is invalid")?;
+    for device in config.devices.iter().flatten() {
+        if device.name == name {
+            anyhow::bail!("A device named {name} already exists");
+        }
+        if device.address == address {
+            anyhow::bail!("{address} is already tracked as {}", device.name);
+        }
+    }
+
+    let mut updated = contents.to_string();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated.push('\n');
+    updated.push_str(&crate::init::device_table(address, name, manufacturer));
+
+    // Catches values the schema rejects, e.g. an unknown manufacturer
+    toml::de::from_str::<AppConfig>(&updated).context("Invalid device")?;
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"# Broker
+[mqtt]
+host = "localhost"
+
+[[devices]]
+address = "00:11:22:33:44:55"
+name = "Phone""#;
+
+    #[test]
+    fn test_append_device() {
+        let address = "00:11:22:33:44:56".parse().unwrap();
+        let updated = append_device(CONFIG, "Car keys", address, Some("Apple")).unwrap();
+        assert!(updated.starts_with("# Broker\n"));
+
+        let config: AppConfig = toml::de::from_str(&updated).unwrap();
+        let devices = config.devices.unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].name, "Car keys");
+        assert_eq!(devices[1].address, address);
+    }
+
+    #[test]
+    fn test_append_device_rejected() {
+        let address = "00:11:22:33:44:56".parse().unwrap();
+        assert!(append_device(CONFIG, "Phone", address, None).is_err());
+        assert!(append_device(CONFIG, "Car keys", address, Some("Nokia")).is_err());
+
+        let existing = "00:11:22:33:44:55".parse().unwrap();
+        assert!(append_device(CONFIG, "Car keys", existing, None).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Low-power background monitoring with BlueZ advertisement monitors: instead of keeping
+//! discovery running, BlueZ (or the controller itself, where supported) filters advertisements
+//! and only reports those carrying the manufacturer data of a tracked device.
+//!
+//! Monitors match advertisement contents, not addresses, so a match triggers an arrival scan
+//! just like a manufacturer match seen while scanning.
+use std::collections::BTreeSet;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use crate::config::BleDevice;
+use crate::messages::StateAnnouncement;
+use crate::stats::Stats;
+
+/// AD type of manufacturer specific data
+const MANUFACTURER_DATA: u8 = 0xFF;
+
+/// Monitor patterns as `(start position, AD type, content)`, one per tracked company ID.
+fn patterns(devices: &[BleDevice]) -> Vec<(u8, u8, Vec<u8>)> {
+    devices
+        .iter()
+        .filter_map(|device| device.manufacturer.as_ref())
+        .flat_map(|manufacturer| manufacturer.company_ids())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .map(|id| (0, MANUFACTURER_DATA, id.to_le_bytes().to_vec()))
+        .collect()
+}
+
+/// Register a monitor on the adapter for the manufacturers of `devices`, sending a device
+/// trigger whenever it matches. Fails if BlueZ doesn't support advertisement monitors, in which
+/// case the adapter should be scanned instead.
+#[cfg(target_os = "linux")]
+pub async fn register(
+    adapter_id: &str,
+    devices: &[BleDevice],
+    rssi_threshold: Option<i16>,
+    tx: mpsc::Sender<StateAnnouncement>,
+    stats: Arc<Stats>,
+) -> anyhow::Result<()> {
+    let patterns = patterns(devices);
+    anyhow::ensure!(
+        !patterns.is_empty(),
+        "no device on adapter {adapter_id} has a manufacturer to monitor for"
+    );
+    bluez::register(
+        adapter_id,
+        bluez::Monitor {
+            patterns,
+            rssi_threshold,
+            tx,
+            stats,
+        },
+    )
+    .await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn register(
+    _adapter_id: &str,
+    _devices: &[BleDevice],
+    _rssi_threshold: Option<i16>,
+    _tx: mpsc::Sender<StateAnnouncement>,
+    _stats: Arc<Stats>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("advertisement monitors are only supported on Linux")
+}
+
+#[cfg(target_os = "linux")]
+mod bluez {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Context as _;
+    use dbus::channel::MatchingReceiver as _;
+    use dbus::message::MatchRule;
+    use dbus::nonblock::Proxy;
+    use dbus_crossroads::{Crossroads, MethodErr};
+    use log::{debug, error, info, warn};
+    use tokio::sync::mpsc;
+
+    use crate::messages::StateAnnouncement;
+    use crate::stats::Stats;
+
+    const DBUS_TIMEOUT: Duration = Duration::from_secs(30);
+    /// How long a device has to stay above or below the RSSI threshold to be found or lost
+    const RSSI_HIGH_TIMEOUT_SECONDS: u16 = 1;
+    const RSSI_LOW_TIMEOUT_SECONDS: u16 = 10;
+
+    pub struct Monitor {
+        pub patterns: Vec<(u8, u8, Vec<u8>)>,
+        pub rssi_threshold: Option<i16>,
+        pub tx: mpsc::Sender<StateAnnouncement>,
+        pub stats: Arc<Stats>,
+    }
+
+    impl Monitor {
+        fn rssi_threshold(&self) -> Result<i16, MethodErr> {
+            // Unset properties are left out, BlueZ then reports every match
+            self.rssi_threshold
+                .ok_or_else(|| MethodErr::no_property("RSSI threshold"))
+        }
+    }
+
+    /// Export the monitor as `org.bluez.AdvertisementMonitor1` under an object manager and
+    /// register that with the adapter's `org.bluez.AdvertisementMonitorManager1`.
+    pub async fn register(adapter_id: &str, monitor: Monitor) -> anyhow::Result<()> {
+        let (resource, connection) =
+            dbus_tokio::connection::new_system_sync().context("connect to the system bus")?;
+        tokio::spawn(async {
+            let err = resource.await;
+            error!("Lost D-Bus connection: {err}");
+        });
+
+        let root = format!("/monitor_rs/{adapter_id}");
+        let mut cr = Crossroads::new();
+        let iface = cr.register("org.bluez.AdvertisementMonitor1", |b| {
+            b.method("Release", (), (), |ctx, _: &mut Monitor, ()| {
+                warn!("BlueZ released advertisement monitor {}", ctx.path());
+                Ok(())
+            });
+            b.method("Activate", (), (), |ctx, _: &mut Monitor, ()| {
+                info!("Advertisement monitor {} is active", ctx.path());
+                Ok(())
+            });
+            b.method(
+                "DeviceFound",
+                ("device",),
+                (),
+                |_, monitor: &mut Monitor, (device,): (dbus::Path<'static>,)| {
+                    debug!("Advertisement monitor matched {device}");
+                    monitor.stats.record_device_trigger();
+                    if let Err(err) =
+                        crate::bus::send_coalesced(&monitor.tx, StateAnnouncement::DeviceTrigger)
+                    {
+                        error!("Error sending scan arrival message: {err:?}");
+                    }
+                    Ok(())
+                },
+            );
+            b.method(
+                "DeviceLost",
+                ("device",),
+                (),
+                |_, _: &mut Monitor, (device,): (dbus::Path<'static>,)| {
+                    // Departures are left to presence checks
+                    debug!("Advertisement monitor lost {device}");
+                    Ok(())
+                },
+            );
+            b.property("Type")
+                .get(|_, _: &mut Monitor| Ok("or_patterns".to_string()));
+            b.property("Patterns")
+                .get(|_, monitor: &mut Monitor| Ok(monitor.patterns.clone()));
+            b.property("RSSIHighThreshold")
+                .get(|_, monitor: &mut Monitor| monitor.rssi_threshold());
+            b.property("RSSILowThreshold")
+                .get(|_, monitor: &mut Monitor| monitor.rssi_threshold());
+            b.property("RSSIHighTimeout")
+                .get(|_, monitor: &mut Monitor| {
+                    monitor.rssi_threshold().map(|_| RSSI_HIGH_TIMEOUT_SECONDS)
+                });
+            b.property("RSSILowTimeout")
+                .get(|_, monitor: &mut Monitor| {
+                    monitor.rssi_threshold().map(|_| RSSI_LOW_TIMEOUT_SECONDS)
+                });
+        });
+        let object_manager = cr.object_manager();
+        cr.insert(root.clone(), &[object_manager], ());
+        cr.insert(format!("{root}/monitor0"), &[iface], monitor);
+        connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                if cr.handle_message(msg, conn).is_err() {
+                    warn!("Unhandled D-Bus call to the advertisement monitor");
+                }
+                true
+            }),
+        );
+
+        let adapter = format!("/org/bluez/{adapter_id}");
+        Proxy::new("org.bluez", adapter, DBUS_TIMEOUT, connection.clone())
+            .method_call::<(), _, _, _>(
+                "org.bluez.AdvertisementMonitorManager1",
+                "RegisterMonitor",
+                (dbus::Path::from(root),),
+            )
+            .await
+            .with_context(|| format!("register advertisement monitor on {adapter_id}"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let devices: Vec<BleDevice> = toml::de::from_str::<crate::config::AppConfig>(
+            r#"
+[mqtt]
+host = "localhost"
+
+[[devices]]
+address = "00:11:22:33:44:55"
+name = "Phone"
+manufacturer = "Apple"
+
+[[devices]]
+address = "00:11:22:33:44:56"
+name = "Watch"
+manufacturer = "Apple"
+
+[[devices]]
+address = "00:11:22:33:44:57"
+name = "Keys"
+"#,
+        )
+        .unwrap()
+        .devices
+        .unwrap();
+        assert_eq!(
+            patterns(&devices),
+            vec![(0, MANUFACTURER_DATA, vec![0x4C, 0x00])]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{AddressType, PeripheralProperties};
+
+use crate::company_ids;
+
+/// Battery Service, whose service data starts with the Battery Level characteristic.
+const BATTERY_SERVICE: u16 = 0x180F;
+
+/// Metadata from the most recent advertisement received from a device.
+#[derive(Debug, Clone)]
+pub struct Advertisement {
+    pub rssi: Option<i16>,
+    pub tx_power: Option<i16>,
+    pub address_type: Option<AddressType>,
+    pub service_count: usize,
+    pub manufacturer_ids: Vec<u16>,
+    /// Battery level in percent, from Battery Service data
+    pub battery: Option<u8>,
+}
+
+impl Advertisement {
+    pub fn from_properties(properties: &PeripheralProperties) -> Self {
+        Advertisement {
+            rssi: properties.rssi,
+            tx_power: properties.tx_power_level,
+            address_type: properties.address_type,
+            service_count: properties.services.len(),
+            manufacturer_ids: properties.manufacturer_data.keys().copied().collect(),
+            battery: properties
+                .service_data
+                .get(&uuid_from_u16(BATTERY_SERVICE))
+                .and_then(|data| data.first().copied())
+                .filter(|level| *level <= 100),
+        }
+    }
+
+    /// Registered name of the first recognized company in the manufacturer data.
+    pub fn manufacturer(&self) -> Option<&'static str> {
+        self.manufacturer_ids
+            .iter()
+            .find_map(|id| company_ids::company_name(*id))
+    }
+}
+
+/// Latest advertisement per tracked device and when it was received, written by the manager's
+/// BLE event loop and read by the scanner.
+#[derive(Debug, Clone, Default)]
+pub struct Advertisements {
+    by_address: Arc<Mutex<HashMap<String, (Advertisement, Instant)>>>,
+}
+
+impl Advertisements {
+    /// Returns when the previous advertisement from the device was received.
+    pub fn record(
+        &self,
+        mac_address: &str,
+        advertisement: Advertisement,
+        now: Instant,
+    ) -> Option<Instant> {
+        self.by_address
+            .lock()
+            .expect("advertisements lock poisoned")
+            .insert(mac_address.to_uppercase(), (advertisement, now))
+            .map(|(_, received)| received)
+    }
+
+    pub fn get(&self, mac_address: &str) -> Option<Advertisement> {
+        self.by_address
+            .lock()
+            .expect("advertisements lock poisoned")
+            .get(&mac_address.to_uppercase())
+            .map(|(advertisement, _)| advertisement.clone())
+    }
+
+    /// When the device's latest advertisement was received.
+    pub fn last_seen(&self, mac_address: &str) -> Option<Instant> {
+        self.by_address
+            .lock()
+            .expect("advertisements lock poisoned")
+            .get(&mac_address.to_uppercase())
+            .map(|(_, received)| *received)
+    }
+}
@@ -0,0 +1,144 @@
+//! Presence merged from this node and the nodes in `mqtt.listen_nodes`, for setups without a hub
+//! or an elected leader: each node publishes its own retained
+//! `<topic_path>/<publisher_id>/anyone_home` and, for every device seen by any of them,
+//! `<topic_path>/<publisher_id>/presence/<device>`, `ON` while any of the nodes sees it.
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Device states from this node and the ones it listens to.
+#[derive(Debug)]
+pub struct Aggregate {
+    publisher_id: String,
+    listen_nodes: HashSet<String>,
+    /// Latest confidence for each device, keyed by node and device topic
+    confidence: HashMap<(String, String), u8>,
+    anyone_home: Option<bool>,
+    present: HashMap<String, bool>,
+}
+
+/// What changed after a sighting, to be published.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub anyone_home: Option<bool>,
+    /// Devices, by topic, whose merged presence changed
+    pub devices: Vec<(String, bool)>,
+}
+
+impl Aggregate {
+    pub fn new(publisher_id: &str, listen_nodes: &[String]) -> Self {
+        Aggregate {
+            publisher_id: publisher_id.to_string(),
+            listen_nodes: listen_nodes
+                .iter()
+                .filter(|node| *node != publisher_id)
+                .cloned()
+                .collect(),
+            confidence: HashMap::new(),
+            anyone_home: None,
+            present: HashMap::new(),
+        }
+    }
+
+    /// Whether device states published by `node` are merged, this node's own are recorded as
+    /// they're announced.
+    pub fn listens_to(&self, node: &str) -> bool {
+        self.listen_nodes.contains(node)
+    }
+
+    pub fn listen_nodes(&self) -> impl Iterator<Item = &str> {
+        self.listen_nodes.iter().map(String::as_str)
+    }
+
+    /// Devices, by topic, that any of the nodes has reported.
+    pub fn devices(&self) -> BTreeSet<&str> {
+        self.present.keys().map(String::as_str).collect()
+    }
+
+    /// Record a device state from this node or one it listens to.
+    pub fn record(&mut self, node: &str, device: &str, confidence: u8) -> Changes {
+        if node != self.publisher_id && !self.listens_to(node) {
+            return Changes::default();
+        }
+        self.confidence
+            .insert((node.to_string(), device.to_string()), confidence);
+        self.changes()
+    }
+
+    /// Drop the device states of a node that went offline, they can't be vouched for anymore.
+    pub fn forget_node(&mut self, node: &str) -> Changes {
+        self.confidence.retain(|(known, _), _| known != node);
+        self.changes()
+    }
+
+    fn changes(&mut self) -> Changes {
+        let mut present = HashMap::<String, bool>::new();
+        for ((_, device), confidence) in &self.confidence {
+            *present.entry(device.clone()).or_default() |= *confidence > 0;
+        }
+        // Devices only a forgotten node reported are absent now
+        for (device, was_present) in &self.present {
+            if *was_present {
+                present.entry(device.clone()).or_default();
+            }
+        }
+
+        let mut devices = present
+            .iter()
+            .filter(|(device, present)| self.present.get(*device) != Some(present))
+            .map(|(device, present)| (device.clone(), *present))
+            .collect::<Vec<_>>();
+        devices.sort();
+        let anyone_home = present.values().any(|present| *present);
+        let changes = Changes {
+            anyone_home: (self.anyone_home != Some(anyone_home)).then_some(anyone_home),
+            devices,
+        };
+        self.anyone_home = Some(anyone_home);
+        self.present = present;
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_nodes() {
+        let mut aggregate = Aggregate::new("kitchen", &["garage".to_string()]);
+        assert_eq!(
+            aggregate.record("kitchen", "phone", 0),
+            Changes {
+                anyone_home: Some(false),
+                devices: vec![("phone".to_string(), false)],
+            }
+        );
+        // Nodes that aren't listened to don't count
+        assert_eq!(aggregate.record("attic", "phone", 100), Changes::default());
+
+        assert_eq!(
+            aggregate.record("garage", "phone", 100),
+            Changes {
+                anyone_home: Some(true),
+                devices: vec![("phone".to_string(), true)],
+            }
+        );
+        // Still present at the garage
+        assert_eq!(aggregate.record("kitchen", "phone", 0), Changes::default());
+        assert_eq!(
+            aggregate.record("kitchen", "keys", 100),
+            Changes {
+                anyone_home: None,
+                devices: vec![("keys".to_string(), true)],
+            }
+        );
+
+        assert_eq!(
+            aggregate.forget_node("garage"),
+            Changes {
+                anyone_home: None,
+                devices: vec![("phone".to_string(), false)],
+            }
+        );
+        assert_eq!(aggregate.devices(), BTreeSet::from(["keys", "phone"]));
+    }
+}
@@ -0,0 +1,481 @@
+//! Simple alert rules evaluated by the daemon itself, published to
+//! `<topic_path>/<publisher_id>/alerts` and handed to a notify command when they start and stop
+//! firing, so "something is wrong" doesn't have to be worked out from raw states in every home
+//! automation setup.
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::clock::Clock;
+use crate::command::{CommandRunner, Program};
+use crate::config::{self, AlertsConfig, BleDevice};
+use crate::messages::{DeviceAnnouncement, DevicePresence, StateAnnouncement};
+use crate::mqtt::MqttClient;
+use crate::stats::Stats;
+
+/// How often the rules are checked.
+const EVALUATE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// A device hasn't been present for longer than `device_unseen_hours`
+    DeviceUnseen,
+    /// More than `check_failure_rate` of this node's checks failed within the window
+    CheckFailures,
+    /// A device advertised a battery level below `battery_below_percent`
+    BatteryLow,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub rule: Rule,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// Whether the alert started (`true`) or stopped (`false`) firing
+    pub active: bool,
+    pub message: String,
+}
+
+/// Which alerts are firing, updated by [Rules::evaluate].
+pub struct Rules {
+    device_unseen: Option<Duration>,
+    check_failure_rate: Option<f64>,
+    window: Duration,
+    battery_below: Option<u8>,
+    /// When each device was last present, or when tracking it started
+    last_present: HashMap<String, Instant>,
+    /// Latest battery level each device advertised
+    battery: HashMap<String, u8>,
+    /// Check and failure counts at the start of the current window
+    window_start: (Instant, u64, u64),
+    active: HashSet<(Rule, Option<String>)>,
+}
+
+impl Rules {
+    pub fn new(cfg: &AlertsConfig, devices: &[BleDevice], now: Instant) -> Self {
+        Rules {
+            device_unseen: cfg
+                .device_unseen_hours
+                .map(|hours| Duration::from_secs(hours * 3600)),
+            check_failure_rate: cfg.check_failure_rate,
+            window: Duration::from_secs(
+                cfg.window_minutes
+                    .unwrap_or(config::DEFAULT_ALERT_WINDOW_MINUTES)
+                    * 60,
+            ),
+            battery_below: cfg.battery_below_percent,
+            last_present: devices
+                .iter()
+                .map(|device| (device.name.clone(), now))
+                .collect(),
+            battery: HashMap::new(),
+            window_start: (now, 0, 0),
+            active: HashSet::new(),
+        }
+    }
+
+    /// Note an announced device state. Devices first announced here, e.g. added over HTTP, are
+    /// tracked from now on.
+    pub fn observe(&mut self, announcement: &DeviceAnnouncement, now: Instant) {
+        let name = &announcement.name;
+        let last_present = self.last_present.entry(name.clone()).or_insert(now);
+        if matches!(announcement.presence, DevicePresence::Present(_)) {
+            *last_present = now;
+        }
+        let battery = announcement.advertisement.as_ref().and_then(|a| a.battery);
+        if let Some(battery) = battery {
+            self.battery.insert(name.clone(), battery);
+        }
+    }
+
+    /// Follow a device added, renamed or removed at runtime, returning the alerts that stopped
+    /// firing along with its old name.
+    pub fn device_changed(
+        &mut self,
+        name: &str,
+        device: Option<&BleDevice>,
+        now: Instant,
+    ) -> Vec<Alert> {
+        if let Some(device) = device {
+            self.last_present.entry(device.name.clone()).or_insert(now);
+            if device.name == name {
+                return Vec::new();
+            }
+        }
+        self.last_present.remove(name);
+        self.battery.remove(name);
+        let mut resolved = self
+            .active
+            .iter()
+            .filter(|(_, device)| device.as_deref() == Some(name))
+            .map(|(rule, _)| Alert {
+                rule: *rule,
+                device: Some(name.to_string()),
+                active: false,
+                message: format!("{name} is no longer tracked"),
+            })
+            .collect::<Vec<_>>();
+        self.active
+            .retain(|(_, device)| device.as_deref() != Some(name));
+        resolved.sort_by_key(|alert| alert.rule);
+        resolved
+    }
+
+    /// Alerts that started or stopped firing since the last evaluation. `checks` and `failures`
+    /// are running totals.
+    pub fn evaluate(&mut self, now: Instant, checks: u64, failures: u64) -> Vec<Alert> {
+        let mut changed = Vec::new();
+        if let Some(limit) = self.device_unseen {
+            for (name, last_present) in &self.last_present {
+                let unseen = now.saturating_duration_since(*last_present);
+                let message = format!("{name} not seen for {}h", unseen.as_secs() / 3600);
+                changed.extend(toggle(
+                    &mut self.active,
+                    Rule::DeviceUnseen,
+                    Some(name),
+                    unseen > limit,
+                    message,
+                ));
+            }
+        }
+
+        if let Some(limit) = self.battery_below {
+            for (name, level) in &self.battery {
+                changed.extend(toggle(
+                    &mut self.active,
+                    Rule::BatteryLow,
+                    Some(name),
+                    *level < limit,
+                    format!("{name} battery at {level}%"),
+                ));
+            }
+        }
+
+        let (started, window_checks, window_failures) = self.window_start;
+        if now.saturating_duration_since(started) >= self.window {
+            if let Some(limit) = self.check_failure_rate {
+                let checks = checks.saturating_sub(window_checks);
+                let failures = failures.saturating_sub(window_failures);
+                let rate = failures as f64 / checks.max(1) as f64;
+                let message = format!("{failures} of {checks} checks failed");
+                changed.extend(toggle(
+                    &mut self.active,
+                    Rule::CheckFailures,
+                    None,
+                    rate > limit,
+                    message,
+                ));
+            }
+            self.window_start = (now, checks, failures);
+        }
+        changed.sort_by(|a, b| (&a.device, a.rule).cmp(&(&b.device, b.rule)));
+        changed
+    }
+}
+
+/// The alert to publish if `firing` changed since the last evaluation.
+fn toggle(
+    active: &mut HashSet<(Rule, Option<String>)>,
+    rule: Rule,
+    device: Option<&String>,
+    firing: bool,
+    message: String,
+) -> Option<Alert> {
+    let key = (rule, device.cloned());
+    let changed = if firing {
+        active.insert(key)
+    } else {
+        active.remove(&key)
+    };
+    changed.then(|| Alert {
+        rule,
+        device: device.cloned(),
+        active: firing,
+        message,
+    })
+}
+
+/// Where alerts go besides the log.
+pub struct Notifiers {
+    mqtt_client: Option<MqttClient>,
+    command: Option<Program>,
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl Notifiers {
+    pub fn new(
+        cfg: &AlertsConfig,
+        mqtt_client: MqttClient,
+        runner: Arc<dyn CommandRunner>,
+    ) -> Self {
+        let publish = cfg.publish.unwrap_or(config::DEFAULT_ALERTS_PUBLISH);
+        Notifiers {
+            mqtt_client: publish.then_some(mqtt_client),
+            command: cfg
+                .notify_command
+                .as_ref()
+                .filter(|command| command.path.is_some())
+                .map(|command| Program::new(Some(command), "")),
+            runner,
+        }
+    }
+
+    async fn notify(&self, alert: &Alert) {
+        if alert.active {
+            warn!("Alert: {}", alert.message);
+        } else {
+            info!("Alert resolved: {}", alert.message);
+        }
+        if let Some(mqtt_client) = &self.mqtt_client
+            && let Err(err) = mqtt_client.publish_alert(alert).await
+        {
+            error!("Error publishing alert: {err:?}");
+        }
+        if let Some(command) = &self.command {
+            let payload = match serde_json::to_string(alert) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    error!("Error serializing alert: {err:?}");
+                    return;
+                }
+            };
+            match command.run(self.runner.as_ref(), &[&payload]).await {
+                Ok(output) if output.success => {}
+                Ok(output) => warn!(
+                    "Alert notify command {} failed: {}",
+                    command.path,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(err) => error!("Error running alert notify command {}: {err}", command.path),
+            }
+        }
+    }
+}
+
+/// Evaluate the rules on `clock` until the announcement queue closes, following the devices
+/// changed at runtime through `requests`.
+pub async fn run(
+    mut rules: Rules,
+    mut announce_rx: mpsc::Receiver<DeviceAnnouncement>,
+    mut requests: mpsc::Receiver<StateAnnouncement>,
+    notifiers: Notifiers,
+    stats: Arc<Stats>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut next_evaluation = clock.now() + EVALUATE_INTERVAL;
+    loop {
+        let evaluation_due = clock.sleep(next_evaluation.saturating_duration_since(clock.now()));
+        let alerts = tokio::select! {
+            announcement = announce_rx.recv() => match announcement {
+                Some(announcement) => {
+                    rules.observe(&announcement, clock.now());
+                    continue;
+                }
+                None => break,
+            },
+            Some(msg) = requests.recv() => match msg {
+                StateAnnouncement::DeviceChanged { name, device, .. } => {
+                    rules.device_changed(&name, device.as_deref(), clock.now())
+                }
+                _ => continue,
+            },
+            _ = evaluation_due => {
+                next_evaluation = clock.now() + EVALUATE_INTERVAL;
+                let (checks, failures) = stats.check_counts();
+                rules.evaluate(clock.now(), checks, failures)
+            }
+        };
+        for alert in alerts {
+            notifiers.notify(&alert).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DeviceType;
+
+    #[test]
+    fn test_rules() {
+        let config: crate::config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+
+            [alerts]
+            device_unseen_hours = 2
+            check_failure_rate = 0.5
+            window_minutes = 10
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Phone"
+            "#,
+        )
+        .unwrap();
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let mut rules = Rules::new(
+            config.alerts.as_ref().unwrap(),
+            config.devices.as_deref().unwrap(),
+            start,
+        );
+
+        assert!(rules.evaluate(at(10), 10, 1).is_empty());
+        let alerts = rules.evaluate(at(121), 20, 10);
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].rule, Rule::CheckFailures);
+        assert_eq!(alerts[1].rule, Rule::DeviceUnseen);
+        assert_eq!(alerts[1].device.as_deref(), Some("Phone"));
+        assert!(alerts.iter().all(|alert| alert.active));
+        // Only published when they change
+        assert!(rules.evaluate(at(125), 20, 10).is_empty());
+
+        rules.observe(
+            &DeviceAnnouncement {
+                name: "Phone".to_string(),
+                mac_address: "00:11:22:33:44:55".to_string(),
+                device_type: DeviceType::KnownMac,
+                presence: DevicePresence::Present(100),
+                transition: None,
+                advertisement: None,
+                reason: Some(crate::messages::Reason::NameRequestOk),
+                last_seen: None,
+                method: "hcitool",
+            },
+            at(130),
+        );
+        let alerts = rules.evaluate(at(140), 30, 10);
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts.iter().all(|alert| !alert.active));
+    }
+
+    fn announcement(name: &str, battery: Option<u8>) -> DeviceAnnouncement {
+        DeviceAnnouncement {
+            name: name.to_string(),
+            mac_address: "00:11:22:33:44:55".to_string(),
+            device_type: DeviceType::KnownMac,
+            presence: DevicePresence::Present(100),
+            transition: None,
+            advertisement: Some(crate::advertisements::Advertisement {
+                rssi: None,
+                tx_power: None,
+                address_type: None,
+                service_count: 1,
+                manufacturer_ids: Vec::new(),
+                battery,
+            }),
+            reason: Some(crate::messages::Reason::AdvertSeen),
+            last_seen: None,
+            method: "advertisement",
+        }
+    }
+
+    #[test]
+    fn test_battery_and_runtime_devices() {
+        let cfg = AlertsConfig {
+            device_unseen_hours: Some(1),
+            battery_below_percent: Some(20),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        let mut rules = Rules::new(&cfg, &[], start);
+
+        rules.observe(&announcement("Keys", Some(15)), at(0));
+        let tablet: BleDevice =
+            toml::de::from_str("address = \"00:11:22:33:44:66\"\nname = \"Tablet\"").unwrap();
+        assert!(
+            rules
+                .device_changed("Tablet", Some(&tablet), at(0))
+                .is_empty()
+        );
+        let alerts = rules.evaluate(at(61), 0, 0);
+        assert_eq!(
+            alerts
+                .iter()
+                .map(|alert| (alert.device.as_deref().unwrap(), alert.rule))
+                .collect::<Vec<_>>(),
+            [
+                ("Keys", Rule::DeviceUnseen),
+                ("Keys", Rule::BatteryLow),
+                ("Tablet", Rule::DeviceUnseen),
+            ]
+        );
+        assert_eq!(alerts[1].message, "Keys battery at 15%");
+
+        // Seen again, charged
+        rules.observe(&announcement("Keys", Some(80)), at(62));
+        let alerts = rules.evaluate(at(63), 0, 0);
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts.iter().all(|alert| !alert.active));
+
+        // Removing a device resolves its alerts
+        let alerts = rules.device_changed("Tablet", None, at(64));
+        assert_eq!(alerts.len(), 1);
+        assert!(!alerts[0].active);
+        assert!(rules.evaluate(at(65), 0, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let cfg = AlertsConfig {
+            device_unseen_hours: Some(1),
+            ..Default::default()
+        };
+        let clock = Arc::new(crate::clock::MockClock::new());
+        let devices =
+            [
+                toml::de::from_str::<BleDevice>(
+                    "address = \"00:11:22:33:44:55\"\nname = \"Phone\"",
+                )
+                .unwrap(),
+            ];
+        let rules = Rules::new(&cfg, &devices, clock.now());
+        let runner = Arc::new(crate::command::ScriptedRunner::stdout(""));
+        let notifiers = Notifiers {
+            mqtt_client: None,
+            command: Some(Program {
+                path: "notify".to_string(),
+                args: vec!["--channel".to_string(), "home".to_string()],
+            }),
+            runner: runner.clone(),
+        };
+        let (announce_tx, announce_rx) = mpsc::channel(16);
+        let (_requests_tx, requests_rx) = mpsc::channel(16);
+
+        tokio::join!(
+            run(
+                rules,
+                announce_rx,
+                requests_rx,
+                notifiers,
+                Arc::new(Stats::default()),
+                clock.clone(),
+            ),
+            async {
+                // Evaluated on the given clock
+                clock.advance(Duration::from_secs(3600));
+                tokio::task::yield_now().await;
+                assert!(runner.calls.lock().unwrap().is_empty());
+                clock.advance(Duration::from_secs(60));
+                while runner.calls.lock().unwrap().is_empty() {
+                    tokio::task::yield_now().await;
+                }
+                drop(announce_tx);
+            }
+        );
+        let calls = runner.calls.lock().unwrap();
+        assert_eq!(calls[0][..3], ["notify", "--channel", "home"]);
+        let alert: serde_json::Value = serde_json::from_str(&calls[0][3]).unwrap();
+        assert_eq!(alert["rule"], "device_unseen");
+        assert_eq!(alert["device"], "Phone");
+        assert_eq!(alert["active"], true);
+    }
+}
@@ -0,0 +1,242 @@
+//! `monitor-rs --bench <passes>`: run departure-style scan passes over every configured device
+//! and report how long each stage takes, to guide tuning of `scan.interscan_delay_seconds` and
+//! how many adapters to spread devices over.
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use rumqttc::{Event, Packet, QoS};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::{self, AppConfig};
+
+const MQTT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Durations of one stage across every pass.
+#[derive(Debug, Default)]
+struct Timings(Vec<Duration>);
+
+impl Timings {
+    fn record(&mut self, duration: Duration) {
+        self.0.push(duration);
+    }
+
+    /// The duration `percent` of the samples are at or below.
+    fn percentile(&self, percent: usize) -> Option<Duration> {
+        let mut sorted = self.0.clone();
+        sorted.sort();
+        let rank = (sorted.len() * percent).div_ceil(100).max(1);
+        sorted.get(rank - 1).copied()
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.percentile(0), self.percentile(100)) {
+            (Some(min), Some(max)) => write!(
+                f,
+                "n={:<5} min={min:<10.1?} p50={:<10.1?} p90={:<10.1?} p99={:<10.1?} max={max:.1?}",
+                self.0.len(),
+                self.percentile(50).unwrap_or_default(),
+                self.percentile(90).unwrap_or_default(),
+                self.percentile(99).unwrap_or_default(),
+            ),
+            _ => write!(f, "n=0"),
+        }
+    }
+}
+
+pub async fn run(config: &AppConfig, passes: u32) -> anyhow::Result<()> {
+    // Static devices are never checked
+    let devices = config
+        .devices
+        .clone()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|device| device.detect != Some(config::Detect::Static))
+        .collect::<Vec<_>>();
+    anyhow::ensure!(!devices.is_empty(), "No devices configured to benchmark");
+    let scan_config = config.scan.clone().unwrap_or_default();
+    let check_timeout = Duration::from_secs(
+        scan_config
+            .check_timeout_seconds
+            .unwrap_or(config::DEFAULT_CHECK_TIMEOUT_SECONDS),
+    );
+    let interscan_delay =
+        scan_config
+            .interscan_delay_seconds
+            .unwrap_or(config::InterscanDelay::fixed(
+                config::DEFAULT_INTERSCAN_DELAY_SECONDS,
+            ));
+
+    let adapter_config = config.adapter.clone().unwrap_or_default();
+    let adapters = crate::adapter::wait_for_adapters(&adapter_config).await?;
+    let (id, adapter) = adapters.first().context("No Bluetooth adapter available")?;
+    crate::adapter::ensure_powered(id, adapter, &adapter_config).await?;
+    // Uncached, every check has to reach the backend
+    let checker = crate::presence::new_checker(&scan_config, adapter, None)?;
+    let publisher = Publisher::connect(&config.mqtt).await?;
+
+    // Stand-in for the announcement queue between the scanners and the manager
+    let (tx, mut rx) = mpsc::unbounded_channel::<Instant>();
+    let (received_tx, received_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let mut received = Timings::default();
+        while let Some(sent) = rx.recv().await {
+            received.record(sent.elapsed());
+        }
+        let _ = received_tx.send(received);
+    });
+
+    println!(
+        "Benchmarking {passes} pass(es) over {} device(s) with the {:?} backend on {id}",
+        devices.len(),
+        scan_config.backend.clone().unwrap_or_default(),
+    );
+    let mut checks = Timings::default();
+    let mut pass_times = Timings::default();
+    let mut failures = 0;
+    for pass in 1..=passes {
+        let started = Instant::now();
+        for (i, device) in devices.iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(interscan_delay.pick()).await;
+            }
+            let address = device.address.to_string();
+            let checked = Instant::now();
+            let present =
+                match tokio::time::timeout(check_timeout, checker.is_present(&address)).await {
+                    Ok(Ok(present)) => present,
+                    Ok(Err(_)) | Err(_) => {
+                        failures += 1;
+                        false
+                    }
+                };
+            checks.record(checked.elapsed());
+            tx.send(Instant::now())?;
+            publisher.publish(&device.name, present).await?;
+        }
+        let elapsed = started.elapsed();
+        pass_times.record(elapsed);
+        println!("Pass {pass}/{passes} took {elapsed:.1?}");
+    }
+    drop(tx);
+    let channel = received_rx.await?;
+    let publishes = publisher.finish().await;
+
+    println!();
+    println!("presence checks  {checks}");
+    println!("channel sends    {channel}");
+    println!("mqtt publishes   {publishes}");
+    println!("full passes      {pass_times}");
+    if failures > 0 {
+        println!("{failures} check(s) failed or timed out after {check_timeout:?}");
+    }
+    println!("Passes include a {interscan_delay} interscan delay between devices, checks don't");
+    Ok(())
+}
+
+/// A separate MQTT client, so a running daemon isn't kicked off, that times publishes until the
+/// broker acknowledges them.
+struct Publisher {
+    client: rumqttc::AsyncClient,
+    topic: String,
+    /// When each unacknowledged publish was sent. QoS 1 acknowledgements arrive in order.
+    sent: Arc<Mutex<VecDeque<Instant>>>,
+    acknowledged: tokio::task::JoinHandle<Timings>,
+}
+
+impl Publisher {
+    async fn connect(cfg: &config::MqttConfig) -> anyhow::Result<Self> {
+        let publisher_id = cfg.publisher_id.clone().unwrap_or_else(config::hostname);
+        let options = crate::mqtt::mqtt_options(cfg, format!("{publisher_id}-bench"));
+        let (client, mut eventloop) = rumqttc::AsyncClient::new(options, 100);
+        tokio::time::timeout(MQTT_CONNECT_TIMEOUT, async {
+            loop {
+                if let Event::Incoming(Packet::ConnAck(_)) = eventloop.poll().await? {
+                    return anyhow::Ok(());
+                }
+            }
+        })
+        .await
+        .with_context(|| format!("Timed out connecting to MQTT broker {}", cfg.host))??;
+
+        let sent = Arc::new(Mutex::new(VecDeque::<Instant>::new()));
+        let acknowledged = tokio::spawn({
+            let sent = sent.clone();
+            async move {
+                let mut acknowledged = Timings::default();
+                while let Ok(event) = eventloop.poll().await {
+                    if let Event::Incoming(Packet::PubAck(_)) = event
+                        && let Some(sent) = sent.lock().expect("bench lock poisoned").pop_front()
+                    {
+                        acknowledged.record(sent.elapsed());
+                    }
+                }
+                acknowledged
+            }
+        });
+        Ok(Publisher {
+            client,
+            topic: format!(
+                "{}/{publisher_id}/bench",
+                cfg.topic_path
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_TOPIC_PATH)
+            ),
+            sent,
+            acknowledged,
+        })
+    }
+
+    async fn publish(&self, name: &str, present: bool) -> anyhow::Result<()> {
+        self.sent
+            .lock()
+            .expect("bench lock poisoned")
+            .push_back(Instant::now());
+        self.client
+            .publish(
+                &self.topic,
+                QoS::AtLeastOnce,
+                false,
+                serde_json::json!({ "name": name, "present": present }).to_string(),
+            )
+            .await
+            .context("Failed to publish MQTT message")
+    }
+
+    /// Wait for outstanding acknowledgements, then disconnect.
+    async fn finish(self) -> Timings {
+        for _ in 0..10 {
+            if self.sent.lock().expect("bench lock poisoned").is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let _ = self.client.disconnect().await;
+        // The event loop stops once the connection closes or every client handle is gone
+        drop(self.client);
+        self.acknowledged.await.unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let mut timings = Timings::default();
+        assert_eq!(timings.percentile(50), None);
+        assert_eq!(timings.to_string(), "n=0");
+        for millis in (1..=100).rev() {
+            timings.record(Duration::from_millis(millis));
+        }
+        assert_eq!(timings.percentile(0), Some(Duration::from_millis(1)));
+        assert_eq!(timings.percentile(50), Some(Duration::from_millis(50)));
+        assert_eq!(timings.percentile(99), Some(Duration::from_millis(99)));
+        assert_eq!(timings.percentile(100), Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,83 @@
+//! BlueZ keeps discovered devices around, which can make name lookups succeed long after a device
+//! left. Removing the cached device object makes the next check start from scratch.
+use futures::future::BoxFuture;
+
+#[cfg(target_os = "linux")]
+pub use bluez::BluezCache;
+
+pub trait DeviceCache: Send + Sync {
+    /// Forget everything cached about the device. Succeeds if nothing was cached.
+    fn forget<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+#[cfg(target_os = "linux")]
+mod bluez {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::Context as _;
+    use dbus::nonblock::stdintf::org_freedesktop_dbus::ObjectManager as _;
+    use dbus::nonblock::{Proxy, SyncConnection};
+    use futures::future::BoxFuture;
+    use log::{debug, error};
+    use tokio::sync::OnceCell;
+
+    use super::DeviceCache;
+
+    const DBUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Removes devices with `org.bluez.Adapter1.RemoveDevice`, from every adapter that knows
+    /// about them.
+    #[derive(Default)]
+    pub struct BluezCache {
+        /// Connected to the system bus on first use
+        connection: OnceCell<Arc<SyncConnection>>,
+    }
+
+    impl BluezCache {
+        async fn connection(&self) -> anyhow::Result<&Arc<SyncConnection>> {
+            self.connection
+                .get_or_try_init(|| async {
+                    let (resource, connection) = dbus_tokio::connection::new_system_sync()
+                        .context("connect to the system bus")?;
+                    tokio::spawn(async {
+                        let err = resource.await;
+                        error!("Lost D-Bus connection: {err}");
+                    });
+                    Ok(connection)
+                })
+                .await
+        }
+    }
+
+    impl DeviceCache for BluezCache {
+        fn forget<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            Box::pin(async move {
+                let connection = self.connection().await?;
+                let objects = Proxy::new("org.bluez", "/", DBUS_TIMEOUT, connection.clone())
+                    .get_managed_objects()
+                    .await
+                    .context("list BlueZ objects")?;
+
+                // Devices live at /org/bluez/<adapter>/dev_AA_BB_CC_DD_EE_FF
+                let suffix = format!("/dev_{}", mac_address.to_uppercase().replace(':', "_"));
+                for (path, interfaces) in &objects {
+                    if !path.ends_with(&suffix) || !interfaces.contains_key("org.bluez.Device1") {
+                        continue;
+                    }
+                    let adapter = &path[..path.len() - suffix.len()];
+                    debug!("Removing {mac_address} from the BlueZ cache of {adapter}");
+                    Proxy::new("org.bluez", adapter, DBUS_TIMEOUT, connection.clone())
+                        .method_call::<(), _, _, _>(
+                            "org.bluez.Adapter1",
+                            "RemoveDevice",
+                            (path.clone(),),
+                        )
+                        .await
+                        .with_context(|| format!("remove {path}"))?;
+                }
+                Ok(())
+            })
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Internal message bus between the daemon's tasks.
+//!
+//! Producers send into a single queue and the manager runs a router that copies every message
+//! into a dedicated queue per consumer. Every queue holds up to `scan.queue_capacity` messages,
+//! and a consumer that falls that far behind (e.g. a scanner stuck in a slow pass) holds the
+//! router, and so the producers, back until it catches up. Only frequent messages marked as
+//! coalesced (advertisement triggers) skip a consumer that is behind, since it has work queued.
+use log::debug;
+use tokio::sync::mpsc;
+
+pub struct Bus<T> {
+    /// Named in logs, e.g. "request"
+    name: &'static str,
+    capacity: usize,
+    coalesced: Option<fn(&T) -> bool>,
+    tx: mpsc::Sender<T>,
+    rx: mpsc::Receiver<T>,
+    consumers: Vec<mpsc::Sender<T>>,
+}
+
+impl<T: Clone> Bus<T> {
+    /// A bus whose queues each hold up to `capacity` messages (at least one).
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        let (tx, rx) = mpsc::channel(capacity);
+        Bus {
+            name,
+            capacity,
+            coalesced: None,
+            tx,
+            rx,
+            consumers: Vec::new(),
+        }
+    }
+
+    /// Skip consumers whose queue is full for messages matching `coalesced`, instead of waiting
+    /// for them.
+    pub fn with_coalesced(mut self, coalesced: fn(&T) -> bool) -> Self {
+        self.coalesced = Some(coalesced);
+        self
+    }
+
+    /// Handle for sending onto the bus, which sees every message once the router is running.
+    pub fn sender(&self) -> mpsc::Sender<T> {
+        self.tx.clone()
+    }
+
+    /// A queue receiving every message sent from now on.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<T> {
+        let (tx, rx) = mpsc::channel(self.capacity);
+        self.consumers.push(tx);
+        rx
+    }
+
+    /// Deliver messages to every consumer until all senders are gone, then close the consumers'
+    /// queues.
+    pub async fn route(self) {
+        let Bus {
+            name,
+            coalesced,
+            tx,
+            mut rx,
+            mut consumers,
+            ..
+        } = self;
+        drop(tx);
+        while let Some(msg) = rx.recv().await {
+            let coalesce = coalesced.is_some_and(|coalesced| coalesced(&msg));
+            let mut open = Vec::with_capacity(consumers.len());
+            for consumer in consumers {
+                let delivered = if coalesce {
+                    match consumer.try_send(msg.clone()) {
+                        Err(mpsc::error::TrySendError::Closed(_)) => false,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            debug!("A {name} consumer is behind, coalescing a message for it");
+                            true
+                        }
+                        Ok(()) => true,
+                    }
+                } else {
+                    consumer.send(msg.clone()).await.is_ok()
+                };
+                if delivered {
+                    open.push(consumer);
+                }
+            }
+            consumers = open;
+        }
+    }
+}
+
+/// Send a coalesced message without waiting, for producers that can't: a full queue means the
+/// consumers are behind with work already queued, so it's skipped.
+pub fn send_coalesced<T>(tx: &mpsc::Sender<T>, msg: T) -> Result<(), mpsc::error::SendError<T>> {
+    match tx.try_send(msg) {
+        Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+        Err(mpsc::error::TrySendError::Closed(msg)) => Err(mpsc::error::SendError(msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_route() {
+        let mut bus = Bus::new("test", 1000);
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+        let tx = bus.sender();
+        let router = tokio::task::spawn(bus.route());
+
+        for i in 0..1000 {
+            tx.send(i).await.unwrap();
+        }
+        drop(tx);
+        router.await.unwrap();
+
+        for consumer in [&mut first, &mut second] {
+            for i in 0..1000 {
+                assert_eq!(consumer.recv().await, Some(i));
+            }
+            assert_eq!(consumer.recv().await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer() {
+        let mut bus = Bus::new("test", 2);
+        let mut slow = bus.subscribe();
+        let mut fast = bus.subscribe();
+        let tx = bus.sender();
+        let router = tokio::task::spawn(bus.route());
+        let producer = tokio::task::spawn(async move {
+            for i in 0..10 {
+                tx.send(i).await.unwrap();
+            }
+        });
+
+        // Nobody reads the slow queue yet, so the producer is held back
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!producer.is_finished());
+
+        for i in 0..10 {
+            assert_eq!(slow.recv().await, Some(i));
+            assert_eq!(fast.recv().await, Some(i));
+        }
+        producer.await.unwrap();
+        router.await.unwrap();
+        assert_eq!(slow.recv().await, None);
+        assert_eq!(fast.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_coalesced() {
+        let mut bus = Bus::new("test", 1).with_coalesced(|msg: &i32| *msg < 0);
+        let mut consumer = bus.subscribe();
+        let tx = bus.sender();
+        let router = tokio::task::spawn(bus.route());
+
+        tx.send(1).await.unwrap();
+        tx.send(-1).await.unwrap();
+        send_coalesced(&tx, -2).unwrap();
+        drop(tx);
+        // Finishes without waiting for the consumer to make room for the coalesced messages
+        router.await.unwrap();
+        assert_eq!(consumer.recv().await, Some(1));
+        assert_eq!(consumer.recv().await, None);
+    }
+}
@@ -0,0 +1,250 @@
+//! `monitor-rs cleanup-discovery` and the `cleanup_discovery` command: clear the retained Home
+//! Assistant discovery configs and states a node published that it no longer publishes, e.g. for
+//! renamed or removed devices, so they don't linger in Home Assistant as ghost entities.
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context as _;
+use log::info;
+use rumqttc::{Event, Packet, QoS};
+
+use crate::config::{self, AppConfig};
+use crate::mqtt::MqttClient;
+use crate::stats::Stats;
+
+/// How long to collect retained messages after subscribing. The broker sends them right away.
+const COLLECT_WINDOW: Duration = Duration::from_secs(2);
+const MQTT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to look for retained topics and how to connect to clear them.
+#[derive(Debug, Clone)]
+pub struct Cleanup {
+    mqtt: config::MqttConfig,
+    client_id: String,
+    topic_path: String,
+    discovery_prefix: String,
+}
+
+impl Cleanup {
+    pub fn new(app_config: &AppConfig) -> Self {
+        let mqtt = app_config.mqtt.clone();
+        Cleanup {
+            client_id: format!(
+                "{}-cleanup",
+                mqtt.publisher_id.clone().unwrap_or_else(config::hostname)
+            ),
+            topic_path: mqtt
+                .topic_path
+                .clone()
+                .unwrap_or_else(|| config::DEFAULT_TOPIC_PATH.to_string()),
+            // Also looked through once discovery is turned off, that's when its configs linger
+            discovery_prefix: app_config
+                .homeassistant
+                .as_ref()
+                .and_then(|ha| ha.discovery_prefix.clone())
+                .unwrap_or_else(|| config::DEFAULT_DISCOVERY_PREFIX.to_string()),
+            mqtt,
+        }
+    }
+
+    /// Filters matching every retained topic published for `publisher_id`.
+    fn filters(&self, publisher_id: &str) -> [String; 2] {
+        [
+            format!("{}/+/{publisher_id}/+/config", self.discovery_prefix),
+            format!("{}/{publisher_id}/#", self.topic_path),
+        ]
+    }
+
+    /// Clear the retained topics published for `publisher_id` other than those in `keep`,
+    /// connecting as a separate client so a running daemon isn't kicked off. Returns the topics
+    /// cleared.
+    pub async fn run(
+        &self,
+        publisher_id: &str,
+        keep: &HashSet<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        let options = crate::mqtt::mqtt_options(&self.mqtt, self.client_id.clone());
+        let (client, mut eventloop) = rumqttc::AsyncClient::new(options, 10);
+        let filters = self.filters(publisher_id);
+
+        let cleanup = async {
+            let mut stale = Vec::new();
+            let mut deadline = None;
+            loop {
+                let event = match deadline {
+                    Some(deadline) => {
+                        match tokio::time::timeout_at(deadline, eventloop.poll()).await {
+                            Ok(event) => event?,
+                            Err(_) => break,
+                        }
+                    }
+                    None => eventloop.poll().await?,
+                };
+                match event {
+                    Event::Incoming(Packet::ConnAck(_)) => {
+                        for filter in &filters {
+                            client.subscribe(filter, QoS::AtMostOnce).await?;
+                        }
+                    }
+                    Event::Incoming(Packet::SubAck(_)) if deadline.is_none() => {
+                        deadline = Some(tokio::time::Instant::now() + COLLECT_WINDOW);
+                    }
+                    Event::Incoming(Packet::Publish(publish))
+                        if is_stale(&publish, keep) && !stale.contains(&publish.topic) =>
+                    {
+                        stale.push(publish.topic);
+                    }
+                    _ => {}
+                }
+            }
+            stale.sort();
+            // The client's request channel only drains while the event loop is polled, so keep
+            // polling while queueing more requests than it holds
+            {
+                let clear = async {
+                    for filter in &filters {
+                        client.unsubscribe(filter).await?;
+                    }
+                    for topic in &stale {
+                        client.publish(topic, QoS::AtLeastOnce, true, "").await?;
+                    }
+                    anyhow::Ok(())
+                };
+                tokio::pin!(clear);
+                let mut queued = false;
+                let mut pending = stale.len();
+                while !queued || pending > 0 {
+                    tokio::select! {
+                        result = &mut clear, if !queued => {
+                            result?;
+                            queued = true;
+                        }
+                        event = eventloop.poll() => {
+                            if let Event::Incoming(Packet::PubAck(_)) = event? {
+                                pending = pending.saturating_sub(1);
+                            }
+                        }
+                    }
+                }
+            }
+            anyhow::Ok(stale)
+        };
+        let result = tokio::time::timeout(MQTT_TIMEOUT, cleanup)
+            .await
+            .with_context(|| format!("Cleanup didn't finish within {MQTT_TIMEOUT:?}"))?;
+        let _ = client.disconnect().await;
+        result
+    }
+}
+
+/// Whether a retained message is left over: the broker only sends the retain flag for messages
+/// it kept, and an empty payload is one already cleared.
+fn is_stale(publish: &rumqttc::Publish, keep: &HashSet<String>) -> bool {
+    publish.retain && !publish.payload.is_empty() && !keep.contains(&publish.topic)
+}
+
+/// Clear this node's stale retained topics, or with `all` every one of them. Another node's are
+/// cleared entirely, e.g. after renaming this one.
+pub async fn run(config: &AppConfig, publisher_id: Option<&str>, all: bool) -> anyhow::Result<()> {
+    let (client, _eventloop) = MqttClient::new(config, Arc::new(Stats::default()));
+    let own = publisher_id.is_none_or(|id| id == client.publisher_id());
+    let publisher_id = publisher_id.unwrap_or(client.publisher_id());
+    let keep = if own && !all {
+        client.retained_topics()
+    } else {
+        HashSet::new()
+    };
+
+    info!("Looking for retained topics published by {publisher_id}");
+    let removed = Cleanup::new(config).run(publisher_id, &keep).await?;
+    for topic in &removed {
+        println!("Cleared {topic}");
+    }
+    println!("Cleared {} retained topic(s)", removed.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_and_staleness() {
+        let config: AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+            publisher_id = "kitchen"
+
+            [homeassistant]
+            discovery_prefix = "ha"
+            "#,
+        )
+        .unwrap();
+        let cleanup = Cleanup::new(&config);
+        assert_eq!(cleanup.client_id, "kitchen-cleanup");
+        assert_eq!(
+            cleanup.filters("hallway"),
+            ["ha/+/hallway/+/config", "monitor/hallway/#"]
+        );
+
+        let keep = HashSet::from(["monitor/kitchen/devices".to_string()]);
+        let retained = |topic: &str, payload: &'static str| {
+            let mut publish = rumqttc::Publish::new(topic, QoS::AtLeastOnce, payload);
+            publish.retain = true;
+            publish
+        };
+        assert!(is_stale(
+            &retained("monitor/kitchen/occupancy", "ON"),
+            &keep
+        ));
+        assert!(!is_stale(&retained("monitor/kitchen/devices", "[]"), &keep));
+        // Already cleared
+        assert!(!is_stale(&retained("monitor/kitchen/occupancy", ""), &keep));
+        // Live device states aren't retained
+        let live = rumqttc::Publish::new("monitor/kitchen/phone", QoS::AtMostOnce, "{}");
+        assert!(!is_stale(&live, &keep));
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // More than the client's request channel holds
+        let retained = (0..20)
+            .map(|i| {
+                let mut publish = rumqttc::Publish::new(
+                    format!("monitor/hallway/device{i:02}"),
+                    QoS::AtMostOnce,
+                    "{}",
+                );
+                publish.retain = true;
+                publish
+            })
+            .collect();
+        let (publish_tx, mut publish_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(crate::mqtt::fake_broker(listener, retained, publish_tx));
+
+        let config: AppConfig = toml::de::from_str(&format!(
+            r#"
+            [mqtt]
+            host = "127.0.0.1"
+            port = {port}
+            publisher_id = "kitchen"
+            "#
+        ))
+        .unwrap();
+        let cleared = Cleanup::new(&config)
+            .run("hallway", &HashSet::new())
+            .await
+            .unwrap();
+        assert_eq!(cleared.len(), 20);
+        assert_eq!(cleared[0], "monitor/hallway/device00");
+        for topic in &cleared {
+            let publish = publish_rx.recv().await.unwrap();
+            assert_eq!(&publish.topic, topic);
+            assert!(publish.retain && publish.payload.is_empty());
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+
+/// Source of time for the scanner, so debounce and timeout logic can be tested without waiting
+/// on the real clock.
+pub trait Clock: Send + Sync {
+    /// Monotonic time, used for debouncing and timeouts. Wall-clock time is only needed for
+    /// published timestamps.
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Simulated clock for tests: time only moves when advanced, waking any sleepers whose deadline
+/// has passed.
+#[cfg(test)]
+pub struct MockClock {
+    now: tokio::sync::watch::Sender<Instant>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now: tokio::sync::watch::Sender::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = self.now() + duration;
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            let _ = now.wait_for(|now| *now >= deadline).await;
+        })
+    }
+}
@@ -0,0 +1,117 @@
+//! Indirection over spawning external processes, so the presence backends that shell out to
+//! BlueZ utilities or a user's own command can be tested against scripted output.
+use futures::future::BoxFuture;
+
+use crate::config::ExternalCommand;
+
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub success: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// An external binary, along with arguments from the config to pass before the caller's own.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub path: String,
+    pub args: Vec<String>,
+}
+
+impl Program {
+    pub fn new(cfg: Option<&ExternalCommand>, default_path: &str) -> Self {
+        Program {
+            path: cfg
+                .and_then(|cfg| cfg.path.clone())
+                .unwrap_or_else(|| default_path.to_string()),
+            args: cfg.and_then(|cfg| cfg.args.clone()).unwrap_or_default(),
+        }
+    }
+
+    pub fn run<'a>(
+        &'a self,
+        runner: &'a dyn CommandRunner,
+        args: &'a [&'a str],
+    ) -> BoxFuture<'a, std::io::Result<CommandOutput>> {
+        Box::pin(async move {
+            let mut all_args = self.args.iter().map(String::as_str).collect::<Vec<_>>();
+            all_args.extend(args);
+            runner.run(&self.path, &all_args).await
+        })
+    }
+}
+
+pub trait CommandRunner: Send + Sync {
+    fn run<'a>(
+        &'a self,
+        program: &'a str,
+        args: &'a [&'a str],
+    ) -> BoxFuture<'a, std::io::Result<CommandOutput>>;
+}
+
+/// Runs commands with `tokio::process`. The child is killed if the returned future is dropped,
+/// e.g. when a presence check times out.
+pub struct TokioCommandRunner;
+
+impl CommandRunner for TokioCommandRunner {
+    fn run<'a>(
+        &'a self,
+        program: &'a str,
+        args: &'a [&'a str],
+    ) -> BoxFuture<'a, std::io::Result<CommandOutput>> {
+        Box::pin(async move {
+            let output = tokio::process::Command::new(program)
+                .args(args)
+                .kill_on_drop(true)
+                .output()
+                .await?;
+            Ok(CommandOutput {
+                success: output.status.success(),
+                stdout: output.stdout,
+                stderr: output.stderr,
+            })
+        })
+    }
+}
+
+/// Replays a fixed result for every command, or never completes if `None`.
+#[cfg(test)]
+pub struct ScriptedRunner {
+    pub output: Option<CommandOutput>,
+    pub calls: std::sync::Mutex<Vec<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl ScriptedRunner {
+    pub fn new(output: Option<CommandOutput>) -> Self {
+        ScriptedRunner {
+            output,
+            calls: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn stdout(stdout: &str) -> Self {
+        Self::new(Some(CommandOutput {
+            success: true,
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+        }))
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for ScriptedRunner {
+    fn run<'a>(
+        &'a self,
+        program: &'a str,
+        args: &'a [&'a str],
+    ) -> BoxFuture<'a, std::io::Result<CommandOutput>> {
+        let mut call = vec![program.to_string()];
+        call.extend(args.iter().map(|arg| arg.to_string()));
+        self.calls.lock().unwrap().push(call);
+        match &self.output {
+            Some(output) => Box::pin(std::future::ready(Ok(output.clone()))),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+//! Names for Bluetooth SIG company identifiers commonly seen in manufacturer data, from
+//! https://bitbucket.org/bluetooth-SIG/public/src/main/assigned_numbers/company_identifiers/company_identifiers.yaml
+//!
+//! This is a subset covering the phones, wearables, tags and chipsets typically tracked for
+//! presence rather than the full registry.
+
+const COMPANY_IDS: &[(u16, &str)] = &[
+    (0x0000, "Ericsson Technology Licensing"),
+    (0x0001, "Nokia Mobile Phones"),
+    (0x0002, "Intel Corp."),
+    (0x0003, "IBM Corp."),
+    (0x0004, "Toshiba Corp."),
+    (0x0006, "Microsoft"),
+    (0x0008, "Motorola"),
+    (0x000A, "Qualcomm Technologies International, Ltd. (QTIL)"),
+    (0x000D, "Texas Instruments Inc."),
+    (0x000F, "Broadcom Corporation"),
+    (0x001D, "Qualcomm"),
+    (0x0030, "ST Microelectronics"),
+    (0x0046, "MediaTek, Inc."),
+    (0x004C, "Apple, Inc."),
+    (0x0059, "Nordic Semiconductor ASA"),
+    (0x005D, "Realtek Semiconductor Corporation"),
+    (0x0075, "Samsung Electronics Co. Ltd."),
+    (0x0078, "Nike, Inc."),
+    (0x0087, "Garmin International, Inc."),
+    (0x009E, "Bose Corporation"),
+    (0x00E0, "Google"),
+    (0x012D, "Sony Corporation"),
+    (0x0157, "Anhui Huami Information Technology Co., Ltd."),
+    (0x0171, "Amazon.com Services LLC"),
+    (0x018E, "Google LLC"),
+    (0x027D, "HUAWEI Technologies Co., Ltd."),
+    (0x02E5, "Espressif Systems (Shanghai) Co., Ltd."),
+    (0x038F, "Xiaomi Inc."),
+    (0x0499, "Ruuvi Innovations Ltd."),
+    (0x067C, "Tile, Inc."),
+];
+
+pub fn company_name(id: u16) -> Option<&'static str> {
+    COMPANY_IDS
+        .binary_search_by_key(&id, |(company_id, _)| *company_id)
+        .ok()
+        .map(|index| COMPANY_IDS[index].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_company_name() {
+        assert!(COMPANY_IDS.is_sorted_by_key(|(id, _)| *id));
+        assert_eq!(company_name(0x004C), Some("Apple, Inc."));
+        assert_eq!(company_name(0xFFFF), None);
+    }
+}
@@ -1,14 +1,156 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::NonZeroU16;
+use std::path::PathBuf;
+
+use anyhow::Context as _;
 use mac_address::MacAddress;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+/// Used in place of the hostname when it can't be determined.
+pub const DEFAULT_PUBLISHER_ID: &str = "monitor-rs";
+pub const DEFAULT_TOPIC_PATH: &str = "monitor";
+pub const DEFAULT_KEEP_ALIVE_SECONDS: u64 = 15;
+pub const DEFAULT_MQTT_REQUEST_CHANNEL_CAPACITY: usize = 10;
+pub const DEFAULT_MQTT_MAX_PACKET_SIZE: usize = 10 * 1024;
+pub const DEFAULT_DEVICE_TRACKER_RETAIN: bool = true;
+pub const DEFAULT_DEVICE_TRACKER_PAYLOAD_HOME: &str = "home";
+pub const DEFAULT_DEVICE_TRACKER_PAYLOAD_NOT_HOME: &str = "not_home";
+pub const DEFAULT_MQTT_INFLIGHT: NonZeroU16 = NonZeroU16::new(100).unwrap();
+pub const DEFAULT_MQTT_PENDING_THROTTLE_MILLIS: u64 = 0;
+pub const DEFAULT_EXIT_ON_AUTH_FAILURE: bool = true;
+pub const DEFAULT_STATS_INTERVAL_SECONDS: u64 = 60;
+pub const DEFAULT_LEARN_WINDOW_SECONDS: u64 = 60;
+
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+pub const DEFAULT_HTTP_LISTEN: &str = "127.0.0.1:8080";
+
+pub const DEFAULT_ALERT_WINDOW_MINUTES: u64 = 15;
+pub const DEFAULT_ALERTS_PUBLISH: bool = true;
+
+pub const DEFAULT_LOG_MAX_SIZE_MB: u64 = 10;
+pub const DEFAULT_LOG_KEEP_FILES: usize = 5;
+
+pub const DEFAULT_ELECTION_HEARTBEAT_SECONDS: u64 = 5;
+pub const DEFAULT_ELECTION_TIMEOUT_SECONDS: u64 = 15;
 
-#[derive(Deserialize, Debug, Clone)]
+pub const DEFAULT_LISTEN_FOR_DISCOVERY: bool = true;
+pub const DEFAULT_PRESENCE_TIMEOUT_SECONDS: u64 = 120;
+pub const DEFAULT_DEVICE_SEEN_DEBOUNCE_SECONDS: u64 = 60;
+pub const DEFAULT_DEVICE_TRIGGER_DEBOUNCE_SECONDS: u64 = 120;
+pub const DEFAULT_INTERSCAN_DELAY_SECONDS: u64 = 5;
+pub const DEFAULT_CHECK_TIMEOUT_SECONDS: u64 = 30;
+pub const DEFAULT_RESULT_CACHE_MILLIS: u64 = 2000;
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+pub const DEFAULT_HCITOOL_PATH: &str = "hcitool";
+pub const DEFAULT_L2PING_PATH: &str = "l2ping";
+
+pub const DEFAULT_ADAPTER_WAIT_SECONDS: u64 = 60;
+pub const DEFAULT_ADAPTER_MAX_RETRY_INTERVAL_SECONDS: u64 = 10;
+
+pub const DEFAULT_CONFIDENCE_MAX: u8 = 100;
+pub const DEFAULT_CONFIDENCE_STEP_UP: u8 = 100;
+pub const DEFAULT_CONFIDENCE_STEP_DOWN: u8 = 100;
+pub const DEFAULT_CONFIDENCE_ABSENT_THRESHOLD: u8 = 0;
+pub const DEFAULT_ARRIVAL_CONFIRMATIONS: u32 = 1;
+pub const DEFAULT_DEPART_FAILURES_REQUIRED: u32 = 1;
+
+pub const DEFAULT_RECOVERY_WARN_AFTER_ERRORS: u32 = 2;
+pub const DEFAULT_RECOVERY_RESTART_ADAPTER_AFTER_ERRORS: u32 = 5;
+pub const DEFAULT_RECOVERY_EXIT_AFTER_ERRORS: u32 = 10;
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AppConfig {
     pub mqtt: MqttConfig,
     pub devices: Option<Vec<BleDevice>>,
     pub scan: Option<ScanConfig>,
+    /// Publish Home Assistant MQTT discovery configs when present
+    pub homeassistant: Option<HomeAssistantConfig>,
+    pub adapter: Option<AdapterConfig>,
+    /// Serve the web UI and HTTP API when present, requires the `http` feature
+    pub http: Option<HttpConfig>,
+    /// Elect one node on the broker to do broker-wide duties when present
+    pub election: Option<ElectionConfig>,
+    /// Log to a rotating file instead of stderr when a file is set
+    pub logging: Option<LoggingConfig>,
+    /// Check alert rules and report them when present
+    pub alerts: Option<AlertsConfig>,
+}
+
+/// Rules are only checked when set.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct AlertsConfig {
+    /// Alert when a device hasn't been present for this long
+    pub device_unseen_hours: Option<u64>,
+    /// Alert when more than this share (0 to 1) of presence checks fail within the window
+    pub check_failure_rate: Option<f64>,
+    /// Window the check failure rate is measured over
+    pub window_minutes: Option<u64>,
+    /// Alert when a device advertises a battery level below this percentage
+    pub battery_below_percent: Option<u8>,
+    /// Publish alerts to `<topic_path>/<publisher_id>/alerts`
+    pub publish: Option<bool>,
+    /// Run this command for every alert, with the alert as JSON after its `args`
+    pub notify_command: Option<ExternalCommand>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct LoggingConfig {
+    /// Overridden by `--log-file`
+    pub file: Option<PathBuf>,
+    /// When to start a new file
+    pub rotation: Option<Rotation>,
+    /// With size rotation, start a new file before the current one grows past this
+    pub max_size_mb: Option<u64>,
+    /// Rotated files kept next to the current one, `<file>.1` being the newest
+    pub keep_files: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Size,
+    Hourly,
+    Daily,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct ElectionConfig {
+    /// How often to tell the other nodes this one is alive
+    pub heartbeat_seconds: Option<u64>,
+    /// Nodes not heard from for this long are considered gone
+    pub timeout_seconds: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct HttpConfig {
+    /// Address to listen on, e.g. `0.0.0.0:8080` to allow other hosts
+    pub listen: Option<String>,
+    /// Bearer token required to add, edit or remove devices. Required unless `listen` is a
+    /// loopback address, so other hosts can't change what's tracked
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct AdapterConfig {
+    /// How long to wait for a Bluetooth adapter to appear at startup, 0 to fail immediately
+    pub wait_seconds: Option<u64>,
+    /// Upper bound for the doubling delay between adapter lookups
+    pub max_retry_interval_seconds: Option<u64>,
+    /// Adapters to use, e.g. `["hci0", "hci1"]`. Defaults to the first adapter found
+    pub ids: Option<Vec<String>>,
+    /// Adapter for each room's devices, unless a device sets its own `adapter`
+    pub rooms: Option<BTreeMap<String, String>>,
+    /// Scenario replayed by fake adapters instead of using real ones, requires the `mock` feature
+    pub mock_scenario: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct MqttConfig {
     pub host: String,
     pub port: Option<u16>,
@@ -17,15 +159,146 @@ pub struct MqttConfig {
     pub publisher_id: Option<String>,
     pub topic_path: Option<String>,
     pub keep_alive_seconds: Option<u64>,
+    /// How often to publish node statistics, 0 to disable
+    pub stats_interval_seconds: Option<u64>,
+    /// Delay scanning at startup until the broker has been reached, for up to this long
+    pub wait_for_broker_seconds: Option<u64>,
+    /// Exit with an error when the broker rejects our credentials, instead of retrying forever
+    pub exit_on_auth_failure: Option<bool>,
+    /// Outgoing requests buffered before publishes start waiting on the event loop
+    pub request_channel_capacity: Option<usize>,
+    /// Largest packet, in bytes, that will be sent or accepted
+    pub max_packet_size: Option<usize>,
+    /// Maximum number of unacknowledged QoS 1/2 publishes
+    pub inflight: Option<NonZeroU16>,
+    /// Delay between resending pending requests after a reconnect
+    pub pending_throttle_millis: Option<u64>,
+    /// IANA time zone (e.g. `America/Los_Angeles`) for payload timestamps, local time if unset
+    pub timezone: Option<chrono_tz::Tz>,
+    /// Encoding of device state payloads
+    pub payload_encoding: Option<PayloadEncoding>,
+    /// JSON type of `confidence` in device state payloads
+    pub confidence_format: Option<ConfidenceFormat>,
+    /// What happens to this node's retained states when it shuts down
+    pub retained_on_shutdown: Option<RetainedOnShutdown>,
+    /// How device names become topic segments
+    pub topic_names: Option<TopicNamesConfig>,
+    /// Publisher ids of other nodes whose device states are merged into this node's
+    /// `anyone_home` and per-device `presence` topics
+    pub listen_nodes: Option<Vec<String>>,
+    /// `home` / `not_home` states for Home Assistant's MQTT device tracker
+    pub device_tracker: Option<DeviceTrackerConfig>,
+}
+
+/// A plain `home` / `not_home` state for each device on `<device topic>/device_tracker`, which
+/// Home Assistant's MQTT device tracker understands without a value template.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct DeviceTrackerConfig {
+    pub enabled: Option<bool>,
+    /// Retain the state, so it's known right away after Home Assistant restarts
+    pub retain: Option<bool>,
+    pub payload_home: Option<String>,
+    pub payload_not_home: Option<String>,
+}
+
+/// How device names become topic segments. By default every character other than a letter or a
+/// digit becomes an underscore, as monitor.sh did.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+pub struct TopicNamesConfig {
+    /// Keep dashes rather than turning them into underscores
+    pub preserve_dashes: Option<bool>,
+    /// Collapse runs of underscores into one and trim them from both ends
+    pub collapse_underscores: Option<bool>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Binary encodings carry the same fields as JSON, for bandwidth-constrained brokers or embedded
+/// consumers.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Json,
+    Cbor,
+    Msgpack,
+}
+
+/// monitor.sh published `"confidence": "100"`, so templates written for it compare strings.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfidenceFormat {
+    #[default]
+    Number,
+    String,
+}
+
+/// `bridge/state` always becomes `offline` on a graceful shutdown, this covers the rest.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RetainedOnShutdown {
+    /// Leave them as they are
+    #[default]
+    Keep,
+    /// Delete them from the broker
+    Clear,
+    /// Mark the room unoccupied, the merged presence of listened nodes `OFF` and device trackers
+    /// `payload_not_home`
+    Off,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct HomeAssistantConfig {
+    pub discovery_prefix: Option<String>,
+    /// Room this node is in, publishes an occupancy `binary_sensor` for it
+    pub room: Option<String>,
+}
+
+/// How a device was identified, published as the `type` field of device payloads.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum DeviceType {
+    /// Confirmed by an active request to its known address
+    KnownMac,
+    /// Seen advertising
+    Beacon,
+    /// Matched by its advertised name
+    NameMatch,
+    /// Not a permanent member of the household
+    Guest,
+    /// Never checked, its presence is set by config or command
+    Static,
+}
+
+/// How a device's presence is decided.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Detect {
+    /// Checked with the configured presence backend
+    #[default]
+    Check,
+    /// Present while its address has advertised within `presence_timeout_seconds`, never
+    /// actively checked. For devices advertising from a fixed address, e.g. ESP32 wearables
+    /// and tags. Requires `listen_for_discovery`
+    Advertisement,
+    /// Never checked, present or absent as set by `present` until changed with the `presence`
+    /// command. For devices that stay home, e.g. a wall-mounted tablet, to count towards room
+    /// occupancy and anyone home without any Bluetooth traffic
+    Static,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub enum Manufacturer {
     Apple,
     Google,
 }
 
 impl Manufacturer {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Manufacturer::Apple => "Apple",
+            Manufacturer::Google => "Google",
+        }
+    }
+
     /// https://bitbucket.org/bluetooth-SIG/public/src/main/assigned_numbers/company_identifiers/company_identifiers.yaml
     pub fn company_ids(&self) -> Vec<u16> {
         match self {
@@ -36,20 +309,663 @@ impl Manufacturer {
 }
 
 #[allow(dead_code)]
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct BleDevice {
+    #[serde(deserialize_with = "deserialize_mac")]
     pub address: MacAddress,
     pub name: String,
     pub manufacturer: Option<Manufacturer>,
+    /// Display name for dashboards, `name` is used for topics
+    pub friendly_name: Option<String>,
+    /// Area the device belongs to, used as the Home Assistant suggested area
+    pub room: Option<String>,
+    /// Material Design icon, e.g. `mdi:cellphone`
+    pub icon: Option<String>,
+    pub detect: Option<Detect>,
+    /// Presence of a `detect = "static"` device at startup, defaults to present
+    pub present: Option<bool>,
+    /// Overrides the `type` reported for this device, which otherwise reflects the backend
+    #[serde(rename = "type")]
+    pub device_type: Option<DeviceType>,
+    /// Adapter to scan this device from, see [AdapterConfig::ids]
+    pub adapter: Option<String>,
+    /// Overrides [ScanConfig::device_seen_debounce_seconds] for this device
+    pub device_seen_debounce_seconds: Option<u64>,
+    /// Overrides [ScanConfig::device_trigger_debounce_seconds] for this device
+    pub device_trigger_debounce_seconds: Option<u64>,
+    /// Overrides [ScanConfig::backend] for this device, with `detect = "check"`
+    pub method: Option<CheckMethod>,
+}
+
+/// How a device with `detect = "check"` is checked, for devices that only answer some probes.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckMethod {
+    /// Name request via `hcitool`, requires the `bluez` feature
+    Hcitool,
+    /// Echo request via `l2ping`, requires the `bluez` feature and usually root
+    L2ping,
+    /// Connecting to the device with btleplug, for BLE devices that accept connections
+    Gatt,
+    /// Advertising within range, as the `btleplug` backend checks
+    Advertisement,
+    /// [ScanConfig::check_command] exiting successfully
+    Command,
+}
+
+impl CheckMethod {
+    pub const ALL: [CheckMethod; 5] = [
+        CheckMethod::Hcitool,
+        CheckMethod::L2ping,
+        CheckMethod::Gatt,
+        CheckMethod::Advertisement,
+        CheckMethod::Command,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CheckMethod::Hcitool => "hcitool",
+            CheckMethod::L2ping => "l2ping",
+            CheckMethod::Gatt => "gatt",
+            CheckMethod::Advertisement => "advertisement",
+            CheckMethod::Command => "command",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceBackend {
+    /// Active name requests via `hcitool`, requires the `bluez` feature
+    Hcitool,
+    /// Passive advertisement tracking via btleplug, available on every platform
+    Btleplug,
+    /// Connection state of already paired devices from BlueZ, Linux only
+    Paired,
+    /// Remote name requests over a raw HCI socket, requires the `hci` feature
+    Hci,
 }
 
-#[derive(Deserialize, Debug, Default, Clone)]
+impl PresenceBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceBackend::Hcitool => "hcitool",
+            PresenceBackend::Btleplug => "btleplug",
+            PresenceBackend::Paired => "paired",
+            PresenceBackend::Hci => "hci",
+        }
+    }
+}
+
+impl Default for PresenceBackend {
+    /// `hcitool` where BlueZ support is compiled in, otherwise passive btleplug scanning.
+    fn default() -> Self {
+        if cfg!(feature = "bluez") {
+            PresenceBackend::Hcitool
+        } else {
+            PresenceBackend::Btleplug
+        }
+    }
+}
+
+/// Scan to run as soon as the daemon starts, before any MQTT request or advertisement.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupScan {
+    Arrive,
+    Depart,
+    Both,
+    #[default]
+    None,
+}
+
+/// Location of an external binary, for installs where it isn't on `PATH`.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct ExternalCommand {
+    pub path: Option<String>,
+    /// Passed before the command's own arguments, e.g. `["-i", "hci1"]`
+    pub args: Option<Vec<String>>,
+}
+
+/// When to remove devices from the BlueZ cache, so stale entries can't make a device that left
+/// look present.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClearBluezCache {
+    #[default]
+    Never,
+    /// After every presence check
+    AfterCheck,
+    /// Before checking each device in a departure scan
+    BeforeDepart,
+}
+
+/// State devices start in before their first check.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupState {
+    /// Not seen, the first successful check announces an arrival
+    #[default]
+    Absent,
+    /// Nothing is published until the device has been checked, and the first check never
+    /// fires an arrival or departure event
+    Unknown,
+    /// Restored from [ScanConfig::state_file], falling back to `absent`
+    Restore,
+}
+
+/// Delay between checking consecutive devices, in seconds.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "DelaySeconds", into = "DelaySeconds")]
+pub struct InterscanDelay {
+    min: u64,
+    max: u64,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum DelaySeconds {
+    Fixed(u64),
+    Range([u64; 2]),
+}
+
+impl TryFrom<DelaySeconds> for InterscanDelay {
+    type Error = String;
+
+    fn try_from(seconds: DelaySeconds) -> Result<Self, Self::Error> {
+        match seconds {
+            DelaySeconds::Fixed(seconds) => Ok(InterscanDelay::fixed(seconds)),
+            DelaySeconds::Range([min, max]) if min <= max => Ok(InterscanDelay { min, max }),
+            DelaySeconds::Range([min, max]) => Err(format!(
+                "interscan delay range [{min}, {max}] has its minimum above its maximum"
+            )),
+        }
+    }
+}
+
+impl From<InterscanDelay> for DelaySeconds {
+    fn from(delay: InterscanDelay) -> Self {
+        if delay.min == delay.max {
+            DelaySeconds::Fixed(delay.min)
+        } else {
+            DelaySeconds::Range([delay.min, delay.max])
+        }
+    }
+}
+
+impl InterscanDelay {
+    pub fn fixed(seconds: u64) -> Self {
+        InterscanDelay {
+            min: seconds,
+            max: seconds,
+        }
+    }
+
+    /// The delay before the next check, picked at random with millisecond resolution from a
+    /// range.
+    pub fn pick(&self) -> std::time::Duration {
+        if self.min == self.max {
+            return std::time::Duration::from_secs(self.min);
+        }
+        std::time::Duration::from_millis(rand::random_range(
+            self.min.saturating_mul(1000)..=self.max.saturating_mul(1000),
+        ))
+    }
+}
+
+impl fmt::Display for InterscanDelay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}s", self.min)
+        } else {
+            write!(f, "{}-{}s", self.min, self.max)
+        }
+    }
+}
+
+/// Which scan requests and triggers a node acts on, for fleets where entry-point nodes watch for
+/// arrivals and interior ones for departures. Present devices are still checked again after
+/// `presence_timeout_seconds` whatever the role.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeRole {
+    #[default]
+    Full,
+    /// Arrival requests and advertisement triggers, departure requests are ignored
+    ArriveOnly,
+    /// Departure requests, arrival requests and advertisement triggers are ignored
+    DepartOnly,
+    /// No requests or triggers, only advertisements of devices detected by them are followed
+    PassiveListener,
+}
+
+impl NodeRole {
+    pub fn arrives(self) -> bool {
+        matches!(self, NodeRole::Full | NodeRole::ArriveOnly)
+    }
+
+    pub fn departs(self) -> bool {
+        matches!(self, NodeRole::Full | NodeRole::DepartOnly)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct ScanConfig {
+    pub backend: Option<PresenceBackend>,
+    pub role: Option<NodeRole>,
     pub listen_for_discovery: Option<bool>,
     pub presence_timeout_seconds: Option<u64>,
     pub device_seen_debounce_seconds: Option<u64>,
     pub device_trigger_debounce_seconds: Option<u64>,
-    pub interscan_delay_seconds: Option<u64>,
+    /// Seconds, or a `[min, max]` range picked from at random for each gap so scans don't fall
+    /// into step with Wi-Fi or other scanners on a busy 2.4GHz band
+    pub interscan_delay_seconds: Option<InterscanDelay>,
+    /// Wait this long after a departure request before scanning, since phones often stop
+    /// answering a little before their owner has actually left
+    pub depart_scan_delay_seconds: Option<u64>,
+    /// Maximum time a single presence check (e.g. `hcitool name`) may take
+    pub check_timeout_seconds: Option<u64>,
+    /// Reuse a device's check result for this long instead of checking again, 0 to disable
+    pub result_cache_millis: Option<u64>,
+    /// How many requests or announcements each message bus queue holds before a slow consumer
+    /// holds back the tasks sending them
+    pub queue_capacity: Option<usize>,
+    /// Warn when a full arrival/departure pass takes longer than this
+    pub pass_budget_seconds: Option<u64>,
+    /// Skip the devices still left in an arrival/departure pass that has run this long, so one
+    /// slow device doesn't hold up everyone else's updates
+    pub pass_timeout_seconds: Option<u64>,
+    pub confidence: Option<ConfidenceConfig>,
+    /// Signal strength a device must advertise at to count as present, btleplug backend only
+    pub rssi: Option<RssiConfig>,
+    /// Consecutive successful checks, spaced by the interscan delay, needed before a device is
+    /// announced present
+    pub arrival_confirmations: Option<u32>,
+    /// Consecutive failed checks, spaced by the interscan delay, needed before a device is
+    /// announced absent
+    pub depart_failures_required: Option<u32>,
+    pub scan_on_startup: Option<StartupScan>,
+    /// Run a Bluetooth Classic inquiry (`hcitool inq`) this often, triggering an arrival scan of
+    /// each tracked device that responds. Requires the `bluez` feature, 0 to disable
+    pub classic_inquiry_interval_seconds: Option<u64>,
+    /// Linux only
+    pub clear_bluez_cache: Option<ClearBluezCache>,
+    /// `hcitool` used by the `hcitool` backend and classic inquiry
+    pub hcitool: Option<ExternalCommand>,
+    /// `l2ping` used by devices with `method = "l2ping"`
+    pub l2ping: Option<ExternalCommand>,
+    /// Run with a device's address as its last argument for devices with `method = "command"`,
+    /// exiting successfully while the device is present
+    pub check_command: Option<ExternalCommand>,
+    pub startup_state: Option<StartupState>,
+    /// Where device states are saved after every scan, required for `startup_state = "restore"`
+    pub state_file: Option<PathBuf>,
+    /// Experimental: keep devices present while advertisements matching their learned
+    /// fingerprint are heard from rotated addresses. Requires `listen_for_discovery`
+    pub fingerprints: Option<bool>,
+    /// Mark beacons (devices identified by their advertisements) absent once they haven't
+    /// advertised for this long, regardless of `presence_timeout_seconds`. 0 to disable
+    pub beacon_expiration_seconds: Option<u64>,
+    /// Linux only: instead of keeping discovery running, let BlueZ (or the controller, where
+    /// supported) watch for the manufacturer data of tracked devices. Falls back to scanning
+    /// where advertisement monitors aren't supported
+    pub adv_monitor: Option<bool>,
+    /// Only report advertisement monitor matches at least this strong, in dBm
+    pub adv_monitor_rssi_threshold: Option<i16>,
+    /// IEEE `oui.txt` or Wireshark `manuf` file for looking up hardware vendors beyond the
+    /// built-in ones
+    pub oui_file: Option<PathBuf>,
+    pub recovery: Option<RecoveryConfig>,
+}
+
+/// What to do about presence checks that keep failing, e.g. because the Bluetooth stack is
+/// wedged. Each step happens once its number of consecutive failed checks is reached, 0 skips it.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct RecoveryConfig {
+    /// Log a warning and report it on the status topic
+    pub warn_after_errors: Option<u32>,
+    /// Power cycle the adapter the checks are failing on (Linux only)
+    pub restart_adapter_after_errors: Option<u32>,
+    /// Exit with an error, for a supervisor like systemd to restart the daemon
+    pub exit_after_errors: Option<u32>,
+}
+
+/// Separate thresholds for becoming and staying present, so a device on the boundary doesn't
+/// flap between present and absent.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub struct RssiConfig {
+    /// RSSI in dBm an absent device must reach to be seen
+    pub enter: i16,
+    /// RSSI in dBm a present device must drop below to be missed, defaults to `enter`
+    pub exit: Option<i16>,
+    /// How long the RSSI has to stay past a threshold before it counts
+    pub dwell_seconds: Option<u64>,
+}
+
+/// How confidence rises and falls with each presence check. The defaults flip a device
+/// between 0 and 100 on a single check.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+pub struct ConfidenceConfig {
+    pub max: Option<u8>,
+    pub step_up: Option<u8>,
+    pub step_down: Option<u8>,
+    /// Devices at or below this confidence are announced as absent
+    pub absent_threshold: Option<u8>,
+}
+
+impl AppConfig {
+    /// A copy of this config with every unset option filled in with the value the daemon will
+    /// actually use.
+    pub fn with_defaults(&self) -> Self {
+        let mut resolved = self.clone();
+        resolved.mqtt = resolved.mqtt.with_defaults();
+        resolved.scan = Some(resolved.scan.unwrap_or_default().with_defaults());
+        resolved.devices.get_or_insert_with(Vec::new);
+        let adapter = resolved.adapter.get_or_insert_with(AdapterConfig::default);
+        adapter
+            .wait_seconds
+            .get_or_insert(DEFAULT_ADAPTER_WAIT_SECONDS);
+        adapter
+            .max_retry_interval_seconds
+            .get_or_insert(DEFAULT_ADAPTER_MAX_RETRY_INTERVAL_SECONDS);
+        if let Some(http) = resolved.http.as_mut() {
+            http.listen
+                .get_or_insert_with(|| DEFAULT_HTTP_LISTEN.to_string());
+        }
+        if let Some(election) = resolved.election.as_mut() {
+            election
+                .heartbeat_seconds
+                .get_or_insert(DEFAULT_ELECTION_HEARTBEAT_SECONDS);
+            election
+                .timeout_seconds
+                .get_or_insert(DEFAULT_ELECTION_TIMEOUT_SECONDS);
+        }
+        if let Some(alerts) = resolved.alerts.as_mut() {
+            alerts
+                .window_minutes
+                .get_or_insert(DEFAULT_ALERT_WINDOW_MINUTES);
+            alerts.publish.get_or_insert(DEFAULT_ALERTS_PUBLISH);
+        }
+        if let Some(logging) = resolved.logging.as_mut() {
+            logging.rotation.get_or_insert_default();
+            logging.max_size_mb.get_or_insert(DEFAULT_LOG_MAX_SIZE_MB);
+            logging.keep_files.get_or_insert(DEFAULT_LOG_KEEP_FILES);
+        }
+        if let Some(homeassistant) = resolved.homeassistant.as_mut() {
+            homeassistant
+                .discovery_prefix
+                .get_or_insert_with(|| DEFAULT_DISCOVERY_PREFIX.to_string());
+        }
+        resolved
+    }
+
+    /// Check that no two devices share an address, a name or a topic, listing every conflict.
+    pub fn validate_devices(&self) -> anyhow::Result<()> {
+        check_devices(
+            self.devices.as_deref().unwrap_or_default(),
+            &self.mqtt.topic_names.unwrap_or_default(),
+        )
+    }
+
+    /// A copy of this config safe to print or log.
+    pub fn redacted(&self) -> Self {
+        let mut redacted = self.clone();
+        if redacted.mqtt.password.is_some() {
+            redacted.mqtt.password = Some(REDACTED.to_string());
+        }
+        if let Some(token) = redacted.http.as_mut().and_then(|http| http.token.as_mut()) {
+            *token = REDACTED.to_string();
+        }
+        redacted
+    }
+}
+
+/// See [AppConfig::validate_devices], also used for devices added at runtime.
+pub fn check_devices(devices: &[BleDevice], topic_names: &TopicNamesConfig) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    for (i, device) in devices.iter().enumerate() {
+        for earlier in &devices[..i] {
+            if earlier.address == device.address {
+                problems.push(format!(
+                    "{:?} and {:?} have the same address {}",
+                    earlier.name, device.name, device.address
+                ));
+            }
+            if earlier.name == device.name {
+                problems.push(format!("More than one device is named {:?}", device.name));
+            }
+        }
+    }
+    // Same names make for the same topic, no need to say it twice
+    if problems.is_empty()
+        && let Err(err) = crate::mqtt::TopicNames::new(topic_names).check_collisions(devices)
+    {
+        problems.push(err.to_string());
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("Conflicting devices: {}", problems.join("; "));
+    }
+    Ok(())
+}
+
+/// This machine's hostname, used as the default publisher ID so nodes sharing a config don't
+/// publish over each other.
+pub fn hostname() -> String {
+    sanitize_hostname(&gethostname::gethostname().to_string_lossy())
+}
+
+fn sanitize_hostname(hostname: &str) -> String {
+    // Drop the domain and anything that isn't safe in a topic level
+    let id = hostname
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if id.is_empty() {
+        DEFAULT_PUBLISHER_ID.to_string()
+    } else {
+        id
+    }
+}
+
+impl MqttConfig {
+    /// Expand `{hostname}` in `publisher_id` and `topic_path`, and `{publisher_id}` in
+    /// `topic_path`, so one config file can be deployed to every node unchanged.
+    pub fn expand_placeholders(&mut self) -> anyhow::Result<()> {
+        self.expand_placeholders_with(&hostname())
+    }
+
+    fn expand_placeholders_with(&mut self, hostname: &str) -> anyhow::Result<()> {
+        let publisher_id = match &self.publisher_id {
+            Some(id) => {
+                expand(id, &[("hostname", hostname)]).context("Invalid mqtt.publisher_id")?
+            }
+            None => hostname.to_string(),
+        };
+        if let Some(topic_path) = &self.topic_path {
+            self.topic_path = Some(
+                expand(
+                    topic_path,
+                    &[("hostname", hostname), ("publisher_id", &publisher_id)],
+                )
+                .context("Invalid mqtt.topic_path")?,
+            );
+        }
+        if self.publisher_id.is_some() {
+            self.publisher_id = Some(publisher_id);
+        }
+        Ok(())
+    }
+
+    fn with_defaults(mut self) -> Self {
+        self.port.get_or_insert(DEFAULT_MQTT_PORT);
+        self.publisher_id.get_or_insert_with(hostname);
+        self.topic_path
+            .get_or_insert_with(|| DEFAULT_TOPIC_PATH.to_string());
+        self.keep_alive_seconds
+            .get_or_insert(DEFAULT_KEEP_ALIVE_SECONDS);
+        self.stats_interval_seconds
+            .get_or_insert(DEFAULT_STATS_INTERVAL_SECONDS);
+        self.wait_for_broker_seconds.get_or_insert(0);
+        self.exit_on_auth_failure
+            .get_or_insert(DEFAULT_EXIT_ON_AUTH_FAILURE);
+        self.request_channel_capacity
+            .get_or_insert(DEFAULT_MQTT_REQUEST_CHANNEL_CAPACITY);
+        self.max_packet_size
+            .get_or_insert(DEFAULT_MQTT_MAX_PACKET_SIZE);
+        self.inflight.get_or_insert(DEFAULT_MQTT_INFLIGHT);
+        self.pending_throttle_millis
+            .get_or_insert(DEFAULT_MQTT_PENDING_THROTTLE_MILLIS);
+        self.payload_encoding.get_or_insert_default();
+        self.confidence_format.get_or_insert_default();
+        self.retained_on_shutdown.get_or_insert_default();
+        self.listen_nodes.get_or_insert_with(Vec::new);
+        let topic_names = self.topic_names.get_or_insert_default();
+        topic_names.preserve_dashes.get_or_insert(false);
+        topic_names.collapse_underscores.get_or_insert(false);
+        let device_tracker = self.device_tracker.get_or_insert_default();
+        device_tracker.enabled.get_or_insert(false);
+        device_tracker
+            .retain
+            .get_or_insert(DEFAULT_DEVICE_TRACKER_RETAIN);
+        device_tracker
+            .payload_home
+            .get_or_insert_with(|| DEFAULT_DEVICE_TRACKER_PAYLOAD_HOME.to_string());
+        device_tracker
+            .payload_not_home
+            .get_or_insert_with(|| DEFAULT_DEVICE_TRACKER_PAYLOAD_NOT_HOME.to_string());
+        self
+    }
+}
+
+impl ScanConfig {
+    fn with_defaults(mut self) -> Self {
+        self.backend.get_or_insert_with(PresenceBackend::default);
+        self.role.get_or_insert_with(NodeRole::default);
+        self.listen_for_discovery
+            .get_or_insert(DEFAULT_LISTEN_FOR_DISCOVERY);
+        self.presence_timeout_seconds
+            .get_or_insert(DEFAULT_PRESENCE_TIMEOUT_SECONDS);
+        self.device_seen_debounce_seconds
+            .get_or_insert(DEFAULT_DEVICE_SEEN_DEBOUNCE_SECONDS);
+        self.device_trigger_debounce_seconds
+            .get_or_insert(DEFAULT_DEVICE_TRIGGER_DEBOUNCE_SECONDS);
+        self.interscan_delay_seconds
+            .get_or_insert(InterscanDelay::fixed(DEFAULT_INTERSCAN_DELAY_SECONDS));
+        self.depart_scan_delay_seconds.get_or_insert(0);
+        self.check_timeout_seconds
+            .get_or_insert(DEFAULT_CHECK_TIMEOUT_SECONDS);
+        self.result_cache_millis
+            .get_or_insert(DEFAULT_RESULT_CACHE_MILLIS);
+        self.queue_capacity.get_or_insert(DEFAULT_QUEUE_CAPACITY);
+        self.confidence = Some(self.confidence.unwrap_or_default().with_defaults());
+        let recovery = self.recovery.get_or_insert_with(RecoveryConfig::default);
+        recovery
+            .warn_after_errors
+            .get_or_insert(DEFAULT_RECOVERY_WARN_AFTER_ERRORS);
+        recovery
+            .restart_adapter_after_errors
+            .get_or_insert(DEFAULT_RECOVERY_RESTART_ADAPTER_AFTER_ERRORS);
+        recovery
+            .exit_after_errors
+            .get_or_insert(DEFAULT_RECOVERY_EXIT_AFTER_ERRORS);
+        self.arrival_confirmations
+            .get_or_insert(DEFAULT_ARRIVAL_CONFIRMATIONS);
+        self.depart_failures_required
+            .get_or_insert(DEFAULT_DEPART_FAILURES_REQUIRED);
+        self.scan_on_startup
+            .get_or_insert_with(StartupScan::default);
+        self.startup_state.get_or_insert_with(StartupState::default);
+        self.classic_inquiry_interval_seconds.get_or_insert(0);
+        self.fingerprints.get_or_insert(false);
+        self.beacon_expiration_seconds.get_or_insert(0);
+        self.adv_monitor.get_or_insert(false);
+        self.clear_bluez_cache
+            .get_or_insert_with(ClearBluezCache::default);
+        let hcitool = self.hcitool.get_or_insert_with(ExternalCommand::default);
+        hcitool
+            .path
+            .get_or_insert_with(|| DEFAULT_HCITOOL_PATH.to_string());
+        hcitool.args.get_or_insert_with(Vec::new);
+        let l2ping = self.l2ping.get_or_insert_with(ExternalCommand::default);
+        l2ping
+            .path
+            .get_or_insert_with(|| DEFAULT_L2PING_PATH.to_string());
+        l2ping.args.get_or_insert_with(Vec::new);
+        self
+    }
+}
+
+impl ConfidenceConfig {
+    fn with_defaults(mut self) -> Self {
+        self.max.get_or_insert(DEFAULT_CONFIDENCE_MAX);
+        self.step_up.get_or_insert(DEFAULT_CONFIDENCE_STEP_UP);
+        self.step_down.get_or_insert(DEFAULT_CONFIDENCE_STEP_DOWN);
+        self.absent_threshold
+            .get_or_insert(DEFAULT_CONFIDENCE_ABSENT_THRESHOLD);
+        self
+    }
+}
+
+/// Parse a MAC address written with `:`, `-`, `.` or space separators, or none at all, in either
+/// case and with surrounding whitespace, e.g. ` aabb.ccdd.eeff `.
+pub fn parse_mac(input: &str) -> anyhow::Result<MacAddress> {
+    let digits = input
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.' | ' '))
+        .collect::<String>();
+    if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid MAC address {input:?}: {c:?} is not a hex digit");
+    }
+    if digits.len() != 12 {
+        anyhow::bail!(
+            "Invalid MAC address {input:?}: expected 12 hex digits, found {}",
+            digits.len()
+        );
+    }
+    let mut bytes = [0; 6];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(MacAddress::new(bytes))
+}
+
+pub(crate) fn deserialize_mac<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<MacAddress, D::Error> {
+    let input = <String as serde::Deserialize>::deserialize(deserializer)?;
+    parse_mac(&input).map_err(serde::de::Error::custom)
+}
+
+/// Replace `{name}` placeholders, failing on any that aren't known.
+fn expand(template: &str, values: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("Unclosed placeholder in {template:?}"))?;
+        let name = &rest[start + 1..start + end];
+        let (_, value) = values
+            .iter()
+            .find(|(known, _)| *known == name)
+            .with_context(|| format!("Unknown placeholder {{{name}}} in {template:?}"))?;
+        expanded.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
 }
 
 #[cfg(test)]
@@ -75,4 +991,208 @@ mod tests {
         assert!(config.scan.is_some());
         assert!(config.scan.map(|s| s.device_seen_debounce_seconds).unwrap() == Some(60));
     }
+
+    #[test]
+    fn test_device_debounce_overrides() {
+        let config_str = r#"
+            [mqtt]
+            host = "localhost"
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Car keys"
+            device_seen_debounce_seconds = 600
+
+            [[devices]]
+            address = "00:11:22:33:44:56"
+            name = "Phone"
+        "#;
+        let config: AppConfig = toml::de::from_str(config_str).unwrap();
+        let devices = config.devices.unwrap();
+        assert_eq!(devices[0].device_seen_debounce_seconds, Some(600));
+        assert_eq!(devices[0].device_trigger_debounce_seconds, None);
+        assert_eq!(devices[1].device_seen_debounce_seconds, None);
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        let expected = MacAddress::new([0xAA, 0xBB, 0xCC, 0x00, 0x11, 0x22]);
+        for input in [
+            "AA:BB:CC:00:11:22",
+            " aa-bb-cc-00-11-22\n",
+            "aabbcc001122",
+            "AABB.CC00.1122",
+        ] {
+            assert_eq!(parse_mac(input).unwrap(), expected, "{input:?}");
+        }
+        assert!(parse_mac("AA:BB:CC:00:11").is_err());
+        assert!(parse_mac("AA:BB:CC:00:11:2G").is_err());
+
+        let config_str = r#"
+            [mqtt]
+            host = "localhost"
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Phone"
+
+            [[devices]]
+            address = "00:11:22:33:44"
+            name = "Watch"
+        "#;
+        let err = toml::de::from_str::<AppConfig>(config_str)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 10"), "{err}");
+        assert!(err.contains("expected 12 hex digits"), "{err}");
+    }
+
+    #[test]
+    fn test_zero_inflight_rejected() {
+        let config_str = r#"
+            [mqtt]
+            host = "localhost"
+            inflight = 0
+        "#;
+        assert!(toml::de::from_str::<AppConfig>(config_str).is_err());
+    }
+
+    #[test]
+    fn test_interscan_delay_range() {
+        let scan = |delay: &str| {
+            toml::de::from_str::<ScanConfig>(&format!("interscan_delay_seconds = {delay}"))
+                .map(|scan| scan.interscan_delay_seconds.unwrap())
+        };
+        assert_eq!(scan("3").unwrap(), InterscanDelay::fixed(3));
+        assert_eq!(scan("[4, 4]").unwrap(), InterscanDelay::fixed(4));
+        let range = scan("[2, 6]").unwrap();
+        assert_eq!(range.to_string(), "2-6s");
+        for _ in 0..100 {
+            let delay = range.pick();
+            assert!(
+                (std::time::Duration::from_secs(2)..=std::time::Duration::from_secs(6))
+                    .contains(&delay)
+            );
+        }
+        assert!(scan("[6, 2]").is_err());
+        assert!(scan("[1, 2, 3]").is_err());
+        // Too long to count in milliseconds, but still picked
+        let huge = InterscanDelay {
+            min: u64::MAX - 1,
+            max: u64::MAX,
+        };
+        assert!(huge.pick() >= std::time::Duration::from_secs(u64::MAX / 1000));
+
+        // Written back the way it was given
+        let config = ScanConfig {
+            interscan_delay_seconds: Some(range),
+            ..Default::default()
+        };
+        assert!(
+            toml::to_string(&config)
+                .unwrap()
+                .contains("interscan_delay_seconds = [2, 6]")
+        );
+    }
+
+    #[test]
+    fn test_with_defaults_redacted() {
+        let config_str = r#"
+            [mqtt]
+            host = "localhost"
+            username = "user"
+            password = "hunter2"
+
+            [scan]
+            interscan_delay_seconds = 10
+        "#;
+        let config: AppConfig = toml::de::from_str(config_str).unwrap();
+        let resolved = config.with_defaults().redacted();
+        assert_eq!(resolved.mqtt.port, Some(DEFAULT_MQTT_PORT));
+        assert_eq!(resolved.mqtt.password.as_deref(), Some(REDACTED));
+        let scan = resolved.scan.as_ref().unwrap();
+        assert_eq!(
+            scan.interscan_delay_seconds,
+            Some(InterscanDelay::fixed(10))
+        );
+        assert_eq!(
+            scan.presence_timeout_seconds,
+            Some(DEFAULT_PRESENCE_TIMEOUT_SECONDS)
+        );
+
+        assert_eq!(resolved.mqtt.inflight, Some(DEFAULT_MQTT_INFLIGHT));
+
+        let dumped = toml::to_string(&resolved).unwrap();
+        assert!(!dumped.contains("hunter2"));
+        let _: AppConfig = toml::de::from_str(&dumped).unwrap();
+    }
+
+    #[test]
+    fn test_sanitize_hostname() {
+        assert_eq!(sanitize_hostname("Kitchen-Pi.local"), "kitchen-pi");
+        assert_eq!(sanitize_hostname("a/b+c#"), "a_b_c_");
+        assert_eq!(sanitize_hostname(""), DEFAULT_PUBLISHER_ID);
+    }
+
+    #[test]
+    fn test_validate_devices() {
+        let config = |devices: &str| -> AppConfig {
+            toml::de::from_str(&format!("[mqtt]\nhost = \"localhost\"\n{devices}")).unwrap()
+        };
+        let device = |address: &str, name: &str| {
+            format!("[[devices]]\naddress = \"{address}\"\nname = \"{name}\"\n")
+        };
+        assert!(
+            config(&(device("00:11:22:33:44:55", "Phone") + &device("00:11:22:33:44:56", "Keys")))
+                .validate_devices()
+                .is_ok()
+        );
+
+        let err = config(
+            &(device("00:11:22:33:44:55", "Phone")
+                + &device("00:11:22:33:44:55", "Keys")
+                + &device("00:11:22:33:44:56", "Keys")),
+        )
+        .validate_devices()
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Conflicting devices: \"Phone\" and \"Keys\" have the same address 00:11:22:33:44:55; \
+             More than one device is named \"Keys\""
+        );
+
+        let err = config(
+            &(device("00:11:22:33:44:55", "Car keys") + &device("00:11:22:33:44:56", "Car-keys")),
+        )
+        .validate_devices()
+        .unwrap_err();
+        assert!(err.to_string().contains("\"car_keys\""), "{err}");
+    }
+
+    #[test]
+    fn test_expand_placeholders() {
+        let config_str = r#"
+            host = "localhost"
+            publisher_id = "node-{hostname}"
+            topic_path = "home/{hostname}/{publisher_id}"
+        "#;
+        let mut config: MqttConfig = toml::de::from_str(config_str).unwrap();
+        config.expand_placeholders_with("kitchen").unwrap();
+        assert_eq!(config.publisher_id.as_deref(), Some("node-kitchen"));
+        assert_eq!(
+            config.topic_path.as_deref(),
+            Some("home/kitchen/node-kitchen")
+        );
+
+        // Unset publisher ID defaults to the hostname
+        let mut config: MqttConfig =
+            toml::de::from_str("host = \"localhost\"\ntopic_path = \"{publisher_id}\"").unwrap();
+        config.expand_placeholders_with("kitchen").unwrap();
+        assert_eq!(config.topic_path.as_deref(), Some("kitchen"));
+
+        config.topic_path = Some("{nope}".to_string());
+        assert!(config.expand_placeholders_with("kitchen").is_err());
+        config.topic_path = Some("{hostname".to_string());
+        assert!(config.expand_placeholders_with("kitchen").is_err());
+    }
 }
@@ -0,0 +1,211 @@
+//! Edits to the `[[devices]]` and `[scan]` tables of a config file that leave the rest of the
+//! file, including comments, untouched.
+// Devices are only edited through the HTTP API
+#![cfg_attr(not(feature = "http"), allow(dead_code))]
+use std::path::Path;
+
+use anyhow::Context as _;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, value};
+
+use crate::config::{AppConfig, BleDevice};
+
+/// Held while editing, since the HTTP API and MQTT commands can both edit the file.
+static EDITING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Rewrite the config file at `path` with `edit`, one edit at a time so none is lost.
+pub async fn edit(
+    path: &Path,
+    edit: impl FnOnce(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    let _editing = EDITING.lock().await;
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    tokio::fs::write(path, edit(&contents)?)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+pub fn add_device(contents: &str, device: &BleDevice) -> anyhow::Result<String> {
+    let mut doc = parse(contents)?;
+    let table = device_table(device)?;
+    match doc.get_mut("devices") {
+        Some(item) => item
+            .as_array_of_tables_mut()
+            .context("`devices` is not an array of tables")?
+            .push(table),
+        None => {
+            let mut devices = ArrayOfTables::new();
+            devices.push(table);
+            doc.insert("devices", Item::ArrayOfTables(devices));
+        }
+    }
+    validate(doc)
+}
+
+pub fn remove_device(contents: &str, name: &str) -> anyhow::Result<String> {
+    let mut doc = parse(contents)?;
+    let devices = devices_mut(&mut doc)?;
+    let index = position(devices, name)?;
+    devices.remove(index);
+    validate(doc)
+}
+
+/// Replace the table for the device currently named `name`, which may be renamed by `device`.
+pub fn replace_device(contents: &str, name: &str, device: &BleDevice) -> anyhow::Result<String> {
+    let mut doc = parse(contents)?;
+    let devices = devices_mut(&mut doc)?;
+    let index = position(devices, name)?;
+    *devices
+        .get_mut(index)
+        .context("device index out of range")? = device_table(device)?;
+    validate(doc)
+}
+
+/// Set options in the `[scan]` table, adding it if needed. An option configured as a range (e.g.
+/// `interscan_delay_seconds = [2, 10]`) is left for the user to change in the file.
+pub fn set_scan_values(contents: &str, values: &[(&str, u64)]) -> anyhow::Result<String> {
+    let mut doc = parse(contents)?;
+    let scan = doc
+        .entry("scan")
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_mut()
+        .context("`scan` is not a table")?;
+    for (key, new) in values {
+        if scan.get(key).is_some_and(Item::is_array) {
+            anyhow::bail!("scan.{key} is a range in the config file, change it there");
+        }
+        scan.insert(key, value(i64::try_from(*new)?));
+    }
+    validate(doc)
+}
+
+fn parse(contents: &str) -> anyhow::Result<DocumentMut> {
+    contents
+        .parse::<DocumentMut>()
+        .context("Existing config is invalid")
+}
+
+fn devices_mut(doc: &mut DocumentMut) -> anyhow::Result<&mut ArrayOfTables> {
+    doc.get_mut("devices")
+        .and_then(Item::as_array_of_tables_mut)
+        .context("Config has no devices")
+}
+
+fn position(devices: &ArrayOfTables, name: &str) -> anyhow::Result<usize> {
+    devices
+        .iter()
+        .position(|table| table.get("name").and_then(Item::as_str) == Some(name))
+        .with_context(|| format!("Device {name} is not in the config file"))
+}
+
+fn device_table(device: &BleDevice) -> anyhow::Result<Table> {
+    let doc = toml_edit::ser::to_document(device).context("Failed to serialize device")?;
+    Ok(doc.as_table().clone())
+}
+
+fn validate(doc: DocumentMut) -> anyhow::Result<String> {
+    let contents = doc.to_string();
+    toml::de::from_str::<AppConfig>(&contents).context("Edited config is invalid")?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"# Broker
+[mqtt]
+host = "localhost"
+
+# The phone
+[[devices]]
+address = "00:11:22:33:44:55"
+name = "Phone"
+"#;
+
+    fn device(address: &str, name: &str) -> BleDevice {
+        toml::de::from_str(&format!("address = \"{address}\"\nname = \"{name}\"")).unwrap()
+    }
+
+    fn devices(contents: &str) -> Vec<BleDevice> {
+        toml::de::from_str::<AppConfig>(contents)
+            .unwrap()
+            .devices
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_add_and_remove() {
+        let added = add_device(CONFIG, &device("00:11:22:33:44:56", "Car keys")).unwrap();
+        assert!(added.starts_with("# Broker\n"));
+        assert_eq!(devices(&added).len(), 2);
+
+        let removed = remove_device(&added, "Phone").unwrap();
+        let remaining = devices(&removed);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].name, "Car keys");
+
+        assert!(remove_device(&removed, "Phone").is_err());
+
+        let first = add_device(
+            "[mqtt]\nhost = \"localhost\"\n",
+            &device("00:11:22:33:44:56", "Car keys"),
+        )
+        .unwrap();
+        assert_eq!(devices(&first).len(), 1);
+    }
+
+    #[test]
+    fn test_set_scan_values() {
+        let updated = set_scan_values(CONFIG, &[("interscan_delay_seconds", 5)]).unwrap();
+        assert!(updated.contains("# The phone"));
+        let config = toml::de::from_str::<AppConfig>(&updated).unwrap();
+        assert_eq!(
+            config.scan.unwrap().interscan_delay_seconds,
+            Some(crate::config::InterscanDelay::fixed(5))
+        );
+
+        let updated = set_scan_values(&updated, &[("interscan_delay_seconds", 2)]).unwrap();
+        assert_eq!(updated.matches("interscan_delay_seconds").count(), 1);
+
+        let range = format!("{CONFIG}\n[scan]\ninterscan_delay_seconds = [2, 10]\n");
+        assert!(set_scan_values(&range, &[("interscan_delay_seconds", 5)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_edit() {
+        let path =
+            std::env::temp_dir().join(format!("monitor-rs-config-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        // Concurrent edits each see the other's result
+        let (first, second) = tokio::join!(
+            edit(&path, |contents| add_device(
+                contents,
+                &device("00:11:22:33:44:56", "Car keys")
+            )),
+            edit(&path, |contents| set_scan_values(
+                contents,
+                &[("interscan_delay_seconds", 5)]
+            )),
+        );
+        first.unwrap();
+        second.unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(devices(&contents).len(), 2);
+        assert!(contents.contains("interscan_delay_seconds = 5"));
+
+        assert!(edit(&path, |_| anyhow::bail!("rejected")).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut phone = device("00:11:22:33:44:55", "Phone");
+        phone.room = Some("Bedroom".to_string());
+        let replaced = replace_device(CONFIG, "Phone", &phone).unwrap();
+        assert!(replaced.contains("# Broker"));
+        assert_eq!(devices(&replaced)[0].room.as_deref(), Some("Bedroom"));
+    }
+}
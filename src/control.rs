@@ -0,0 +1,291 @@
+//! JSON commands accepted on `<topic_path>/cmd`, e.g.
+//! `{"action": "scan", "kind": "arrive", "devices": ["Phone"]}` or
+//! `{"action": "set", "interscan_delay_seconds": 5, "persist": true}`. Every node answers on
+//! `<topic_path>/<publisher_id>/cmd/result`, echoing the request's optional `id`, so malformed
+//! commands don't just disappear.
+use serde::Serialize;
+use serde_derive::Deserialize;
+
+use crate::config::{BleDevice, Detect};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Request {
+    /// Anything identifying the request, echoed in the result
+    #[serde(default)]
+    pub id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Command {
+    /// Check devices for arrival or departure, every device unless `devices` is given
+    Scan {
+        kind: ScanKind,
+        #[serde(default)]
+        devices: Option<Vec<String>>,
+    },
+    /// Publish every device heard for a while, see [LearnWindow](crate::learn::LearnWindow)
+    Learn {
+        #[serde(default)]
+        seconds: Option<u64>,
+    },
+    /// Change scan settings until the next restart, or for good with `persist`
+    Set {
+        #[serde(flatten)]
+        tuning: Tuning,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Stop active checks until resumed, and with `triggers` passive ones too
+    Pause {
+        #[serde(default)]
+        triggers: bool,
+    },
+    /// Catch up on the checks skipped while paused
+    Resume,
+    /// Mark a `detect = "static"` device present or absent until the next restart
+    Presence { device: String, present: bool },
+    /// Clear retained discovery configs and states this node no longer publishes
+    CleanupDiscovery,
+}
+
+impl Command {
+    pub fn action(&self) -> &'static str {
+        match self {
+            Command::Scan { .. } => "scan",
+            Command::Learn { .. } => "learn",
+            Command::Set { .. } => "set",
+            Command::Pause { .. } => "pause",
+            Command::Resume => "resume",
+            Command::Presence { .. } => "presence",
+            Command::CleanupDiscovery => "cleanup_discovery",
+        }
+    }
+}
+
+/// `[scan]` settings that can be changed at runtime, each left alone when `None`. Devices that
+/// set their own debounces keep them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct Tuning {
+    pub interscan_delay_seconds: Option<u64>,
+    pub device_seen_debounce_seconds: Option<u64>,
+    pub device_trigger_debounce_seconds: Option<u64>,
+    pub presence_timeout_seconds: Option<u64>,
+}
+
+impl Tuning {
+    /// The settings being changed, by their `[scan]` option name.
+    pub fn values(&self) -> Vec<(&'static str, u64)> {
+        [
+            ("interscan_delay_seconds", self.interscan_delay_seconds),
+            (
+                "device_seen_debounce_seconds",
+                self.device_seen_debounce_seconds,
+            ),
+            (
+                "device_trigger_debounce_seconds",
+                self.device_trigger_debounce_seconds,
+            ),
+            ("presence_timeout_seconds", self.presence_timeout_seconds),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, value?)))
+        .collect()
+    }
+}
+
+/// What is paused, reported in `bridge/state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Paused {
+    /// Whether advertisement triggers and advertisement-detected devices are paused too
+    pub triggers: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanKind {
+    Arrive,
+    Depart,
+}
+
+/// Published in reply to every request on the command topic.
+#[derive(Debug, Serialize)]
+pub struct CommandResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<&'static str>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CommandResult {
+    pub fn ok(request: &Request) -> Self {
+        CommandResult {
+            id: request.id.clone(),
+            action: Some(request.command.action()),
+            ok: true,
+            error: None,
+        }
+    }
+
+    pub fn error(request: Option<&Request>, error: &anyhow::Error) -> Self {
+        CommandResult {
+            id: request.and_then(|request| request.id.clone()),
+            action: request.map(|request| request.command.action()),
+            ok: false,
+            error: Some(format!("{error:#}")),
+        }
+    }
+}
+
+/// Parse and validate a request. Device names have to be among `devices`, this node's tracked
+/// devices, so a node answers with an error for a device only other nodes track.
+pub fn parse(payload: &[u8], devices: &[BleDevice]) -> anyhow::Result<Request> {
+    let request: Request =
+        serde_json::from_slice(payload).map_err(|err| anyhow::anyhow!("Invalid command: {err}"))?;
+    if let Command::Scan {
+        devices: Some(names),
+        ..
+    } = &request.command
+    {
+        let unknown = names
+            .iter()
+            .filter(|name| !devices.iter().any(|device| &device.name == *name))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if !unknown.is_empty() {
+            anyhow::bail!("Unknown device(s): {}", unknown.join(", "));
+        }
+    }
+    if let Command::Presence { device, .. } = &request.command {
+        match devices.iter().find(|known| &known.name == device) {
+            None => anyhow::bail!("Unknown device(s): {device}"),
+            Some(known) if known.detect != Some(Detect::Static) => {
+                anyhow::bail!("{device} isn't a static device, its presence is checked")
+            }
+            Some(_) => {}
+        }
+    }
+    if let Command::Set { tuning, .. } = &request.command {
+        if tuning.values().is_empty() {
+            anyhow::bail!("Nothing to set");
+        }
+        if tuning.presence_timeout_seconds == Some(0) {
+            anyhow::bail!("presence_timeout_seconds must be positive");
+        }
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: crate::config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Phone"
+
+            [[devices]]
+            address = "00:11:22:33:44:56"
+            name = "Wall panel"
+            detect = "static"
+            "#,
+        )
+        .unwrap();
+        let devices = config.devices.unwrap();
+
+        let request = parse(
+            br#"{"id": 7, "action": "scan", "kind": "arrive", "devices": ["Phone"]}"#,
+            &devices,
+        )
+        .unwrap();
+        assert_eq!(request.id, Some(serde_json::json!(7)));
+        assert_eq!(
+            request.command,
+            Command::Scan {
+                kind: ScanKind::Arrive,
+                devices: Some(vec!["Phone".to_string()]),
+            }
+        );
+        assert_eq!(
+            parse(br#"{"action": "learn"}"#, &devices).unwrap().command,
+            Command::Learn { seconds: None }
+        );
+
+        assert_eq!(
+            parse(
+                br#"{"action": "set", "interscan_delay_seconds": 2, "persist": true}"#,
+                &devices
+            )
+            .unwrap()
+            .command,
+            Command::Set {
+                tuning: Tuning {
+                    interscan_delay_seconds: Some(2),
+                    ..Default::default()
+                },
+                persist: true,
+            }
+        );
+
+        assert_eq!(
+            parse(br#"{"action": "pause", "triggers": true}"#, &devices)
+                .unwrap()
+                .command,
+            Command::Pause { triggers: true }
+        );
+        assert_eq!(
+            parse(br#"{"action": "resume"}"#, &devices).unwrap().command,
+            Command::Resume
+        );
+
+        assert_eq!(
+            parse(
+                br#"{"action": "presence", "device": "Wall panel", "present": false}"#,
+                &devices
+            )
+            .unwrap()
+            .command,
+            Command::Presence {
+                device: "Wall panel".to_string(),
+                present: false,
+            }
+        );
+        assert_eq!(
+            parse(br#"{"action": "cleanup_discovery"}"#, &devices)
+                .unwrap()
+                .command,
+            Command::CleanupDiscovery
+        );
+
+        let error = |payload: &[u8]| format!("{:#}", parse(payload, &devices).unwrap_err());
+        assert_eq!(
+            error(br#"{"action": "scan", "kind": "arrive", "devices": ["Tablet", "Watch"]}"#),
+            "Unknown device(s): Tablet, Watch"
+        );
+        assert!(error(br#"{"action": "scan", "kind": "sideways"}"#).contains("unknown variant"));
+        assert_eq!(error(br#"{"action": "set"}"#), "Nothing to set");
+        assert_eq!(
+            error(br#"{"action": "presence", "device": "Phone", "present": true}"#),
+            "Phone isn't a static device, its presence is checked"
+        );
+        assert!(error(b"scan").starts_with("Invalid command: expected value at line 1"));
+
+        let result = CommandResult::error(None, &anyhow::anyhow!("Invalid command"));
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"ok":false,"error":"Invalid command"}"#
+        );
+    }
+}
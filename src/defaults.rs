@@ -0,0 +1,473 @@
+//! `monitor-rs defaults`: list every config option with its default and what it does.
+use crate::config::AppConfig;
+
+/// Every option a config file can set, with a one-line description. Defaults come from
+/// [AppConfig::with_defaults], so only the descriptions have to be kept up to date here.
+const OPTIONS: &[(&str, &str)] = &[
+    ("mqtt.host", "MQTT broker host, required"),
+    ("mqtt.port", "MQTT broker port"),
+    ("mqtt.username", "MQTT username"),
+    ("mqtt.password", "MQTT password"),
+    (
+        "mqtt.publisher_id",
+        "Identifies this node in topics, defaults to the hostname",
+    ),
+    ("mqtt.topic_path", "Prefix of every topic"),
+    (
+        "mqtt.keep_alive_seconds",
+        "MQTT keep alive, the broker publishes the offline last will after 1.5 times this",
+    ),
+    (
+        "mqtt.stats_interval_seconds",
+        "How often to publish node statistics, 0 to disable",
+    ),
+    (
+        "mqtt.wait_for_broker_seconds",
+        "Delay scanning at startup until the broker has been reached, for up to this long",
+    ),
+    (
+        "mqtt.exit_on_auth_failure",
+        "Exit when the broker rejects our credentials, instead of retrying forever",
+    ),
+    (
+        "mqtt.request_channel_capacity",
+        "Outgoing requests buffered before publishes wait on the event loop",
+    ),
+    (
+        "mqtt.max_packet_size",
+        "Largest packet, in bytes, that will be sent or accepted",
+    ),
+    (
+        "mqtt.inflight",
+        "Maximum number of unacknowledged QoS 1/2 publishes",
+    ),
+    (
+        "mqtt.pending_throttle_millis",
+        "Delay between resending pending requests after a reconnect",
+    ),
+    (
+        "mqtt.timezone",
+        "IANA time zone for payload timestamps, local time if unset",
+    ),
+    (
+        "mqtt.payload_encoding",
+        "Encoding of device state payloads: json, cbor or msgpack",
+    ),
+    (
+        "mqtt.confidence_format",
+        "JSON type of confidence in device states: number, or string as monitor.sh sent",
+    ),
+    (
+        "mqtt.retained_on_shutdown",
+        "What happens to retained states on shutdown: keep, clear or off",
+    ),
+    (
+        "mqtt.topic_names.preserve_dashes",
+        "Keep dashes in device topics rather than turning them into underscores",
+    ),
+    (
+        "mqtt.topic_names.collapse_underscores",
+        "Collapse runs of underscores in device topics and trim them from the ends",
+    ),
+    (
+        "mqtt.listen_nodes",
+        "Other nodes whose device states count towards this node's anyone_home and presence topics",
+    ),
+    (
+        "mqtt.device_tracker.enabled",
+        "Publish home / not_home to each device's topic + /device_tracker",
+    ),
+    ("mqtt.device_tracker.retain", "Retain device_tracker states"),
+    (
+        "mqtt.device_tracker.payload_home",
+        "device_tracker payload of present devices",
+    ),
+    (
+        "mqtt.device_tracker.payload_not_home",
+        "device_tracker payload of absent devices",
+    ),
+    (
+        "scan.backend",
+        "Presence backend: hcitool, btleplug, paired or hci",
+    ),
+    (
+        "scan.role",
+        "Requests and triggers acted on: full, arrive_only, depart_only or passive_listener",
+    ),
+    (
+        "scan.listen_for_discovery",
+        "Trigger arrival scans from advertisements of tracked manufacturers",
+    ),
+    (
+        "scan.presence_timeout_seconds",
+        "Check present devices again after this long",
+    ),
+    (
+        "scan.device_seen_debounce_seconds",
+        "Skip arrival checks for devices seen this recently",
+    ),
+    (
+        "scan.device_trigger_debounce_seconds",
+        "Minimum time between scans triggered by advertisements",
+    ),
+    (
+        "scan.interscan_delay_seconds",
+        "Delay between checking consecutive devices, or a [min, max] range picked from at random",
+    ),
+    (
+        "scan.depart_scan_delay_seconds",
+        "Wait this long after a departure request before scanning",
+    ),
+    (
+        "scan.check_timeout_seconds",
+        "Maximum time a single presence check may take",
+    ),
+    (
+        "scan.result_cache_millis",
+        "Reuse a device's check result for this long, 0 to disable",
+    ),
+    (
+        "scan.queue_capacity",
+        "Requests or announcements each message bus queue holds",
+    ),
+    (
+        "scan.pass_budget_seconds",
+        "Warn when a full arrival or departure pass takes longer than this",
+    ),
+    (
+        "scan.pass_timeout_seconds",
+        "Skip the devices left in an arrival or departure pass that has run this long",
+    ),
+    (
+        "scan.confidence.max",
+        "Confidence of a device that is surely present",
+    ),
+    (
+        "scan.confidence.step_up",
+        "Confidence gained with each successful check",
+    ),
+    (
+        "scan.confidence.step_down",
+        "Confidence lost with each failed check",
+    ),
+    (
+        "scan.confidence.absent_threshold",
+        "Devices at or below this confidence are announced absent",
+    ),
+    (
+        "scan.rssi.enter",
+        "btleplug backend: RSSI in dBm an absent device must reach to be seen",
+    ),
+    (
+        "scan.rssi.exit",
+        "btleplug backend: RSSI in dBm a present device must drop below to be missed",
+    ),
+    (
+        "scan.rssi.dwell_seconds",
+        "btleplug backend: how long the RSSI has to stay past a threshold",
+    ),
+    (
+        "scan.recovery.warn_after_errors",
+        "Consecutive failed checks or scans before warning on the status topic, 0 to never",
+    ),
+    (
+        "scan.recovery.restart_adapter_after_errors",
+        "Consecutive failed checks or scans before power cycling the adapter, 0 to never",
+    ),
+    (
+        "scan.recovery.exit_after_errors",
+        "Consecutive failed checks or scans before exiting for a supervisor to restart, 0 to never",
+    ),
+    (
+        "scan.arrival_confirmations",
+        "Consecutive successful checks needed before a device is announced present",
+    ),
+    (
+        "scan.depart_failures_required",
+        "Consecutive failed checks needed before a device is announced absent",
+    ),
+    (
+        "scan.scan_on_startup",
+        "Scan to run at startup: arrive, depart, both or none",
+    ),
+    (
+        "scan.classic_inquiry_interval_seconds",
+        "Run a Bluetooth Classic inquiry this often, 0 to disable",
+    ),
+    (
+        "scan.clear_bluez_cache",
+        "Linux: when to forget devices in BlueZ: never, after_check or before_depart",
+    ),
+    ("scan.hcitool.path", "Path of the hcitool binary"),
+    (
+        "scan.hcitool.args",
+        "Arguments passed to hcitool before its own, e.g. [\"-i\", \"hci1\"]",
+    ),
+    ("scan.l2ping.path", "Path of the l2ping binary"),
+    (
+        "scan.l2ping.args",
+        "Arguments passed to l2ping before its own",
+    ),
+    (
+        "scan.check_command.path",
+        "Command checking devices with method = \"command\", present if it exits successfully",
+    ),
+    (
+        "scan.check_command.args",
+        "Arguments passed to the check command before the device's address",
+    ),
+    (
+        "scan.startup_state",
+        "State devices start in: absent, unknown or restore",
+    ),
+    (
+        "scan.state_file",
+        "Where device states are saved, required for startup_state = \"restore\"",
+    ),
+    (
+        "scan.fingerprints",
+        "Experimental: follow devices across rotated addresses by their advertisements",
+    ),
+    (
+        "scan.beacon_expiration_seconds",
+        "Mark beacons absent once they haven't advertised for this long, 0 to disable",
+    ),
+    (
+        "scan.adv_monitor",
+        "Linux: let BlueZ watch for tracked manufacturers instead of scanning continuously",
+    ),
+    (
+        "scan.adv_monitor_rssi_threshold",
+        "Only report advertisement monitor matches at least this strong, in dBm",
+    ),
+    (
+        "scan.oui_file",
+        "IEEE oui.txt or Wireshark manuf file for looking up more hardware vendors",
+    ),
+    (
+        "adapter.wait_seconds",
+        "How long to wait for a Bluetooth adapter at startup, 0 to fail immediately",
+    ),
+    (
+        "adapter.max_retry_interval_seconds",
+        "Upper bound for the delay between adapter lookups",
+    ),
+    (
+        "adapter.ids",
+        "Adapters to use, defaults to the first one found",
+    ),
+    (
+        "adapter.rooms",
+        "Adapter for each room's devices, e.g. { Kitchen = \"hci1\" }",
+    ),
+    (
+        "adapter.mock_scenario",
+        "Replay this scenario on fake adapters instead of using real ones, requires the mock feature",
+    ),
+    (
+        "homeassistant.discovery_prefix",
+        "Topic prefix for Home Assistant MQTT discovery",
+    ),
+    (
+        "homeassistant.room",
+        "Room this node is in, publishes an occupancy binary_sensor",
+    ),
+    (
+        "http.listen",
+        "Address the web UI and HTTP API listen on, requires the http feature",
+    ),
+    (
+        "http.token",
+        "Bearer token required to change devices over HTTP, and to listen beyond loopback",
+    ),
+    (
+        "election.heartbeat_seconds",
+        "How often to tell the other nodes this one is alive",
+    ),
+    (
+        "election.timeout_seconds",
+        "Nodes not heard from for this long are considered gone",
+    ),
+    (
+        "alerts.device_unseen_hours",
+        "Alert when a device hasn't been present for this long",
+    ),
+    (
+        "alerts.check_failure_rate",
+        "Alert when more than this share (0 to 1) of checks fail within the window",
+    ),
+    (
+        "alerts.window_minutes",
+        "Window the check failure rate is measured over",
+    ),
+    (
+        "alerts.battery_below_percent",
+        "Alert when a device advertises a battery level below this percentage",
+    ),
+    (
+        "alerts.publish",
+        "Publish alerts to <topic_path>/<publisher_id>/alerts",
+    ),
+    (
+        "alerts.notify_command.path",
+        "Run this command for every alert, with the alert as JSON as its last argument",
+    ),
+    (
+        "alerts.notify_command.args",
+        "Passed to the notify command before the alert",
+    ),
+    ("logging.file", "Log to this file instead of stderr"),
+    (
+        "logging.rotation",
+        "When to start a new log file: size, hourly or daily",
+    ),
+    (
+        "logging.max_size_mb",
+        "With size rotation, start a new file before the current one grows past this",
+    ),
+    ("logging.keep_files", "Rotated log files to keep"),
+    ("devices.address", "MAC address, required"),
+    ("devices.name", "Name used in topics, required"),
+    (
+        "devices.manufacturer",
+        "Whose advertisements trigger an arrival scan: Apple or Google",
+    ),
+    ("devices.friendly_name", "Display name for dashboards"),
+    ("devices.room", "Area the device belongs to"),
+    ("devices.icon", "Material Design icon, e.g. mdi:cellphone"),
+    (
+        "devices.detect",
+        "How presence is decided: check, advertisement or static",
+    ),
+    (
+        "devices.present",
+        "Presence of a static device at startup, defaults to present",
+    ),
+    (
+        "devices.type",
+        "Overrides the type reported for this device",
+    ),
+    ("devices.adapter", "Adapter to scan this device from"),
+    (
+        "devices.device_seen_debounce_seconds",
+        "Overrides scan.device_seen_debounce_seconds",
+    ),
+    (
+        "devices.device_trigger_debounce_seconds",
+        "Overrides scan.device_trigger_debounce_seconds",
+    ),
+    (
+        "devices.method",
+        "Overrides scan.backend: hcitool, l2ping, gatt, advertisement or command",
+    ),
+];
+
+/// A config with every optional section present, so all of their defaults get filled in.
+const SKELETON: &str = r#"
+[mqtt]
+host = ""
+[homeassistant]
+[http]
+[election]
+[logging]
+[alerts]
+"#;
+
+pub fn run() -> anyhow::Result<()> {
+    print!("{}", render()?);
+    Ok(())
+}
+
+fn render() -> anyhow::Result<String> {
+    let resolved = toml::Value::try_from(defaults()?)?;
+    let width = OPTIONS.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for (key, description) in OPTIONS {
+        let value = lookup(&resolved, key)
+            .filter(|value| !matches!(value, toml::Value::String(s) if s.is_empty()))
+            .map_or_else(|| "unset".to_string(), ToString::to_string);
+        out.push_str(&format!(
+            "{key:width$}  {value}\n{:width$}  {description}\n",
+            ""
+        ));
+    }
+    Ok(out)
+}
+
+fn defaults() -> anyhow::Result<AppConfig> {
+    let config: AppConfig = toml::de::from_str(SKELETON)?;
+    Ok(config.with_defaults())
+}
+
+fn lookup<'a>(value: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    key.split('.')
+        .try_fold(value, |value, part| value.as_table()?.get(part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every option with a default has to be described, including those of sections nested in
+    /// the ones SKELETON opens.
+    #[test]
+    fn test_options_described() {
+        fn leaves(prefix: &str, value: &toml::Value, keys: &mut Vec<String>) {
+            match value.as_table() {
+                // Maps of user-chosen keys are a single option
+                Some(table) if prefix != "adapter.rooms" => {
+                    for (key, value) in table {
+                        let key = if prefix.is_empty() {
+                            key.clone()
+                        } else {
+                            format!("{prefix}.{key}")
+                        };
+                        leaves(&key, value, keys);
+                    }
+                }
+                // Described per device, and empty here
+                _ if prefix == "devices" => {}
+                _ => keys.push(prefix.to_string()),
+            }
+        }
+        let nested = r#"
+[mqtt.topic_names]
+[mqtt.device_tracker]
+[scan]
+[scan.confidence]
+[scan.recovery]
+[scan.rssi]
+enter = -75
+[adapter]
+"#;
+        let config: AppConfig = toml::de::from_str(&format!("{SKELETON}{nested}")).unwrap();
+        let resolved = toml::Value::try_from(config.with_defaults()).unwrap();
+        let mut keys = Vec::new();
+        leaves("", &resolved, &mut keys);
+        // Guards against the walk silently finding nothing
+        for section in ["mqtt.topic_names", "scan.rssi", "scan.recovery"] {
+            assert!(
+                keys.iter()
+                    .any(|key| key.starts_with(&format!("{section}."))),
+                "{section} wasn't walked"
+            );
+        }
+        for key in keys {
+            assert!(
+                OPTIONS.iter().any(|(option, _)| *option == key),
+                "{key} has no description"
+            );
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let rendered = render().unwrap();
+        assert!(rendered.contains("scan.interscan_delay_seconds"));
+        assert!(rendered.contains(&format!(
+            "  {}\n",
+            crate::config::DEFAULT_INTERSCAN_DELAY_SECONDS
+        )));
+        assert!(rendered.contains("mqtt.username"));
+    }
+}
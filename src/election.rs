@@ -0,0 +1,162 @@
+//! Optional leader election between nodes sharing a broker, so broker-wide duties are done by
+//! exactly one of them.
+//!
+//! Every node publishes a heartbeat to `<topic_path>/election/<publisher_id>` and the live node
+//! with the lowest publisher ID leads. Every node also follows the device states published by
+//! the others, so whichever node takes over already knows whether anyone is home.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone)]
+pub struct Election {
+    publisher_id: String,
+    timeout: Duration,
+    started: Instant,
+    state: Arc<Mutex<State>>,
+    anyone_home: watch::Sender<bool>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Last heartbeat from each other node
+    heartbeats: HashMap<String, Instant>,
+    /// Latest confidence for each device, keyed by node and device
+    confidence: HashMap<(String, String), u8>,
+    leading: bool,
+}
+
+impl Election {
+    /// Nodes whose heartbeat is older than `timeout` are considered gone. No node claims
+    /// leadership until it has been running that long, so it hears from the others first.
+    pub fn new(publisher_id: &str, timeout: Duration, now: Instant) -> Self {
+        Election {
+            publisher_id: publisher_id.to_string(),
+            timeout,
+            started: now,
+            state: Arc::default(),
+            anyone_home: watch::Sender::new(false),
+        }
+    }
+
+    pub fn heartbeat(&self, node: &str, now: Instant) {
+        if node == self.publisher_id {
+            return;
+        }
+        let mut state = self.state.lock().expect("election lock poisoned");
+        state.heartbeats.insert(node.to_string(), now);
+    }
+
+    /// Record a device state published by any node, including this one.
+    pub fn record_sighting(&self, node: &str, device: &str, confidence: u8) {
+        let mut state = self.state.lock().expect("election lock poisoned");
+        state
+            .confidence
+            .insert((node.to_string(), device.to_string()), confidence);
+        self.update_anyone_home(&state);
+    }
+
+    /// Forget nodes that stopped sending heartbeats and work out who leads, returning whether
+    /// this node leads if that changed.
+    pub fn update(&self, now: Instant) -> Option<bool> {
+        if now.saturating_duration_since(self.started) < self.timeout {
+            return None;
+        }
+        let mut state = self.state.lock().expect("election lock poisoned");
+        let timeout = self.timeout;
+        state.heartbeats.retain(|node, last| {
+            let alive = now.saturating_duration_since(*last) <= timeout;
+            if !alive {
+                debug!("No heartbeat from node {node} for {timeout:?}, forgetting it");
+            }
+            alive
+        });
+        // Devices seen only by a node that's gone can't be vouched for anymore
+        let State {
+            heartbeats,
+            confidence,
+            ..
+        } = &mut *state;
+        confidence
+            .retain(|(node, _), _| *node == self.publisher_id || heartbeats.contains_key(node));
+        self.update_anyone_home(&state);
+
+        let leading = state
+            .heartbeats
+            .keys()
+            .all(|node| self.publisher_id < *node);
+        (state.leading != leading).then(|| {
+            state.leading = leading;
+            leading
+        })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.state.lock().expect("election lock poisoned").leading
+    }
+
+    /// Whether any tracked device is present at any live node.
+    pub fn anyone_home(&self) -> watch::Receiver<bool> {
+        self.anyone_home.subscribe()
+    }
+
+    fn update_anyone_home(&self, state: &State) {
+        let anyone_home = state.confidence.values().any(|confidence| *confidence > 0);
+        self.anyone_home.send_if_modified(|current| {
+            let changed = *current != anyone_home;
+            *current = anyone_home;
+            changed
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(15);
+
+    #[test]
+    fn test_lowest_live_node_leads() {
+        let start = Instant::now();
+        let election = Election::new("b", TIMEOUT, start);
+        election.heartbeat("c", start);
+        // Still listening for other nodes
+        assert_eq!(election.update(start), None);
+
+        let now = start + TIMEOUT;
+        election.heartbeat("a", now);
+        assert_eq!(election.update(now), None);
+        assert!(!election.is_leader());
+
+        // "a" stopped sending heartbeats
+        let later = now + TIMEOUT * 2;
+        election.heartbeat("c", later);
+        assert_eq!(election.update(later), Some(true));
+        assert!(election.is_leader());
+
+        election.heartbeat("a", later);
+        assert_eq!(election.update(later), Some(false));
+    }
+
+    #[test]
+    fn test_anyone_home() {
+        let start = Instant::now();
+        let election = Election::new("a", TIMEOUT, start);
+        let anyone_home = election.anyone_home();
+
+        election.heartbeat("b", start);
+        election.record_sighting("b", "phone", 100);
+        assert!(*anyone_home.borrow());
+
+        election.record_sighting("a", "watch", 0);
+        assert!(*anyone_home.borrow());
+
+        // Node "b" went away, taking its sightings with it
+        election.update(start + TIMEOUT * 2);
+        assert!(!*anyone_home.borrow());
+    }
+}
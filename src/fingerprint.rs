@@ -0,0 +1,243 @@
+//! Experimental: recognize tracked devices after their address rotates, by the shape of their
+//! advertisements.
+//!
+//! While a device is present, its advertisements (advertised services, manufacturer data
+//! lengths and how often it advertises) are recorded as its fingerprint. Advertisements from
+//! unknown random addresses matching exactly one fingerprint count as a sighting of that device,
+//! which keeps it present when an active check misses it.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use btleplug::api::{AddressType, PeripheralProperties};
+use log::debug;
+
+/// Advertisements with an unchanged signature needed before a fingerprint is used.
+const MIN_SAMPLES: u32 = 5;
+/// Gaps longer than this are the device leaving and coming back, not its advertising interval.
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+/// How long an unknown address is remembered for measuring its advertising interval.
+const STRANGER_TTL: Duration = Duration::from_secs(300);
+const MAX_STRANGERS: usize = 1024;
+
+/// Parts of an advertisement that stay the same when the address rotates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Signature {
+    services: BTreeSet<u128>,
+    manufacturer_data_lengths: BTreeMap<u16, usize>,
+}
+
+impl Signature {
+    fn from_properties(properties: &PeripheralProperties) -> Self {
+        Signature {
+            services: properties
+                .services
+                .iter()
+                .map(|uuid| uuid.as_u128())
+                .collect(),
+            manufacturer_data_lengths: properties
+                .manufacturer_data
+                .iter()
+                .map(|(id, data)| (*id, data.len()))
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.services.is_empty() && self.manufacturer_data_lengths.is_empty()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Fingerprint {
+    signature: Option<Signature>,
+    samples: u32,
+    /// Smoothed time between advertisements
+    interval: Option<Duration>,
+    last_advertisement: Option<Instant>,
+    present: bool,
+    matched_at: Option<Instant>,
+}
+
+impl Fingerprint {
+    fn learn(&mut self, signature: Signature, now: Instant) {
+        if let Some(gap) = self
+            .last_advertisement
+            .map(|last| now.saturating_duration_since(last))
+            .filter(|gap| *gap <= MAX_INTERVAL)
+        {
+            self.interval = Some(match self.interval {
+                Some(interval) => (interval * 3 + gap) / 4,
+                None => gap,
+            });
+        }
+        self.last_advertisement = Some(now);
+
+        if self.signature.as_ref() == Some(&signature) {
+            self.samples = self.samples.saturating_add(1);
+        } else {
+            self.signature = Some(signature);
+            self.samples = 1;
+        }
+    }
+
+    fn matches(&self, signature: &Signature, interval: Option<Duration>) -> bool {
+        let interval_matches = match (self.interval, interval) {
+            (Some(learned), Some(interval)) => interval >= learned / 2 && interval <= learned * 2,
+            _ => true,
+        };
+        self.samples >= MIN_SAMPLES
+            && self.signature.as_ref() == Some(signature)
+            && interval_matches
+    }
+}
+
+/// Learned fingerprints for every tracked device, keyed by uppercase MAC address. Fed by the
+/// manager's BLE event loop and consulted by the scanners.
+#[derive(Debug, Clone, Default)]
+pub struct Fingerprints {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    devices: HashMap<String, Fingerprint>,
+    /// Last advertisement from each unknown address, for measuring its interval
+    strangers: HashMap<String, Instant>,
+}
+
+impl Fingerprints {
+    /// Only present devices are learned from, so an address reused by something else can't
+    /// poison the fingerprint.
+    pub fn set_present(&self, mac_address: &str, present: bool) {
+        let mut state = self.state.lock().expect("fingerprints lock poisoned");
+        state
+            .devices
+            .entry(mac_address.to_uppercase())
+            .or_default()
+            .present = present;
+    }
+
+    /// Learn from an advertisement of a tracked device, or match an unknown one against what
+    /// has been learned.
+    pub fn observe(&self, properties: &PeripheralProperties, now: Instant) {
+        let address = properties.address.to_string().to_uppercase();
+        let signature = Signature::from_properties(properties);
+        if signature.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().expect("fingerprints lock poisoned");
+        if let Some(fingerprint) = state.devices.get_mut(&address) {
+            if fingerprint.present {
+                fingerprint.learn(signature, now);
+            }
+            return;
+        }
+        // Public addresses never rotate
+        if properties.address_type == Some(AddressType::Public) {
+            return;
+        }
+
+        if state.strangers.len() >= MAX_STRANGERS {
+            state
+                .strangers
+                .retain(|_, seen| now.saturating_duration_since(*seen) < STRANGER_TTL);
+        }
+        let interval = state
+            .strangers
+            .insert(address.clone(), now)
+            .map(|last| now.saturating_duration_since(last))
+            .filter(|gap| *gap <= MAX_INTERVAL);
+
+        let mut matches = state
+            .devices
+            .iter_mut()
+            .filter(|(_, fingerprint)| fingerprint.matches(&signature, interval));
+        // Several devices of the same model look alike, don't guess between them
+        if let (Some((mac_address, fingerprint)), None) = (matches.next(), matches.next()) {
+            debug!("Advertisement from {address} matches the fingerprint of {mac_address}");
+            fingerprint.matched_at = Some(now);
+        }
+    }
+
+    /// Whether the device's fingerprint was matched within `window` of `now`.
+    pub fn matched_within(&self, mac_address: &str, now: Instant, window: Duration) -> bool {
+        let state = self.state.lock().expect("fingerprints lock poisoned");
+        state
+            .devices
+            .get(&mac_address.to_uppercase())
+            .and_then(|fingerprint| fingerprint.matched_at)
+            .is_some_and(|matched_at| now.saturating_duration_since(matched_at) <= window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use btleplug::api::BDAddr;
+
+    use super::*;
+
+    fn advertisement(address: &str, data_len: usize) -> PeripheralProperties {
+        PeripheralProperties {
+            address: address.parse::<BDAddr>().unwrap(),
+            address_type: Some(AddressType::Random),
+            manufacturer_data: HashMap::from([(0x004C, vec![0; data_len])]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_match_after_rotation() {
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        let fingerprints = Fingerprints::default();
+        fingerprints.set_present("00:11:22:33:44:55", true);
+
+        for i in 0..MIN_SAMPLES {
+            fingerprints.observe(
+                &advertisement("00:11:22:33:44:55", 10),
+                now + Duration::from_secs(i.into()),
+            );
+        }
+
+        // Different payload shape
+        fingerprints.observe(&advertisement("AA:BB:CC:DD:EE:01", 12), now);
+        assert!(!fingerprints.matched_within("00:11:22:33:44:55", now, window));
+
+        fingerprints.observe(&advertisement("AA:BB:CC:DD:EE:02", 10), now);
+        assert!(fingerprints.matched_within("00:11:22:33:44:55", now, window));
+        assert!(!fingerprints.matched_within("00:11:22:33:44:55", now + window * 2, window));
+    }
+
+    #[test]
+    fn test_ambiguous_and_absent() {
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        let fingerprints = Fingerprints::default();
+        fingerprints.set_present("00:11:22:33:44:55", true);
+        fingerprints.set_present("00:11:22:33:44:56", false);
+
+        for i in 0..MIN_SAMPLES {
+            let at = now + Duration::from_secs(i.into());
+            fingerprints.observe(&advertisement("00:11:22:33:44:55", 10), at);
+            // Not learned while absent
+            fingerprints.observe(&advertisement("00:11:22:33:44:56", 10), at);
+        }
+        fingerprints.observe(&advertisement("AA:BB:CC:DD:EE:01", 10), now);
+        assert!(fingerprints.matched_within("00:11:22:33:44:55", now, window));
+        assert!(!fingerprints.matched_within("00:11:22:33:44:56", now, window));
+
+        fingerprints.set_present("00:11:22:33:44:56", true);
+        for i in 0..MIN_SAMPLES {
+            let at = now + Duration::from_secs(i.into());
+            fingerprints.observe(&advertisement("00:11:22:33:44:56", 10), at);
+        }
+        let later = now + Duration::from_secs(120);
+        fingerprints.observe(&advertisement("AA:BB:CC:DD:EE:02", 10), later);
+        assert!(!fingerprints.matched_within("00:11:22:33:44:55", later, window));
+        assert!(!fingerprints.matched_within("00:11:22:33:44:56", later, window));
+    }
+}
@@ -0,0 +1,332 @@
+//! [Home Assistant MQTT discovery](https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery)
+//! payloads for the devices this node tracks.
+use std::collections::HashSet;
+
+use serde_json::json;
+
+use crate::config::{self, BleDevice, HomeAssistantConfig};
+use crate::mqtt::sanitize_name;
+
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    prefix: String,
+    publisher_id: String,
+    room: Option<String>,
+}
+
+/// A retained config message to publish to the discovery prefix.
+pub struct DiscoveryMessage {
+    pub topic: String,
+    pub payload: serde_json::Value,
+}
+
+/// Where a `device_tracker` reads its state from.
+pub enum TrackerState<'a> {
+    /// The device's JSON state topic
+    Device(&'a str),
+    /// The device's `mqtt.device_tracker` topic, with its payloads
+    Tracker {
+        topic: &'a str,
+        payload_home: &'a str,
+        payload_not_home: &'a str,
+    },
+}
+
+impl Discovery {
+    pub fn new(cfg: &HomeAssistantConfig, publisher_id: &str) -> Self {
+        Discovery {
+            prefix: cfg
+                .discovery_prefix
+                .clone()
+                .unwrap_or_else(|| config::DEFAULT_DISCOVERY_PREFIX.to_string()),
+            publisher_id: publisher_id.to_string(),
+            room: cfg.room.clone(),
+        }
+    }
+
+    pub fn room(&self) -> Option<&str> {
+        self.room.as_deref()
+    }
+
+    /// Where Home Assistant announces it's `online` after starting, when it needs the discovery
+    /// configs and states again.
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    /// An `event` entity firing `arrived` / `departed` from the device's event topic.
+    pub fn event(&self, device: &BleDevice, event_topic: &str) -> DiscoveryMessage {
+        let object_id = self.object_id(device);
+        let mut payload = json!({
+            "name": "Presence",
+            "unique_id": format!("{object_id}_event"),
+            "state_topic": event_topic,
+            "event_types": ["arrived", "departed"],
+            // The event topic carries a bare event name, HA expects JSON
+            "value_template": "{\"event_type\": \"{{ value }}\"}",
+            "device": self.device_info(device),
+        });
+        if let Some(icon) = &device.icon {
+            payload["icon"] = json!(icon);
+        }
+        DiscoveryMessage {
+            topic: format!(
+                "{}/event/{}/{object_id}/config",
+                self.prefix, self.publisher_id
+            ),
+            payload,
+        }
+    }
+
+    /// A `device_tracker` with the attributes published alongside its state. It follows the
+    /// device's `home` / `not_home` topic when `mqtt.device_tracker` publishes one, otherwise it's
+    /// `home` while the confidence in the device's state topic is above zero.
+    pub fn device_tracker(
+        &self,
+        device: &BleDevice,
+        state: TrackerState,
+        attributes_topic: &str,
+    ) -> DiscoveryMessage {
+        let object_id = self.object_id(device);
+        let mut payload = json!({
+            "name": serde_json::Value::Null,
+            "unique_id": format!("{object_id}_tracker"),
+            "json_attributes_topic": attributes_topic,
+            "source_type": "bluetooth_le",
+            "device": self.device_info(device),
+        });
+        match state {
+            TrackerState::Device(topic) => {
+                payload["state_topic"] = json!(topic);
+                payload["value_template"] =
+                    json!("{{ 'home' if value_json.confidence | int > 0 else 'not_home' }}");
+                payload["payload_home"] = json!("home");
+                payload["payload_not_home"] = json!("not_home");
+            }
+            TrackerState::Tracker {
+                topic,
+                payload_home,
+                payload_not_home,
+            } => {
+                payload["state_topic"] = json!(topic);
+                payload["payload_home"] = json!(payload_home);
+                payload["payload_not_home"] = json!(payload_not_home);
+            }
+        }
+        if let Some(icon) = &device.icon {
+            payload["icon"] = json!(icon);
+        }
+        DiscoveryMessage {
+            topic: format!(
+                "{}/device_tracker/{}/{object_id}/config",
+                self.prefix, self.publisher_id
+            ),
+            payload,
+        }
+    }
+
+    /// A `binary_sensor.<room>_occupancy` that's on while any tracked device is present at this
+    /// node, if a room is configured.
+    pub fn occupancy(&self, state_topic: &str) -> Option<DiscoveryMessage> {
+        let room = self.room.as_ref()?;
+        let object_id = format!(
+            "monitor_rs_{}_{}_occupancy",
+            self.publisher_id,
+            sanitize_name(room)
+        );
+        let payload = json!({
+            "name": format!("{room} occupancy"),
+            "object_id": format!("{}_occupancy", sanitize_name(room)),
+            "unique_id": object_id,
+            "state_topic": state_topic,
+            "device_class": "occupancy",
+            "payload_on": "ON",
+            "payload_off": "OFF",
+            "device": {
+                "identifiers": [format!("monitor_rs_{}", self.publisher_id)],
+                "name": format!("monitor-rs {}", self.publisher_id),
+                "suggested_area": room,
+            },
+        });
+        Some(DiscoveryMessage {
+            topic: format!(
+                "{}/binary_sensor/{}/{object_id}/config",
+                self.prefix, self.publisher_id
+            ),
+            payload,
+        })
+    }
+
+    /// A fleet-wide `binary_sensor` that's on while any tracked device is present at any node,
+    /// published by the elected leader.
+    pub fn anyone_home(&self, topic_path: &str, state_topic: &str) -> DiscoveryMessage {
+        let fleet = sanitize_name(topic_path);
+        let object_id = format!("monitor_rs_{fleet}_anyone_home");
+        DiscoveryMessage {
+            topic: format!("{}/binary_sensor/{fleet}/{object_id}/config", self.prefix),
+            payload: json!({
+                "name": "Anyone home",
+                "unique_id": object_id,
+                "state_topic": state_topic,
+                "device_class": "presence",
+                "payload_on": "ON",
+                "payload_off": "OFF",
+                "device": {
+                    "identifiers": [format!("monitor_rs_{fleet}")],
+                    "name": format!("monitor-rs {topic_path}"),
+                },
+            }),
+        }
+    }
+
+    fn device_info(&self, device: &BleDevice) -> serde_json::Value {
+        let mut info = json!({
+            "identifiers": [self.object_id(device)],
+            "name": device.friendly_name.as_ref().unwrap_or(&device.name),
+        });
+        if let Some(room) = &device.room {
+            info["suggested_area"] = json!(room);
+        }
+        info
+    }
+
+    /// Stable per-node identifier for a device, derived from its MAC address so renaming the
+    /// device doesn't create a new entity.
+    fn object_id(&self, device: &BleDevice) -> String {
+        let mac = device.address.to_string().replace(':', "").to_lowercase();
+        format!("monitor_rs_{}_{mac}", self.publisher_id)
+    }
+}
+
+/// Which tracked devices are present at this node, for the room occupancy sensor.
+#[derive(Debug, Default)]
+pub struct Occupancy {
+    present: HashSet<String>,
+    occupied: Option<bool>,
+}
+
+impl Occupancy {
+    /// Record a device's presence, returning the new occupancy if it changed (or was unknown).
+    pub fn update(&mut self, name: &str, present: bool) -> Option<bool> {
+        if present {
+            self.present.insert(name.to_string());
+        } else {
+            self.present.remove(name);
+        }
+        let occupied = !self.present.is_empty();
+        (self.occupied.replace(occupied) != Some(occupied)).then_some(occupied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_discovery() {
+        let device: BleDevice = toml::de::from_str(
+            r#"
+            address = "AA:BB:CC:DD:EE:FF"
+            name = "Phone"
+            "#,
+        )
+        .unwrap();
+        let discovery = Discovery::new(&HomeAssistantConfig::default(), "kitchen");
+        let message = discovery.event(&device, "monitor/kitchen/phone/event");
+
+        assert_eq!(
+            message.topic,
+            "homeassistant/event/kitchen/monitor_rs_kitchen_aabbccddeeff/config"
+        );
+        assert_eq!(
+            message.payload["unique_id"],
+            "monitor_rs_kitchen_aabbccddeeff_event"
+        );
+        assert_eq!(
+            message.payload["state_topic"],
+            "monitor/kitchen/phone/event"
+        );
+    }
+
+    #[test]
+    fn test_device_tracker_discovery() {
+        let device: BleDevice = toml::de::from_str(
+            r#"
+            address = "AA:BB:CC:DD:EE:FF"
+            name = "Phone"
+            "#,
+        )
+        .unwrap();
+        let discovery = Discovery::new(&HomeAssistantConfig::default(), "kitchen");
+        let message = discovery.device_tracker(
+            &device,
+            TrackerState::Device("monitor/kitchen/phone"),
+            "monitor/kitchen/phone/attributes",
+        );
+
+        assert_eq!(
+            message.topic,
+            "homeassistant/device_tracker/kitchen/monitor_rs_kitchen_aabbccddeeff/config"
+        );
+        assert_eq!(
+            message.payload["unique_id"],
+            "monitor_rs_kitchen_aabbccddeeff_tracker"
+        );
+        assert_eq!(message.payload["source_type"], "bluetooth_le");
+        assert_eq!(
+            message.payload["json_attributes_topic"],
+            "monitor/kitchen/phone/attributes"
+        );
+        // Shares the device with the event entity
+        assert_eq!(
+            message.payload["device"],
+            discovery.event(&device, "").payload["device"]
+        );
+        assert_eq!(discovery.status_topic(), "homeassistant/status");
+
+        let message = discovery.device_tracker(
+            &device,
+            TrackerState::Tracker {
+                topic: "monitor/kitchen/phone/device_tracker",
+                payload_home: "present",
+                payload_not_home: "away",
+            },
+            "monitor/kitchen/phone/attributes",
+        );
+        assert_eq!(
+            message.payload["state_topic"],
+            "monitor/kitchen/phone/device_tracker"
+        );
+        assert_eq!(message.payload["payload_home"], "present");
+        assert_eq!(message.payload["payload_not_home"], "away");
+        assert!(message.payload.get("value_template").is_none());
+    }
+
+    #[test]
+    fn test_occupancy_discovery() {
+        let discovery = Discovery::new(&HomeAssistantConfig::default(), "kitchen");
+        assert!(discovery.occupancy("monitor/kitchen/occupancy").is_none());
+
+        let config: HomeAssistantConfig = toml::de::from_str(r#"room = "Living Room""#).unwrap();
+        let discovery = Discovery::new(&config, "kitchen");
+        let message = discovery.occupancy("monitor/kitchen/occupancy").unwrap();
+
+        assert_eq!(
+            message.topic,
+            "homeassistant/binary_sensor/kitchen/monitor_rs_kitchen_living_room_occupancy/config"
+        );
+        assert_eq!(message.payload["object_id"], "living_room_occupancy");
+        assert_eq!(message.payload["device_class"], "occupancy");
+        assert_eq!(message.payload["device"]["suggested_area"], "Living Room");
+    }
+
+    #[test]
+    fn test_occupancy() {
+        let mut occupancy = Occupancy::default();
+        assert_eq!(occupancy.update("phone", false), Some(false));
+        assert_eq!(occupancy.update("phone", true), Some(true));
+        assert_eq!(occupancy.update("watch", true), None);
+        assert_eq!(occupancy.update("phone", false), None);
+        assert_eq!(occupancy.update("watch", false), Some(false));
+    }
+}
@@ -0,0 +1,641 @@
+//! Web UI and HTTP API for viewing device states and managing tracked devices without editing
+//! the config file by hand.
+//!
+//! | Route                    | |
+//! |--------------------------|-|
+//! | `GET /`                  | Web UI |
+//! | `GET /devices`           | Tracked devices with their latest announced state |
+//! | `POST /devices`          | Add a device, the body is a `[[devices]]` entry as JSON |
+//! | `PATCH /devices/{name}`  | Change some of a device's fields, `null` removes a field |
+//! | `DELETE /devices/{name}` | Stop tracking a device |
+//! | `GET /ws/events`         | WebSocket streaming every device announcement as JSON |
+//! | `GET /events`            | The same stream as Server-Sent Events, e.g. for `curl -N` |
+//!
+//! Changes apply to the running scanners straight away and are written back to the config file
+//! unless `?persist=false` is passed. Discovery configs and advertisement triggers only pick them
+//! up after a restart. When `http.token` is set, changes need an `Authorization: Bearer <token>`
+//! header.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context as _;
+use axum::{
+    Json, Router,
+    extract::{
+        Path, Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, StatusCode, header},
+    response::{
+        Html, IntoResponse, Response,
+        sse::{Event, KeepAlive, Sse},
+    },
+    routing::{get, patch},
+};
+use futures::StreamExt as _;
+use log::{debug, error, info};
+use serde::Serialize;
+use serde_derive::Deserialize;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    config::{self, AdapterConfig, BleDevice, CheckMethod, HttpConfig, TopicNamesConfig},
+    config_file,
+    messages::{DeviceAnnouncement, DevicePresence, Reason, StateAnnouncement},
+};
+
+/// How device changes reach the running scanners.
+#[derive(Debug, Clone)]
+pub struct Scanners {
+    pub tx: mpsc::Sender<StateAnnouncement>,
+    pub adapter_ids: Vec<String>,
+    pub adapter_config: AdapterConfig,
+    /// Methods the scanners have a checker for, see [BleDevice::method]
+    pub methods: HashSet<CheckMethod>,
+}
+
+impl Scanners {
+    /// Without a checker for its method, every check of the device would fail.
+    fn check_method(&self, device: &BleDevice) -> Result<(), ApiError> {
+        match device.method {
+            Some(method) if !self.methods.contains(&method) => Err(ApiError(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "method = {:?} isn't available, it needs setting up in the config file first",
+                    method.as_str()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn change(&self, name: &str, device: Option<&BleDevice>) -> anyhow::Result<()> {
+        let adapter = match device {
+            Some(device) => crate::adapter::assign_devices(
+                std::slice::from_ref(device),
+                &self.adapter_ids,
+                &self.adapter_config,
+            )
+            .iter()
+            .position(|devices| !devices.is_empty())
+            .and_then(|i| self.adapter_ids.get(i))
+            .cloned()
+            .unwrap_or_default(),
+            None => String::new(),
+        };
+        self.tx
+            .send(StateAnnouncement::DeviceChanged {
+                name: name.to_string(),
+                device: device.cloned().map(Box::new),
+                adapter,
+            })
+            .await
+            .context("Failed to notify scanners")?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    token: Option<Arc<str>>,
+    scanners: Scanners,
+    config_path: Arc<PathBuf>,
+    /// How device names become topics, which must stay unique
+    topic_names: TopicNamesConfig,
+    /// Devices in the config file, locked for the whole of an edit so edits can't interleave
+    devices: Arc<tokio::sync::Mutex<Vec<BleDevice>>>,
+    statuses: Arc<Mutex<HashMap<String, DeviceStatus>>>,
+    /// Announcements serialized once for every event stream client
+    events: broadcast::Sender<Arc<str>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DeviceStatus {
+    present: bool,
+    confidence: u8,
+}
+
+/// A device announcement as sent to event stream clients.
+#[derive(Debug, Serialize)]
+struct DeviceEvent<'a> {
+    name: &'a str,
+    #[serde(rename = "id")]
+    mac_address: &'a str,
+    #[serde(rename = "type")]
+    device_type: config::DeviceType,
+    #[serde(flatten)]
+    status: &'a DeviceStatus,
+    /// `arrived` or `departed` when the device changed state
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transition: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<Reason>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceView {
+    #[serde(flatten)]
+    device: BleDevice,
+    /// Latest announcement, missing until the device has been checked
+    status: Option<DeviceStatus>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeParams {
+    /// Write the change to the config file, defaults to true
+    persist: Option<bool>,
+}
+
+/// Events buffered per stream client before it starts missing them
+const EVENT_BUFFER: usize = 64;
+
+/// Serve the UI and API until the listener fails.
+pub async fn serve(
+    cfg: &HttpConfig,
+    config_path: PathBuf,
+    devices: Vec<BleDevice>,
+    topic_names: TopicNamesConfig,
+    announce_rx: mpsc::Receiver<DeviceAnnouncement>,
+    scanners: Scanners,
+) -> anyhow::Result<()> {
+    let listen = cfg
+        .listen
+        .clone()
+        .unwrap_or_else(|| config::DEFAULT_HTTP_LISTEN.to_string());
+    let state = AppState {
+        token: cfg.token.as_deref().map(Arc::from),
+        scanners,
+        config_path: Arc::new(config_path),
+        topic_names,
+        devices: Arc::new(tokio::sync::Mutex::new(devices)),
+        statuses: Arc::default(),
+        events: broadcast::channel(EVENT_BUFFER).0,
+    };
+    tokio::task::spawn(track_statuses(
+        announce_rx,
+        state.statuses.clone(),
+        state.events.clone(),
+    ));
+
+    let listener = tokio::net::TcpListener::bind(&listen)
+        .await
+        .with_context(|| format!("Failed to listen on {listen}"))?;
+    check_exposed(listener.local_addr()?, cfg.token.as_deref())?;
+    info!("Serving web UI on http://{listen}");
+    axum::serve(listener, router(state))
+        .await
+        .context("HTTP server failed")
+}
+
+/// Refuse to let other hosts change the tracked devices without a token.
+fn check_exposed(addr: std::net::SocketAddr, token: Option<&str>) -> anyhow::Result<()> {
+    if token.is_none() && !addr.ip().is_loopback() {
+        anyhow::bail!(
+            "http.listen = {addr} accepts connections from other hosts, set http.token so they can't change the tracked devices"
+        );
+    }
+    Ok(())
+}
+
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(index))
+        .route("/devices", get(list_devices).post(add_device))
+        .route(
+            "/devices/{name}",
+            patch(update_device).delete(remove_device),
+        )
+        .route("/ws/events", get(ws_events))
+        .route("/events", get(sse_events))
+        .with_state(state)
+}
+
+/// Keep the latest status of every device and forward announcements to event stream clients.
+async fn track_statuses(
+    mut announce_rx: mpsc::Receiver<DeviceAnnouncement>,
+    statuses: Arc<Mutex<HashMap<String, DeviceStatus>>>,
+    events: broadcast::Sender<Arc<str>>,
+) {
+    while let Some(announcement) = announce_rx.recv().await {
+        let (present, confidence) = match announcement.presence {
+            DevicePresence::Present(confidence) => (true, confidence),
+            DevicePresence::Absent => (false, 0),
+        };
+        let status = DeviceStatus {
+            present,
+            confidence,
+        };
+        match serde_json::to_string(&DeviceEvent {
+            name: &announcement.name,
+            mac_address: &announcement.mac_address,
+            device_type: announcement.device_type,
+            status: &status,
+            transition: announcement.transition.map(|t| t.as_str()),
+            reason: announcement.reason,
+        }) {
+            // No clients is fine
+            Ok(event) => _ = events.send(event.into()),
+            Err(err) => error!("Error serializing device event: {err:?}"),
+        }
+        statuses
+            .lock()
+            .expect("statuses lock poisoned")
+            .insert(announcement.name, status);
+    }
+}
+
+async fn ws_events(State(state): State<AppState>, ws: WebSocketUpgrade) -> Response {
+    let events = state.events.subscribe();
+    ws.on_upgrade(|socket| stream_events(socket, events))
+}
+
+async fn stream_events(mut socket: WebSocket, mut events: broadcast::Receiver<Arc<str>>) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if socket.send(Message::Text(event.as_ref().into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("WebSocket client missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Clients only ever close, anything else they send is ignored
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn sse_events(
+    State(state): State<AppState>,
+) -> Sse<impl futures::Stream<Item = Result<Event, Infallible>>> {
+    let events = event_stream(state.events.subscribe())
+        .map(|event| Ok(Event::default().data(event.as_ref())));
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Events as a stream, skipping over any a slow client missed.
+fn event_stream(events: broadcast::Receiver<Arc<str>>) -> impl futures::Stream<Item = Arc<str>> {
+    futures::stream::unfold(events, |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((event, events)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    debug!("Event stream client missed {missed} events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+async fn index() -> Html<&'static str> {
+    Html(include_str!("http/index.html"))
+}
+
+async fn list_devices(State(state): State<AppState>) -> Json<Vec<DeviceView>> {
+    let devices = state.devices.lock().await;
+    let statuses = state.statuses.lock().expect("statuses lock poisoned");
+    Json(
+        devices
+            .iter()
+            .map(|device| DeviceView {
+                device: device.clone(),
+                status: statuses.get(&device.name).cloned(),
+            })
+            .collect(),
+    )
+}
+
+async fn add_device(
+    State(state): State<AppState>,
+    Query(params): Query<ChangeParams>,
+    headers: HeaderMap,
+    Json(device): Json<BleDevice>,
+) -> Result<(StatusCode, Json<BleDevice>), ApiError> {
+    state.authorize(&headers)?;
+    let mut devices = state.devices.lock().await;
+    check_conflicts(&devices, &device, None, &state.topic_names)?;
+    state.scanners.check_method(&device)?;
+    if params.persist.unwrap_or(true) {
+        state
+            .edit_config(|contents| config_file::add_device(contents, &device))
+            .await?;
+    }
+    state.scanners.change(&device.name, Some(&device)).await?;
+    info!("Added device {} ({})", device.name, device.address);
+    devices.push(device.clone());
+    Ok((StatusCode::CREATED, Json(device)))
+}
+
+async fn update_device(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(params): Query<ChangeParams>,
+    headers: HeaderMap,
+    Json(patch): Json<serde_json::Value>,
+) -> Result<Json<BleDevice>, ApiError> {
+    state.authorize(&headers)?;
+    let mut devices = state.devices.lock().await;
+    let index = find(&devices, &name)?;
+    let device = apply_patch(&devices[index], patch)?;
+    check_conflicts(&devices, &device, Some(index), &state.topic_names)?;
+    state.scanners.check_method(&device)?;
+    if params.persist.unwrap_or(true) {
+        state
+            .edit_config(|contents| config_file::replace_device(contents, &name, &device))
+            .await?;
+    }
+    state.scanners.change(&name, Some(&device)).await?;
+    info!("Updated device {name}");
+    devices[index] = device.clone();
+    Ok(Json(device))
+}
+
+async fn remove_device(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Query(params): Query<ChangeParams>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    state.authorize(&headers)?;
+    let mut devices = state.devices.lock().await;
+    let index = find(&devices, &name)?;
+    if params.persist.unwrap_or(true) {
+        state
+            .edit_config(|contents| config_file::remove_device(contents, &name))
+            .await?;
+    }
+    state.scanners.change(&name, None).await?;
+    info!("Removed device {name}");
+    devices.remove(index);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl AppState {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let Some(token) = &self.token else {
+            return Ok(());
+        };
+        let provided = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if provided.is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()))
+        {
+            Ok(())
+        } else {
+            Err(ApiError(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid bearer token".to_string(),
+            ))
+        }
+    }
+
+    async fn edit_config(
+        &self,
+        edit: impl FnOnce(&str) -> anyhow::Result<String>,
+    ) -> anyhow::Result<()> {
+        config_file::edit(&self.config_path, edit).await
+    }
+}
+
+/// Compare without returning early, so response times don't reveal how much of a guessed token
+/// was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn find(devices: &[BleDevice], name: &str) -> Result<usize, ApiError> {
+    devices
+        .iter()
+        .position(|device| device.name == name)
+        .ok_or_else(|| ApiError(StatusCode::NOT_FOUND, format!("No device named {name}")))
+}
+
+/// Names, addresses and topics must stay unique, ignoring the device at `skip` when it's being
+/// replaced.
+fn check_conflicts(
+    devices: &[BleDevice],
+    device: &BleDevice,
+    skip: Option<usize>,
+    topic_names: &TopicNamesConfig,
+) -> Result<(), ApiError> {
+    let mut candidates = devices
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| Some(*i) != skip)
+        .map(|(_, existing)| existing.clone())
+        .collect::<Vec<_>>();
+    candidates.push(device.clone());
+    config::check_devices(&candidates, topic_names)
+        .map_err(|err| ApiError(StatusCode::CONFLICT, err.to_string()))
+}
+
+/// Apply a JSON merge patch: fields in `patch` replace the device's, `null` removes them.
+fn apply_patch(device: &BleDevice, patch: serde_json::Value) -> Result<BleDevice, ApiError> {
+    let bad_request = |message: String| ApiError(StatusCode::BAD_REQUEST, message);
+    let serde_json::Value::Object(patch) = patch else {
+        return Err(bad_request("Expected a JSON object".to_string()));
+    };
+    let mut value = serde_json::to_value(device).map_err(anyhow::Error::from)?;
+    let fields = value
+        .as_object_mut()
+        .context("device did not serialize to an object")?;
+    for (key, field) in patch {
+        if field.is_null() {
+            fields.remove(&key);
+        } else {
+            fields.insert(key, field);
+        }
+    }
+    serde_json::from_value(value).map_err(|err| bad_request(err.to_string()))
+}
+
+#[derive(Debug)]
+struct ApiError(StatusCode, String);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}"))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, self.1).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(address: &str, name: &str) -> BleDevice {
+        toml::de::from_str(&format!("address = \"{address}\"\nname = \"{name}\"")).unwrap()
+    }
+
+    #[test]
+    fn test_apply_patch() {
+        let mut phone = device("00:11:22:33:44:55", "Phone");
+        phone.room = Some("Bedroom".to_string());
+
+        let patched = apply_patch(
+            &phone,
+            serde_json::json!({"friendly_name": "Alice's phone", "room": null}),
+        )
+        .unwrap();
+        assert_eq!(patched.friendly_name.as_deref(), Some("Alice's phone"));
+        assert_eq!(patched.room, None);
+        assert_eq!(patched.address, phone.address);
+
+        let err = apply_patch(&phone, serde_json::json!({"address": "nope"})).unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let (tx, _) = mpsc::channel(16);
+        let state = AppState {
+            token: Some(Arc::from("secret")),
+            scanners: Scanners {
+                tx,
+                adapter_ids: vec!["hci0".to_string()],
+                adapter_config: AdapterConfig::default(),
+                methods: HashSet::new(),
+            },
+            config_path: Arc::new(PathBuf::from("config.toml")),
+            topic_names: TopicNamesConfig::default(),
+            devices: Arc::default(),
+            statuses: Arc::default(),
+            events: broadcast::channel(1).0,
+        };
+        let mut headers = HeaderMap::new();
+        assert!(state.authorize(&headers).is_err());
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(state.authorize(&headers).is_err());
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(state.authorize(&headers).is_ok());
+
+        let open = AppState {
+            token: None,
+            ..state
+        };
+        assert!(open.authorize(&HeaderMap::new()).is_ok());
+
+        // Only loopback listeners may go without a token
+        assert!(check_exposed("127.0.0.1:8080".parse().unwrap(), None).is_ok());
+        assert!(check_exposed("[::1]:8080".parse().unwrap(), None).is_ok());
+        assert!(check_exposed("0.0.0.0:8080".parse().unwrap(), None).is_err());
+        assert!(check_exposed("192.168.1.2:8080".parse().unwrap(), Some("secret")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_event_stream_skips_missed() {
+        let (tx, rx) = broadcast::channel(2);
+        for event in ["a", "b", "c"] {
+            tx.send(Arc::from(event)).unwrap();
+        }
+        drop(tx);
+        let events = event_stream(rx).collect::<Vec<_>>().await;
+        assert_eq!(events, [Arc::from("b"), Arc::from("c")]);
+    }
+
+    #[tokio::test]
+    async fn test_track_statuses() {
+        let (announce_tx, announce_rx) = mpsc::channel(16);
+        let (events_tx, mut events) = broadcast::channel(1);
+        let statuses = Arc::default();
+        let tracker = tokio::task::spawn(track_statuses(
+            announce_rx,
+            Arc::clone(&statuses),
+            events_tx,
+        ));
+
+        announce_tx
+            .send(DeviceAnnouncement {
+                name: "Phone".to_string(),
+                mac_address: "00:11:22:33:44:55".to_string(),
+                device_type: config::DeviceType::KnownMac,
+                presence: DevicePresence::Present(100),
+                transition: Some(crate::state::Transition::Arrived),
+                advertisement: None,
+                reason: Some(Reason::NameRequestOk),
+                last_seen: None,
+                method: "hcitool",
+            })
+            .await
+            .unwrap();
+        let event: serde_json::Value = serde_json::from_str(&events.recv().await.unwrap()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "name": "Phone",
+                "id": "00:11:22:33:44:55",
+                "type": "KNOWN_MAC",
+                "present": true,
+                "confidence": 100,
+                "transition": "arrived",
+                "reason": "name_request_ok",
+            })
+        );
+
+        drop(announce_tx);
+        tracker.await.unwrap();
+        assert!(statuses.lock().unwrap()["Phone"].present);
+    }
+
+    #[test]
+    fn test_check_conflicts() {
+        let devices = vec![
+            device("00:11:22:33:44:55", "Phone"),
+            device("00:11:22:33:44:56", "Car keys"),
+        ];
+        let topic_names = TopicNamesConfig::default();
+        let renamed = device("00:11:22:33:44:55", "Car keys");
+        assert!(check_conflicts(&devices, &renamed, Some(0), &topic_names).is_err());
+
+        let moved = device("00:11:22:33:44:57", "Phone");
+        assert!(check_conflicts(&devices, &moved, Some(0), &topic_names).is_ok());
+        assert!(check_conflicts(&devices, &moved, None, &topic_names).is_err());
+
+        // Different names publishing to the same topic conflict too
+        let same_topic = device("00:11:22:33:44:57", "Car-keys");
+        let err = check_conflicts(&devices, &same_topic, None, &topic_names).unwrap_err();
+        assert_eq!(err.0, StatusCode::CONFLICT);
+        let topic_names = TopicNamesConfig {
+            preserve_dashes: Some(true),
+            ..Default::default()
+        };
+        assert!(check_conflicts(&devices, &same_topic, None, &topic_names).is_ok());
+    }
+
+    #[test]
+    fn test_check_method() {
+        let (tx, _) = mpsc::channel(16);
+        let scanners = Scanners {
+            tx,
+            adapter_ids: vec!["hci0".to_string()],
+            adapter_config: AdapterConfig::default(),
+            methods: HashSet::from([CheckMethod::Gatt]),
+        };
+        let mut phone = device("00:11:22:33:44:55", "Phone");
+        assert!(scanners.check_method(&phone).is_ok());
+        phone.method = Some(CheckMethod::Gatt);
+        assert!(scanners.check_method(&phone).is_ok());
+        phone.method = Some(CheckMethod::Command);
+        assert_eq!(
+            scanners.check_method(&phone).unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! `monitor-rs init`: write a commented starter config.
+use std::io::Write as _;
+use std::path::Path;
+
+use anyhow::Context as _;
+use mac_address::MacAddress;
+
+/// Every option is listed with its default, commented out unless a value is required.
+const TEMPLATE: &str = r#"# monitor-rs configuration. Run `monitor-rs --print-config` to see every option
+# with the value that will actually be used.
+
+[mqtt]
+host = {host}
+# port = 1883
+# username = "user"
+# password = "pass"
+# Identifies this node in topics, e.g. `monitor/<publisher_id>/<device>`
+# Defaults to this machine's hostname. `{hostname}` is replaced with it here and in topic_path,
+# as is `{publisher_id}` in topic_path, so one config can be shared by every node
+# publisher_id = "kitchen-pi"
+# topic_path = "monitor"
+# keep_alive_seconds = 15
+# How often to publish node statistics, 0 to disable
+# stats_interval_seconds = 60
+# Delay scanning at startup until the broker has been reached, for up to this long
+# wait_for_broker_seconds = 0
+# IANA time zone for payload timestamps, local time if unset
+# timezone = "America/Los_Angeles"
+# Encoding of device state payloads: "json", "cbor" or "msgpack"
+# payload_encoding = "json"
+# Publish confidence as a "number" or, like monitor.sh, a "string"
+# confidence_format = "number"
+# On shutdown, "keep" retained states (room occupancy, merged presence, fleet-wide state when
+# leading), "clear" them or mark them unoccupied / not present with "off"
+# retained_on_shutdown = "keep"
+# Merge the device states of other nodes with this one's into
+# `<topic_path>/<publisher_id>/anyone_home` and `<topic_path>/<publisher_id>/presence/<device>`
+# listen_nodes = ["garage", "hallway"]
+# Publish `home` / `not_home` to `<topic_path>/<publisher_id>/<device>/device_tracker`, for Home
+# Assistant's MQTT device tracker. payload_home and payload_not_home change the payloads
+# device_tracker = { enabled = false, retain = true }
+# Every character in device names other than letters and digits becomes an underscore in topics.
+# Keep dashes, or collapse "bob_s__phone_" into "bob_s_phone", instead
+# topic_names = { preserve_dashes = false, collapse_underscores = false }
+
+# Publish Home Assistant MQTT discovery configs
+# [homeassistant]
+# discovery_prefix = "homeassistant"
+# Room this node is in, publishes a binary_sensor.<room>_occupancy
+# room = "Kitchen"
+
+# With several nodes on one broker, elect one of them to publish the fleet-wide
+# `<topic_path>/anyone_home` state (and its discovery config). Every node still reports its own
+# devices
+# [election]
+# heartbeat_seconds = 5
+# timeout_seconds = 15
+
+# [scan]
+# One of "hcitool", "btleplug", "paired" or "hci"
+# backend = "hcitool"
+# One of "full", "arrive_only", "depart_only" or "passive_listener": the scan requests and
+# triggers this node acts on
+# role = "full"
+# Check present devices again after this long
+# presence_timeout_seconds = 120
+# Skip arrival checks for devices seen this recently
+# device_seen_debounce_seconds = 60
+# Minimum time between scans triggered by advertisements
+# device_trigger_debounce_seconds = 120
+# Delay between checking consecutive devices, or a [min, max] range to pick from at random
+# interscan_delay_seconds = 5
+# Wait this long after a departure request before scanning
+# depart_scan_delay_seconds = 0
+# Requests or announcements each message bus queue holds before a slow consumer holds back senders
+# queue_capacity = 1024
+# Consecutive successful checks needed before a device is announced present
+# arrival_confirmations = 1
+# Consecutive failed checks needed before a device is announced absent
+# depart_failures_required = 1
+# One of "arrive", "depart", "both" or "none"
+# scan_on_startup = "none"
+# One of "absent", "unknown" or "restore" (requires state_file)
+# startup_state = "absent"
+# state_file = "/var/lib/monitor-rs/state.json"
+# Mark beacons absent once they haven't advertised for this long, 0 to disable
+# beacon_expiration_seconds = 0
+# btleplug backend only: RSSI needed to become present, and to stay present, after holding for
+# dwell_seconds
+# rssi = { enter = -75, exit = -85, dwell_seconds = 10 }
+# Linux only: let BlueZ watch for tracked manufacturers instead of scanning continuously
+# adv_monitor = false
+# adv_monitor_rssi_threshold = -80
+# Full IEEE registry (or Wireshark's manuf) for naming device vendors, a few are built in
+# oui_file = "/usr/share/ieee-data/oui.txt"
+
+# Publish to `<topic_path>/<publisher_id>/alerts` when a rule starts or stops firing
+# [alerts]
+# device_unseen_hours = 24
+# Share of presence checks (0 to 1) that may fail within window_minutes
+# check_failure_rate = 0.5
+# window_minutes = 15
+# Battery level advertised by the device, in percent
+# battery_below_percent = 20
+# publish = true
+# Also run a command for every alert, with the alert as JSON as its last argument
+# notify_command = { path = "/usr/local/bin/notify", args = ["--channel", "home"] }
+
+# Log to a file instead of stderr, e.g. on SD cards. `--log-file` overrides the file
+# [logging]
+# file = "/var/log/monitor-rs.log"
+# One of "size", "hourly" or "daily"
+# rotation = "size"
+# max_size_mb = 10
+# keep_files = 5
+
+# [adapter]
+# How long to wait for a Bluetooth adapter to appear at startup
+# wait_seconds = 60
+# Adapters to use, defaults to the first one found
+# ids = ["hci0"]
+
+# One [[devices]] table per device to track
+{devices}"#;
+
+const EXAMPLE_DEVICE: &str = r#"# [[devices]]
+# address = "00:11:22:33:44:55"
+# name = "phone"
+# friendly_name = "Alice's phone"
+# room = "Bedroom"
+# "advertisement" for devices advertising from a fixed address, never checked actively,
+# "static" for devices that stay home, present unless `present = false`
+# detect = "check"
+# Overrides scan.backend for this device: "hcitool", "l2ping", "gatt", "advertisement" or
+# "command" (runs scan.check_command with the device's address)
+# method = "hcitool"
+"#;
+
+/// Write a starter config to `path`, asking for the broker host and a device if `interactive`.
+pub fn run(path: &Path, force: bool, interactive: bool) -> anyhow::Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        );
+    }
+
+    let contents = if interactive {
+        let stdin = std::io::stdin();
+        let mut input = stdin.lock();
+        let host = prompt(&mut input, "MQTT broker host [localhost]: ")?;
+        let host = if host.is_empty() {
+            "localhost".to_string()
+        } else {
+            host
+        };
+        let device = prompt_device(&mut input)?;
+        render(&host, device.as_ref())
+    } else {
+        render("localhost", None)
+    };
+
+    std::fs::write(path, contents)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+pub fn prompt(input: &mut impl std::io::BufRead, question: &str) -> anyhow::Result<String> {
+    print!("{question}");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+fn prompt_device(
+    input: &mut impl std::io::BufRead,
+) -> anyhow::Result<Option<(MacAddress, String)>> {
+    loop {
+        let address = prompt(input, "Device MAC address (blank to skip): ")?;
+        if address.is_empty() {
+            return Ok(None);
+        }
+        match crate::config::parse_mac(&address) {
+            Ok(address) => {
+                let name = prompt(input, "Device name: ")?;
+                if name.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some((address, name)));
+            }
+            Err(err) => println!("{err}"),
+        }
+    }
+}
+
+fn render(host: &str, device: Option<&(MacAddress, String)>) -> String {
+    let devices = match device {
+        Some((address, name)) => device_table(*address, name, None),
+        None => EXAMPLE_DEVICE.to_string(),
+    };
+    TEMPLATE
+        .replace("{host}", &toml_string(host))
+        .replace("{devices}", &devices)
+}
+
+/// A `[[devices]]` table for appending to a config file.
+pub fn device_table(address: MacAddress, name: &str, manufacturer: Option<&str>) -> String {
+    let mut table = format!(
+        "[[devices]]\naddress = \"{address}\"\nname = {}\n",
+        toml_string(name)
+    );
+    if let Some(manufacturer) = manufacturer {
+        table.push_str(&format!("manufacturer = {}\n", toml_string(manufacturer)));
+    }
+    table
+}
+
+pub fn toml_string(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    #[test]
+    fn test_template_parses() {
+        let config: AppConfig = toml::de::from_str(&render("localhost", None)).unwrap();
+        assert_eq!(config.mqtt.host, "localhost");
+        assert!(config.devices.is_none());
+
+        let device = (
+            "00:11:22:33:44:55".parse().unwrap(),
+            "Bob's \"phone\"".to_string(),
+        );
+        let config: AppConfig = toml::de::from_str(&render("broker", Some(&device))).unwrap();
+        let devices = config.devices.unwrap();
+        assert_eq!(devices[0].name, "Bob's \"phone\"");
+        assert_eq!(devices[0].address, device.0);
+    }
+
+    #[test]
+    fn test_prompt_device() {
+        let mut input = "not-a-mac\n00:11:22:33:44:55\nphone\n".as_bytes();
+        let (address, name) = prompt_device(&mut input).unwrap().unwrap();
+        assert_eq!(address.to_string(), "00:11:22:33:44:55");
+        assert_eq!(name, "phone");
+
+        let mut input = "\n".as_bytes();
+        assert!(prompt_device(&mut input).unwrap().is_none());
+    }
+}
@@ -0,0 +1,163 @@
+//! Periodic Bluetooth Classic inquiry via `hcitool inq`, so discoverable classic-only devices
+//! (older headsets, some car kits) that never send LE advertisements can still trigger an
+//! arrival scan of themselves.
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info};
+use tokio::sync::mpsc;
+
+use crate::command::{CommandRunner, Program};
+use crate::config::{self, BleDevice};
+use crate::messages::StateAnnouncement;
+use crate::stats::Stats;
+
+pub struct ClassicInquiry {
+    runner: Arc<dyn CommandRunner>,
+    program: Program,
+    /// Adapter to inquire from (`hcitool -i`), otherwise hcitool picks one
+    interface: Option<String>,
+    tracked_addresses: HashSet<String>,
+}
+
+impl ClassicInquiry {
+    pub fn new(runner: Arc<dyn CommandRunner>, devices: &[BleDevice]) -> Self {
+        ClassicInquiry {
+            runner,
+            program: Program::new(None, config::DEFAULT_HCITOOL_PATH),
+            interface: None,
+            tracked_addresses: devices
+                .iter()
+                .map(|device| device.address.to_string().to_uppercase())
+                .collect(),
+        }
+    }
+
+    pub fn with_program(mut self, program: Program) -> Self {
+        self.program = program;
+        self
+    }
+
+    pub fn with_interface(mut self, interface: Option<String>) -> Self {
+        self.interface = interface;
+        self
+    }
+
+    /// Run a single inquiry, returning the tracked devices that responded.
+    pub async fn inquire(&self) -> anyhow::Result<Vec<String>> {
+        let runner = self.runner.as_ref();
+        let output = match &self.interface {
+            Some(interface) => self.program.run(runner, &["-i", interface, "inq"]).await?,
+            None => self.program.run(runner, &["inq"]).await?,
+        };
+        if !output.success {
+            anyhow::bail!(
+                "Command exited non-zero {:?}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let found = parse_addresses(&stdout)
+            .into_iter()
+            .filter(|address| self.tracked_addresses.contains(address))
+            .collect::<Vec<_>>();
+        if found.is_empty() {
+            debug!("Classic inquiry found no tracked devices");
+        } else {
+            info!("Classic inquiry found tracked device(s) {found:?}");
+        }
+        Ok(found)
+    }
+
+    /// Inquire every `interval`, asking for an arrival scan of each tracked device that responds.
+    pub async fn run(
+        self,
+        interval: Duration,
+        tx: mpsc::Sender<StateAnnouncement>,
+        stats: Arc<Stats>,
+    ) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match self.inquire().await {
+                Ok(found) => {
+                    for mac_address in found {
+                        stats.record_device_trigger();
+                        if let Err(err) =
+                            tx.send(StateAnnouncement::DeviceSeen { mac_address }).await
+                        {
+                            error!("Error sending scan arrival message: {err:?}");
+                        }
+                    }
+                }
+                Err(err) => error!("Error running classic inquiry: {err:?}"),
+            }
+        }
+    }
+}
+
+/// Pull the device addresses out of `hcitool inq` output, e.g.
+///
+/// ```text
+/// Inquiring ...
+///         00:11:22:33:44:55       clock offset: 0x1b0a    class: 0x240404
+/// ```
+fn parse_addresses(stdout: &str) -> Vec<String> {
+    stdout
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|token| token.parse::<mac_address::MacAddress>().is_ok())
+        .map(str::to_uppercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::ScriptedRunner;
+
+    const OUTPUT: &str = "Inquiring ...\n\t00:11:22:33:44:55\tclock offset: 0x1b0a\tclass: 0x240404\n\taa:bb:cc:dd:ee:ff\tclock offset: 0x0001\tclass: 0x5a020c\n";
+
+    fn devices() -> Vec<BleDevice> {
+        let config: crate::config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+
+            [[devices]]
+            address = "AA:BB:CC:DD:EE:FF"
+            name = "Headset"
+            "#,
+        )
+        .unwrap();
+        config.devices.unwrap()
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        assert_eq!(
+            parse_addresses(OUTPUT),
+            vec!["00:11:22:33:44:55", "AA:BB:CC:DD:EE:FF"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inquire() {
+        let runner = Arc::new(ScriptedRunner::stdout(OUTPUT));
+        let inquiry = ClassicInquiry::new(runner.clone(), &devices())
+            .with_interface(Some("hci1".to_string()));
+        assert_eq!(inquiry.inquire().await.unwrap(), vec!["AA:BB:CC:DD:EE:FF"]);
+        assert_eq!(
+            runner.calls.lock().unwrap()[0],
+            vec!["hcitool", "-i", "hci1", "inq"]
+        );
+
+        let inquiry = ClassicInquiry::new(
+            Arc::new(ScriptedRunner::stdout("Inquiring ...\n")),
+            &devices(),
+        );
+        assert!(inquiry.inquire().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+//! `monitor-rs learn`: listen for advertisements and add one of the devices heard to the config.
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use btleplug::api::{Central as _, Peripheral as _, PeripheralProperties, ScanFilter};
+use mac_address::MacAddress;
+use serde::Serialize;
+
+use crate::{
+    advertisements::Advertisement,
+    config::{AppConfig, Manufacturer},
+};
+
+/// A device heard while learning.
+#[derive(Debug, Clone, Serialize)]
+pub struct Candidate {
+    #[serde(rename = "id")]
+    address: MacAddress,
+    #[serde(rename = "name")]
+    local_name: Option<String>,
+    rssi: Option<i16>,
+    manufacturer: Option<&'static str>,
+    /// Registered vendor of the address, for devices advertising from their public address
+    vendor: Option<&'static str>,
+    #[serde(skip)]
+    manufacturer_ids: Vec<u16>,
+}
+
+impl Candidate {
+    pub fn from_properties(properties: &PeripheralProperties) -> Option<Self> {
+        let advertisement = Advertisement::from_properties(properties);
+        let address = properties.address.to_string().parse().ok()?;
+        Some(Candidate {
+            address,
+            vendor: crate::oui::vendor(&address),
+            local_name: properties.local_name.clone(),
+            rssi: properties.rssi,
+            manufacturer: advertisement.manufacturer(),
+            manufacturer_ids: advertisement.manufacturer_ids,
+        })
+    }
+}
+
+/// Scan for `duration`, list untracked devices by signal strength and add the one picked.
+pub async fn run(config_path: &Path, config: &AppConfig, duration: Duration) -> anyhow::Result<()> {
+    let adapter_config = config.adapter.clone().unwrap_or_default();
+    let adapters = crate::adapter::wait_for_adapters(&adapter_config).await?;
+    let (id, adapter) = adapters.first().context("No Bluetooth adapter available")?;
+    crate::adapter::ensure_powered(id, adapter, &adapter_config).await?;
+
+    println!("Listening for advertisements on {id} for {duration:?}...");
+    adapter
+        .start_scan(ScanFilter::default())
+        .await
+        .context("Failed to start scan")?;
+    tokio::time::sleep(duration).await;
+    adapter.stop_scan().await.context("Failed to stop scan")?;
+
+    let mut heard = Vec::new();
+    for peripheral in adapter.peripherals().await? {
+        if let Some(candidate) = peripheral
+            .properties()
+            .await?
+            .as_ref()
+            .and_then(Candidate::from_properties)
+        {
+            heard.push(candidate);
+        }
+    }
+    let tracked = config
+        .devices
+        .iter()
+        .flatten()
+        .map(|device| device.address)
+        .collect::<HashSet<_>>();
+    let candidates = candidates(heard, &tracked);
+    if candidates.is_empty() {
+        println!("No untracked devices heard");
+        return Ok(());
+    }
+
+    for (i, candidate) in candidates.iter().enumerate() {
+        println!(
+            "{:>3}. {}  {:>4} dBm  {:<24}  {:<12}  {}",
+            i + 1,
+            candidate.address,
+            candidate
+                .rssi
+                .map_or_else(|| "?".to_string(), |rssi| rssi.to_string()),
+            candidate.local_name.as_deref().unwrap_or("-"),
+            candidate.manufacturer.unwrap_or("-"),
+            candidate.vendor.unwrap_or("-"),
+        );
+    }
+
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let choice = crate::init::prompt(&mut input, "Device to add (blank to quit): ")?;
+    if choice.is_empty() {
+        return Ok(());
+    }
+    let candidate = choice
+        .parse::<usize>()
+        .ok()
+        .and_then(|choice| candidates.get(choice.checked_sub(1)?))
+        .with_context(|| format!("Invalid choice {choice}"))?;
+
+    let default_name = candidate.local_name.clone().unwrap_or_default();
+    let name = crate::init::prompt(&mut input, &format!("Name [{default_name}]: "))?;
+    let name = if name.is_empty() { default_name } else { name };
+    if name.is_empty() {
+        anyhow::bail!("A name is required");
+    }
+
+    crate::add_device::run(
+        config_path,
+        &name,
+        candidate.address,
+        config_manufacturer(&candidate.manufacturer_ids),
+    )
+}
+
+/// Window opened by an MQTT learn request, during which every device heard is published once so
+/// it can be picked remotely.
+#[derive(Debug, Clone, Default)]
+pub struct LearnWindow {
+    state: Arc<Mutex<LearnState>>,
+}
+
+#[derive(Debug, Default)]
+struct LearnState {
+    until: Option<Instant>,
+    published: HashSet<MacAddress>,
+}
+
+impl LearnWindow {
+    /// Open (or reopen) the window, publishing every device again.
+    pub fn open(&self, now: Instant, duration: Duration) {
+        let mut state = self.state.lock().expect("learn window lock poisoned");
+        state.until = Some(now + duration);
+        state.published.clear();
+    }
+
+    /// Whether a sighting of `candidate` should be published.
+    pub fn admit(&self, now: Instant, candidate: &Candidate) -> bool {
+        let mut state = self.state.lock().expect("learn window lock poisoned");
+        match state.until {
+            Some(until) if now < until => state.published.insert(candidate.address),
+            _ => false,
+        }
+    }
+}
+
+/// Untracked devices, strongest signal first.
+fn candidates(heard: Vec<Candidate>, tracked: &HashSet<MacAddress>) -> Vec<Candidate> {
+    let mut candidates = heard
+        .into_iter()
+        .filter(|candidate| !tracked.contains(&candidate.address))
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.rssi.unwrap_or(i16::MIN)));
+    candidates
+}
+
+/// The config's `manufacturer` value for a device advertising one of these company IDs, so its
+/// advertisements trigger arrival scans.
+fn config_manufacturer(manufacturer_ids: &[u16]) -> Option<&'static str> {
+    [
+        (Manufacturer::Apple, "Apple"),
+        (Manufacturer::Google, "Google"),
+    ]
+    .into_iter()
+    .find(|(manufacturer, _)| {
+        manufacturer
+            .company_ids()
+            .iter()
+            .any(|id| manufacturer_ids.contains(id))
+    })
+    .map(|(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(address: &str, rssi: Option<i16>) -> Candidate {
+        Candidate {
+            address: address.parse().unwrap(),
+            local_name: None,
+            rssi,
+            manufacturer: None,
+            vendor: None,
+            manufacturer_ids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let tracked = HashSet::from(["00:11:22:33:44:55".parse().unwrap()]);
+        let candidates = candidates(
+            vec![
+                candidate("00:11:22:33:44:56", None),
+                candidate("00:11:22:33:44:55", Some(-30)),
+                candidate("00:11:22:33:44:57", Some(-80)),
+                candidate("00:11:22:33:44:58", Some(-50)),
+            ],
+            &tracked,
+        );
+        let addresses = candidates
+            .iter()
+            .map(|candidate| candidate.address.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addresses,
+            [
+                "00:11:22:33:44:58",
+                "00:11:22:33:44:57",
+                "00:11:22:33:44:56"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_learn_window() {
+        let now = Instant::now();
+        let window = LearnWindow::default();
+        let phone = candidate("00:11:22:33:44:55", Some(-40));
+        assert!(!window.admit(now, &phone));
+
+        window.open(now, Duration::from_secs(60));
+        assert!(window.admit(now, &phone));
+        assert!(!window.admit(now, &phone));
+        assert!(!window.admit(now + Duration::from_secs(60), &phone));
+
+        window.open(now, Duration::from_secs(60));
+        assert!(window.admit(now, &phone));
+    }
+
+    #[test]
+    fn test_config_manufacturer() {
+        assert_eq!(config_manufacturer(&[0x0006, 0x004C]), Some("Apple"));
+        assert_eq!(config_manufacturer(&[0x00E0]), Some("Google"));
+        assert_eq!(config_manufacturer(&[0x0006]), None);
+    }
+}
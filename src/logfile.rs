@@ -0,0 +1,143 @@
+//! Logging to a file that rotates by size or time, for nodes on SD cards where capturing stdout
+//! without bounds slowly fills (and wears out) the card.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{self, LoggingConfig, Rotation};
+
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    max_size: u64,
+    keep_files: usize,
+    file: File,
+    size: u64,
+    /// Hour or day the current file was started in, for time based rotation
+    period: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, cfg: &LoggingConfig) -> io::Result<Self> {
+        let file = open(path)?;
+        let metadata = file.metadata()?;
+        let rotation = cfg.rotation.unwrap_or_default();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            rotation,
+            max_size: cfg.max_size_mb.unwrap_or(config::DEFAULT_LOG_MAX_SIZE_MB) * 1024 * 1024,
+            keep_files: cfg.keep_files.unwrap_or(config::DEFAULT_LOG_KEEP_FILES),
+            file,
+            size: metadata.len(),
+            // A file left over from an earlier period is rotated on the first write
+            period: period(
+                rotation,
+                metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            ),
+        })
+    }
+
+    fn should_rotate(&self, len: usize, now: SystemTime) -> bool {
+        match self.rotation {
+            Rotation::Size => self.size > 0 && self.size + len as u64 > self.max_size,
+            Rotation::Hourly | Rotation::Daily => period(self.rotation, now) != self.period,
+        }
+    }
+
+    /// Shift `<file>.1` .. `<file>.<n-1>` up by one, move the current file to `<file>.1` and
+    /// start a new one. The oldest file is overwritten.
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        if self.keep_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep_files).rev() {
+                match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.period = period(self.rotation, now);
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = SystemTime::now();
+        if self.should_rotate(buf.len(), now) {
+            self.rotate(now)?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// The hour or day (UTC) `time` falls in.
+fn period(rotation: Rotation, time: SystemTime) -> u64 {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    match rotation {
+        Rotation::Size => 0,
+        Rotation::Hourly => seconds / 3600,
+        Rotation::Daily => seconds / 86400,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("monitor-rs-logs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("monitor.log");
+        let cfg = LoggingConfig {
+            keep_files: Some(2),
+            ..LoggingConfig::default()
+        };
+        let mut file = RotatingFile::open(&path, &cfg).unwrap();
+        file.max_size = 10;
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(file.rotated(1)), "third\n");
+        assert_eq!(read(file.rotated(2)), "second\n");
+        assert!(!file.rotated(3).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_period() {
+        let time = UNIX_EPOCH + Duration::from_secs(86400 + 7200 + 5);
+        assert_eq!(period(Rotation::Hourly, time), 26);
+        assert_eq!(period(Rotation::Daily, time), 1);
+        assert_eq!(period(Rotation::Size, time), 0);
+    }
+}
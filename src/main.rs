@@ -1,18 +1,56 @@
-use btleplug::api::Manager as _;
-use btleplug::platform::Manager;
-use clap::Parser;
-use log::{LevelFilter, debug, info};
+use clap::{Parser, Subcommand};
+use log::{LevelFilter, debug, error, info, warn};
 use std::error::Error;
 use std::fs::File;
 use std::io::Read as _;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 
+mod adapter;
+mod add_device;
+mod adv_monitor;
+mod advertisements;
+mod aggregate;
+mod alerts;
+mod bench;
+mod bluez_cache;
+mod bus;
+mod cleanup;
+mod clock;
+mod command;
+mod company_ids;
 mod config;
+mod config_file;
+mod control;
+mod defaults;
+mod election;
+mod fingerprint;
+mod homeassistant;
+#[cfg(feature = "http")]
+mod http;
+mod init;
+#[cfg(feature = "bluez")]
+mod inquiry;
+mod learn;
+mod logfile;
 mod manager;
 mod messages;
+#[cfg(all(feature = "mock", target_os = "linux"))]
+mod mock;
+mod monitor_sh;
 mod mqtt;
+mod oui;
+mod persist;
+mod presence;
+mod recovery;
 mod scanner;
+mod selftest;
+#[cfg(windows)]
+mod service;
+mod state;
+mod stats;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 struct Args {
     /// Path to the config file
     #[arg(short, long, default_value = "config.toml")]
@@ -20,45 +58,304 @@ struct Args {
 
     #[arg(short, long)]
     verbose: bool,
+
+    /// Override the MQTT broker host from the config file
+    #[arg(long)]
+    mqtt_host: Option<String>,
+
+    /// Override the MQTT broker port from the config file
+    #[arg(long)]
+    mqtt_port: Option<u16>,
+
+    /// Override the MQTT username from the config file
+    #[arg(long)]
+    mqtt_username: Option<String>,
+
+    /// Print the fully resolved configuration (with secrets redacted) and exit
+    #[arg(long)]
+    print_config: bool,
+
+    /// Run this many scan passes over the configured devices, report how long checks, channel
+    /// sends and MQTT publishes take, and exit
+    #[arg(long, value_name = "PASSES")]
+    bench: Option<u32>,
+
+    /// Log to this file, rotated as set in `[logging]`, instead of stderr
+    #[arg(long)]
+    log_file: Option<std::path::PathBuf>,
+
+    /// Run under the Windows Service Control Manager, logging to the event log
+    #[arg(long)]
+    service: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Write a commented starter config to the `--config` path and exit
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+        /// Ask for the MQTT broker host and a device to track
+        #[arg(short, long)]
+        interactive: bool,
+    },
+    /// Append a device to the `--config` file and exit
+    AddDevice {
+        /// Name used in MQTT topics
+        name: String,
+        /// MAC address, e.g. 00:11:22:33:44:55
+        #[arg(value_parser = config::parse_mac)]
+        address: mac_address::MacAddress,
+        /// Manufacturer whose advertisements trigger an arrival scan (Apple or Google)
+        #[arg(long)]
+        manufacturer: Option<String>,
+    },
+    /// Check the adapter, scanning, a presence check and the MQTT broker, reporting what to fix
+    SelfTest {
+        /// MAC address to check presence of, defaults to the first configured device
+        #[arg(value_parser = config::parse_mac)]
+        address: Option<mac_address::MacAddress>,
+    },
+    /// Print every config option with its default value and a description, then exit
+    Defaults,
+    /// Listen for nearby devices and add one of them to the `--config` file
+    Learn {
+        /// How long to listen for advertisements
+        #[arg(long, default_value_t = 30)]
+        seconds: u64,
+    },
+    /// Print a config converted from the monitor.sh configuration files in a directory
+    ImportMonitorSh {
+        /// Directory holding `mqtt_preferences`, `behavior_preferences` and
+        /// `known_static_addresses`
+        #[arg(default_value = ".")]
+        dir: std::path::PathBuf,
+    },
+    /// Clear retained Home Assistant discovery configs and states this node no longer publishes
+    CleanupDiscovery {
+        /// Clean up after another node, e.g. this one's name before a rename, clearing everything
+        #[arg(long)]
+        publisher_id: Option<String>,
+        /// Clear everything this node published, not only what its config no longer has
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
+    if args.service {
+        #[cfg(windows)]
+        return service::start(args);
+        #[cfg(not(windows))]
+        return Err("--service is only supported on Windows".into());
+    }
+
     let default_level = if args.verbose {
         LevelFilter::Debug
     } else {
         LevelFilter::Info
     };
 
-    pretty_env_logger::formatted_builder()
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger
         .filter_module("monitor_rs", default_level)
-        .parse_default_env()
-        .init();
+        .parse_default_env();
+    let logging = logging_config(&args);
+    if let Some(path) = &logging.file {
+        let file = logfile::RotatingFile::open(path, &logging)
+            .map_err(|err| format!("Failed to open log file {}: {err}", path.display()))?;
+        logger.target(pretty_env_logger::env_logger::Target::Pipe(Box::new(file)));
+    }
+    logger.init();
+    log_panics();
+
+    match &args.command {
+        Some(Command::Init { force, interactive }) => {
+            init::run(std::path::Path::new(&args.config), *force, *interactive)?;
+            return Ok(());
+        }
+        Some(Command::AddDevice {
+            name,
+            address,
+            manufacturer,
+        }) => {
+            add_device::run(
+                std::path::Path::new(&args.config),
+                name,
+                *address,
+                manufacturer.as_deref(),
+            )?;
+            return Ok(());
+        }
+        Some(Command::SelfTest { address }) => {
+            let config = load_config(&args)?;
+            tokio::runtime::Runtime::new()?.block_on(selftest::run(&config, *address))?;
+            return Ok(());
+        }
+        Some(Command::ImportMonitorSh { dir }) => {
+            monitor_sh::run(dir)?;
+            return Ok(());
+        }
+        Some(Command::CleanupDiscovery { publisher_id, all }) => {
+            let config = load_config(&args)?;
+            tokio::runtime::Runtime::new()?.block_on(cleanup::run(
+                &config,
+                publisher_id.as_deref(),
+                *all,
+            ))?;
+            return Ok(());
+        }
+        Some(Command::Defaults) => {
+            defaults::run()?;
+            return Ok(());
+        }
+        Some(Command::Learn { seconds }) => {
+            let config = load_config(&args)?;
+            load_oui_file(&config)?;
+            tokio::runtime::Runtime::new()?.block_on(learn::run(
+                std::path::Path::new(&args.config),
+                &config,
+                std::time::Duration::from_secs(*seconds),
+            ))?;
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if args.print_config {
+        let config = load_config(&args)?;
+        print!("{}", toml::to_string(&config.with_defaults().redacted())?);
+        return Ok(());
+    }
+
+    if let Some(passes) = args.bench {
+        let config = load_config(&args)?;
+        load_oui_file(&config)?;
+        tokio::runtime::Runtime::new()?.block_on(bench::run(&config, passes))?;
+        return Ok(());
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let shutdown = CancellationToken::new();
+    runtime.spawn(shutdown_on_signal(shutdown.clone()));
+    runtime.block_on(run(args, shutdown))
+}
 
-    let mut file = File::open(args.config)?;
+/// Cancel `shutdown` on Ctrl-C or SIGTERM (e.g. `systemctl stop`), so the node publishes that it's
+/// going offline before it exits.
+async fn shutdown_on_signal(shutdown: CancellationToken) {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {err}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => _ = terminate.recv().await,
+            Err(err) => {
+                warn!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => info!("Interrupted, shutting down"),
+        _ = terminate => info!("Terminated, shutting down"),
+    }
+    shutdown.cancel();
+}
+
+/// Log panics and flush the logs, which would otherwise go to stderr only or be lost. A panicking
+/// subsystem also brings the daemon down with a `panicked` offline state, see
+/// [manager::Manager::run_loop].
+fn log_panics() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        error!("Panicked: {info}");
+        log::logger().flush();
+    }));
+}
+
+/// The daemon's `[logging]` section with `--log-file` applied. Config errors are ignored here,
+/// they're reported once the config is loaded for real.
+fn logging_config(args: &Args) -> config::LoggingConfig {
+    let mut logging = match args.command {
+        None => load_config(args)
+            .ok()
+            .and_then(|config| config.logging)
+            .unwrap_or_default(),
+        Some(_) => config::LoggingConfig::default(),
+    };
+    if let Some(file) = &args.log_file {
+        logging.file = Some(file.clone());
+    }
+    logging
+}
+
+fn load_oui_file(config: &config::AppConfig) -> anyhow::Result<()> {
+    match config.scan.as_ref().and_then(|scan| scan.oui_file.as_ref()) {
+        Some(path) => oui::load(path),
+        None => Ok(()),
+    }
+}
+
+fn load_config(args: &Args) -> Result<config::AppConfig, Box<dyn Error>> {
+    let mut file = File::open(&args.config)?;
     let mut config_contents = String::new();
     file.read_to_string(&mut config_contents)?;
 
-    let config: config::AppConfig = toml::de::from_str(&config_contents)?;
+    let mut config: config::AppConfig = toml::de::from_str(&config_contents)?;
 
-    debug!("Configured to look for devices: {:?}", config.devices);
+    if let Some(host) = &args.mqtt_host {
+        config.mqtt.host = host.clone();
+    }
+    if let Some(port) = args.mqtt_port {
+        config.mqtt.port = Some(port);
+    }
+    if let Some(username) = &args.mqtt_username {
+        config.mqtt.username = Some(username.clone());
+    }
+    config.mqtt.expand_placeholders()?;
 
-    let (mqtt_client, eventloop) = mqtt::MqttClient::new(&config.mqtt);
+    Ok(config)
+}
+
+/// Run the daemon until it fails or `shutdown` is cancelled.
+async fn run(args: Args, shutdown: CancellationToken) -> Result<(), Box<dyn Error>> {
+    let config = load_config(&args)?;
+
+    debug!("Configured to look for devices: {:?}", config.devices);
+    load_oui_file(&config)?;
+    oui::check_manufacturers(config.devices.as_deref().unwrap_or_default());
+    config.validate_devices()?;
 
-    let bt_manager = Manager::new().await?;
+    let stats = Arc::new(stats::Stats::default());
+    let (mqtt_client, eventloop) = mqtt::MqttClient::new(&config, stats.clone());
+    let mqtt_client = mqtt_client.with_config_path(&args.config);
 
-    // get the first bluetooth adapter
-    let adapters = bt_manager.adapters().await?;
-    let central = adapters
-        .into_iter()
-        .next()
-        .ok_or("No Bluetooth adapter found")?;
+    let adapter_config = config.adapter.clone().unwrap_or_default();
+    let adapters = adapter::wait_for_adapters(&adapter_config).await?;
+    for (id, central) in &adapters {
+        adapter::ensure_powered(id, central, &adapter_config).await?;
+    }
 
     info!("Devices initialized, starting event loop");
 
-    let core = manager::Manager::new(&config, central, mqtt_client, eventloop);
+    let core = manager::Manager::new(&config, adapters, mqtt_client, eventloop, stats)
+        .with_config_path(&args.config)
+        .with_shutdown(shutdown);
     core.run_loop().await?;
 
     Ok(())
@@ -1,130 +1,609 @@
 use std::collections::HashSet;
+use std::sync::Arc;
 
 use anyhow::Context as _;
 use btleplug::api::{Central as _, CentralEvent, Peripheral as _, ScanFilter};
 use futures::StreamExt as _;
-use log::{debug, error, warn};
-use tokio::sync::broadcast;
+use futures::future::BoxFuture;
+use log::{debug, error, info, warn};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    config::{AppConfig, BleDevice},
+    adapter,
+    advertisements::{Advertisement, Advertisements},
+    bluez_cache::DeviceCache,
+    bus::Bus,
+    clock::{Clock, SystemClock},
+    config::{
+        self, AppConfig, BleDevice, CheckMethod, ClearBluezCache, Detect, StartupScan, StartupState,
+    },
+    fingerprint::Fingerprints,
+    homeassistant::Occupancy,
+    learn::Candidate,
     messages::{DeviceAnnouncement, DevicePresence, StateAnnouncement},
     mqtt::MqttClient,
+    persist::StateFile,
+    presence::{self, CachedChecker},
+    recovery::{Escalation, Report},
     scanner::Scanner,
+    stats::Stats,
 };
 
+/// An advertisement after this long without one may be a device tracked by its advertisements
+/// coming back.
+const ADVERTISEMENT_GAP: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long to wait before listening for BLE events again after the event stream failed.
+const EVENT_STREAM_RETRY: std::time::Duration = std::time::Duration::from_secs(10);
+
+const MQTT_EVENT_LOOP: &str = "MQTT event loop";
+/// How long subsystems get to stop on their own once shutdown starts.
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Tasks the manager runs until one of them fails, each labeled for error reports.
+type Subsystems = tokio::task::JoinSet<(String, anyhow::Result<()>)>;
+
 pub struct Manager {
     cfg: AppConfig,
-    adapter: btleplug::platform::Adapter,
+    /// Adapters to scan from, along with their short names (e.g. `hci0`)
+    adapters: Vec<(String, crate::adapter::Adapter)>,
     mqtt_client: MqttClient,
     mqtt_event_loop: rumqttc::EventLoop,
     devices: Vec<BleDevice>,
+    stats: Arc<Stats>,
+    /// Where the config was loaded from, for the HTTP API to save device changes to
+    config_path: std::path::PathBuf,
+    /// Cancelled to stop every subsystem, by the caller or when one of them fails
+    shutdown: CancellationToken,
 }
 
 impl Manager {
     pub fn new(
         cfg: &AppConfig,
-        adapter: btleplug::platform::Adapter,
+        adapters: Vec<(String, crate::adapter::Adapter)>,
         mqtt_client: MqttClient,
         mqtt_event_loop: rumqttc::EventLoop,
+        stats: Arc<Stats>,
     ) -> Self {
         Manager {
             cfg: cfg.clone(),
-            adapter,
+            adapters,
             mqtt_client,
             mqtt_event_loop,
             devices: cfg.devices.clone().unwrap_or_default().clone(),
+            stats,
+            config_path: std::path::PathBuf::from("config.toml"),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    pub fn with_config_path(mut self, config_path: impl Into<std::path::PathBuf>) -> Self {
+        self.config_path = config_path.into();
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Run every subsystem until the MQTT client is gone, one of them fails or shutdown is
+    /// requested, returning the first failure so the process can exit non-zero and be restarted.
     pub async fn run_loop(mut self) -> anyhow::Result<()> {
-        self.adapter
-            .start_scan(ScanFilter::default())
-            .await
-            .context("start adapter scan")?;
+        let shutdown = self.shutdown.clone();
+        let scan_config = self.cfg.scan.clone().unwrap_or_default();
+        let queue_capacity = scan_config
+            .queue_capacity
+            .unwrap_or(config::DEFAULT_QUEUE_CAPACITY);
+        // Requests (e.g. scans) for the scanners, and the device states they announce
+        let mut requests = Bus::new("request", queue_capacity)
+            .with_coalesced(|msg| matches!(msg, StateAnnouncement::DeviceTrigger));
+        let tx = requests.sender();
+        let mut announcements = Bus::new("announcement", queue_capacity);
+        let announce_tx = announcements.sender();
+        let announce_rx = announcements.subscribe();
+        let advertisements = Advertisements::default();
 
-        let (tx, rx) = broadcast::channel(10);
-        let (announce_tx, announce_rx) = broadcast::channel(10);
+        let backend = scan_config.backend.clone().unwrap_or_default();
+        debug!("Using {backend:?} presence backend");
 
-        let btle_tx = tx.clone();
+        // Each adapter gets its own scanner for the devices assigned to it, all announcing
+        // through the same pipeline
+        let adapter_config = self.cfg.adapter.clone().unwrap_or_default();
+        let adapter_ids = self
+            .adapters
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        let assignments = adapter::assign_devices(&self.devices, &adapter_ids, &adapter_config);
 
-        let scan_config = self.cfg.scan.unwrap_or_default();
-        let mut scanner = Scanner::new(&scan_config, rx, announce_tx, tx.clone(), &self.devices);
+        for ((id, adapter), devices) in self.adapters.iter().zip(&assignments) {
+            // The btleplug backend needs every advertisement, not just monitor matches
+            if scan_config.adv_monitor.unwrap_or(false)
+                && !matches!(backend, config::PresenceBackend::Btleplug)
+            {
+                match crate::adv_monitor::register(
+                    id,
+                    devices,
+                    scan_config.adv_monitor_rssi_threshold,
+                    tx.clone(),
+                    self.stats.clone(),
+                )
+                .await
+                {
+                    Ok(()) => {
+                        info!("Monitoring advertisements on adapter {id} instead of scanning");
+                        continue;
+                    }
+                    Err(err) => warn!("Falling back to scanning on adapter {id}: {err:?}"),
+                }
+            }
+            // Counted towards the adapter's recovery, which restarts scanning
+            if let Err(err) = adapter.start_scan(ScanFilter::default()).await {
+                scan_failed(&tx, id, anyhow::Error::new(err).context("start scan")).await;
+            }
+        }
+        let state_file = scan_config.state_file.clone().map(StateFile::new);
+        let device_cache = device_cache(scan_config.clear_bluez_cache.unwrap_or_default())?;
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let result_cache = std::time::Duration::from_millis(
+            scan_config
+                .result_cache_millis
+                .unwrap_or(config::DEFAULT_RESULT_CACHE_MILLIS),
+        );
+        let fingerprints = scan_config
+            .fingerprints
+            .unwrap_or(false)
+            .then(Fingerprints::default);
+        let (escalation_tx, escalation_rx) = mpsc::unbounded_channel();
+        let mut scanners = Vec::new();
+        let mut available_methods = HashSet::new();
+        for ((id, adapter), devices) in self.adapters.iter().zip(assignments) {
+            debug!("Scanning {} device(s) from adapter {id}", devices.len());
+            // Only pin checks to an adapter when adapters were chosen explicitly
+            let interface = adapter_config.ids.as_ref().map(|_| id.as_str());
+            let cached = |checker: Box<dyn presence::PresenceChecker>| {
+                if result_cache > std::time::Duration::ZERO {
+                    Box::new(CachedChecker::new(checker, result_cache, clock.clone()))
+                } else {
+                    checker
+                }
+            };
+            let checker = cached(presence::new_checker(&scan_config, adapter, interface)?);
+            let methods = devices
+                .iter()
+                .filter(|device| device.detect.unwrap_or_default() == config::Detect::Check)
+                .filter_map(|device| device.method)
+                .collect::<HashSet<_>>();
+            let mut scanner = Scanner::new(
+                &scan_config,
+                requests.subscribe(),
+                announce_tx.clone(),
+                &devices,
+                checker,
+                clock.clone(),
+            )
+            .with_stats(self.stats.clone())
+            .with_adapter_id(id)
+            .with_advertisements(advertisements.clone())
+            .with_state_file(state_file.clone())
+            .with_escalations(escalation_tx.clone());
+            // Every method that can be set up gets a checker, devices added at runtime may use any
+            for method in CheckMethod::ALL {
+                match presence::method_checker(method, &scan_config, adapter, interface) {
+                    Ok(checker) => {
+                        scanner = scanner.with_method_checker(method, cached(checker));
+                        available_methods.insert(method);
+                    }
+                    Err(err) if methods.contains(&method) => {
+                        return Err(err)
+                            .with_context(|| format!("set up method = {:?}", method.as_str()));
+                    }
+                    Err(err) => debug!("method = {:?} is unavailable: {err:#}", method.as_str()),
+                }
+            }
+            if let Some(device_cache) = &device_cache {
+                scanner = scanner.with_device_cache(device_cache.clone());
+            }
+            if let Some(fingerprints) = &fingerprints {
+                scanner = scanner.with_fingerprints(fingerprints.clone());
+            }
+            if scan_config.startup_state == Some(StartupState::Restore) {
+                scanner
+                    .restore_state()
+                    .context("restore saved device states")?;
+            }
+            scanners.push((id.clone(), scanner));
+        }
+        // Long-running parts of the daemon, the first one to fail takes the others down with it
+        let mut subsystems = Subsystems::new();
+        if let Some(http_config) = &self.cfg.http {
+            // Commands may name the devices added over HTTP
+            let mut device_changes = requests.subscribe();
+            let mqtt_client = self.mqtt_client.clone();
+            tokio::task::spawn(async move {
+                while let Some(msg) = device_changes.recv().await {
+                    if let StateAnnouncement::DeviceChanged { name, device, .. } = msg {
+                        mqtt_client.device_changed(&name, device.as_deref());
+                    }
+                }
+            });
+            let server = self.http_server(
+                http_config,
+                announcements.subscribe(),
+                &tx,
+                &adapter_ids,
+                &adapter_config,
+                &available_methods,
+            )?;
+            let shutdown = shutdown.clone();
+            subsystems.spawn(async move {
+                let result = tokio::select! {
+                    result = server => result,
+                    _ = shutdown.cancelled() => Ok(()),
+                };
+                ("HTTP server".to_string(), result)
+            });
+        }
+        if let Some(alerts_config) = &self.cfg.alerts {
+            let rules = crate::alerts::Rules::new(alerts_config, &self.devices, clock.now());
+            let notifiers = crate::alerts::Notifiers::new(
+                alerts_config,
+                self.mqtt_client.clone(),
+                Arc::new(crate::command::TokioCommandRunner),
+            );
+            let alerts = crate::alerts::run(
+                rules,
+                announcements.subscribe(),
+                requests.subscribe(),
+                notifiers,
+                self.stats.clone(),
+                clock.clone(),
+            );
+            let shutdown = shutdown.clone();
+            subsystems.spawn(async move {
+                tokio::select! {
+                    _ = alerts => {}
+                    _ = shutdown.cancelled() => {}
+                }
+                ("Alerts".to_string(), Ok(()))
+            });
+        }
+        drop(announce_tx);
+        drop(escalation_tx);
+        let escalations = handle_escalations(
+            escalation_rx,
+            self.adapters.clone(),
+            self.mqtt_client.clone(),
+            shutdown.clone(),
+        );
+        subsystems.spawn(async move {
+            escalations.await;
+            ("Recovery".to_string(), Ok(()))
+        });
+        tokio::task::spawn(requests.route());
+        tokio::task::spawn(announcements.route());
 
         let mqtt_client = self.mqtt_client.clone();
+        let mqtt_tx = tx.clone();
+        // Stopped last, so the final offline state still goes out
+        let mqtt_shutdown = CancellationToken::new();
+        let mqtt_stop = mqtt_shutdown.clone();
+        let final_client = self.mqtt_client.clone();
 
         // Handle incoming MQTT messages (e.g. arrival scan requests)
-        tokio::task::spawn(async move {
-            mqtt_client.event_loop(&mut self.mqtt_event_loop, tx).await;
+        subsystems.spawn(async move {
+            let result = mqtt_client
+                .event_loop(&mut self.mqtt_event_loop, mqtt_tx, mqtt_shutdown)
+                .await;
+            (MQTT_EVENT_LOOP.to_string(), result)
         });
 
-        tokio::task::spawn(async move {
-            if let Err(err) = scanner.run().await {
-                error!("Error handling scanner events: {err:?}");
+        // Hold off scanning until announcements can actually be published
+        let broker_wait = self.cfg.mqtt.wait_for_broker_seconds.unwrap_or(0);
+        if broker_wait > 0 {
+            info!("Waiting up to {broker_wait}s for the MQTT broker");
+            if !self
+                .mqtt_client
+                .wait_connected(std::time::Duration::from_secs(broker_wait))
+                .await
+            {
+                warn!("MQTT broker not reached after {broker_wait}s, scanning anyway");
             }
-            debug!("Done scanning devices");
-        });
+        }
 
-        let announce_handle = tokio::task::spawn(async move {
-            if let Err(err) = announce_scan_results(announce_rx, &self.mqtt_client).await {
-                error!("Error handling scan results: {err:?}");
-            }
-            debug!("Done announcing scan results");
-        });
+        let arrive = StateAnnouncement::ScanArrive { devices: None };
+        let depart = StateAnnouncement::ScanDepart { devices: None };
+        let startup_scans = match scan_config.scan_on_startup.unwrap_or_default() {
+            StartupScan::Arrive => vec![arrive],
+            StartupScan::Depart => vec![depart],
+            StartupScan::Both => vec![arrive, depart],
+            StartupScan::None => vec![],
+        };
+        for msg in startup_scans {
+            debug!("Queueing startup scan {msg:?}");
+            tx.send(msg).await.context("queue startup scan")?;
+        }
+
+        if scan_config.classic_inquiry_interval_seconds.unwrap_or(0) > 0 {
+            spawn_classic_inquiry(
+                &self.adapters,
+                &self.devices,
+                &adapter_config,
+                &scan_config,
+                &tx,
+                &self.stats,
+                &shutdown,
+            )?;
+        }
 
-        let listen_for_discovery = scan_config.listen_for_discovery.unwrap_or(true);
+        for (id, mut scanner) in scanners {
+            let shutdown = shutdown.clone();
+            subsystems.spawn(async move {
+                let result = scanner.run(shutdown).await;
+                (format!("Scanner for adapter {id}"), result)
+            });
+        }
 
-        let btle_handle = tokio::task::spawn(async move {
-            if listen_for_discovery {
-                if let Err(err) = handle_btle_events(&self.adapter, self.devices, btle_tx).await {
-                    error!("Error handling BLE events: {err:?}");
+        let stats_interval = self
+            .cfg
+            .mqtt
+            .stats_interval_seconds
+            .unwrap_or(config::DEFAULT_STATS_INTERVAL_SECONDS);
+        if stats_interval > 0 {
+            let mqtt_client = self.mqtt_client.clone();
+            let shutdown = shutdown.clone();
+            subsystems.spawn(async move {
+                let mut interval =
+                    tokio::time::interval(std::time::Duration::from_secs(stats_interval));
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = shutdown.cancelled() => break,
+                    }
+                    if let Err(err) = mqtt_client.publish_stats().await {
+                        error!("Error publishing stats: {err:?}");
+                    }
                 }
-                debug!("Done handling BLE events");
-            }
+                ("Stats publisher".to_string(), Ok(()))
+            });
+        }
+
+        if let Some(election_config) = &self.cfg.election {
+            let heartbeat = election_config
+                .heartbeat_seconds
+                .unwrap_or(config::DEFAULT_ELECTION_HEARTBEAT_SECONDS);
+            let election = run_election(
+                self.mqtt_client.clone(),
+                std::time::Duration::from_secs(heartbeat),
+            );
+            let shutdown = shutdown.clone();
+            subsystems.spawn(async move {
+                tokio::select! {
+                    _ = election => {}
+                    _ = shutdown.cancelled() => {}
+                }
+                ("Election".to_string(), Ok(()))
+            });
+        }
+
+        let learn_client = self.mqtt_client.clone();
+        subsystems.spawn(async move {
+            let result = announce_scan_results(announce_rx, &self.mqtt_client).await;
+            ("Announcer".to_string(), result)
         });
 
-        if let Err(err) = btle_handle.await {
-            error!("Error handling BLE events: {err:?}");
+        let listen_for_discovery = scan_config
+            .listen_for_discovery
+            .unwrap_or(config::DEFAULT_LISTEN_FOR_DISCOVERY);
+        if !listen_for_discovery
+            && self
+                .devices
+                .iter()
+                .any(|device| device.detect == Some(Detect::Advertisement))
+        {
+            warn!(
+                "Devices with detect = \"advertisement\" need scan.listen_for_discovery and will never be seen"
+            );
+        }
+
+        let listener = BtleListener {
+            devices: self.devices.clone(),
+            tx: tx.clone(),
+            advertisements: advertisements.clone(),
+            stats: self.stats.clone(),
+            mqtt_client: learn_client,
+            fingerprints: fingerprints.clone(),
+        };
+        for (id, adapter) in self.adapters {
+            if !listen_for_discovery {
+                continue;
+            }
+            let listener = listener.clone();
+            let shutdown = shutdown.clone();
+            subsystems.spawn(async move {
+                let result = handle_btle_events(&id, &adapter, listener, shutdown).await;
+                (format!("BLE event handler for adapter {id}"), result)
+            });
+        }
+
+        // Shut everything down once a subsystem fails or MQTT is gone, or when asked to
+        let mut failure = None;
+        let mut panic = None;
+        let mut mqtt_running = true;
+        while let Some(finished) = tokio::select! {
+            finished = subsystems.join_next() => finished,
+            _ = shutdown.cancelled() => None,
+        } {
+            let name = subsystem_finished(finished, &mut failure, &mut panic);
+            mqtt_running &= name != MQTT_EVENT_LOOP;
+            if failure.is_some() || !mqtt_running {
+                break;
+            }
         }
-        if let Err(err) = announce_handle.await {
-            error!("Error announcing scan results: {err:?}");
+        info!("Shutting down");
+        shutdown.cancel();
+        let drained = tokio::time::timeout(SHUTDOWN_GRACE, async {
+            // Everything but MQTT, so no state is announced after the offline one
+            while subsystems.len() > usize::from(mqtt_running) {
+                let Some(finished) = subsystems.join_next().await else {
+                    break;
+                };
+                mqtt_running &=
+                    subsystem_finished(finished, &mut failure, &mut panic) != MQTT_EVENT_LOOP;
+            }
+            if mqtt_running && let Err(err) = final_client.publish_offline(panic.as_deref()).await {
+                warn!("Error publishing offline state: {err:?}");
+            }
+            mqtt_stop.cancel();
+            while let Some(finished) = subsystems.join_next().await {
+                subsystem_finished(finished, &mut failure, &mut panic);
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "{} subsystem(s) still running after {SHUTDOWN_GRACE:?}, aborting them",
+                subsystems.len()
+            );
         }
         debug!("Exiting manager event loop");
 
-        Ok(())
+        failure.map_or(Ok(()), Err)
+    }
+
+    #[cfg(feature = "http")]
+    fn http_server(
+        &self,
+        cfg: &config::HttpConfig,
+        announce_rx: mpsc::Receiver<DeviceAnnouncement>,
+        tx: &mpsc::Sender<StateAnnouncement>,
+        adapter_ids: &[String],
+        adapter_config: &config::AdapterConfig,
+        methods: &HashSet<CheckMethod>,
+    ) -> anyhow::Result<BoxFuture<'static, anyhow::Result<()>>> {
+        let cfg = cfg.clone();
+        let config_path = self.config_path.clone();
+        let devices = self.devices.clone();
+        let topic_names = self.cfg.mqtt.topic_names.unwrap_or_default();
+        let scanners = crate::http::Scanners {
+            tx: tx.clone(),
+            adapter_ids: adapter_ids.to_vec(),
+            adapter_config: adapter_config.clone(),
+            methods: methods.clone(),
+        };
+        Ok(Box::pin(async move {
+            crate::http::serve(
+                &cfg,
+                config_path,
+                devices,
+                topic_names,
+                announce_rx,
+                scanners,
+            )
+            .await
+        }))
+    }
+
+    #[cfg(not(feature = "http"))]
+    fn http_server(
+        &self,
+        _cfg: &config::HttpConfig,
+        _announce_rx: mpsc::Receiver<DeviceAnnouncement>,
+        _tx: &mpsc::Sender<StateAnnouncement>,
+        _adapter_ids: &[String],
+        _adapter_config: &config::AdapterConfig,
+        _methods: &HashSet<CheckMethod>,
+    ) -> anyhow::Result<BoxFuture<'static, anyhow::Result<()>>> {
+        anyhow::bail!("[http] requires monitor-rs to be built with the `http` feature")
     }
 }
 
+fn device_cache(clear: ClearBluezCache) -> anyhow::Result<Option<Arc<dyn DeviceCache>>> {
+    match clear {
+        ClearBluezCache::Never => Ok(None),
+        #[cfg(target_os = "linux")]
+        _ => Ok(Some(Arc::new(crate::bluez_cache::BluezCache::default()))),
+        #[cfg(not(target_os = "linux"))]
+        _ => anyhow::bail!("scan.clear_bluez_cache is only supported on Linux"),
+    }
+}
+
+#[cfg(feature = "bluez")]
+fn spawn_classic_inquiry(
+    adapters: &[(String, crate::adapter::Adapter)],
+    devices: &[BleDevice],
+    adapter_config: &config::AdapterConfig,
+    scan_config: &config::ScanConfig,
+    tx: &mpsc::Sender<StateAnnouncement>,
+    stats: &Arc<Stats>,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<()> {
+    let interval =
+        std::time::Duration::from_secs(scan_config.classic_inquiry_interval_seconds.unwrap_or(0));
+    for (id, _) in adapters {
+        let inquiry = crate::inquiry::ClassicInquiry::new(
+            Arc::new(crate::command::TokioCommandRunner),
+            devices,
+        )
+        .with_program(crate::command::Program::new(
+            scan_config.hcitool.as_ref(),
+            config::DEFAULT_HCITOOL_PATH,
+        ))
+        .with_interface(adapter_config.ids.as_ref().map(|_| id.clone()));
+        debug!("Running classic inquiry from adapter {id} every {interval:?}");
+        let inquiry = inquiry.run(interval, tx.clone(), stats.clone());
+        let shutdown = shutdown.clone();
+        tokio::task::spawn(async move {
+            tokio::select! {
+                _ = inquiry => {}
+                _ = shutdown.cancelled() => {}
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "bluez"))]
+fn spawn_classic_inquiry(
+    _adapters: &[(String, crate::adapter::Adapter)],
+    _devices: &[BleDevice],
+    _adapter_config: &config::AdapterConfig,
+    _scan_config: &config::ScanConfig,
+    _tx: &mpsc::Sender<StateAnnouncement>,
+    _stats: &Arc<Stats>,
+    _shutdown: &CancellationToken,
+) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "scan.classic_inquiry_interval_seconds requires monitor-rs to be built with the `bluez` feature"
+    )
+}
+
 async fn announce_scan_results(
-    mut announce_rx: broadcast::Receiver<DeviceAnnouncement>,
+    mut announce_rx: mpsc::Receiver<DeviceAnnouncement>,
     mqtt_client: &MqttClient,
 ) -> anyhow::Result<()> {
     debug!("Start announce scan results loop");
+    let mut occupancy = Occupancy::default();
     loop {
         match announce_rx.recv().await {
-            Ok(msg) => match msg {
-                DeviceAnnouncement {
-                    presence: DevicePresence::Absent,
-                    mac_address,
-                    name,
-                } => mqtt_client.announce_device(&name, mac_address, 0).await?,
-                DeviceAnnouncement {
-                    presence: DevicePresence::Present(confidence),
-                    mac_address,
-                    name,
-                } => {
-                    mqtt_client
-                        .announce_device(&name, mac_address, confidence)
-                        .await?;
-                }
-            },
-            Err(broadcast::error::RecvError::Closed) => {
+            Some(msg) => {
+                mqtt_client.announce_device(&msg).await?;
+                if let Some(transition) = msg.transition {
+                    mqtt_client.announce_event(&msg.name, transition).await?;
+                }
+                if mqtt_client.tracks_occupancy() {
+                    let present = matches!(msg.presence, DevicePresence::Present(_));
+                    if let Some(occupied) = occupancy.update(&msg.name, present) {
+                        mqtt_client.announce_occupancy(occupied).await?;
+                    }
+                }
+            }
+            None => {
                 debug!("Receiver closed");
                 break;
             }
-            Err(broadcast::error::RecvError::Lagged(_)) => {
-                debug!("Receiver lagged");
-            }
         }
     }
 
@@ -132,15 +611,144 @@ async fn announce_scan_results(
     Ok(())
 }
 
-async fn handle_btle_events(
-    adapter: &btleplug::platform::Adapter,
+/// Publish the escalations of failing checks and restart adapters when asked to. Escalations
+/// queued by the time shutdown starts are still published, the last one is often the cause.
+async fn handle_escalations(
+    mut escalation_rx: mpsc::UnboundedReceiver<Report>,
+    adapters: Vec<(String, crate::adapter::Adapter)>,
+    mqtt_client: MqttClient,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let report = tokio::select! {
+            report = escalation_rx.recv() => report,
+            _ = shutdown.cancelled() => break,
+        };
+        let Some(report) = report else {
+            return;
+        };
+        if let Err(err) = mqtt_client.publish_escalation(&report).await {
+            error!("Error publishing escalation: {err:?}");
+        }
+        if report.escalation != Escalation::RestartAdapter {
+            continue;
+        }
+        let adapter = adapters
+            .iter()
+            .find(|(id, _)| Some(id) == report.adapter.as_ref());
+        if let Some((id, adapter)) = adapter
+            && let Err(err) = adapter::restart(id, adapter).await
+        {
+            error!("Error restarting adapter {id}: {err:?}");
+        }
+    }
+    while let Ok(report) = escalation_rx.try_recv() {
+        if let Err(err) = mqtt_client.publish_escalation(&report).await {
+            error!("Error publishing escalation: {err:?}");
+        }
+    }
+}
+
+/// Log a subsystem that finished, keeping the first failure. Returns the subsystem's name.
+fn subsystem_finished(
+    finished: Result<(String, anyhow::Result<()>), tokio::task::JoinError>,
+    failure: &mut Option<anyhow::Error>,
+    panic: &mut Option<String>,
+) -> String {
+    let (name, result) = finished.unwrap_or_else(|err| {
+        // Brings the node down, so the offline state says why
+        if err.is_panic() {
+            panic.get_or_insert_with(|| err.to_string());
+        }
+        (
+            "Subsystem".to_string(),
+            Err(anyhow::Error::new(err).context("panicked")),
+        )
+    });
+    match result {
+        Ok(()) => debug!("{name} finished"),
+        // The first failure is returned, later ones are only logged
+        Err(err) if failure.is_some() => error!("{name} failed: {err:?}"),
+        Err(err) => *failure = Some(err.context(format!("{name} failed"))),
+    }
+    name
+}
+
+/// Send heartbeats and, while this node leads, keep the fleet-wide state up to date.
+async fn run_election(mqtt_client: MqttClient, heartbeat: std::time::Duration) {
+    let Some(election) = mqtt_client.election().cloned() else {
+        return;
+    };
+    let mut anyone_home = election.anyone_home();
+    let mut interval = tokio::time::interval(heartbeat);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(err) = mqtt_client.publish_heartbeat().await {
+                    error!("Error publishing heartbeat: {err:?}");
+                }
+                match election.update(std::time::Instant::now()) {
+                    Some(true) => {
+                        info!("Elected leader");
+                        let anyone_home = *anyone_home.borrow_and_update();
+                        if let Err(err) = mqtt_client.publish_leader(anyone_home).await {
+                            error!("Error taking over as leader: {err:?}");
+                        }
+                    }
+                    Some(false) => info!("Another node took over as leader"),
+                    None => {}
+                }
+            }
+            Ok(()) = anyone_home.changed() => {
+                let anyone_home = *anyone_home.borrow_and_update();
+                if election.is_leader()
+                    && let Err(err) = mqtt_client.announce_anyone_home(anyone_home).await
+                {
+                    error!("Error publishing anyone home: {err:?}");
+                }
+            }
+        }
+    }
+}
+
+/// What the BLE event handler of every adapter shares.
+#[derive(Clone)]
+struct BtleListener {
     devices: Vec<BleDevice>,
-    tx: broadcast::Sender<StateAnnouncement>,
-) -> anyhow::Result<()> {
-    let mut events = adapter.events().await.context("start event stream")?;
+    tx: mpsc::Sender<StateAnnouncement>,
+    advertisements: Advertisements,
+    stats: Arc<Stats>,
+    mqtt_client: MqttClient,
+    fingerprints: Option<Fingerprints>,
+}
 
-    let mut event_stream_closed = false;
+/// Report a scanning failure to the scanner of adapter `id`, see [Recovery](crate::recovery::Recovery).
+async fn scan_failed(tx: &mpsc::Sender<StateAnnouncement>, id: &str, err: anyhow::Error) {
+    if let Err(err) = tx
+        .send(StateAnnouncement::ScanFailed {
+            adapter: id.to_string(),
+            error: format!("{err:#}"),
+        })
+        .await
+    {
+        error!("Error reporting scan failure: {err:?}");
+    }
+}
 
+async fn handle_btle_events(
+    adapter_id: &str,
+    adapter: &crate::adapter::Adapter,
+    listener: BtleListener,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let BtleListener {
+        devices,
+        tx,
+        advertisements,
+        stats,
+        mqtt_client,
+        fingerprints,
+    } = listener;
     let device_filters = devices
         .iter()
         .flat_map(|device| {
@@ -152,56 +760,187 @@ async fn handle_btle_events(
         .flatten()
         .collect::<HashSet<_>>();
 
-    loop {
-        if event_stream_closed {
-            break;
-        }
-        match events.next().await {
-            Some(CentralEvent::DeviceDiscovered(id)) => {
-                let peripheral = adapter.peripheral(&id).await.context("get peripheral")?;
-                let properties = peripheral
-                    .properties()
-                    .await
-                    .context("get device properties")?;
-
-                if matching_device(&device_filters, properties)
-                    && let Err(err) = tx.send(StateAnnouncement::DeviceTrigger)
-                {
-                    error!("Error sending scan arrival message: {err:?}");
+    let tracked_addresses = devices
+        .iter()
+        .map(|device| device.address.to_string().to_uppercase())
+        .collect::<HashSet<_>>();
+    let advertised_addresses = devices
+        .iter()
+        .filter(|device| device.detect == Some(Detect::Advertisement))
+        .map(|device| device.address.to_string().to_uppercase())
+        .collect::<HashSet<_>>();
+
+    'stream: loop {
+        let mut events = match adapter.events().await {
+            Ok(events) => events,
+            Err(err) => {
+                scan_failed(
+                    &tx,
+                    adapter_id,
+                    anyhow::Error::new(err).context("start event stream"),
+                )
+                .await;
+                tokio::select! {
+                    _ = tokio::time::sleep(EVENT_STREAM_RETRY) => continue,
+                    _ = shutdown.cancelled() => break,
                 }
             }
-            Some(_) => {}
-            None => {
-                warn!("No more BLE events");
-                event_stream_closed = true;
+        };
+        loop {
+            let event = tokio::select! {
+                event = events.next() => event,
+                _ = shutdown.cancelled() => break 'stream,
+            };
+            match event {
+                Some(CentralEvent::DeviceDiscovered(id)) => {
+                    let properties = match peripheral_properties(adapter, &id).await {
+                        Ok(properties) => properties,
+                        Err(err) => {
+                            debug!("Skipping discovered device {id:?}: {err:#}");
+                            continue;
+                        }
+                    };
+
+                    if let Some(props) = &properties {
+                        if record_advertisement(&tracked_addresses, &advertisements, props) {
+                            announce_advertised(&advertised_addresses, &tx, props).await;
+                        }
+                        publish_learned(&mqtt_client, props).await;
+                        if let Some(fingerprints) = &fingerprints {
+                            fingerprints.observe(props, std::time::Instant::now());
+                        }
+                    }
+
+                    if matching_device(&device_filters, properties.as_ref()) {
+                        stats.record_device_trigger();
+                        if let Err(err) =
+                            crate::bus::send_coalesced(&tx, StateAnnouncement::DeviceTrigger)
+                        {
+                            error!("Error sending scan arrival message: {err:?}");
+                        }
+                    }
+                }
+                Some(CentralEvent::DeviceUpdated(id)) => {
+                    let properties = match peripheral_properties(adapter, &id).await {
+                        Ok(properties) => properties,
+                        Err(err) => {
+                            debug!("Skipping updated device {id:?}: {err:#}");
+                            continue;
+                        }
+                    };
+                    if let Some(props) = properties {
+                        if record_advertisement(&tracked_addresses, &advertisements, &props) {
+                            announce_advertised(&advertised_addresses, &tx, &props).await;
+                        }
+                        publish_learned(&mqtt_client, &props).await;
+                        if let Some(fingerprints) = &fingerprints {
+                            fingerprints.observe(&props, std::time::Instant::now());
+                        }
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    scan_failed(&tx, adapter_id, anyhow::anyhow!("BLE event stream closed")).await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(EVENT_STREAM_RETRY) => continue 'stream,
+                        _ = shutdown.cancelled() => break 'stream,
+                    }
+                }
             }
         }
     }
     Ok(())
 }
 
+/// An advertising device's properties. Looking them up fails routinely once BlueZ has removed the
+/// device (e.g. after clearing its cache), so callers skip the event rather than stop listening.
+async fn peripheral_properties(
+    adapter: &crate::adapter::Adapter,
+    id: &btleplug::platform::PeripheralId,
+) -> anyhow::Result<Option<btleplug::api::PeripheralProperties>> {
+    adapter
+        .peripheral(id)
+        .await
+        .context("get peripheral")?
+        .properties()
+        .await
+        .context("get device properties")
+}
+
+/// Returns whether this is the device's first advertisement in a while.
+fn record_advertisement(
+    tracked_addresses: &HashSet<String>,
+    advertisements: &Advertisements,
+    properties: &btleplug::api::PeripheralProperties,
+) -> bool {
+    let address = properties.address.to_string().to_uppercase();
+    if !tracked_addresses.contains(&address) {
+        return false;
+    }
+    let now = std::time::Instant::now();
+    advertisements
+        .record(&address, Advertisement::from_properties(properties), now)
+        .is_none_or(|previous| now.saturating_duration_since(previous) >= ADVERTISEMENT_GAP)
+}
+
+/// Let scanners check a device tracked by its advertisements as soon as it advertises again.
+async fn announce_advertised(
+    advertised_addresses: &HashSet<String>,
+    tx: &mpsc::Sender<StateAnnouncement>,
+    properties: &btleplug::api::PeripheralProperties,
+) {
+    let mac_address = properties.address.to_string().to_uppercase();
+    if advertised_addresses.contains(&mac_address)
+        && let Err(err) = tx.send(StateAnnouncement::DeviceSeen { mac_address }).await
+    {
+        error!("Error sending advertisement message: {err:?}");
+    }
+}
+
+async fn publish_learned(
+    mqtt_client: &MqttClient,
+    properties: &btleplug::api::PeripheralProperties,
+) {
+    let Some(candidate) = Candidate::from_properties(properties) else {
+        return;
+    };
+    if mqtt_client
+        .learn_window()
+        .admit(std::time::Instant::now(), &candidate)
+        && let Err(err) = mqtt_client.publish_learned(&candidate).await
+    {
+        error!("Error publishing learned device: {err:?}");
+    }
+}
+
 fn matching_device(
     company_ids: &HashSet<u16>,
-    properties: Option<btleplug::api::PeripheralProperties>,
+    properties: Option<&btleplug::api::PeripheralProperties>,
 ) -> bool {
     match properties {
         Some(props) => {
             let name = props
                 .local_name
+                .as_ref()
                 .map(|name| format!(" name: {name}"))
                 .unwrap_or_default();
-            let manufacturer_data = props.manufacturer_data;
-            let manufacturer_id = manufacturer_data.keys().find(|id| company_ids.contains(id));
+            let vendor = crate::oui::vendor_of(&props.address.to_string())
+                .map(|vendor| format!(" vendor: {vendor}"))
+                .unwrap_or_default();
+            let manufacturer_id = props
+                .manufacturer_data
+                .keys()
+                .find(|id| company_ids.contains(id));
 
             if let Some(manufacturer_id) = manufacturer_id {
                 debug!(
-                    "Discovered device passing manufacturer filter {}{name} [{manufacturer_id}]",
+                    "Discovered device passing manufacturer filter {}{name}{vendor} [{manufacturer_id}]",
                     props.address
                 );
                 true
             } else {
                 debug!(
-                    "Discovered device but not interested in manufacturer {}{name}",
+                    "Discovered device but not interested in manufacturer {}{name}{vendor}",
                     props.address
                 );
                 false
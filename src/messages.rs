@@ -1,9 +1,56 @@
+use serde::Serialize;
+
+use crate::{
+    advertisements::Advertisement,
+    config::{BleDevice, DeviceType},
+    control::{Paused, Tuning},
+    state::Transition,
+};
+
 #[derive(Clone, Debug)]
 pub enum StateAnnouncement {
     DeviceTrigger,
-    ScanArrive,
-    ScanDepart,
-    CheckStillPresent(/* device name */ String),
+    /// A specific tracked device was heard from, e.g. it answered a classic inquiry or started
+    /// advertising again
+    DeviceSeen {
+        mac_address: String,
+    },
+    /// Check for arrivals, only of the named devices if given
+    ScanArrive {
+        devices: Option<Vec<String>>,
+    },
+    /// Check for departures, only of the named devices if given
+    ScanDepart {
+        devices: Option<Vec<String>>,
+    },
+    /// Change scan settings at runtime
+    Tune(Tuning),
+    /// Hold off on checks until [StateAnnouncement::Resume]
+    Pause(Paused),
+    /// Check the devices whose checks were held off while paused
+    Resume,
+    /// Set the presence of a device that's never checked, see [Detect::Static](crate::config::Detect::Static)
+    SetPresence {
+        device: String,
+        present: bool,
+    },
+    /// Re-announce every device's current state without checking it, e.g. after a reconnect
+    Republish,
+    /// A tracked device was added, edited or (with no `device`) removed at runtime
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    DeviceChanged {
+        /// Name the device was tracked under before the change
+        name: String,
+        device: Option<Box<BleDevice>>,
+        /// Adapter whose scanner should track the device from now on
+        adapter: String,
+    },
+    /// Scanning for advertisements failed on an adapter, counted by its scanner's
+    /// [Recovery](crate::recovery::Recovery) like a failed check
+    ScanFailed {
+        adapter: String,
+        error: String,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -12,9 +59,36 @@ pub enum DevicePresence {
     Absent,
 }
 
+/// Why a device's state is what it is, published as `reason`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The device answered its presence check
+    NameRequestOk,
+    /// The device didn't answer its presence check
+    NameRequestTimeout,
+    /// The device, or an advertisement matching its fingerprint, was heard advertising
+    AdvertSeen,
+    /// Presence is set by config or command rather than checked
+    ManualOverride,
+    /// Nothing was heard from the device for longer than its timeout
+    StaleExpired,
+}
+
 #[derive(Clone, Debug)]
 pub struct DeviceAnnouncement {
     pub name: String,
     pub mac_address: String,
+    pub device_type: DeviceType,
     pub presence: DevicePresence,
+    /// Set when this check caused the device to arrive or depart
+    pub transition: Option<Transition>,
+    /// Most recent advertisement seen from the device, if it advertises
+    pub advertisement: Option<Advertisement>,
+    /// Why the device is in this state, unknown for states restored from the state file
+    pub reason: Option<Reason>,
+    /// When the device was last heard from, by a check or an advertisement
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    /// How the device's presence is decided: the presence backend, `advertisement` or `static`
+    pub method: &'static str,
 }
@@ -0,0 +1,525 @@
+//! Fake Bluetooth adapters, enabled by the `mock` feature, that replay advertisements from a
+//! scenario file so the daemon can run without Bluetooth hardware, e.g. in CI. Point
+//! `adapter.mock_scenario` at a file like:
+//!
+//! ```toml
+//! # Defaults to a single hci0
+//! adapters = ["hci0", "hci1"]
+//!
+//! # Milliseconds after the adapter starts scanning
+//! [[events]]
+//! after_millis = 1000
+//! address = "00:11:22:33:44:55"
+//! local_name = "Phone"
+//! rssi = -60
+//! manufacturer_id = 76
+//! manufacturer_data = [2, 21]
+//!
+//! # The device stops advertising and is forgotten by the adapter
+//! [[events]]
+//! after_millis = 60000
+//! address = "00:11:22:33:44:55"
+//! gone = true
+//! ```
+//!
+//! Use the `btleplug` backend, the other ones talk to real hardware.
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use btleplug::api::{
+    BDAddr, CentralEvent, CentralState, Characteristic, Descriptor, PeripheralProperties,
+    ScanFilter, Service, ValueNotification, WriteType,
+};
+use btleplug::platform::PeripheralId;
+use futures::Stream;
+use log::debug;
+use mac_address::MacAddress;
+use serde_derive::Deserialize;
+use tokio::sync::broadcast;
+
+/// Events buffered for the first subscriber, which usually subscribes after scanning started.
+const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+struct Scenario {
+    #[serde(default = "default_adapters")]
+    adapters: Vec<String>,
+    #[serde(default)]
+    events: Vec<ScenarioEvent>,
+}
+
+fn default_adapters() -> Vec<String> {
+    vec!["hci0".to_string()]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ScenarioEvent {
+    after_millis: u64,
+    /// Adapter that hears the advertisement, defaults to the first one
+    adapter: Option<String>,
+    #[serde(deserialize_with = "crate::config::deserialize_mac")]
+    address: MacAddress,
+    local_name: Option<String>,
+    rssi: Option<i16>,
+    manufacturer_id: Option<u16>,
+    #[serde(default)]
+    manufacturer_data: Vec<u8>,
+    #[serde(default)]
+    gone: bool,
+}
+
+/// The adapters described by a scenario file.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Adapter>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read mock scenario {}", path.display()))?;
+    let scenario: Scenario = toml::de::from_str(&contents)
+        .with_context(|| format!("Failed to parse mock scenario {}", path.display()))?;
+    let first = scenario.adapters.first().context("No mock adapters")?;
+    scenario
+        .adapters
+        .iter()
+        .map(|id| {
+            let events = scenario
+                .events
+                .iter()
+                .filter(|event| event.adapter.as_ref().unwrap_or(first) == id)
+                .cloned()
+                .collect();
+            Ok(Adapter::new(id, events))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Adapter {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    id: String,
+    events: Mutex<Option<Vec<ScenarioEvent>>>,
+    peripherals: Mutex<Vec<Peripheral>>,
+    sender: broadcast::Sender<CentralEvent>,
+    /// Receiver holding every event until the first call to `events()`
+    first_receiver: Mutex<Option<broadcast::Receiver<CentralEvent>>>,
+}
+
+impl Adapter {
+    fn new(id: &str, mut events: Vec<ScenarioEvent>) -> Self {
+        events.sort_by_key(|event| event.after_millis);
+        let (sender, receiver) = broadcast::channel(EVENT_CAPACITY);
+        Adapter {
+            inner: Arc::new(Inner {
+                id: id.to_string(),
+                events: Mutex::new(Some(events)),
+                peripherals: Mutex::default(),
+                sender,
+                first_receiver: Mutex::new(Some(receiver)),
+            }),
+        }
+    }
+
+    /// Replay the scenario, once.
+    fn start(&self) {
+        let Some(events) = self.inner.events.lock().expect("mock lock poisoned").take() else {
+            return;
+        };
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            let started = tokio::time::Instant::now();
+            for event in events {
+                tokio::time::sleep_until(started + Duration::from_millis(event.after_millis)).await;
+                if let Some(event) = inner.apply(&event) {
+                    let _ = inner.sender.send(event);
+                }
+            }
+            debug!("Mock adapter {} finished its scenario", inner.id);
+        });
+    }
+}
+
+impl Inner {
+    fn apply(&self, event: &ScenarioEvent) -> Option<CentralEvent> {
+        let address = BDAddr::from(event.address.bytes());
+        let mut peripherals = self.peripherals.lock().expect("mock lock poisoned");
+        let known = peripherals
+            .iter()
+            .position(|peripheral| peripheral.properties.address == address);
+        if event.gone {
+            if let Some(index) = known {
+                peripherals.remove(index);
+            }
+            return None;
+        }
+        let (peripheral, discovered) = match known {
+            Some(index) => (&mut peripherals[index], false),
+            None => {
+                peripherals.push(Peripheral {
+                    id: peripheral_id(&self.id, address),
+                    properties: PeripheralProperties {
+                        address,
+                        ..Default::default()
+                    },
+                });
+                (peripherals.last_mut()?, true)
+            }
+        };
+        let properties = &mut peripheral.properties;
+        if event.local_name.is_some() {
+            properties.local_name.clone_from(&event.local_name);
+        }
+        properties.rssi = event.rssi;
+        if let Some(id) = event.manufacturer_id {
+            properties
+                .manufacturer_data
+                .insert(id, event.manufacturer_data.clone());
+        }
+        let id = peripheral.id.clone();
+        Some(if discovered {
+            CentralEvent::DeviceDiscovered(id)
+        } else {
+            CentralEvent::DeviceUpdated(id)
+        })
+    }
+}
+
+/// The id BlueZ would give the device, e.g. `/org/bluez/hci0/dev_00_11_22_33_44_55`.
+fn peripheral_id(adapter: &str, address: BDAddr) -> PeripheralId {
+    let path = format!(
+        "/org/bluez/{adapter}/dev_{}",
+        address.to_string().replace(':', "_")
+    );
+    let device: bluez_async::DeviceId =
+        serde_json::from_value(serde_json::json!({ "object_path": path }))
+            .expect("BlueZ device paths are valid");
+    device.into()
+}
+
+#[async_trait]
+impl btleplug::api::Central for Adapter {
+    type Peripheral = Peripheral;
+
+    async fn events(&self) -> btleplug::Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+        let receiver = self
+            .inner
+            .first_receiver
+            .lock()
+            .expect("mock lock poisoned")
+            .take()
+            .unwrap_or_else(|| self.inner.sender.subscribe());
+        Ok(Box::pin(futures::stream::unfold(
+            receiver,
+            |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) => return Some((event, receiver)),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )))
+    }
+
+    async fn start_scan(&self, _filter: ScanFilter) -> btleplug::Result<()> {
+        self.start();
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> btleplug::Result<()> {
+        Ok(())
+    }
+
+    async fn peripherals(&self) -> btleplug::Result<Vec<Peripheral>> {
+        Ok(self
+            .inner
+            .peripherals
+            .lock()
+            .expect("mock lock poisoned")
+            .clone())
+    }
+
+    async fn peripheral(&self, id: &PeripheralId) -> btleplug::Result<Peripheral> {
+        self.inner
+            .peripherals
+            .lock()
+            .expect("mock lock poisoned")
+            .iter()
+            .find(|peripheral| &peripheral.id == id)
+            .cloned()
+            .ok_or(btleplug::Error::DeviceNotFound)
+    }
+
+    async fn add_peripheral(&self, _id: &PeripheralId) -> btleplug::Result<Peripheral> {
+        Err(not_supported())
+    }
+
+    async fn clear_peripherals(&self) -> btleplug::Result<()> {
+        self.inner
+            .peripherals
+            .lock()
+            .expect("mock lock poisoned")
+            .clear();
+        Ok(())
+    }
+
+    async fn adapter_info(&self) -> btleplug::Result<String> {
+        Ok(format!("{} (mock)", self.inner.id))
+    }
+
+    async fn adapter_state(&self) -> btleplug::Result<CentralState> {
+        Ok(CentralState::PoweredOn)
+    }
+}
+
+/// A snapshot of a device heard by a mock [Adapter]. Only its advertised properties are
+/// available, it can't be connected to.
+#[derive(Debug, Clone)]
+pub struct Peripheral {
+    id: PeripheralId,
+    properties: PeripheralProperties,
+}
+
+fn not_supported() -> btleplug::Error {
+    btleplug::Error::NotSupported("mock adapter".to_string())
+}
+
+#[async_trait]
+impl btleplug::api::Peripheral for Peripheral {
+    fn id(&self) -> PeripheralId {
+        self.id.clone()
+    }
+
+    fn address(&self) -> BDAddr {
+        self.properties.address
+    }
+
+    fn mtu(&self) -> u16 {
+        btleplug::api::DEFAULT_MTU_SIZE
+    }
+
+    async fn properties(&self) -> btleplug::Result<Option<PeripheralProperties>> {
+        Ok(Some(self.properties.clone()))
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        BTreeSet::new()
+    }
+
+    async fn is_connected(&self) -> btleplug::Result<bool> {
+        Ok(false)
+    }
+
+    async fn connect(&self) -> btleplug::Result<()> {
+        Err(not_supported())
+    }
+
+    async fn disconnect(&self) -> btleplug::Result<()> {
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> btleplug::Result<()> {
+        Err(not_supported())
+    }
+
+    async fn write(
+        &self,
+        _characteristic: &Characteristic,
+        _data: &[u8],
+        _write_type: WriteType,
+    ) -> btleplug::Result<()> {
+        Err(not_supported())
+    }
+
+    async fn read(&self, _characteristic: &Characteristic) -> btleplug::Result<Vec<u8>> {
+        Err(not_supported())
+    }
+
+    async fn subscribe(&self, _characteristic: &Characteristic) -> btleplug::Result<()> {
+        Err(not_supported())
+    }
+
+    async fn unsubscribe(&self, _characteristic: &Characteristic) -> btleplug::Result<()> {
+        Err(not_supported())
+    }
+
+    async fn notifications(
+        &self,
+    ) -> btleplug::Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+        Err(not_supported())
+    }
+
+    async fn write_descriptor(
+        &self,
+        _descriptor: &Descriptor,
+        _data: &[u8],
+    ) -> btleplug::Result<()> {
+        Err(not_supported())
+    }
+
+    async fn read_descriptor(&self, _descriptor: &Descriptor) -> btleplug::Result<Vec<u8>> {
+        Err(not_supported())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use btleplug::api::{Central as _, Peripheral as _};
+    use futures::StreamExt as _;
+
+    use super::*;
+    use crate::presence::{BtleplugChecker, PresenceChecker as _};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scenario() {
+        let path =
+            std::env::temp_dir().join(format!("monitor-rs-mock-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [[events]]
+            after_millis = 1000
+            address = "00:11:22:33:44:55"
+            local_name = "Phone"
+            rssi = -60
+            manufacturer_id = 76
+
+            [[events]]
+            after_millis = 2000
+            address = "00:11:22:33:44:55"
+            rssi = -65
+
+            [[events]]
+            after_millis = 3000
+            address = "00:11:22:33:44:55"
+            gone = true
+            "#,
+        )
+        .unwrap();
+        let adapter = load(&path).unwrap().remove(0);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(adapter.adapter_info().await.unwrap(), "hci0 (mock)");
+
+        adapter.start_scan(ScanFilter::default()).await.unwrap();
+        let checker = BtleplugChecker::new(adapter.clone());
+        assert!(!checker.is_present("00:11:22:33:44:55").await.unwrap());
+        let mut events = adapter.events().await.unwrap();
+
+        let Some(CentralEvent::DeviceDiscovered(id)) = events.next().await else {
+            panic!("expected a discovery");
+        };
+        let properties = adapter.peripheral(&id).await.unwrap().properties().await;
+        let properties = properties.unwrap().unwrap();
+        assert_eq!(properties.local_name.as_deref(), Some("Phone"));
+        assert!(properties.manufacturer_data.contains_key(&76));
+        assert!(checker.is_present("00:11:22:33:44:55").await.unwrap());
+
+        assert!(matches!(
+            events.next().await,
+            Some(CentralEvent::DeviceUpdated(_))
+        ));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!checker.is_present("00:11:22:33:44:55").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_manager() {
+        let path = std::env::temp_dir().join(format!(
+            "monitor-rs-mock-manager-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"
+            [[events]]
+            after_millis = 100
+            address = "00:11:22:33:44:55"
+            local_name = "Phone"
+            rssi = -60
+            "#,
+        )
+        .unwrap();
+        let adapters = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let adapters = adapters
+            .into_iter()
+            .map(|adapter| (adapter.inner.id.clone(), adapter))
+            .collect();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (publish_tx, mut publish_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(crate::mqtt::fake_broker(listener, Vec::new(), publish_tx));
+
+        let config: crate::config::AppConfig = toml::de::from_str(&format!(
+            r#"
+            [mqtt]
+            host = "127.0.0.1"
+            port = {port}
+            publisher_id = "mock"
+            stats_interval_seconds = 0
+            # Far fewer than the publishes made on connecting
+            request_channel_capacity = 2
+
+            [homeassistant]
+
+            [scan]
+            backend = "btleplug"
+            scan_on_startup = "arrive"
+            interscan_delay_seconds = 0
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "phone"
+            detect = "advertisement"
+
+            [[devices]]
+            address = "00:11:22:33:44:66"
+            name = "keys"
+            "#
+        ))
+        .unwrap();
+        let stats = Arc::new(crate::stats::Stats::default());
+        let (mqtt_client, eventloop) = crate::mqtt::MqttClient::new(&config, stats.clone());
+        let shutdown = tokio_util::sync::CancellationToken::new();
+        let manager =
+            crate::manager::Manager::new(&config, adapters, mqtt_client, eventloop, stats)
+                .with_shutdown(shutdown.clone());
+        let manager = tokio::spawn(manager.run_loop());
+
+        // The latest state of each device, until the phone advertised, and the discovery configs
+        let mut confidences = std::collections::HashMap::new();
+        let mut discovered = std::collections::HashSet::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while confidences.len() < 2 || confidences["phone"] != 100 || discovered.len() < 4 {
+                let publish = publish_rx.recv().await.unwrap();
+                if publish.topic.starts_with("homeassistant/") {
+                    discovered.insert(publish.topic);
+                    continue;
+                }
+                let Some(name) = publish.topic.strip_prefix("monitor/mock/") else {
+                    continue;
+                };
+                let Ok(state) = serde_json::from_slice::<serde_json::Value>(&publish.payload)
+                else {
+                    continue;
+                };
+                if let Some(confidence) = state.get("confidence") {
+                    confidences.insert(name.to_string(), confidence.clone());
+                }
+            }
+        })
+        .await
+        .expect("the phone announced present and both devices discovered");
+        assert_eq!(confidences["keys"], 0);
+
+        shutdown.cancel();
+        manager.await.unwrap().unwrap();
+    }
+}
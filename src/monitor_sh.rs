@@ -0,0 +1,280 @@
+//! `monitor-rs import-monitor-sh`: convert the configuration of the bash
+//! [monitor](https://github.com/andrewjfreyer/monitor) script into a config file.
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context as _;
+
+use crate::init::{device_table, toml_string};
+
+/// Settings from `mqtt_preferences`, with the `[mqtt]` option they become.
+const MQTT_OPTIONS: &[(&str, &str)] = &[
+    ("mqtt_address", "host"),
+    ("mqtt_port", "port"),
+    ("mqtt_user", "username"),
+    ("mqtt_password", "password"),
+    ("mqtt_publisher_identity", "publisher_id"),
+    ("mqtt_topicpath", "topic_path"),
+];
+
+/// Settings from `behavior_preferences`, with the `[scan]` option they become. The rest have no
+/// equivalent.
+const SCAN_OPTIONS: &[(&str, &str)] = &[
+    ("PREF_INTERSCAN_DELAY", "interscan_delay_seconds"),
+    ("PREF_DEPART_SCAN_ATTEMPTS", "depart_failures_required"),
+    ("PREF_BEACON_EXPIRATION", "beacon_expiration_seconds"),
+    (
+        "PREF_MINIMUM_TIME_BETWEEN_SCANS",
+        "device_trigger_debounce_seconds",
+    ),
+];
+
+/// Options taking a number rather than a string.
+const INTEGER_OPTIONS: &[&str] = &[
+    "port",
+    "interscan_delay_seconds",
+    "depart_failures_required",
+    "beacon_expiration_seconds",
+    "device_trigger_debounce_seconds",
+];
+
+/// Print a config equivalent to the monitor.sh configuration in `dir`.
+pub fn run(dir: &Path) -> anyhow::Result<()> {
+    let read = |name: &str| {
+        let path = dir.join(name);
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    };
+    let mqtt = read("mqtt_preferences")?
+        .with_context(|| format!("No mqtt_preferences in {}", dir.display()))?;
+    let behavior = read("behavior_preferences")?.unwrap_or_default();
+    let addresses = read("known_static_addresses")?.unwrap_or_default();
+
+    let config = convert(&mqtt, &behavior, &addresses)?;
+    print!("{config}");
+    Ok(())
+}
+
+/// Convert the contents of monitor.sh's `mqtt_preferences`, `behavior_preferences` and
+/// `known_static_addresses`, listing anything that couldn't be carried over in a comment.
+fn convert(mqtt: &str, behavior: &str, addresses: &str) -> anyhow::Result<String> {
+    let mut skipped = Vec::new();
+
+    let mut mqtt_table = table("mqtt", &parse_variables(mqtt), MQTT_OPTIONS, &mut skipped)?;
+    if !mqtt_table.contains("\nhost = ") {
+        mqtt_table.push_str("host = \"localhost\"\n");
+    }
+    // Keeps templates written for monitor.sh working
+    mqtt_table.push_str("confidence_format = \"string\"\n");
+    let scan_table = table(
+        "scan",
+        &parse_variables(behavior),
+        SCAN_OPTIONS,
+        &mut skipped,
+    )?;
+
+    let mut devices = String::new();
+    let mut imported = Vec::new();
+    for line in addresses.lines() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (address, alias) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let Ok(address) = crate::config::parse_mac(address) else {
+            skipped.push(line.to_string());
+            continue;
+        };
+        // monitor.sh publishes devices without an alias under their address
+        let alias = alias.trim();
+        let name = if alias.is_empty() {
+            address.to_string()
+        } else {
+            alias.to_string()
+        };
+        if imported
+            .iter()
+            .any(|(known_address, known_name)| *known_address == address || *known_name == name)
+        {
+            skipped.push(line.to_string());
+            continue;
+        }
+        devices.push('\n');
+        devices.push_str(&device_table(address, &name, None));
+        imported.push((address, name));
+    }
+
+    let mut config = String::from(
+        "# Imported from monitor.sh by `monitor-rs import-monitor-sh`. Run `monitor-rs defaults` to\n\
+         # see every other option.\n",
+    );
+    if !skipped.is_empty() {
+        config.push_str("# Not carried over:\n");
+        for line in &skipped {
+            config.push_str(&format!("#   {line}\n"));
+        }
+    }
+    config.push('\n');
+    config.push_str(&mqtt_table);
+    if scan_table.lines().count() > 1 {
+        config.push('\n');
+        config.push_str(&scan_table);
+    }
+    config.push_str(&devices);
+
+    toml::de::from_str::<crate::config::AppConfig>(&config)
+        .context("Imported settings don't make a valid config")?;
+    Ok(config)
+}
+
+/// A TOML table of the `variables` that have an equivalent in `options`, adding the others to
+/// `skipped`.
+fn table(
+    name: &str,
+    variables: &BTreeMap<String, String>,
+    options: &[(&str, &str)],
+    skipped: &mut Vec<String>,
+) -> anyhow::Result<String> {
+    let mut table = format!("[{name}]\n");
+    for (variable, value) in variables {
+        // Left empty for monitor.sh's default, nothing to carry over
+        if value.is_empty() {
+            continue;
+        }
+        let Some((_, option)) = options.iter().find(|(known, _)| known == variable) else {
+            skipped.push(format!("{variable}={value}"));
+            continue;
+        };
+        let value = if INTEGER_OPTIONS.contains(option) {
+            value
+                .parse::<u64>()
+                .with_context(|| format!("{variable} isn't a whole number: {value:?}"))?
+                .to_string()
+        } else {
+            toml_string(value)
+        };
+        table.push_str(&format!("{option} = {value}\n"));
+    }
+    Ok(table)
+}
+
+/// `name=value` assignments in a shell script, unquoted.
+fn parse_variables(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (name, value) = line.split_once('=')?;
+            if name.starts_with('#') || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                return None;
+            }
+            Some((name.to_string(), unquote(value)))
+        })
+        .collect()
+}
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    for quote in ['"', '\''] {
+        if let Some(quoted) = value.strip_prefix(quote)
+            && let Some(end) = quoted.find(quote)
+        {
+            return quoted[..end].to_string();
+        }
+    }
+    strip_comment(value).trim().to_string()
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split_once('#').map_or(line, |(before, _)| before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    const MQTT: &str = r#"
+# ---------------------------
+#    MQTT PREFERENCES
+# ---------------------------
+
+mqtt_address=192.168.1.10
+mqtt_user=monitor
+mqtt_password="p#ss word"
+mqtt_topicpath=presence
+mqtt_publisher_identity=''
+mqtt_port='1884'
+mqtt_certificate_path=''
+mqtt_version=mqttv311
+"#;
+
+    const BEHAVIOR: &str = r#"
+PREF_ARRIVAL_SCAN_ATTEMPTS=1
+PREF_DEPART_SCAN_ATTEMPTS=3 # more reliable
+PREF_BEACON_EXPIRATION=240
+PREF_INTERSCAN_DELAY=3
+"#;
+
+    const ADDRESSES: &str = "
+# Phones
+00:11:22:33:44:55 Alice's phone # upstairs
+aa-bb-cc-dd-ee-ff
+00:11:22:33:44:55 Alice's phone
+not-a-mac Tablet
+";
+
+    #[test]
+    fn test_convert() {
+        let converted = convert(MQTT, BEHAVIOR, ADDRESSES).unwrap();
+        let config: AppConfig = toml::de::from_str(&converted).unwrap();
+        assert_eq!(config.mqtt.host, "192.168.1.10");
+        assert_eq!(config.mqtt.port, Some(1884));
+        assert_eq!(config.mqtt.password.as_deref(), Some("p#ss word"));
+        assert_eq!(config.mqtt.topic_path.as_deref(), Some("presence"));
+        // Empty means the hostname, as it does here
+        assert_eq!(config.mqtt.publisher_id, None);
+        assert_eq!(
+            config.mqtt.confidence_format,
+            Some(crate::config::ConfidenceFormat::String)
+        );
+
+        let scan = config.scan.unwrap();
+        assert_eq!(
+            scan.interscan_delay_seconds,
+            Some(crate::config::InterscanDelay::fixed(3))
+        );
+        assert_eq!(scan.depart_failures_required, Some(3));
+        assert_eq!(scan.beacon_expiration_seconds, Some(240));
+
+        let devices = config.devices.unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "Alice's phone");
+        assert_eq!(devices[1].name, "AA:BB:CC:DD:EE:FF");
+
+        for skipped in [
+            "#   PREF_ARRIVAL_SCAN_ATTEMPTS=1\n",
+            "#   mqtt_version=mqttv311\n",
+            "#   00:11:22:33:44:55 Alice's phone\n",
+            "#   not-a-mac Tablet\n",
+        ] {
+            assert!(converted.contains(skipped), "{converted}");
+        }
+    }
+
+    #[test]
+    fn test_convert_defaults() {
+        let converted = convert("", "", "").unwrap();
+        let config: AppConfig = toml::de::from_str(&converted).unwrap();
+        assert_eq!(config.mqtt.host, "localhost");
+        assert!(config.scan.is_none());
+        assert!(config.devices.is_none());
+
+        let err = convert("", "PREF_INTERSCAN_DELAY=soon", "").unwrap_err();
+        assert!(format!("{err:#}").contains("PREF_INTERSCAN_DELAY"));
+    }
+}
@@ -1,18 +1,162 @@
+use std::collections::HashSet;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context as _;
-use log::{debug, error, info};
-use rumqttc::{MqttOptions, QoS, SubscribeFilter};
-use serde::Serialize;
-use tokio::sync::broadcast;
+use btleplug::api::AddressType;
+use log::{debug, error, info, warn};
+use rumqttc::{ConnectReturnCode, LastWill, MqttOptions, QoS, SubscribeFilter};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_derive::Deserialize;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
 
-use crate::{config, messages::StateAnnouncement};
+use crate::{
+    aggregate::{self, Aggregate},
+    alerts::Alert,
+    cleanup::Cleanup,
+    config, config_file,
+    control::{self, Command, CommandResult, Paused, ScanKind, Tuning},
+    election::Election,
+    homeassistant::{Discovery, DiscoveryMessage, TrackerState},
+    learn::{Candidate, LearnWindow},
+    messages::{DeviceAnnouncement, DevicePresence, Reason, StateAnnouncement},
+    recovery::Report,
+    state::Transition,
+    stats::Stats,
+};
+
+/// Version of the JSON payloads published over MQTT, sent as `schema_version` in every one of
+/// them (Home Assistant discovery configs aside, they follow Home Assistant's schema).
+///
+/// It's bumped when a field is removed or renamed or its meaning changes, with the change noted
+/// in the changelog. New fields can be added without a bump, so consumers should ignore fields
+/// they don't know.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How long the event loop keeps running after shutdown is requested, for final publishes.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+/// QoS 1+ publishes the broker hasn't acknowledged after this long are counted as lost.
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A JSON payload tagged with [`SCHEMA_VERSION`].
+#[derive(Debug, Serialize)]
+struct Versioned<T> {
+    schema_version: u32,
+    #[serde(flatten)]
+    payload: T,
+}
+
+impl<T: Serialize> Versioned<T> {
+    fn new(payload: T) -> Self {
+        Versioned {
+            schema_version: SCHEMA_VERSION,
+            payload,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    fn encode(&self, encoding: config::PayloadEncoding) -> anyhow::Result<Vec<u8>> {
+        Ok(match encoding {
+            config::PayloadEncoding::Json => serde_json::to_vec(self)?,
+            config::PayloadEncoding::Cbor => {
+                let mut payload = Vec::new();
+                ciborium::into_writer(self, &mut payload)?;
+                payload
+            }
+            // Named, so fields are keyed like the JSON ones rather than positional
+            config::PayloadEncoding::Msgpack => rmp_serde::to_vec_named(self)?,
+        })
+    }
+}
+
+fn decode<T: DeserializeOwned>(encoding: config::PayloadEncoding, payload: &[u8]) -> Option<T> {
+    match encoding {
+        config::PayloadEncoding::Json => serde_json::from_slice(payload).ok(),
+        config::PayloadEncoding::Cbor => ciborium::from_reader(payload).ok(),
+        config::PayloadEncoding::Msgpack => rmp_serde::from_slice(payload).ok(),
+    }
+}
+
+/// The part of another node's device state payload needed to tell whether it's present.
+#[derive(Deserialize)]
+struct Sighting {
+    #[serde(deserialize_with = "number_or_string")]
+    confidence: u8,
+}
+
+/// A confidence published as configured by `confidence_format`.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Confidence {
+    Number(u8),
+    String(String),
+}
+
+impl Confidence {
+    fn new(confidence: u8, format: config::ConfidenceFormat) -> Self {
+        match format {
+            config::ConfidenceFormat::Number => Confidence::Number(confidence),
+            config::ConfidenceFormat::String => Confidence::String(confidence.to_string()),
+        }
+    }
+}
+
+/// Other nodes may publish confidences in either format.
+fn number_or_string<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Either {
+        Number(u8),
+        String(String),
+    }
+    match <Either as serde::Deserialize>::deserialize(deserializer)? {
+        Either::Number(confidence) => Ok(confidence),
+        Either::String(confidence) => confidence.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// What the event loop hands to [MqttClient::handle_received].
+enum Received {
+    Connected { reconnected: bool },
+    Publish(rumqttc::Publish),
+}
 
 #[derive(Debug, Clone)]
 pub struct MqttClient {
     client: rumqttc::AsyncClient,
     publisher_id: String,
     topic_path: String,
+    devices: Arc<[config::BleDevice]>,
+    /// [devices](Self::devices) with the changes made at runtime, which commands are checked
+    /// against
+    live_devices: Arc<std::sync::Mutex<Vec<config::BleDevice>>>,
+    discovery: Option<Discovery>,
+    stats: Arc<Stats>,
+    /// Whether the broker has acknowledged a connection at least once
+    connected: watch::Sender<bool>,
+    /// Reported in `bridge/state` while scans are paused
+    paused: watch::Sender<Option<Paused>>,
+    exit_on_auth_failure: bool,
+    timezone: Option<chrono_tz::Tz>,
+    payload_encoding: config::PayloadEncoding,
+    topic_names: TopicNames,
+    confidence_format: config::ConfidenceFormat,
+    learn_window: LearnWindow,
+    election: Option<Election>,
+    /// Presence merged with the nodes in `listen_nodes`
+    aggregate: Option<Arc<std::sync::Mutex<Aggregate>>>,
+    device_tracker: Option<DeviceTracker>,
+    /// Redacted, resolved config published in `bridge/info`
+    bridge_config: Arc<serde_json::Value>,
+    retained_on_shutdown: config::RetainedOnShutdown,
+    /// Where settings changed with `"persist": true` are written
+    config_path: Option<Arc<std::path::PathBuf>>,
+    /// Connection details for the `cleanup_discovery` command
+    cleanup: Arc<Cleanup>,
 }
 
 #[derive(Debug, Serialize)]
@@ -20,145 +164,1902 @@ struct DeviceMqttMessage {
     name: String,
     #[serde(rename = "id")]
     mac_address: String,
-    confidence: u8,
+    #[serde(rename = "type")]
+    device_type: config::DeviceType,
+    confidence: Confidence,
     retained: bool,
+    timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    friendly_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_power: Option<i16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<&'static str>,
+    /// Registered vendor of the device's address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vendor: Option<&'static str>,
+    /// Why the device is in this state, e.g. `name_request_timeout`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<Reason>,
+}
+
+/// Retained Home Assistant entity attributes of a device, published next to its state.
+#[derive(Debug, Serialize)]
+struct DeviceAttributes<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_seen: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rssi: Option<i16>,
+    /// Publisher id of the node that saw the device
+    node: &'a str,
+    method: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<&'static str>,
+}
+
+/// Entry in the retained `devices` list describing what this node tracks.
+#[derive(Debug, Serialize)]
+struct TrackedDevice<'a> {
+    name: &'a str,
+    #[serde(rename = "id")]
+    mac_address: String,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    device_type: Option<config::DeviceType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    friendly_name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<&'a str>,
+}
+
+/// Entry in the retained `bridge/devices` list, named like zigbee2mqtt's.
+#[derive(Debug, Serialize)]
+struct BridgeDevice<'a> {
+    ieee_address: String,
+    friendly_name: &'a str,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    device_type: Option<config::DeviceType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<&'a str>,
+}
+
+/// Options for connecting to the configured broker as `client_id`: address, credentials,
+/// keep-alive and packet size, shared by the daemon and the one-off subcommands.
+pub fn mqtt_options(config: &config::MqttConfig, client_id: String) -> MqttOptions {
+    let mut options = MqttOptions::new(
+        client_id,
+        config.host.clone(),
+        config.port.unwrap_or(config::DEFAULT_MQTT_PORT),
+    );
+    options.set_keep_alive(Duration::from_secs(
+        config
+            .keep_alive_seconds
+            .unwrap_or(config::DEFAULT_KEEP_ALIVE_SECONDS),
+    ));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username.clone(), password.clone());
+    }
+    let max_packet_size = config
+        .max_packet_size
+        .unwrap_or(config::DEFAULT_MQTT_MAX_PACKET_SIZE);
+    options.set_max_packet_size(max_packet_size, max_packet_size);
+    options
 }
 
 impl MqttClient {
-    pub fn new(config: &config::MqttConfig) -> (Self, rumqttc::EventLoop) {
-        let publisher_id = config
-            .publisher_id
-            .as_ref()
-            .unwrap_or(&"monitor-rs".to_string())
-            .to_string();
+    pub fn new(app_config: &config::AppConfig, stats: Arc<Stats>) -> (Self, rumqttc::EventLoop) {
+        let config = &app_config.mqtt;
+        let publisher_id = config.publisher_id.clone().unwrap_or_else(config::hostname);
 
-        let mut mqttoptions = MqttOptions::new(
-            publisher_id.clone(),
-            config.host.clone(),
-            config.port.unwrap_or(1883),
-        );
+        let mut mqttoptions = mqtt_options(config, publisher_id.clone());
 
-        mqttoptions.set_keep_alive(Duration::from_secs(config.keep_alive_seconds.unwrap_or(15)));
+        mqttoptions.set_last_will(LastWill::new(
+            bridge_topic(
+                config
+                    .topic_path
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_TOPIC_PATH),
+                &publisher_id,
+                "state",
+            ),
+            bridge_state("offline").unwrap_or_default(),
+            QoS::AtLeastOnce,
+            true,
+        ));
 
-        if let (Some(username), Some(password)) =
-            (config.username.as_ref(), config.password.as_ref())
-        {
-            mqttoptions.set_credentials(username.clone(), password.clone());
-        }
+        mqttoptions
+            .set_inflight(
+                config
+                    .inflight
+                    .unwrap_or(config::DEFAULT_MQTT_INFLIGHT)
+                    .get(),
+            )
+            .set_pending_throttle(Duration::from_millis(
+                config
+                    .pending_throttle_millis
+                    .unwrap_or(config::DEFAULT_MQTT_PENDING_THROTTLE_MILLIS),
+            ));
+
+        let election = app_config.election.as_ref().map(|election| {
+            let timeout = election
+                .timeout_seconds
+                .unwrap_or(config::DEFAULT_ELECTION_TIMEOUT_SECONDS);
+            Election::new(
+                &publisher_id,
+                Duration::from_secs(timeout),
+                std::time::Instant::now(),
+            )
+        });
+
+        let aggregate = config
+            .listen_nodes
+            .as_deref()
+            .filter(|nodes| !nodes.is_empty())
+            .map(|nodes| Arc::new(std::sync::Mutex::new(Aggregate::new(&publisher_id, nodes))));
 
-        let (client, eventloop) = rumqttc::AsyncClient::new(mqttoptions, 10);
+        let (client, eventloop) = rumqttc::AsyncClient::new(
+            mqttoptions,
+            config
+                .request_channel_capacity
+                .unwrap_or(config::DEFAULT_MQTT_REQUEST_CHANNEL_CAPACITY),
+        );
 
         (
             MqttClient {
                 client,
+                devices: app_config.devices.clone().unwrap_or_default().into(),
+                live_devices: Arc::new(std::sync::Mutex::new(
+                    app_config.devices.clone().unwrap_or_default(),
+                )),
+                discovery: app_config
+                    .homeassistant
+                    .as_ref()
+                    .map(|ha| Discovery::new(ha, &publisher_id)),
                 publisher_id,
-                topic_path: config.topic_path.clone().unwrap_or("monitor".to_string()),
+                stats,
+                connected: watch::Sender::new(false),
+                paused: watch::Sender::new(None),
+                exit_on_auth_failure: config
+                    .exit_on_auth_failure
+                    .unwrap_or(config::DEFAULT_EXIT_ON_AUTH_FAILURE),
+                timezone: config.timezone,
+                payload_encoding: config.payload_encoding.unwrap_or_default(),
+                topic_names: TopicNames::new(&config.topic_names.unwrap_or_default()),
+                confidence_format: config.confidence_format.unwrap_or_default(),
+                retained_on_shutdown: config.retained_on_shutdown.unwrap_or_default(),
+                config_path: None,
+                learn_window: LearnWindow::default(),
+                election,
+                aggregate,
+                device_tracker: DeviceTracker::new(
+                    &config.device_tracker.clone().unwrap_or_default(),
+                ),
+                bridge_config: Arc::new(bridge_config(app_config)),
+                cleanup: Arc::new(Cleanup::new(app_config)),
+                topic_path: config
+                    .topic_path
+                    .clone()
+                    .unwrap_or_else(|| config::DEFAULT_TOPIC_PATH.to_string()),
             },
             eventloop,
         )
     }
 
+    pub fn with_config_path(mut self, config_path: impl Into<std::path::PathBuf>) -> Self {
+        self.config_path = Some(Arc::new(config_path.into()));
+        self
+    }
+
     pub async fn subscribe(&self) -> Result<(), rumqttc::ClientError> {
-        self.client
-            .subscribe_many(vec![
-                SubscribeFilter::new(format!("{}/scan/arrive", self.topic_path), QoS::AtMostOnce),
-                SubscribeFilter::new(format!("{}/scan/depart", self.topic_path), QoS::AtMostOnce),
-            ])
-            .await?;
+        let mut filters = vec![
+            SubscribeFilter::new(format!("{}/scan/arrive", self.topic_path), QoS::AtMostOnce),
+            SubscribeFilter::new(format!("{}/scan/depart", self.topic_path), QoS::AtMostOnce),
+            SubscribeFilter::new(format!("{}/learn", self.topic_path), QoS::AtMostOnce),
+            SubscribeFilter::new(format!("{}/pause", self.topic_path), QoS::AtLeastOnce),
+            SubscribeFilter::new(format!("{}/resume", self.topic_path), QoS::AtLeastOnce),
+            SubscribeFilter::new(format!("{}/cmd", self.topic_path), QoS::AtLeastOnce),
+        ];
+        if self.election.is_some() {
+            filters.extend([
+                SubscribeFilter::new(format!("{}/election/+", self.topic_path), QoS::AtMostOnce),
+                // Device states from every node, for working out whether anyone is home
+                SubscribeFilter::new(format!("{}/+/+", self.topic_path), QoS::AtMostOnce),
+            ]);
+        }
+        if let Some(discovery) = &self.discovery {
+            filters.push(SubscribeFilter::new(
+                discovery.status_topic(),
+                QoS::AtLeastOnce,
+            ));
+        }
+        if let Some(aggregate) = &self.aggregate {
+            let aggregate = aggregate.lock().expect("aggregate lock poisoned");
+            for node in aggregate.listen_nodes() {
+                filters.extend([
+                    SubscribeFilter::new(format!("{}/{node}/+", self.topic_path), QoS::AtMostOnce),
+                    // Going offline drops the node's device states
+                    SubscribeFilter::new(
+                        bridge_topic(&self.topic_path, node, "state"),
+                        QoS::AtLeastOnce,
+                    ),
+                ]);
+            }
+        }
+        self.client.subscribe_many(filters).await?;
 
         Ok(())
     }
 
+    /// Poll the connection until it fails fatally, e.g. because the broker rejected our
+    /// credentials and `exit_on_auth_failure` is set.
     pub async fn event_loop(
         &self,
         eventloop: &mut rumqttc::EventLoop,
-        tx: broadcast::Sender<StateAnnouncement>,
-    ) {
+        tx: mpsc::Sender<StateAnnouncement>,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        let (received_tx, received_rx) = mpsc::unbounded_channel();
+        let handler = tokio::task::spawn(self.clone().handle_received(received_rx, tx));
+        let result = self.poll(eventloop, received_tx, shutdown).await;
+        handler.abort();
+        result
+    }
+
+    /// Drive the connection, handing what arrives to [handle_received](Self::handle_received).
+    /// Nothing here may wait on a publish: rumqttc only takes them off its request channel while
+    /// this polls, so a full channel would never drain.
+    async fn poll(
+        &self,
+        eventloop: &mut rumqttc::EventLoop,
+        received_tx: mpsc::UnboundedSender<Received>,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        let mut connected_before = false;
+        let mut retry = RetryDelay::default();
+        // Once shutting down, keep polling for a little while so final publishes go out
+        let mut stop_at = None;
         loop {
-            match eventloop.poll().await {
+            let polled = tokio::select! {
+                polled = eventloop.poll() => polled,
+                _ = shutdown.cancelled(), if stop_at.is_none() => {
+                    stop_at = Some(tokio::time::Instant::now() + SHUTDOWN_GRACE);
+                    continue;
+                }
+                _ = tokio::time::sleep_until(stop_at.unwrap_or_else(tokio::time::Instant::now)),
+                    if stop_at.is_some() => {
+                    debug!("MQTT client still connected after {SHUTDOWN_GRACE:?}, stopping anyway");
+                    return Ok(());
+                }
+            };
+            let unacked = self
+                .stats
+                .expire_unacked(std::time::Instant::now(), ACK_TIMEOUT);
+            if !unacked.is_empty() {
+                warn!(
+                    "{} publish(es) not acknowledged by the broker within {ACK_TIMEOUT:?} (packet IDs {unacked:?})",
+                    unacked.len()
+                );
+            }
+            match polled {
                 Ok(notification) => match notification {
                     rumqttc::Event::Incoming(rumqttc::Packet::Publish(p)) => {
-                        let payload = p.payload;
-                        debug!("Received MQTT message on topic {}: {payload:?}", p.topic);
-
-                        let message = match p.topic {
-                            t if t.ends_with("/arrive") => StateAnnouncement::ScanArrive,
-                            _ => StateAnnouncement::ScanDepart,
-                        };
-
-                        if let Err(err) = tx.send(message) {
-                            error!("Error announcing scan: {err:?}");
-                        }
+                        received_tx.send(Received::Publish(p)).ok();
                     }
                     rumqttc::Event::Incoming(rumqttc::Packet::SubAck(_)) => {
                         debug!("Subscription acknowledged");
                     }
+                    rumqttc::Event::Outgoing(rumqttc::Outgoing::Publish(pkid)) => {
+                        self.stats
+                            .record_publish_sent(pkid, std::time::Instant::now());
+                    }
+                    rumqttc::Event::Incoming(
+                        rumqttc::Packet::PubAck(rumqttc::PubAck { pkid, .. })
+                        | rumqttc::Packet::PubComp(rumqttc::PubComp { pkid, .. }),
+                    ) => {
+                        self.stats
+                            .record_publish_acked(pkid, std::time::Instant::now());
+                    }
                     rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
                         debug!("Connection acknowledged");
-                        if let Err(err) = self.subscribe().await {
-                            error!("Error subscribing to MQTT topics: {err:?}");
+                        retry.reset();
+                        if connected_before {
+                            self.stats.record_mqtt_reconnect();
                         }
+                        self.connected.send_replace(true);
+                        received_tx
+                            .send(Received::Connected {
+                                reconnected: connected_before,
+                            })
+                            .ok();
+                        connected_before = true;
                     }
                     _ => {}
                 },
+                Err(rumqttc::ConnectionError::ConnectionRefused(
+                    code @ (ConnectReturnCode::BadUserNamePassword
+                    | ConnectReturnCode::NotAuthorized),
+                )) if self.exit_on_auth_failure => {
+                    anyhow::bail!("MQTT broker refused the connection: {code:?}");
+                }
                 Err(e) => {
-                    error!("Error polling MQTT event loop: {e:?}");
+                    let class = ErrorClass::of(&e);
+                    match class {
+                        ErrorClass::Network => self.stats.record_mqtt_network_error(),
+                        ErrorClass::Protocol => self.stats.record_mqtt_protocol_error(),
+                        ErrorClass::Rejected => self.stats.record_mqtt_rejection(),
+                        ErrorClass::Closed => {
+                            debug!("MQTT client dropped, stopping event loop");
+                            return Ok(());
+                        }
+                    }
+                    if shutdown.is_cancelled() {
+                        debug!("Not reconnecting to MQTT while shutting down: {e:?}");
+                        return Ok(());
+                    }
+                    let delay = retry.next(class);
+                    error!("{class:?} error polling MQTT event loop, retrying in {delay:?}: {e:?}");
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Act on connections and incoming messages in order, publishing as needed.
+    async fn handle_received(
+        self,
+        mut received_rx: mpsc::UnboundedReceiver<Received>,
+        tx: mpsc::Sender<StateAnnouncement>,
+    ) {
+        while let Some(received) = received_rx.recv().await {
+            match received {
+                Received::Connected { reconnected } => self.connected(reconnected, &tx).await,
+                Received::Publish(p) => self.handle_publish(p, &tx).await,
+            }
+        }
+    }
+
+    async fn connected(&self, reconnected: bool, tx: &mpsc::Sender<StateAnnouncement>) {
+        if reconnected && let Err(err) = tx.send(StateAnnouncement::Republish).await {
+            error!("Error requesting state republish: {err:?}");
+        }
+        if let Err(err) = self.subscribe().await {
+            error!("Error subscribing to MQTT topics: {err:?}");
+        }
+        if let Err(err) = self.publish_devices().await {
+            error!("Error publishing tracked devices: {err:?}");
+        }
+        if let Err(err) = self.publish_bridge().await {
+            error!("Error publishing bridge documents: {err:?}");
+        }
+        if let Err(err) = self.publish_discovery().await {
+            error!("Error publishing Home Assistant discovery: {err:?}");
+        }
+    }
+
+    async fn handle_publish(&self, p: rumqttc::Publish, tx: &mpsc::Sender<StateAnnouncement>) {
+        let payload = p.payload;
+        debug!("Received MQTT message on topic {}: {payload:?}", p.topic);
+
+        if let Some(discovery) = &self.discovery
+            && p.topic == discovery.status_topic()
+        {
+            if payload.trim_ascii() == b"online" {
+                info!("Home Assistant is online, republishing discovery");
+                if let Err(err) = self.publish_discovery().await {
+                    error!("Error publishing Home Assistant discovery: {err:?}");
+                }
+                if let Err(err) = tx.send(StateAnnouncement::Republish).await {
+                    error!("Error requesting state republish: {err:?}");
+                }
+            }
+            return;
+        }
+        let Some(levels) = p
+            .topic
+            .strip_prefix(self.topic_path.as_str())
+            .and_then(|topic| topic.strip_prefix('/'))
+        else {
+            return;
+        };
+        let levels = levels.split('/').collect::<Vec<_>>();
+        match levels.as_slice() {
+            ["cmd"] => {
+                let parsed = control::parse(
+                    &payload,
+                    &self.live_devices.lock().expect("devices lock poisoned"),
+                );
+                let result = match parsed {
+                    Ok(request) => match self.execute(&request.command, tx).await {
+                        Ok(()) => CommandResult::ok(&request),
+                        Err(err) => CommandResult::error(Some(&request), &err),
+                    },
+                    Err(err) => CommandResult::error(None, &err),
+                };
+                if let Some(error) = &result.error {
+                    warn!("Rejected command: {error}");
+                }
+                if let Err(err) = self.publish_command_result(&result).await {
+                    error!("Error publishing command result: {err:?}");
+                }
+            }
+            // Shorthands for the commands from before the command topic existed
+            ["learn"] => {
+                let seconds = std::str::from_utf8(&payload)
+                    .ok()
+                    .and_then(|payload| payload.trim().parse().ok());
+                self.execute_logged(&Command::Learn { seconds }, tx).await;
+            }
+            ["scan", "arrive"] => {
+                self.execute_logged(
+                    &Command::Scan {
+                        kind: ScanKind::Arrive,
+                        devices: None,
+                    },
+                    tx,
+                )
+                .await
+            }
+            ["scan", "depart"] => {
+                self.execute_logged(
+                    &Command::Scan {
+                        kind: ScanKind::Depart,
+                        devices: None,
+                    },
+                    tx,
+                )
+                .await
+            }
+            // `triggers` as the payload pauses advertisement triggers too
+            ["pause"] => {
+                let triggers = payload.trim_ascii() == b"triggers";
+                self.execute_logged(&Command::Pause { triggers }, tx).await;
+            }
+            ["resume"] => self.execute_logged(&Command::Resume, tx).await,
+            ["election", node] => {
+                if let Some(election) = &self.election {
+                    election.heartbeat(node, std::time::Instant::now());
+                }
+            }
+            [node, device] => {
+                // Anything else on these topics, like stats, isn't a device state
+                if let Some(sighting) = decode::<Sighting>(self.payload_encoding, &payload) {
+                    if let Some(election) = &self.election {
+                        election.record_sighting(node, device, sighting.confidence);
+                    }
+                    self.merge_sighting(node, device, sighting.confidence).await;
+                }
+            }
+            [node, "bridge", "state"] => {
+                let offline = serde_json::from_slice::<serde_json::Value>(&payload)
+                    .is_ok_and(|state| state["state"] == "offline");
+                if offline {
+                    self.forget_node(node).await;
                 }
             }
+            _ => {}
         }
     }
 
-    pub async fn announce_device(
+    async fn execute(
         &self,
-        name: &str,
-        mac_address: String,
-        confidence: u8,
+        command: &Command,
+        tx: &mpsc::Sender<StateAnnouncement>,
     ) -> anyhow::Result<()> {
+        match command {
+            Command::Scan { kind, devices } => {
+                let message = match kind {
+                    ScanKind::Arrive => {
+                        self.stats.record_arrive_request();
+                        StateAnnouncement::ScanArrive {
+                            devices: devices.clone(),
+                        }
+                    }
+                    ScanKind::Depart => {
+                        self.stats.record_depart_request();
+                        StateAnnouncement::ScanDepart {
+                            devices: devices.clone(),
+                        }
+                    }
+                };
+                tx.send(message).await.context("Scanners have stopped")?;
+            }
+            Command::Learn { seconds } => {
+                let seconds = seconds.unwrap_or(config::DEFAULT_LEARN_WINDOW_SECONDS);
+                info!("Publishing devices heard for the next {seconds}s");
+                self.learn_window
+                    .open(std::time::Instant::now(), Duration::from_secs(seconds));
+            }
+            Command::Set { tuning, persist } => {
+                // Persisted first, so a failure leaves everything as it was
+                if *persist {
+                    self.persist_tuning(tuning).await?;
+                }
+                tx.send(StateAnnouncement::Tune(*tuning))
+                    .await
+                    .context("Scanners have stopped")?;
+            }
+            Command::Pause { triggers } => {
+                let paused = Paused {
+                    triggers: *triggers,
+                };
+                tx.send(StateAnnouncement::Pause(paused))
+                    .await
+                    .context("Scanners have stopped")?;
+                self.set_paused(Some(paused)).await?;
+            }
+            Command::Resume => {
+                tx.send(StateAnnouncement::Resume)
+                    .await
+                    .context("Scanners have stopped")?;
+                self.set_paused(None).await?;
+            }
+            Command::Presence { device, present } => {
+                tx.send(StateAnnouncement::SetPresence {
+                    device: device.clone(),
+                    present: *present,
+                })
+                .await
+                .context("Scanners have stopped")?;
+            }
+            Command::CleanupDiscovery => {
+                // Takes a couple of seconds of listening for retained messages, the event loop
+                // can't wait that long
+                let (cleanup, publisher_id, keep) = (
+                    self.cleanup.clone(),
+                    self.publisher_id.clone(),
+                    self.retained_topics(),
+                );
+                tokio::spawn(async move {
+                    match cleanup.run(&publisher_id, &keep).await {
+                        Ok(removed) if removed.is_empty() => info!("No stale retained topics"),
+                        Ok(removed) => {
+                            info!("Cleared stale retained topics: {}", removed.join(", "))
+                        }
+                        Err(err) => error!("Error cleaning up retained topics: {err:#}"),
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    async fn persist_tuning(&self, tuning: &Tuning) -> anyhow::Result<()> {
+        let path = self
+            .config_path
+            .as_deref()
+            .context("No config file to persist settings to")?;
+        config_file::edit(path, |contents| {
+            config_file::set_scan_values(contents, &tuning.values())
+        })
+        .await
+    }
+
+    async fn execute_logged(&self, command: &Command, tx: &mpsc::Sender<StateAnnouncement>) {
+        if let Err(err) = self.execute(command, tx).await {
+            error!("Error executing {} command: {err:#}", command.action());
+        }
+    }
+
+    /// Remember whether scans are paused and report it in `bridge/state`.
+    async fn set_paused(&self, paused: Option<Paused>) -> anyhow::Result<()> {
+        self.paused.send_replace(paused);
+        self.client
+            .publish(
+                bridge_topic(&self.topic_path, &self.publisher_id, "state"),
+                QoS::AtLeastOnce,
+                true,
+                bridge_online(paused).context("Failed to serialize bridge state")?,
+            )
+            .await
+            .context("Failed to publish bridge/state")
+    }
+
+    /// Reply to a request on the command topic.
+    async fn publish_command_result(&self, result: &CommandResult) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/{}/cmd/result", self.topic_path, self.publisher_id),
+                QoS::AtLeastOnce,
+                false,
+                Versioned::new(result)
+                    .to_json()
+                    .context("Failed to serialize command result")?,
+            )
+            .await
+            .context("Failed to publish command result")?;
+
+        Ok(())
+    }
+
+    /// Wait until the broker has been reached, returning false if it wasn't within `timeout`.
+    pub async fn wait_connected(&self, timeout: Duration) -> bool {
+        let mut connected = self.connected.subscribe();
+        tokio::time::timeout(timeout, connected.wait_for(|connected| *connected))
+            .await
+            .is_ok()
+    }
+
+    pub async fn announce_device(&self, announcement: &DeviceAnnouncement) -> anyhow::Result<()> {
+        let name = &announcement.name;
+        let confidence = match announcement.presence {
+            DevicePresence::Absent => 0,
+            DevicePresence::Present(confidence) => confidence,
+        };
         info!("Announcing device {name} (confidence: {confidence}) on MQTT");
-        // TODO: Implement device tracker (`home` / `not_home`)
-        // b"{\"id\":\"<mac address>\",\"confidence\":\"0\",\"name\":\"<name>\",\"manufacturer\":\"Apple Inc\",\"type\":\"KNOWN_MAC\",\"retained\":\"false\",\"timestamp\":\"2025-04-06T13:23:39-0700\",\"version\":\"0.2.200\"}"
+        let device = self.device(name);
+        let advertisement = announcement.advertisement.as_ref();
         let message = DeviceMqttMessage {
             name: name.to_string(),
-            mac_address,
-            confidence,
+            mac_address: announcement.mac_address.clone(),
+            device_type: announcement.device_type,
+            confidence: Confidence::new(confidence, self.confidence_format),
             retained: false,
+            timestamp: format_timestamp(chrono::Utc::now(), self.timezone),
+            friendly_name: device.and_then(|d| d.friendly_name.clone()),
+            room: device.and_then(|d| d.room.clone()),
+            icon: device.and_then(|d| d.icon.clone()),
+            tx_power: advertisement.and_then(|a| a.tx_power),
+            address_type: advertisement
+                .and_then(|a| a.address_type)
+                .map(|address_type| match address_type {
+                    AddressType::Public => "public",
+                    AddressType::Random => "random",
+                }),
+            service_count: advertisement.map(|a| a.service_count),
+            manufacturer: advertisement.and_then(|a| a.manufacturer()),
+            vendor: crate::oui::vendor_of(&announcement.mac_address),
+            reason: announcement.reason,
         };
 
-        let channel_name = sanitize_name(name);
-
         self.client
             .publish(
-                format!("{}/{}/{}", self.topic_path, self.publisher_id, channel_name),
+                self.device_topic(name),
                 QoS::AtMostOnce,
                 false,
-                serde_json::to_string(&message).context("Failed to serialize MQTT message")?,
+                Versioned::new(&message)
+                    .encode(self.payload_encoding)
+                    .context("Failed to serialize MQTT message")?,
             )
             .await
             .context("Failed to publish MQTT message")?;
 
+        if let Some(device_tracker) = &self.device_tracker {
+            self.client
+                .publish(
+                    self.device_tracker_topic(name),
+                    QoS::AtLeastOnce,
+                    device_tracker.retain,
+                    device_tracker.payload(confidence > 0),
+                )
+                .await
+                .context("Failed to publish device_tracker state")?;
+        }
+        if self.discovery.is_some() {
+            self.publish_attributes(announcement).await?;
+        }
+        self.merge_sighting(
+            &self.publisher_id,
+            &self.topic_names.sanitize(name),
+            confidence,
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// Merge a device state from this node or one it listens to, see [Aggregate].
+    async fn merge_sighting(&self, node: &str, device: &str, confidence: u8) {
+        let Some(aggregate) = &self.aggregate else {
+            return;
+        };
+        let changes = aggregate
+            .lock()
+            .expect("aggregate lock poisoned")
+            .record(node, device, confidence);
+        self.publish_merged(changes).await;
+    }
+
+    async fn forget_node(&self, node: &str) {
+        let Some(aggregate) = &self.aggregate else {
+            return;
+        };
+        let changes = {
+            let mut aggregate = aggregate.lock().expect("aggregate lock poisoned");
+            if !aggregate.listens_to(node) {
+                return;
+            }
+            info!("Node {node} went offline, dropping its device states");
+            aggregate.forget_node(node)
+        };
+        self.publish_merged(changes).await;
+    }
+
+    /// Publish the retained `ON` / `OFF` merged presence that changed.
+    async fn publish_merged(&self, changes: aggregate::Changes) {
+        let states = changes
+            .anyone_home
+            .map(|anyone_home| (self.merged_anyone_home_topic(), anyone_home))
+            .into_iter()
+            .chain(
+                changes
+                    .devices
+                    .into_iter()
+                    .map(|(device, present)| (self.merged_presence_topic(&device), present)),
+            );
+        for (topic, on) in states {
+            if let Err(err) = self
+                .client
+                .publish(
+                    &topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    if on { "ON" } else { "OFF" },
+                )
+                .await
+            {
+                error!("Error publishing {topic}: {err:?}");
+            }
+        }
+    }
+
+    /// Publish the retained attributes of a device's Home Assistant `device_tracker`.
+    async fn publish_attributes(&self, announcement: &DeviceAnnouncement) -> anyhow::Result<()> {
+        let advertisement = announcement.advertisement.as_ref();
+        let attributes = DeviceAttributes {
+            last_seen: announcement
+                .last_seen
+                .map(|last_seen| format_timestamp(last_seen, self.timezone)),
+            rssi: advertisement.and_then(|a| a.rssi),
+            node: &self.publisher_id,
+            method: announcement.method,
+            manufacturer: advertisement.and_then(|a| a.manufacturer()),
+        };
+
+        self.client
+            .publish(
+                self.attributes_topic(&announcement.name),
+                QoS::AtMostOnce,
+                true,
+                Versioned::new(&attributes)
+                    .to_json()
+                    .context("Failed to serialize device attributes")?,
+            )
+            .await
+            .context("Failed to publish device attributes")?;
+
         Ok(())
     }
 
+    /// Publish a one-shot `arrived` / `departed` event, only sent when a device changes state.
+    pub async fn announce_event(&self, name: &str, transition: Transition) -> anyhow::Result<()> {
+        info!("Announcing device {name} {} on MQTT", transition.as_str());
+
+        self.client
+            .publish(
+                self.event_topic(name),
+                QoS::AtMostOnce,
+                false,
+                transition.as_str(),
+            )
+            .await
+            .context("Failed to publish MQTT event")?;
+
+        Ok(())
+    }
+
+    /// Whether a room is configured, so occupancy should be published.
+    pub fn tracks_occupancy(&self) -> bool {
+        self.discovery
+            .as_ref()
+            .is_some_and(|discovery| discovery.room().is_some())
+    }
+
+    /// Publish the retained `ON` / `OFF` state of this node's room occupancy sensor.
+    pub async fn announce_occupancy(&self, occupied: bool) -> anyhow::Result<()> {
+        info!(
+            "Announcing room {}",
+            if occupied { "occupied" } else { "unoccupied" }
+        );
+
+        self.client
+            .publish(
+                self.occupancy_topic(),
+                QoS::AtLeastOnce,
+                true,
+                if occupied { "ON" } else { "OFF" },
+            )
+            .await
+            .context("Failed to publish occupancy")?;
+
+        Ok(())
+    }
+
+    pub fn election(&self) -> Option<&Election> {
+        self.election.as_ref()
+    }
+
+    pub async fn publish_heartbeat(&self) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/election/{}", self.topic_path, self.publisher_id),
+                QoS::AtMostOnce,
+                false,
+                "alive",
+            )
+            .await
+            .context("Failed to publish heartbeat")?;
+
+        Ok(())
+    }
+
+    /// Take over broker-wide duties after being elected: announce this node as the leader and
+    /// publish the fleet-wide Home Assistant discovery and state.
+    pub async fn publish_leader(&self, anyone_home: bool) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/leader", self.topic_path),
+                QoS::AtLeastOnce,
+                true,
+                self.publisher_id.as_str(),
+            )
+            .await
+            .context("Failed to publish leader")?;
+        if let Some(discovery) = &self.discovery {
+            let message = discovery.anyone_home(&self.topic_path, &self.anyone_home_topic());
+            self.client
+                .publish(
+                    message.topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    serde_json::to_string(&message.payload)
+                        .context("Failed to serialize discovery message")?,
+                )
+                .await
+                .context("Failed to publish discovery message")?;
+        }
+        self.announce_anyone_home(anyone_home).await
+    }
+
+    /// Publish the retained fleet-wide `ON` / `OFF` anyone home state, leader only.
+    pub async fn announce_anyone_home(&self, anyone_home: bool) -> anyhow::Result<()> {
+        info!(
+            "Announcing {}",
+            if anyone_home {
+                "someone home"
+            } else {
+                "nobody home"
+            }
+        );
+
+        self.client
+            .publish(
+                self.anyone_home_topic(),
+                QoS::AtLeastOnce,
+                true,
+                if anyone_home { "ON" } else { "OFF" },
+            )
+            .await
+            .context("Failed to publish anyone home")?;
+
+        Ok(())
+    }
+
+    pub fn learn_window(&self) -> &LearnWindow {
+        &self.learn_window
+    }
+
+    /// Publish a device heard during a learn window, for picking devices from a remote UI.
+    pub async fn publish_learned(&self, candidate: &Candidate) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/{}/learn", self.topic_path, self.publisher_id),
+                QoS::AtMostOnce,
+                false,
+                Versioned::new(candidate)
+                    .to_json()
+                    .context("Failed to serialize learned device")?,
+            )
+            .await
+            .context("Failed to publish learned device")?;
+
+        Ok(())
+    }
+
+    pub async fn publish_stats(&self) -> anyhow::Result<()> {
+        self.client
+            .publish(
+                format!("{}/{}/stats", self.topic_path, self.publisher_id),
+                QoS::AtMostOnce,
+                false,
+                Versioned::new(self.stats.snapshot())
+                    .to_json()
+                    .context("Failed to serialize stats")?,
+            )
+            .await
+            .context("Failed to publish stats")?;
+
+        Ok(())
+    }
+
+    /// Publish an alert starting or stopping to fire.
+    pub async fn publish_alert(&self, alert: &Alert) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct Timestamped<'a> {
+            #[serde(flatten)]
+            alert: &'a Alert,
+            timestamp: String,
+        }
+
+        self.client
+            .publish(
+                format!("{}/{}/alerts", self.topic_path, self.publisher_id),
+                QoS::AtLeastOnce,
+                false,
+                Versioned::new(Timestamped {
+                    alert,
+                    timestamp: format_timestamp(chrono::Utc::now(), self.timezone),
+                })
+                .to_json()
+                .context("Failed to serialize alert")?,
+            )
+            .await
+            .context("Failed to publish alert")?;
+
+        Ok(())
+    }
+
+    /// Report a step taken to recover from failing presence checks.
+    pub async fn publish_escalation(&self, report: &Report) -> anyhow::Result<()> {
+        #[derive(Serialize)]
+        struct Timestamped<'a> {
+            #[serde(flatten)]
+            report: &'a Report,
+            timestamp: String,
+        }
+
+        self.client
+            .publish(
+                format!("{}/{}/status", self.topic_path, self.publisher_id),
+                QoS::AtLeastOnce,
+                false,
+                Versioned::new(Timestamped {
+                    report,
+                    timestamp: format_timestamp(chrono::Utc::now(), self.timezone),
+                })
+                .to_json()
+                .context("Failed to serialize escalation")?,
+            )
+            .await
+            .context("Failed to publish escalation")?;
+
+        Ok(())
+    }
+
+    /// Publish the retained list of devices tracked by this node.
+    pub async fn publish_devices(&self) -> anyhow::Result<()> {
+        let devices = self
+            .devices
+            .iter()
+            .map(|device| {
+                Versioned::new(TrackedDevice {
+                    name: &device.name,
+                    mac_address: device.address.to_string(),
+                    device_type: device.device_type,
+                    friendly_name: device.friendly_name.as_deref(),
+                    room: device.room.as_deref(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.client
+            .publish(
+                format!("{}/{}/devices", self.topic_path, self.publisher_id),
+                QoS::AtLeastOnce,
+                true,
+                serde_json::to_string(&devices).context("Failed to serialize device list")?,
+            )
+            .await
+            .context("Failed to publish device list")?;
+
+        Ok(())
+    }
+
+    /// Publish the retained zigbee2mqtt-style `bridge/info`, `bridge/devices` and `bridge/state`
+    /// documents describing this node.
+    pub async fn publish_bridge(&self) -> anyhow::Result<()> {
+        let info = serde_json::json!({
+            "schema_version": SCHEMA_VERSION,
+            "version": env!("CARGO_PKG_VERSION"),
+            "publisher_id": self.publisher_id,
+            "config": *self.bridge_config,
+        });
+        let devices = self
+            .devices
+            .iter()
+            .map(|device| {
+                Versioned::new(BridgeDevice {
+                    ieee_address: device.address.to_string(),
+                    friendly_name: &device.name,
+                    device_type: device.device_type,
+                    description: device.friendly_name.as_deref(),
+                    room: device.room.as_deref(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let documents = [
+            ("info", serde_json::to_string(&info)),
+            ("devices", serde_json::to_string(&devices)),
+            ("state", bridge_online(*self.paused.borrow())),
+        ];
+        for (name, document) in documents {
+            self.client
+                .publish(
+                    bridge_topic(&self.topic_path, &self.publisher_id, name),
+                    QoS::AtLeastOnce,
+                    true,
+                    document.context("Failed to serialize bridge document")?,
+                )
+                .await
+                .with_context(|| format!("Failed to publish bridge/{name}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Say goodbye before shutting down: publish `offline` to `bridge/state` (rather than leaving it
+    /// to the broker's last will, which only fires after the keep alive runs out), with a
+    /// `panicked` reason if a panic is what brought the node down, deal with the other retained
+    /// states as configured, then disconnect.
+    pub async fn publish_offline(&self, panic: Option<&str>) -> anyhow::Result<()> {
+        info!("Publishing offline state");
+        let state = match panic {
+            Some(message) => Versioned::new(serde_json::json!({
+                "state": "offline",
+                "reason": "panicked",
+                "message": message,
+            }))
+            .to_json(),
+            None => bridge_state("offline"),
+        };
+        let mut retained = vec![(
+            bridge_topic(&self.topic_path, &self.publisher_id, "state"),
+            state.context("Failed to serialize bridge state")?,
+        )];
+        match self.retained_on_shutdown {
+            config::RetainedOnShutdown::Keep => {}
+            config::RetainedOnShutdown::Clear => {
+                retained.extend(
+                    self.retained_states()
+                        .into_iter()
+                        .map(|(topic, _)| (topic, String::new())),
+                );
+                // Whoever is elected next publishes these again
+                if self.election.as_ref().is_some_and(Election::is_leader) {
+                    retained.push((format!("{}/leader", self.topic_path), String::new()));
+                    retained.push((self.anyone_home_topic(), String::new()));
+                }
+            }
+            config::RetainedOnShutdown::Off => {
+                retained.extend(
+                    self.retained_states()
+                        .into_iter()
+                        .filter_map(|(topic, off)| Some((topic, off?))),
+                );
+            }
+        }
+        for (topic, payload) in retained {
+            debug!("Publishing {payload:?} to {topic} before shutting down");
+            self.client
+                .publish(&topic, QoS::AtLeastOnce, true, payload)
+                .await
+                .with_context(|| format!("Failed to publish to {topic}"))?;
+        }
+        self.client
+            .disconnect()
+            .await
+            .context("Failed to disconnect from MQTT")?;
+
+        Ok(())
+    }
+
+    /// Publish retained Home Assistant discovery configs for every tracked device, if enabled.
+    pub async fn publish_discovery(&self) -> anyhow::Result<()> {
+        let Some(discovery) = &self.discovery else {
+            return Ok(());
+        };
+        debug!("Publishing Home Assistant discovery");
+
+        let messages = self.devices.iter().flat_map(|device| {
+            [
+                discovery.event(device, &self.event_topic(&device.name)),
+                self.device_tracker_discovery(discovery, device),
+            ]
+        });
+        let occupancy = discovery.occupancy(&self.occupancy_topic());
+        for message in messages.chain(occupancy) {
+            self.client
+                .publish(
+                    message.topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    serde_json::to_string(&message.payload)
+                        .context("Failed to serialize discovery message")?,
+                )
+                .await
+                .context("Failed to publish discovery message")?;
+        }
+
+        Ok(())
+    }
+
+    fn device_tracker_discovery(
+        &self,
+        discovery: &Discovery,
+        device: &config::BleDevice,
+    ) -> DiscoveryMessage {
+        let device_topic = self.device_topic(&device.name);
+        let tracker_topic = self.device_tracker_topic(&device.name);
+        let state = match &self.device_tracker {
+            Some(tracker) => TrackerState::Tracker {
+                topic: &tracker_topic,
+                payload_home: &tracker.payload_home,
+                payload_not_home: &tracker.payload_not_home,
+            },
+            None => TrackerState::Device(&device_topic),
+        };
+        discovery.device_tracker(device, state, &self.attributes_topic(&device.name))
+    }
+
+    pub fn publisher_id(&self) -> &str {
+        &self.publisher_id
+    }
+
+    /// Every retained topic this node publishes under its publisher id with its current config,
+    /// which a cleanup leaves alone.
+    pub fn retained_topics(&self) -> HashSet<String> {
+        let mut topics =
+            HashSet::from([format!("{}/{}/devices", self.topic_path, self.publisher_id)]);
+        topics.extend(
+            ["info", "devices", "state"]
+                .map(|name| bridge_topic(&self.topic_path, &self.publisher_id, name)),
+        );
+        topics.extend(self.retained_states().into_iter().map(|(topic, _)| topic));
+        if let Some(discovery) = &self.discovery {
+            topics.extend(self.devices.iter().flat_map(|device| {
+                [
+                    discovery
+                        .event(device, &self.event_topic(&device.name))
+                        .topic,
+                    self.device_tracker_discovery(discovery, device).topic,
+                ]
+            }));
+            topics.extend(
+                discovery
+                    .occupancy(&self.occupancy_topic())
+                    .map(|message| message.topic),
+            );
+        }
+        topics
+    }
+
+    /// The retained states among [retained_topics](Self::retained_topics), each with the payload
+    /// turning it off, if it has one, for `mqtt.retained_on_shutdown`.
+    fn retained_states(&self) -> Vec<(String, Option<String>)> {
+        let mut states = Vec::new();
+        if self.tracks_occupancy() {
+            states.push((self.occupancy_topic(), Some("OFF".to_string())));
+        }
+        if let Some(tracker) = self
+            .device_tracker
+            .as_ref()
+            .filter(|tracker| tracker.retain)
+        {
+            states.extend(self.devices.iter().map(|device| {
+                (
+                    self.device_tracker_topic(&device.name),
+                    Some(tracker.payload_not_home.clone()),
+                )
+            }));
+        }
+        if self.discovery.is_some() {
+            states.extend(
+                self.devices
+                    .iter()
+                    .map(|device| (self.attributes_topic(&device.name), None)),
+            );
+        }
+        if let Some(aggregate) = &self.aggregate {
+            states.push((self.merged_anyone_home_topic(), Some("OFF".to_string())));
+            let aggregate = aggregate.lock().expect("aggregate lock poisoned");
+            states.extend(
+                aggregate
+                    .devices()
+                    .into_iter()
+                    .map(|device| (self.merged_presence_topic(device), Some("OFF".to_string()))),
+            );
+        }
+        states
+    }
+
+    /// Follow a device added, changed or removed at runtime, see
+    /// [StateAnnouncement::DeviceChanged].
+    pub fn device_changed(&self, name: &str, device: Option<&config::BleDevice>) {
+        let mut devices = self.live_devices.lock().expect("devices lock poisoned");
+        devices.retain(|known| known.name != name);
+        devices.extend(device.cloned());
+    }
+
+    fn device(&self, name: &str) -> Option<&config::BleDevice> {
+        self.devices.iter().find(|device| device.name == name)
+    }
+
+    fn event_topic(&self, name: &str) -> String {
+        format!("{}/event", self.device_topic(name))
+    }
+
+    fn device_tracker_topic(&self, name: &str) -> String {
+        format!("{}/device_tracker", self.device_topic(name))
+    }
+
+    fn merged_anyone_home_topic(&self) -> String {
+        format!("{}/{}/anyone_home", self.topic_path, self.publisher_id)
+    }
+
+    fn merged_presence_topic(&self, device: &str) -> String {
+        format!(
+            "{}/{}/presence/{device}",
+            self.topic_path, self.publisher_id
+        )
+    }
+
+    fn attributes_topic(&self, name: &str) -> String {
+        format!("{}/attributes", self.device_topic(name))
+    }
+
+    fn anyone_home_topic(&self) -> String {
+        format!("{}/anyone_home", self.topic_path)
+    }
+
+    fn occupancy_topic(&self) -> String {
+        format!("{}/{}/occupancy", self.topic_path, self.publisher_id)
+    }
+
+    fn device_topic(&self, name: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.topic_path,
+            self.publisher_id,
+            self.topic_names.sanitize(name)
+        )
+    }
+
     pub async fn disconnect(&self) -> Result<(), rumqttc::ClientError> {
         debug!("Disconnecting MQTT client");
         self.client.disconnect().await
     }
 }
 
-fn sanitize_name(name: &str) -> String {
-    // Remove any non-alphanumeric characters and replace spaces with underscores
-    name.to_lowercase()
-        .chars()
-        .map(|c| if c.is_alphanumeric() { c } else { '_' })
-        .collect::<String>()
+/// How an event loop error is handled: network errors and protocol errors are retried with
+/// backoff, broker rejections back off for longer since retrying quickly won't change the answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorClass {
+    Network,
+    Protocol,
+    Rejected,
+    /// Every client handle was dropped, nothing more to do
+    Closed,
+}
+
+impl ErrorClass {
+    fn of(err: &rumqttc::ConnectionError) -> Self {
+        use rumqttc::ConnectionError;
+        match err {
+            ConnectionError::Io(_)
+            | ConnectionError::NetworkTimeout
+            | ConnectionError::FlushTimeout => ErrorClass::Network,
+            ConnectionError::ConnectionRefused(_) => ErrorClass::Rejected,
+            ConnectionError::RequestsDone => ErrorClass::Closed,
+            _ => ErrorClass::Protocol,
+        }
+    }
+}
+
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+const RETRY_REJECTED_MIN: Duration = Duration::from_secs(15);
+
+/// Doubling delay between reconnect attempts, reset once the broker accepts a connection.
+#[derive(Debug, Default)]
+struct RetryDelay {
+    last: Option<Duration>,
+}
+
+impl RetryDelay {
+    fn next(&mut self, class: ErrorClass) -> Duration {
+        let min = match class {
+            ErrorClass::Rejected => RETRY_REJECTED_MIN,
+            _ => RETRY_MIN,
+        };
+        let delay = self
+            .last
+            .map_or(min, |last| (last * 2).clamp(min, RETRY_MAX));
+        self.last = Some(delay);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+/// Bridge documents live under this node's own topics, so nodes sharing a topic path don't
+/// overwrite each other.
+fn bridge_topic(topic_path: &str, publisher_id: &str, name: &str) -> String {
+    format!("{topic_path}/{publisher_id}/bridge/{name}")
+}
+
+fn bridge_state(state: &str) -> serde_json::Result<String> {
+    Versioned::new(serde_json::json!({ "state": state })).to_json()
+}
+
+/// `online`, and whether scans (and advertisement triggers) are paused.
+fn bridge_online(paused: Option<Paused>) -> serde_json::Result<String> {
+    Versioned::new(serde_json::json!({
+        "state": "online",
+        "paused": paused.is_some(),
+        "triggers_paused": paused.is_some_and(|paused| paused.triggers),
+    }))
+    .to_json()
+}
+
+/// The resolved config for `bridge/info`, without secrets or the device list (that's in
+/// `bridge/devices`).
+fn bridge_config(app_config: &config::AppConfig) -> serde_json::Value {
+    let mut resolved = app_config.with_defaults().redacted();
+    resolved.devices = None;
+    serde_json::to_value(&resolved).unwrap_or_default()
+}
+
+/// ISO-8601 with a numeric offset, matching monitor.sh (`2025-04-06T13:23:39-0700`).
+fn format_timestamp(now: chrono::DateTime<chrono::Utc>, timezone: Option<chrono_tz::Tz>) -> String {
+    const FORMAT: &str = "%Y-%m-%dT%H:%M:%S%z";
+    match timezone {
+        Some(tz) => now.with_timezone(&tz).format(FORMAT).to_string(),
+        None => now.with_timezone(&chrono::Local).format(FORMAT).to_string(),
+    }
+}
+
+pub(crate) fn sanitize_name(name: &str) -> String {
+    TopicNames::default().sanitize(name)
+}
+
+/// Plain device states for Home Assistant's MQTT device tracker, see
+/// [config::DeviceTrackerConfig].
+#[derive(Debug, Clone)]
+struct DeviceTracker {
+    retain: bool,
+    payload_home: String,
+    payload_not_home: String,
+}
+
+impl DeviceTracker {
+    /// `None` unless enabled.
+    fn new(cfg: &config::DeviceTrackerConfig) -> Option<Self> {
+        cfg.enabled.unwrap_or(false).then(|| DeviceTracker {
+            retain: cfg.retain.unwrap_or(config::DEFAULT_DEVICE_TRACKER_RETAIN),
+            payload_home: cfg
+                .payload_home
+                .clone()
+                .unwrap_or_else(|| config::DEFAULT_DEVICE_TRACKER_PAYLOAD_HOME.to_string()),
+            payload_not_home: cfg
+                .payload_not_home
+                .clone()
+                .unwrap_or_else(|| config::DEFAULT_DEVICE_TRACKER_PAYLOAD_NOT_HOME.to_string()),
+        })
+    }
+
+    fn payload(&self, present: bool) -> &str {
+        if present {
+            &self.payload_home
+        } else {
+            &self.payload_not_home
+        }
+    }
+}
+
+/// Topics under `<topic_path>/<publisher_id>` that belong to the node rather than a device.
+const NODE_TOPICS: [&str; 10] = [
+    "alerts",
+    "anyone_home",
+    "bridge",
+    "cmd",
+    "devices",
+    "learn",
+    "occupancy",
+    "presence",
+    "stats",
+    "status",
+];
+
+/// How device names become topic segments, see [config::TopicNamesConfig].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TopicNames {
+    preserve_dashes: bool,
+    collapse_underscores: bool,
+}
+
+impl TopicNames {
+    pub fn new(cfg: &config::TopicNamesConfig) -> Self {
+        TopicNames {
+            preserve_dashes: cfg.preserve_dashes.unwrap_or(false),
+            collapse_underscores: cfg.collapse_underscores.unwrap_or(false),
+        }
+    }
+
+    pub fn sanitize(&self, name: &str) -> String {
+        // Replace any non-alphanumeric characters, including spaces, with underscores
+        let sanitized = name
+            .to_lowercase()
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || (self.preserve_dashes && c == '-') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        if !self.collapse_underscores {
+            return sanitized;
+        }
+        let collapsed = sanitized
+            .split('_')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("_");
+        // A name made of nothing but punctuation still needs a topic
+        if collapsed.is_empty() {
+            "_".to_string()
+        } else {
+            collapsed
+        }
+    }
+
+    /// Fail if two devices would publish to the same topic, or a device to one of the node's.
+    pub fn check_collisions(&self, devices: &[config::BleDevice]) -> anyhow::Result<()> {
+        let mut topics = std::collections::HashMap::new();
+        for device in devices {
+            let topic = self.sanitize(&device.name);
+            if NODE_TOPICS.contains(&topic.as_str()) {
+                anyhow::bail!(
+                    "Device {:?} would publish to the node's own {topic:?} topic, rename it",
+                    device.name
+                );
+            }
+            if let Some(other) = topics.insert(topic.clone(), &device.name) {
+                anyhow::bail!(
+                    "Devices {other:?} and {:?} would both publish to the {topic:?} topic, rename one \
+                     or change mqtt.topic_names",
+                    device.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accept one MQTT client, acknowledging what it sends and forwarding its publishes. Every
+/// subscription is answered with the `retained` messages.
+#[cfg(test)]
+pub async fn fake_broker(
+    listener: tokio::net::TcpListener,
+    retained: Vec<rumqttc::Publish>,
+    publishes: tokio::sync::mpsc::UnboundedSender<rumqttc::Publish>,
+) {
+    use rumqttc::{ConnAck, ConnectReturnCode, Packet, PubAck, SubAck, SubscribeReasonCode};
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut read = bytes::BytesMut::new();
+    loop {
+        let packet = match Packet::read(&mut read, 1 << 20) {
+            Ok(packet) => packet,
+            Err(rumqttc::Error::InsufficientBytes(_)) => {
+                if stream.read_buf(&mut read).await.unwrap() == 0 {
+                    return;
+                }
+                continue;
+            }
+            Err(err) => panic!("bad packet: {err:?}"),
+        };
+        let mut reply = bytes::BytesMut::new();
+        match packet {
+            Packet::Connect(_) => ConnAck::new(ConnectReturnCode::Success, false).write(&mut reply),
+            Packet::Subscribe(subscribe) => {
+                let codes = subscribe
+                    .filters
+                    .iter()
+                    .map(|filter| SubscribeReasonCode::Success(filter.qos))
+                    .collect();
+                SubAck::new(subscribe.pkid, codes)
+                    .write(&mut reply)
+                    .unwrap();
+                for publish in &retained {
+                    publish.write(&mut reply).unwrap();
+                }
+                stream.write_all(&reply).await.unwrap();
+                continue;
+            }
+            Packet::Publish(publish) => {
+                let pkid = publish.pkid;
+                let _ = publishes.send(publish);
+                if pkid == 0 {
+                    continue;
+                }
+                PubAck::new(pkid).write(&mut reply)
+            }
+            Packet::PingReq => rumqttc::PingResp.write(&mut reply),
+            _ => continue,
+        }
+        .unwrap();
+        stream.write_all(&reply).await.unwrap();
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        let mut retry = RetryDelay::default();
+        assert_eq!(retry.next(ErrorClass::Network), Duration::from_secs(1));
+        assert_eq!(retry.next(ErrorClass::Network), Duration::from_secs(2));
+        assert_eq!(retry.next(ErrorClass::Rejected), Duration::from_secs(15));
+        for _ in 0..5 {
+            retry.next(ErrorClass::Protocol);
+        }
+        assert_eq!(retry.next(ErrorClass::Network), RETRY_MAX);
+
+        retry.reset();
+        assert_eq!(retry.next(ErrorClass::Network), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_error_class() {
+        assert_eq!(
+            ErrorClass::of(&rumqttc::ConnectionError::NetworkTimeout),
+            ErrorClass::Network
+        );
+        assert_eq!(
+            ErrorClass::of(&rumqttc::ConnectionError::ConnectionRefused(
+                ConnectReturnCode::ServiceUnavailable
+            )),
+            ErrorClass::Rejected
+        );
+        assert_eq!(
+            ErrorClass::of(&rumqttc::ConnectionError::RequestsDone),
+            ErrorClass::Closed
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let now = chrono::DateTime::parse_from_rfc3339("2025-04-06T20:23:39Z")
+            .unwrap()
+            .to_utc();
+        assert_eq!(
+            format_timestamp(now, Some(chrono_tz::America::Los_Angeles)),
+            "2025-04-06T13:23:39-0700"
+        );
+        assert_eq!(
+            format_timestamp(now, Some(chrono_tz::UTC)),
+            "2025-04-06T20:23:39+0000"
+        );
+    }
+
     #[test]
     fn test_sanitize_name() {
         let name = "Test's Device 123";
         let sanitized = super::sanitize_name(name);
         assert_eq!(sanitized, "test_s_device_123");
+
+        let topic_names = TopicNames::new(&config::TopicNamesConfig {
+            preserve_dashes: Some(true),
+            collapse_underscores: Some(true),
+        });
+        assert_eq!(topic_names.sanitize("Bob's  phone (2)"), "bob_s_phone_2");
+        assert_eq!(topic_names.sanitize("ESP32-tag"), "esp32-tag");
+        assert_eq!(topic_names.sanitize("!!!"), "_");
+    }
+
+    #[test]
+    fn test_topic_collisions() {
+        let config: config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Bob's phone"
+
+            [[devices]]
+            address = "00:11:22:33:44:56"
+            name = "Bob-s phone"
+            "#,
+        )
+        .unwrap();
+        let devices = config.devices.unwrap();
+        let err = TopicNames::default()
+            .check_collisions(&devices)
+            .unwrap_err();
+        assert!(err.to_string().contains("\"bob_s_phone\""), "{err}");
+
+        let preserve_dashes = TopicNames::new(&config::TopicNamesConfig {
+            preserve_dashes: Some(true),
+            collapse_underscores: None,
+        });
+        assert!(preserve_dashes.check_collisions(&devices).is_ok());
+
+        let mut anyone_home = devices[0].clone();
+        anyone_home.name = "Anyone home".to_string();
+        let err = TopicNames::default()
+            .check_collisions(&[anyone_home])
+            .unwrap_err();
+        assert!(err.to_string().contains("\"anyone_home\""), "{err}");
+    }
+
+    #[test]
+    fn test_bridge_config_redacted() {
+        let config: config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+            password = "hunter2"
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "phone"
+            "#,
+        )
+        .unwrap();
+        let info = bridge_config(&config);
+        assert_eq!(info["mqtt"]["host"], "localhost");
+        assert_ne!(info["mqtt"]["password"], "hunter2");
+        assert!(info["devices"].is_null());
+        assert_eq!(
+            bridge_topic("monitor", "kitchen", "state"),
+            "monitor/kitchen/bridge/state"
+        );
+    }
+
+    #[test]
+    fn test_versioned() {
+        let device = TrackedDevice {
+            name: "phone",
+            mac_address: "00:11:22:33:44:55".to_string(),
+            device_type: None,
+            friendly_name: None,
+            room: None,
+        };
+        let json: serde_json::Value =
+            serde_json::from_str(&Versioned::new(device).to_json().unwrap()).unwrap();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["name"], "phone");
+
+        let state: serde_json::Value =
+            serde_json::from_str(&bridge_state("offline").unwrap()).unwrap();
+        assert_eq!(state["schema_version"], SCHEMA_VERSION);
+        assert_eq!(state["state"], "offline");
+
+        let state: serde_json::Value =
+            serde_json::from_str(&bridge_online(Some(Paused { triggers: false })).unwrap())
+                .unwrap();
+        assert_eq!(state["state"], "online");
+        assert_eq!(state["paused"], true);
+        assert_eq!(state["triggers_paused"], false);
+    }
+
+    #[test]
+    fn test_payload_encodings() {
+        #[derive(Serialize)]
+        struct State {
+            name: &'static str,
+            confidence: u8,
+        }
+        let state = Versioned::new(State {
+            name: "phone",
+            confidence: 100,
+        });
+        for encoding in [
+            config::PayloadEncoding::Json,
+            config::PayloadEncoding::Cbor,
+            config::PayloadEncoding::Msgpack,
+        ] {
+            let payload = state.encode(encoding).unwrap();
+            let sighting = decode::<Sighting>(encoding, &payload).unwrap();
+            assert_eq!(sighting.confidence, 100, "{encoding:?}");
+        }
+        assert!(decode::<Sighting>(config::PayloadEncoding::Cbor, b"{}").is_none());
+    }
+
+    #[test]
+    fn test_device_attributes() {
+        let attributes = DeviceAttributes {
+            last_seen: None,
+            rssi: Some(-60),
+            node: "kitchen",
+            method: "hcitool",
+            manufacturer: None,
+        };
+        assert_eq!(
+            serde_json::to_value(Versioned::new(&attributes)).unwrap(),
+            serde_json::json!({
+                "schema_version": SCHEMA_VERSION,
+                "rssi": -60,
+                "node": "kitchen",
+                "method": "hcitool",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_device_tracker() {
+        let config = |device_tracker: &str| -> config::AppConfig {
+            toml::de::from_str(&format!(
+                r#"
+                [mqtt]
+                host = "localhost"
+                publisher_id = "kitchen"
+                device_tracker = {device_tracker}
+
+                [[devices]]
+                address = "00:11:22:33:44:55"
+                name = "Phone"
+                "#
+            ))
+            .unwrap()
+        };
+        let retained = |device_tracker| {
+            let (client, _eventloop) =
+                MqttClient::new(&config(device_tracker), Arc::new(Stats::default()));
+            client
+                .retained_topics()
+                .contains("monitor/kitchen/phone/device_tracker")
+        };
+        assert!(retained("{ enabled = true }"));
+        assert!(!retained("{ enabled = true, retain = false }"));
+        assert!(!retained("{ enabled = false }"));
+
+        let tracker = DeviceTracker::new(&config::DeviceTrackerConfig {
+            enabled: Some(true),
+            payload_home: Some("present".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(tracker.payload(true), "present");
+        assert_eq!(tracker.payload(false), "not_home");
+    }
+
+    #[test]
+    fn test_retained_states() {
+        let config: config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+            publisher_id = "kitchen"
+            listen_nodes = ["hallway"]
+            "#,
+        )
+        .unwrap();
+        let (client, _eventloop) = MqttClient::new(&config, Arc::new(Stats::default()));
+        client
+            .aggregate
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .record("hallway", "phone", 100);
+
+        let states = client.retained_states();
+        for topic in [
+            "monitor/kitchen/anyone_home",
+            "monitor/kitchen/presence/phone",
+        ] {
+            assert!(
+                states.contains(&(topic.to_string(), Some("OFF".to_string()))),
+                "{topic}"
+            );
+            assert!(client.retained_topics().contains(topic), "{topic}");
+        }
+
+        let config: config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+            publisher_id = "kitchen"
+            device_tracker = { enabled = true, payload_not_home = "away" }
+
+            [homeassistant]
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Phone"
+            "#,
+        )
+        .unwrap();
+        let (client, _eventloop) = MqttClient::new(&config, Arc::new(Stats::default()));
+        let states = client.retained_states();
+        assert!(states.contains(&("monitor/kitchen/phone/attributes".to_string(), None)));
+        assert!(states.contains(&(
+            "monitor/kitchen/phone/device_tracker".to_string(),
+            Some("away".to_string())
+        )));
+    }
+
+    #[test]
+    fn test_device_changed() {
+        let config: config::AppConfig = toml::de::from_str(
+            r#"
+            [mqtt]
+            host = "localhost"
+
+            [[devices]]
+            address = "00:11:22:33:44:55"
+            name = "Phone"
+            "#,
+        )
+        .unwrap();
+        let (client, _eventloop) = MqttClient::new(&config, Arc::new(Stats::default()));
+        let parse =
+            |payload: &[u8]| control::parse(payload, &client.live_devices.lock().unwrap()).is_ok();
+        let scan_tablet = br#"{"action": "scan", "kind": "arrive", "devices": ["Tablet"]}"#;
+        assert!(!parse(scan_tablet));
+
+        let mut tablet = config.devices.unwrap()[0].clone();
+        tablet.name = "Tablet".to_string();
+        client.device_changed("Phone", Some(&tablet));
+        assert!(parse(scan_tablet));
+        assert!(!parse(
+            br#"{"action": "scan", "kind": "arrive", "devices": ["Phone"]}"#
+        ));
+        client.device_changed("Tablet", None);
+        assert!(!parse(scan_tablet));
+    }
+
+    #[test]
+    fn test_confidence_format() {
+        let json = |format| serde_json::to_value(Confidence::new(90, format)).unwrap();
+        assert_eq!(
+            json(config::ConfidenceFormat::Number),
+            serde_json::json!(90)
+        );
+        assert_eq!(
+            json(config::ConfidenceFormat::String),
+            serde_json::json!("90")
+        );
+
+        // Sightings from nodes using either format count
+        for payload in [
+            br#"{"confidence": 90}"#.as_slice(),
+            br#"{"confidence": "90"}"#,
+        ] {
+            let sighting = decode::<Sighting>(config::PayloadEncoding::Json, payload).unwrap();
+            assert_eq!(sighting.confidence, 90);
+        }
+        assert!(
+            decode::<Sighting>(config::PayloadEncoding::Json, br#"{"confidence": "x"}"#).is_none()
+        );
     }
 }
@@ -0,0 +1,253 @@
+//! Hardware vendors by the OUI (first three octets) of public MAC addresses, from the IEEE
+//! registry at https://standards-oui.ieee.org/oui/oui.txt
+//!
+//! This is a subset covering the configurable manufacturers and other common phone, wearable and
+//! board makers. `scan.oui_file` can point at a full copy of the registry (or Wireshark's `manuf`)
+//! to look up everything else.
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use anyhow::Context as _;
+use log::{debug, warn};
+use mac_address::MacAddress;
+
+use crate::config::BleDevice;
+
+/// Vendors loaded from `scan.oui_file`, looked up before the built-in table.
+static LOADED: OnceLock<HashMap<u32, String>> = OnceLock::new();
+
+const OUIS: &[(u32, &str)] = &[
+    (0x000393, "Apple, Inc."),
+    (0x000502, "Apple, Inc."),
+    (0x0007AB, "Samsung Electronics Co.,Ltd"),
+    (0x000A27, "Apple, Inc."),
+    (0x000A95, "Apple, Inc."),
+    (0x000D93, "Apple, Inc."),
+    (0x0010FA, "Apple, Inc."),
+    (0x001124, "Apple, Inc."),
+    (0x0012FB, "Samsung Electronics Co.,Ltd"),
+    (0x001451, "Apple, Inc."),
+    (0x001599, "Samsung Electronics Co.,Ltd"),
+    (0x001632, "Samsung Electronics Co.,Ltd"),
+    (0x0016CB, "Apple, Inc."),
+    (0x0017F2, "Apple, Inc."),
+    (0x0019E3, "Apple, Inc."),
+    (0x001A11, "Google, Inc."),
+    (0x001B63, "Apple, Inc."),
+    (0x001CB3, "Apple, Inc."),
+    (0x001D25, "Samsung Electronics Co.,Ltd"),
+    (0x001D4F, "Apple, Inc."),
+    (0x001E52, "Apple, Inc."),
+    (0x001EC2, "Apple, Inc."),
+    (0x001F5B, "Apple, Inc."),
+    (0x001FF3, "Apple, Inc."),
+    (0x002119, "Samsung Electronics Co.,Ltd"),
+    (0x0021E9, "Apple, Inc."),
+    (0x002241, "Apple, Inc."),
+    (0x002312, "Apple, Inc."),
+    (0x002332, "Apple, Inc."),
+    (0x002339, "Samsung Electronics Co.,Ltd"),
+    (0x00236C, "Apple, Inc."),
+    (0x0023DF, "Apple, Inc."),
+    (0x002436, "Apple, Inc."),
+    (0x002500, "Apple, Inc."),
+    (0x00254B, "Apple, Inc."),
+    (0x0025BC, "Apple, Inc."),
+    (0x002608, "Apple, Inc."),
+    (0x002637, "Samsung Electronics Co.,Ltd"),
+    (0x00264A, "Apple, Inc."),
+    (0x0026B0, "Apple, Inc."),
+    (0x0026BB, "Apple, Inc."),
+    (0x040CCE, "Apple, Inc."),
+    (0x041552, "Apple, Inc."),
+    (0x041E64, "Apple, Inc."),
+    (0x042665, "Apple, Inc."),
+    (0x045453, "Apple, Inc."),
+    (0x04DB56, "Apple, Inc."),
+    (0x04E536, "Apple, Inc."),
+    (0x04F13E, "Apple, Inc."),
+    (0x04F7E4, "Apple, Inc."),
+    (0x086698, "Apple, Inc."),
+    (0x086D41, "Apple, Inc."),
+    (0x089E08, "Google, Inc."),
+    (0x0C74C2, "Apple, Inc."),
+    (0x1040F3, "Apple, Inc."),
+    (0x109ADD, "Apple, Inc."),
+    (0x18AF61, "Apple, Inc."),
+    (0x1C1AC0, "Apple, Inc."),
+    (0x20C9D0, "Apple, Inc."),
+    (0x20DFB9, "Google, Inc."),
+    (0x240AC4, "Espressif Inc."),
+    (0x246F28, "Espressif Inc."),
+    (0x286C07, "XIAOMI Electronics,CO.,LTD"),
+    (0x28CDC1, "Raspberry Pi Trading Ltd"),
+    (0x28CFE9, "Apple, Inc."),
+    (0x30AEA4, "Espressif Inc."),
+    (0x34C059, "Apple, Inc."),
+    (0x34CE00, "XIAOMI Electronics,CO.,LTD"),
+    (0x3C0754, "Apple, Inc."),
+    (0x3C286D, "Google, Inc."),
+    (0x3C5AB4, "Google, Inc."),
+    (0x3C71BF, "Espressif Inc."),
+    (0x40A6D9, "Apple, Inc."),
+    (0x442A60, "Apple, Inc."),
+    (0x4860BC, "Apple, Inc."),
+    (0x48D6D5, "Google, Inc."),
+    (0x542696, "Apple, Inc."),
+    (0x546009, "Google, Inc."),
+    (0x582429, "Google, Inc."),
+    (0x5855CA, "Apple, Inc."),
+    (0x5CF938, "Apple, Inc."),
+    (0x60FB42, "Apple, Inc."),
+    (0x640980, "XIAOMI Electronics,CO.,LTD"),
+    (0x64B9E8, "Apple, Inc."),
+    (0x68A86D, "Apple, Inc."),
+    (0x703ACB, "Google, Inc."),
+    (0x70CD60, "Apple, Inc."),
+    (0x78CA39, "Apple, Inc."),
+    (0x7C6D62, "Apple, Inc."),
+    (0x7CD95C, "Google, Inc."),
+    (0x80929F, "Apple, Inc."),
+    (0x840D8E, "Espressif Inc."),
+    (0x8866A5, "Apple, Inc."),
+    (0x8C2DAA, "Apple, Inc."),
+    (0x9027E4, "Apple, Inc."),
+    (0x94EB2C, "Google, Inc."),
+    (0x9801A7, "Apple, Inc."),
+    (0xA47733, "Google, Inc."),
+    (0xA4B197, "Apple, Inc."),
+    (0xA4CF12, "Espressif Inc."),
+    (0xAC87A3, "Apple, Inc."),
+    (0xB4E62D, "Espressif Inc."),
+    (0xB817C2, "Apple, Inc."),
+    (0xB827EB, "Raspberry Pi Foundation"),
+    (0xBC52B7, "Apple, Inc."),
+    (0xC44F33, "Espressif Inc."),
+    (0xC82A14, "Apple, Inc."),
+    (0xCC50E3, "Espressif Inc."),
+    (0xD023DB, "Apple, Inc."),
+    (0xD83062, "Apple, Inc."),
+    (0xD83ADD, "Raspberry Pi Trading Ltd"),
+    (0xD86C63, "Google, Inc."),
+    (0xDCA632, "Raspberry Pi Trading Ltd"),
+    (0xE0F847, "Apple, Inc."),
+    (0xE45F01, "Raspberry Pi Trading Ltd"),
+    (0xE4F042, "Google, Inc."),
+    (0xECFABC, "Espressif Inc."),
+    (0xF0DBE2, "Apple, Inc."),
+    (0xF45C89, "Apple, Inc."),
+    (0xF4F5D8, "Google, Inc."),
+    (0xF4F5E8, "Google, Inc."),
+    (0xF88FCA, "Google, Inc."),
+    (0xF8A45F, "Xiaomi Communications Co Ltd"),
+];
+
+/// The registered vendor of a public MAC address. Locally administered (random) addresses have
+/// no vendor.
+pub fn vendor(address: &MacAddress) -> Option<&'static str> {
+    let [a, b, c, ..] = address.bytes();
+    let oui = u32::from_be_bytes([0, a, b, c]);
+    if let Some(vendor) = LOADED.get().and_then(|loaded| loaded.get(&oui)) {
+        return Some(vendor);
+    }
+    OUIS.binary_search_by_key(&oui, |(oui, _)| *oui)
+        .ok()
+        .map(|index| OUIS[index].1)
+}
+
+/// [vendor] of an address in its string form.
+pub fn vendor_of(address: &str) -> Option<&'static str> {
+    vendor(&address.parse().ok()?)
+}
+
+/// Add the vendors in an IEEE `oui.txt` or Wireshark `manuf` file to the lookup. Only the first
+/// file loaded is used.
+pub fn load(path: &Path) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read OUI file {}", path.display()))?;
+    let vendors = parse(&contents);
+    debug!("Loaded {} vendors from {}", vendors.len(), path.display());
+    if LOADED.set(vendors).is_err() {
+        warn!("OUI file already loaded, ignoring {}", path.display());
+    }
+    Ok(())
+}
+
+/// Parse lines like `00-03-93   (hex)\t\tApple, Inc.` (IEEE) or `00:03:93\tApple\tApple, Inc.`
+/// (Wireshark, the last column being the full name). Anything else, including Wireshark's
+/// ranges smaller than an OUI (`00:1B:C5:00:00:00/36`), is skipped.
+fn parse(contents: &str) -> HashMap<u32, String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (prefix, rest) = line.split_once(char::is_whitespace)?;
+            let digits = prefix.replace(['-', ':'], "");
+            if digits.len() != 6 || line.starts_with('#') {
+                return None;
+            }
+            let oui = u32::from_str_radix(&digits, 16).ok()?;
+            let rest = rest.trim_start();
+            let name = match rest.strip_prefix("(hex)") {
+                Some(name) => name,
+                None => rest.rsplit('\t').next()?,
+            }
+            .trim();
+            (!name.is_empty()).then(|| (oui, name.to_string()))
+        })
+        .collect()
+}
+
+/// Warn about devices whose address belongs to a different vendor than their `manufacturer`,
+/// usually a MAC address pasted into the wrong entry. Their advertisements would never trigger
+/// an arrival scan.
+pub fn check_manufacturers(devices: &[BleDevice]) {
+    for device in devices {
+        let Some(manufacturer) = &device.manufacturer else {
+            continue;
+        };
+        if let Some(vendor) = vendor(&device.address)
+            && !vendor.starts_with(manufacturer.name())
+        {
+            warn!(
+                "Device {} is configured as {manufacturer:?} but its address {} belongs to {vendor}",
+                device.name, device.address
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vendor() {
+        assert!(OUIS.is_sorted_by_key(|(oui, _)| *oui));
+        let address = |s: &str| s.parse::<MacAddress>().unwrap();
+        assert_eq!(vendor(&address("00:03:93:12:34:56")), Some("Apple, Inc."));
+        assert_eq!(vendor(&address("3C:5A:B4:12:34:56")), Some("Google, Inc."));
+        assert_eq!(vendor(&address("02:00:00:12:34:56")), None);
+        assert_eq!(
+            vendor_of("B8:27:EB:12:34:56"),
+            Some("Raspberry Pi Foundation")
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let vendors = parse(concat!(
+            "OUI/MA-L                                                    Organization\n",
+            "00-03-93   (hex)\t\tApple, Inc.\n",
+            "000393     (base 16)\t\tApple, Inc.\n",
+            "\t\t\t\t1 Infinite Loop\n",
+            "# Wireshark\n",
+            "00:1A:11\tGoogle\tGoogle, Inc.\n",
+            "00:1B:C5:00:00:00/36\tConverge\tConverging Systems Inc.\n",
+        ));
+        assert_eq!(vendors.len(), 2);
+        assert_eq!(vendors[&0x000393], "Apple, Inc.");
+        assert_eq!(vendors[&0x001A11], "Google, Inc.");
+    }
+}
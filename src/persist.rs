@@ -0,0 +1,117 @@
+//! Device states saved to [scan.state_file] so they can be restored after a restart.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use serde_derive::{Deserialize, Serialize};
+
+/// Saved states, keyed by uppercase MAC address so renaming a device keeps its state.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+pub struct SavedState {
+    pub devices: HashMap<String, SavedDevice>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavedDevice {
+    pub present: bool,
+    pub confidence: u8,
+}
+
+/// Shared handle to the state file. With several adapters each scanner only knows about its own
+/// devices, so updates are merged into the existing file under a lock.
+#[derive(Debug, Clone)]
+pub struct StateFile {
+    path: PathBuf,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        StateFile {
+            path,
+            lock: Arc::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&self) -> anyhow::Result<Option<SavedState>> {
+        load(&self.path)
+    }
+
+    /// Replace the saved state of the given devices, keeping every other device as is.
+    pub async fn update(&self, devices: HashMap<String, SavedDevice>) -> anyhow::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut state = load(&self.path)?.unwrap_or_default();
+        state.devices.extend(devices);
+        save(&self.path, &state).await
+    }
+}
+
+/// Load saved states, returning `None` if nothing has been saved yet.
+pub fn load(path: &Path) -> anyhow::Result<Option<SavedState>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to read {}", path.display()));
+        }
+    };
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+        .map(Some)
+}
+
+/// Save states, writing to a temporary file first so a crash never leaves a truncated file.
+pub async fn save(path: &Path, state: &SavedState) -> anyhow::Result<()> {
+    let contents = serde_json::to_string(state).context("Failed to serialize device states")?;
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, contents)
+        .await
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("monitor-rs-state-{}.json", std::process::id()));
+        assert_eq!(load(&path).unwrap(), None);
+
+        let state = SavedState {
+            devices: HashMap::from([(
+                "00:11:22:33:44:55".to_string(),
+                SavedDevice {
+                    present: true,
+                    confidence: 80,
+                },
+            )]),
+        };
+        save(&path, &state).await.unwrap();
+        assert_eq!(load(&path).unwrap(), Some(state));
+
+        let state_file = StateFile::new(path.clone());
+        state_file
+            .update(HashMap::from([(
+                "00:11:22:33:44:56".to_string(),
+                SavedDevice {
+                    present: false,
+                    confidence: 0,
+                },
+            )]))
+            .await
+            .unwrap();
+        assert_eq!(state_file.load().unwrap().unwrap().devices.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
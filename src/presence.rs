@@ -0,0 +1,139 @@
+//! Presence backends used by the [Scanner](crate::scanner::Scanner) to actively check whether a
+//! device is nearby.
+use futures::future::BoxFuture;
+
+use anyhow::Context as _;
+
+use crate::config::{CheckMethod, DeviceType, PresenceBackend, ScanConfig};
+
+mod cached;
+mod external;
+mod gatt;
+#[cfg(all(feature = "hci", target_os = "linux"))]
+mod hci;
+#[cfg(feature = "bluez")]
+mod hcitool;
+#[cfg(feature = "bluez")]
+mod l2ping;
+#[cfg(target_os = "linux")]
+mod paired;
+mod passive;
+
+pub use cached::CachedChecker;
+pub use external::CommandChecker;
+pub use gatt::GattChecker;
+#[cfg(all(feature = "hci", target_os = "linux"))]
+pub use hci::HciChecker;
+#[cfg(feature = "bluez")]
+pub use hcitool::HcitoolChecker;
+#[cfg(feature = "bluez")]
+pub use l2ping::L2pingChecker;
+#[cfg(target_os = "linux")]
+pub use paired::PairedChecker;
+pub use passive::BtleplugChecker;
+
+pub trait PresenceChecker: Send + Sync {
+    /// Returns whether the device with the given MAC address is currently reachable.
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// How devices confirmed by this checker were identified.
+    fn device_type(&self) -> DeviceType;
+}
+
+/// Build the checker for the configured backend. `interface` pins active checks to a specific adapter, e.g.
+/// `hci1`, when several are in use.
+pub fn new_checker(
+    cfg: &ScanConfig,
+    adapter: &crate::adapter::Adapter,
+    interface: Option<&str>,
+) -> anyhow::Result<Box<dyn PresenceChecker>> {
+    backend_checker(
+        cfg.backend.clone().unwrap_or_default(),
+        cfg,
+        adapter,
+        interface,
+    )
+}
+
+/// Build the checker for devices with `method` set, see [new_checker].
+#[cfg_attr(not(feature = "bluez"), allow(unused_variables))]
+pub fn method_checker(
+    method: CheckMethod,
+    cfg: &ScanConfig,
+    adapter: &crate::adapter::Adapter,
+    interface: Option<&str>,
+) -> anyhow::Result<Box<dyn PresenceChecker>> {
+    match method {
+        CheckMethod::Hcitool => backend_checker(PresenceBackend::Hcitool, cfg, adapter, interface),
+        CheckMethod::Advertisement => {
+            backend_checker(PresenceBackend::Btleplug, cfg, adapter, interface)
+        }
+        #[cfg(feature = "bluez")]
+        CheckMethod::L2ping => Ok(Box::new(
+            L2pingChecker::new(std::sync::Arc::new(crate::command::TokioCommandRunner))
+                .with_program(crate::command::Program::new(
+                    cfg.l2ping.as_ref(),
+                    crate::config::DEFAULT_L2PING_PATH,
+                ))
+                .with_interface(interface.map(str::to_string)),
+        )),
+        #[cfg(not(feature = "bluez"))]
+        CheckMethod::L2ping => Err(anyhow::anyhow!(
+            "method = \"l2ping\" requires monitor-rs to be built with the `bluez` feature"
+        )),
+        CheckMethod::Gatt => Ok(Box::new(GattChecker::new(adapter.clone()))),
+        CheckMethod::Command => {
+            let command = cfg
+                .check_command
+                .as_ref()
+                .filter(|command| command.path.is_some())
+                .context("method = \"command\" requires scan.check_command.path")?;
+            Ok(Box::new(CommandChecker::new(
+                std::sync::Arc::new(crate::command::TokioCommandRunner),
+                crate::command::Program::new(Some(command), ""),
+            )))
+        }
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "bluez", all(feature = "hci", target_os = "linux"))),
+    allow(unused_variables)
+)]
+fn backend_checker(
+    backend: PresenceBackend,
+    cfg: &ScanConfig,
+    adapter: &crate::adapter::Adapter,
+    interface: Option<&str>,
+) -> anyhow::Result<Box<dyn PresenceChecker>> {
+    match backend {
+        #[cfg(feature = "bluez")]
+        PresenceBackend::Hcitool => Ok(Box::new(
+            HcitoolChecker::new(std::sync::Arc::new(crate::command::TokioCommandRunner))
+                .with_program(crate::command::Program::new(
+                    cfg.hcitool.as_ref(),
+                    crate::config::DEFAULT_HCITOOL_PATH,
+                ))
+                .with_interface(interface.map(str::to_string)),
+        )),
+        #[cfg(not(feature = "bluez"))]
+        PresenceBackend::Hcitool => Err(anyhow::anyhow!(
+            "The hcitool backend requires monitor-rs to be built with the `bluez` feature"
+        )),
+        PresenceBackend::Btleplug => Ok(Box::new(
+            BtleplugChecker::new(adapter.clone()).with_rssi(cfg.rssi),
+        )),
+        #[cfg(all(feature = "hci", target_os = "linux"))]
+        PresenceBackend::Hci => Ok(Box::new(HciChecker::new(interface)?)),
+        #[cfg(not(all(feature = "hci", target_os = "linux")))]
+        PresenceBackend::Hci => Err(anyhow::anyhow!(
+            "The hci backend requires Linux and monitor-rs to be built with the `hci` feature"
+        )),
+        #[cfg(target_os = "linux")]
+        PresenceBackend::Paired => Ok(Box::new(PairedChecker::default())),
+        #[cfg(not(target_os = "linux"))]
+        PresenceBackend::Paired => Err(anyhow::anyhow!(
+            "The paired backend queries BlueZ and is only available on Linux"
+        )),
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use log::debug;
+
+use super::PresenceChecker;
+use crate::clock::Clock;
+use crate::config::DeviceType;
+
+/// Reuses a recent result for the same device, so near-simultaneous requests (e.g. a device
+/// trigger followed by an MQTT arrival scan) don't each run their own check. Failed checks are
+/// never cached.
+pub struct CachedChecker {
+    inner: Box<dyn PresenceChecker>,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    results: Mutex<HashMap<String, (Instant, bool)>>,
+}
+
+impl CachedChecker {
+    pub fn new(inner: Box<dyn PresenceChecker>, ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        CachedChecker {
+            inner,
+            ttl,
+            clock,
+            results: Mutex::default(),
+        }
+    }
+}
+
+impl PresenceChecker for CachedChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let now = self.clock.now();
+            let cached = self
+                .results
+                .lock()
+                .unwrap()
+                .get(mac_address)
+                .filter(|(checked_at, _)| now.saturating_duration_since(*checked_at) < self.ttl)
+                .map(|(_, present)| *present);
+            if let Some(present) = cached {
+                debug!("Reusing cached result for {mac_address}: present = {present}");
+                return Ok(present);
+            }
+
+            let present = self.inner.is_present(mac_address).await?;
+            self.results
+                .lock()
+                .unwrap()
+                .insert(mac_address.to_string(), (self.clock.now(), present));
+            Ok(present)
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    struct CountingChecker(Arc<AtomicUsize>);
+
+    impl PresenceChecker for CountingChecker {
+        fn is_present<'a>(&'a self, _mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(std::future::ready(Ok(true)))
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::KnownMac
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let clock = Arc::new(MockClock::new());
+        let checker = CachedChecker::new(
+            Box::new(CountingChecker(calls.clone())),
+            Duration::from_secs(2),
+            clock.clone(),
+        );
+
+        assert!(checker.is_present("00:11:22:33:44:55").await.unwrap());
+        assert!(checker.is_present("00:11:22:33:44:55").await.unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        checker.is_present("00:11:22:33:44:56").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        clock.advance(Duration::from_secs(2));
+        checker.is_present("00:11:22:33:44:55").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use log::debug;
+
+use super::PresenceChecker;
+use crate::command::{CommandRunner, Program};
+use crate::config::DeviceType;
+
+/// Run a user-provided command with the device's address as its last argument, for probes
+/// monitor-rs doesn't know about. The device is present while the command exits successfully.
+pub struct CommandChecker {
+    runner: Arc<dyn CommandRunner>,
+    program: Program,
+}
+
+impl CommandChecker {
+    pub fn new(runner: Arc<dyn CommandRunner>, program: Program) -> Self {
+        CommandChecker { runner, program }
+    }
+}
+
+impl PresenceChecker for CommandChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let output = self
+                .program
+                .run(self.runner.as_ref(), &[mac_address])
+                .await?;
+            if output.success {
+                debug!(
+                    "Device {mac_address} is present: check command exited successfully {:?}",
+                    String::from_utf8_lossy(&output.stdout).trim()
+                );
+            } else {
+                debug!(
+                    "Device {mac_address} is not present: check command exited non-zero {:?}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(output.success)
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::KnownMac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandOutput, ScriptedRunner};
+
+    #[tokio::test]
+    async fn test_exit_status() {
+        let program = Program {
+            path: "/usr/local/bin/ping-watch".to_string(),
+            args: vec!["--quick".to_string()],
+        };
+        let runner = Arc::new(ScriptedRunner::stdout(""));
+        let checker = CommandChecker::new(runner.clone(), program.clone());
+        assert!(checker.is_present("00:11:22:33:44:55").await.unwrap());
+        assert_eq!(
+            runner.calls.lock().unwrap()[0],
+            vec!["/usr/local/bin/ping-watch", "--quick", "00:11:22:33:44:55"]
+        );
+
+        let runner = Arc::new(ScriptedRunner::new(Some(CommandOutput::default())));
+        let checker = CommandChecker::new(runner, program);
+        assert!(!checker.is_present("00:11:22:33:44:55").await.unwrap());
+    }
+}
@@ -0,0 +1,58 @@
+use btleplug::api::{Central as _, Peripheral as _};
+use futures::future::BoxFuture;
+use log::debug;
+
+use super::PresenceChecker;
+use crate::config::DeviceType;
+
+/// Connect to the device with btleplug, for BLE devices that accept connections but neither
+/// answer name requests nor advertise often. Like the passive check, it only knows about devices
+/// the adapter has seen during its scan.
+pub struct GattChecker {
+    adapter: crate::adapter::Adapter,
+}
+
+impl GattChecker {
+    pub fn new(adapter: crate::adapter::Adapter) -> Self {
+        GattChecker { adapter }
+    }
+}
+
+impl PresenceChecker for GattChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            for peripheral in self.adapter.peripherals().await? {
+                if !peripheral
+                    .address()
+                    .to_string()
+                    .eq_ignore_ascii_case(mac_address)
+                {
+                    continue;
+                }
+                if peripheral.is_connected().await? {
+                    debug!("Device {mac_address} is present: already connected");
+                    return Ok(true);
+                }
+                return match peripheral.connect().await {
+                    Ok(()) => {
+                        debug!("Device {mac_address} is present: accepted a connection");
+                        if let Err(err) = peripheral.disconnect().await {
+                            debug!("Error disconnecting from {mac_address}: {err:?}");
+                        }
+                        Ok(true)
+                    }
+                    Err(err) => {
+                        debug!("Device {mac_address} is not present: {err}");
+                        Ok(false)
+                    }
+                };
+            }
+            debug!("Device {mac_address} is not present: not seen by adapter");
+            Ok(false)
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::KnownMac
+    }
+}
@@ -0,0 +1,314 @@
+//! Remote name requests sent straight to the controller over a raw HCI socket. This is the same
+//! request `hcitool name` makes, without depending on the deprecated binary at runtime. Opening
+//! a raw HCI socket requires `CAP_NET_RAW`.
+use std::io;
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+
+use anyhow::Context as _;
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use tokio::io::unix::AsyncFd;
+
+use super::PresenceChecker;
+use crate::config::DeviceType;
+
+const AF_BLUETOOTH: libc::c_int = 31;
+const BTPROTO_HCI: libc::c_int = 1;
+const SOL_HCI: libc::c_int = 0;
+const HCI_FILTER: libc::c_int = 2;
+const HCI_CHANNEL_RAW: u16 = 0;
+
+const HCI_COMMAND_PKT: u8 = 0x01;
+const HCI_EVENT_PKT: u8 = 0x04;
+
+const EVT_REMOTE_NAME_REQ_COMPLETE: u8 = 0x07;
+const EVT_CMD_STATUS: u8 = 0x0f;
+
+/// Link control commands (OGF 0x01)
+const OP_REMOTE_NAME_REQ: u16 = 0x0419;
+const OP_REMOTE_NAME_REQ_CANCEL: u16 = 0x041a;
+
+/// Page scan repetition mode R2, what `hcitool name` uses when the mode isn't known
+const PAGE_SCAN_REPETITION_MODE: u8 = 0x02;
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+#[repr(C)]
+struct HciFilter {
+    type_mask: u32,
+    event_mask: [u32; 2],
+    opcode: u16,
+}
+
+pub struct HciChecker {
+    /// Controller index, e.g. 1 for `hci1`
+    dev_id: u16,
+}
+
+impl HciChecker {
+    /// Use the adapter named by `interface` (e.g. `hci1`), or `hci0` if not given.
+    pub fn new(interface: Option<&str>) -> anyhow::Result<Self> {
+        let dev_id = match interface {
+            Some(interface) => interface
+                .strip_prefix("hci")
+                .and_then(|id| id.parse().ok())
+                .with_context(|| format!("Invalid HCI interface {interface}"))?,
+            None => 0,
+        };
+        Ok(HciChecker { dev_id })
+    }
+}
+
+impl PresenceChecker for HciChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let bdaddr = bdaddr(mac_address)?;
+            match request_name(self.dev_id, bdaddr).await? {
+                Some(name) => {
+                    debug!("Device {mac_address} is present: remote name '{name}'");
+                    Ok(true)
+                }
+                None => {
+                    debug!("Device {mac_address} is not present: remote name request failed");
+                    Ok(false)
+                }
+            }
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::KnownMac
+    }
+}
+
+/// Cancels the outstanding name request if it's dropped before completing, e.g. when the scanner
+/// gives up on a check after `check_timeout_seconds`.
+struct PendingRequest<'a> {
+    socket: &'a AsyncFd<OwnedFd>,
+    bdaddr: [u8; 6],
+    done: bool,
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        if !self.done
+            && let Err(err) = send(
+                self.socket.get_ref(),
+                &command(OP_REMOTE_NAME_REQ_CANCEL, &self.bdaddr),
+            )
+        {
+            warn!("Error cancelling remote name request: {err}");
+        }
+    }
+}
+
+async fn request_name(dev_id: u16, bdaddr: [u8; 6]) -> anyhow::Result<Option<String>> {
+    let socket =
+        AsyncFd::new(open(dev_id).with_context(|| format!("open HCI socket hci{dev_id}"))?)?;
+
+    let mut params = bdaddr.to_vec();
+    params.extend([PAGE_SCAN_REPETITION_MODE, 0, 0, 0]);
+    send(socket.get_ref(), &command(OP_REMOTE_NAME_REQ, &params)).context("send name request")?;
+    let mut pending = PendingRequest {
+        socket: &socket,
+        bdaddr,
+        done: false,
+    };
+
+    let mut buf = [0u8; 260];
+    loop {
+        let mut guard = socket.readable().await?;
+        let len = match guard.try_io(|inner| {
+            // SAFETY: reading into a buffer we own, bounded by its length
+            let len = unsafe {
+                libc::read(
+                    inner.get_ref().as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(len as usize)
+            }
+        }) {
+            Ok(result) => result.context("read HCI event")?,
+            Err(_would_block) => continue,
+        };
+
+        match parse_event(&buf[..len], &bdaddr) {
+            Some(Event::Rejected(status)) => {
+                pending.done = true;
+                anyhow::bail!("Controller rejected remote name request: status {status:#04x}");
+            }
+            Some(Event::Complete(name)) => {
+                pending.done = true;
+                return Ok(name);
+            }
+            None => {}
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    /// Command Status for our request with a non-zero status
+    Rejected(u8),
+    /// Remote Name Request Complete for our address, `None` if the device didn't answer
+    Complete(Option<String>),
+}
+
+/// Find the events for our request. Other requests on the controller are also visible on a raw
+/// socket, so events for other addresses and commands are ignored.
+fn parse_event(packet: &[u8], bdaddr: &[u8; 6]) -> Option<Event> {
+    let [HCI_EVENT_PKT, code, len, params @ ..] = packet else {
+        return None;
+    };
+    let params = params.get(..usize::from(*len))?;
+    match (*code, params) {
+        (EVT_CMD_STATUS, [status, _ncmd, opcode_lo, opcode_hi, ..])
+            if u16::from_le_bytes([*opcode_lo, *opcode_hi]) == OP_REMOTE_NAME_REQ
+                && *status != 0 =>
+        {
+            Some(Event::Rejected(*status))
+        }
+        (EVT_REMOTE_NAME_REQ_COMPLETE, [status, rest @ ..]) if rest.get(..6)? == bdaddr => {
+            if *status != 0 {
+                return Some(Event::Complete(None));
+            }
+            let name = &rest[6..];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+            Some(Event::Complete(Some(
+                String::from_utf8_lossy(name).into_owned(),
+            )))
+        }
+        _ => None,
+    }
+}
+
+fn command(opcode: u16, params: &[u8]) -> Vec<u8> {
+    let mut packet = vec![HCI_COMMAND_PKT];
+    packet.extend(opcode.to_le_bytes());
+    packet.push(params.len() as u8);
+    packet.extend(params);
+    packet
+}
+
+/// HCI addresses are little-endian, the reverse of how they're written
+fn bdaddr(mac_address: &str) -> anyhow::Result<[u8; 6]> {
+    let mut bytes = mac_address
+        .parse::<mac_address::MacAddress>()
+        .with_context(|| format!("Invalid MAC address {mac_address}"))?
+        .bytes();
+    bytes.reverse();
+    Ok(bytes)
+}
+
+fn open(dev_id: u16) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket(2) call, the descriptor is owned immediately after
+    let fd = unsafe {
+        libc::socket(
+            AF_BLUETOOTH,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+            BTPROTO_HCI,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a freshly created, valid descriptor nobody else owns
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let addr = SockaddrHci {
+        hci_family: AF_BLUETOOTH as libc::sa_family_t,
+        hci_dev: dev_id,
+        hci_channel: HCI_CHANNEL_RAW,
+    };
+    // SAFETY: addr is a valid sockaddr_hci and the length matches it
+    if unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            (&addr as *const SockaddrHci).cast(),
+            size_of::<SockaddrHci>() as libc::socklen_t,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut filter = HciFilter {
+        type_mask: 1 << HCI_EVENT_PKT,
+        event_mask: [0; 2],
+        opcode: 0,
+    };
+    for code in [EVT_REMOTE_NAME_REQ_COMPLETE, EVT_CMD_STATUS] {
+        filter.event_mask[usize::from(code >> 5)] |= 1 << (code & 31);
+    }
+    // SAFETY: filter is a valid hci_filter and the length matches it
+    if unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            SOL_HCI,
+            HCI_FILTER,
+            (&filter as *const HciFilter).cast(),
+            size_of::<HciFilter>() as libc::socklen_t,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(fd)
+}
+
+fn send(fd: &OwnedFd, packet: &[u8]) -> io::Result<()> {
+    // SAFETY: writing from a buffer we own, bounded by its length
+    let written = unsafe { libc::write(fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+    if written < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BDADDR: [u8; 6] = [0x55, 0x44, 0x33, 0x22, 0x11, 0x00];
+
+    #[test]
+    fn test_command() {
+        assert_eq!(bdaddr("00:11:22:33:44:55").unwrap(), BDADDR);
+        assert_eq!(
+            command(OP_REMOTE_NAME_REQ_CANCEL, &BDADDR),
+            vec![0x01, 0x1a, 0x04, 6, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let mut complete = vec![HCI_EVENT_PKT, EVT_REMOTE_NAME_REQ_COMPLETE, 255, 0];
+        complete.extend(BDADDR);
+        complete.extend(b"Pixel 9");
+        complete.resize(3 + 255, 0);
+        assert_eq!(
+            parse_event(&complete, &BDADDR),
+            Some(Event::Complete(Some("Pixel 9".to_string())))
+        );
+        assert_eq!(parse_event(&complete, &[0; 6]), None);
+
+        // Page timeout
+        complete[3] = 0x04;
+        assert_eq!(parse_event(&complete, &BDADDR), Some(Event::Complete(None)));
+
+        let status = [HCI_EVENT_PKT, EVT_CMD_STATUS, 4, 0x0c, 1, 0x19, 0x04];
+        assert_eq!(parse_event(&status, &BDADDR), Some(Event::Rejected(0x0c)));
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use log::debug;
+
+use super::PresenceChecker;
+use crate::command::{CommandRunner, Program};
+use crate::config::{self, DeviceType};
+
+/// Shell out to `hcitool name <MAC>` like the Bash version of this utility does.
+/// Theoretically this is something that could be done in Rust, but `btleplug` only supports direct
+/// connecting via MAC address on Android, not Windows/Linux/macOS. That means this
+/// backend only works on Linux, since `hcitool` is a `bluez` utility.
+pub struct HcitoolChecker {
+    runner: Arc<dyn CommandRunner>,
+    program: Program,
+    /// Adapter to send name requests from (`hcitool -i`), otherwise hcitool picks one
+    interface: Option<String>,
+}
+
+impl HcitoolChecker {
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        HcitoolChecker {
+            runner,
+            program: Program::new(None, config::DEFAULT_HCITOOL_PATH),
+            interface: None,
+        }
+    }
+
+    pub fn with_program(mut self, program: Program) -> Self {
+        self.program = program;
+        self
+    }
+
+    pub fn with_interface(mut self, interface: Option<String>) -> Self {
+        self.interface = interface;
+        self
+    }
+}
+
+impl PresenceChecker for HcitoolChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let runner = self.runner.as_ref();
+            let output = match &self.interface {
+                Some(interface) => {
+                    self.program
+                        .run(runner, &["-i", interface, "name", mac_address])
+                        .await?
+                }
+                None => self.program.run(runner, &["name", mac_address]).await?,
+            };
+
+            if output.success {
+                let output_str = String::from_utf8_lossy(&output.stdout);
+                if output_str.trim().is_empty() {
+                    debug!("Device {mac_address} is not present: empty reply from hcitool");
+                    Ok(false)
+                } else {
+                    debug!(
+                        "Device {mac_address} is present: hcitool returned '{}'",
+                        output_str.trim()
+                    );
+                    Ok(true)
+                }
+            } else {
+                Err(anyhow::anyhow!(
+                    "Command exited non-zero {:?}",
+                    String::from_utf8_lossy(&output.stderr)
+                ))
+            }
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::KnownMac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandOutput, ScriptedRunner};
+
+    const MAC: &str = "00:11:22:33:44:55";
+
+    #[tokio::test]
+    async fn test_name_reply() {
+        let runner = Arc::new(ScriptedRunner::stdout("Pixel 9\n"));
+        let checker = HcitoolChecker::new(runner.clone());
+        assert!(checker.is_present(MAC).await.unwrap());
+        assert_eq!(
+            runner.calls.lock().unwrap()[0],
+            vec!["hcitool", "name", MAC]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interface() {
+        let runner = Arc::new(ScriptedRunner::stdout("Pixel 9\n"));
+        let checker = HcitoolChecker::new(runner.clone()).with_interface(Some("hci1".to_string()));
+        assert!(checker.is_present(MAC).await.unwrap());
+        assert_eq!(
+            runner.calls.lock().unwrap()[0],
+            vec!["hcitool", "-i", "hci1", "name", MAC]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_program() {
+        let runner = Arc::new(ScriptedRunner::stdout("Pixel 9\n"));
+        let program = Program {
+            path: "/opt/bluez/bin/hcitool".to_string(),
+            args: vec!["-i".to_string(), "hci1".to_string()],
+        };
+        let checker = HcitoolChecker::new(runner.clone()).with_program(program);
+        assert!(checker.is_present(MAC).await.unwrap());
+        assert_eq!(
+            runner.calls.lock().unwrap()[0],
+            vec!["/opt/bluez/bin/hcitool", "-i", "hci1", "name", MAC]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty_reply() {
+        let checker = HcitoolChecker::new(Arc::new(ScriptedRunner::stdout("\n")));
+        assert!(!checker.is_present(MAC).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_non_zero_exit() {
+        let checker = HcitoolChecker::new(Arc::new(ScriptedRunner::new(Some(CommandOutput {
+            success: false,
+            stdout: Vec::new(),
+            stderr: b"Device is not available.".to_vec(),
+        }))));
+        let err = checker.is_present(MAC).await.unwrap_err();
+        assert!(err.to_string().contains("Device is not available"));
+    }
+}
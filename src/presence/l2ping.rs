@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use log::debug;
+
+use super::PresenceChecker;
+use crate::command::{CommandRunner, Program};
+use crate::config::{self, DeviceType};
+
+/// Send a single L2CAP echo request with `l2ping -c 1 <MAC>`. Some devices answer these without
+/// answering name requests, though it takes a raw socket and so usually root.
+pub struct L2pingChecker {
+    runner: Arc<dyn CommandRunner>,
+    program: Program,
+    /// Adapter to ping from (`l2ping -i`), otherwise l2ping picks one
+    interface: Option<String>,
+}
+
+impl L2pingChecker {
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        L2pingChecker {
+            runner,
+            program: Program::new(None, config::DEFAULT_L2PING_PATH),
+            interface: None,
+        }
+    }
+
+    pub fn with_program(mut self, program: Program) -> Self {
+        self.program = program;
+        self
+    }
+
+    pub fn with_interface(mut self, interface: Option<String>) -> Self {
+        self.interface = interface;
+        self
+    }
+}
+
+impl PresenceChecker for L2pingChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let runner = self.runner.as_ref();
+            let output = match &self.interface {
+                Some(interface) => {
+                    self.program
+                        .run(runner, &["-i", interface, "-c", "1", mac_address])
+                        .await?
+                }
+                None => self.program.run(runner, &["-c", "1", mac_address]).await?,
+            };
+
+            if output.success {
+                debug!("Device {mac_address} is present: answered l2ping");
+                return Ok(true);
+            }
+            // l2ping fails the same way for a device that's away as for one that never answers,
+            // only failing to even send the request is an error
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if stderr.contains("Can't connect") || stderr.contains("no response") {
+                debug!("Device {mac_address} is not present: {}", stderr.trim());
+                Ok(false)
+            } else {
+                Err(anyhow::anyhow!("Command exited non-zero {:?}", stderr))
+            }
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::KnownMac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{CommandOutput, ScriptedRunner};
+
+    const MAC: &str = "00:11:22:33:44:55";
+
+    fn failed(stderr: &str) -> Arc<ScriptedRunner> {
+        Arc::new(ScriptedRunner::new(Some(CommandOutput {
+            success: false,
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        })))
+    }
+
+    #[tokio::test]
+    async fn test_l2ping() {
+        let runner = Arc::new(ScriptedRunner::stdout(
+            "Ping: 00:11:22:33:44:55 from AA:BB:CC:DD:EE:FF (data size 44) ...\n",
+        ));
+        let checker = L2pingChecker::new(runner.clone()).with_interface(Some("hci1".to_string()));
+        assert!(checker.is_present(MAC).await.unwrap());
+        assert_eq!(
+            runner.calls.lock().unwrap()[0],
+            vec!["l2ping", "-i", "hci1", "-c", "1", MAC]
+        );
+
+        let checker = L2pingChecker::new(failed("Can't connect: Host is down\n"));
+        assert!(!checker.is_present(MAC).await.unwrap());
+
+        let checker = L2pingChecker::new(failed("Can't create socket: Operation not permitted\n"));
+        let err = checker.is_present(MAC).await.unwrap_err();
+        assert!(err.to_string().contains("Operation not permitted"));
+    }
+}
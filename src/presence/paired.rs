@@ -0,0 +1,69 @@
+use anyhow::Context as _;
+use bluez_async::BluetoothSession;
+use futures::future::BoxFuture;
+use log::debug;
+use tokio::sync::OnceCell;
+
+use super::PresenceChecker;
+use crate::config::DeviceType;
+
+/// Ask BlueZ whether an already paired device is currently connected to this host. Devices that
+/// keep a connection open (keyboards, watches) are reported present without any active probing,
+/// unpaired devices are always absent.
+#[derive(Default)]
+pub struct PairedChecker {
+    /// Connected to the system bus on first use
+    session: OnceCell<BluetoothSession>,
+}
+
+impl PairedChecker {
+    async fn session(&self) -> anyhow::Result<&BluetoothSession> {
+        self.session
+            .get_or_try_init(|| async {
+                let (_, session) = BluetoothSession::new().await.context("connect to BlueZ")?;
+                Ok(session)
+            })
+            .await
+    }
+}
+
+impl PresenceChecker for PairedChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let devices = self
+                .session()
+                .await?
+                .get_devices()
+                .await
+                .context("list BlueZ devices")?;
+            let Some(device) = devices.iter().find(|device| {
+                device
+                    .mac_address
+                    .to_string()
+                    .eq_ignore_ascii_case(mac_address)
+            }) else {
+                debug!("Device {mac_address} is not present: unknown to BlueZ");
+                return Ok(false);
+            };
+
+            match (device.paired, device.connected) {
+                (true, true) => {
+                    debug!("Device {mac_address} is present: paired and connected");
+                    Ok(true)
+                }
+                (true, false) => {
+                    debug!("Device {mac_address} is not present: paired but not connected");
+                    Ok(false)
+                }
+                (false, _) => {
+                    debug!("Device {mac_address} is not present: not paired");
+                    Ok(false)
+                }
+            }
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::KnownMac
+    }
+}
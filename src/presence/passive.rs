@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use btleplug::api::{Central as _, Peripheral as _};
+use futures::future::BoxFuture;
+use log::debug;
+
+use super::PresenceChecker;
+use crate::config::{DeviceType, RssiConfig};
+
+/// Passive presence check: a device is considered present if the adapter currently knows about a
+/// peripheral with its address and has received a signal strength for it. This relies on the
+/// adapter scan started by the manager, so it only detects devices that advertise, but works on
+/// every platform btleplug supports.
+pub struct BtleplugChecker {
+    adapter: crate::adapter::Adapter,
+    rssi: Option<RssiConfig>,
+    /// Whether each device is currently within range, by uppercase MAC address
+    in_range: Mutex<HashMap<String, InRange>>,
+}
+
+impl BtleplugChecker {
+    pub fn new(adapter: crate::adapter::Adapter) -> Self {
+        BtleplugChecker {
+            adapter,
+            rssi: None,
+            in_range: Mutex::default(),
+        }
+    }
+
+    /// Also require the device's RSSI to pass the configured thresholds.
+    pub fn with_rssi(mut self, rssi: Option<RssiConfig>) -> Self {
+        self.rssi = rssi;
+        self
+    }
+
+    fn in_range(&self, mac_address: &str, rssi: i16) -> bool {
+        let Some(cfg) = &self.rssi else {
+            return true;
+        };
+        let mut in_range = self.in_range.lock().expect("RSSI lock poisoned");
+        in_range
+            .entry(mac_address.to_uppercase())
+            .or_default()
+            .update(cfg, rssi, Instant::now())
+    }
+}
+
+/// RSSI hysteresis for a single device.
+#[derive(Debug, Default)]
+struct InRange {
+    inside: bool,
+    /// When the RSSI first crossed the threshold for leaving the current side
+    crossing_since: Option<Instant>,
+}
+
+impl InRange {
+    fn update(&mut self, cfg: &RssiConfig, rssi: i16, now: Instant) -> bool {
+        let threshold = if self.inside {
+            cfg.exit.unwrap_or(cfg.enter)
+        } else {
+            cfg.enter
+        };
+        if (rssi >= threshold) == self.inside {
+            self.crossing_since = None;
+            return self.inside;
+        }
+        let since = *self.crossing_since.get_or_insert(now);
+        let dwell = Duration::from_secs(cfg.dwell_seconds.unwrap_or(0));
+        if now.saturating_duration_since(since) >= dwell {
+            self.inside = !self.inside;
+            self.crossing_since = None;
+        }
+        self.inside
+    }
+}
+
+impl PresenceChecker for BtleplugChecker {
+    fn is_present<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            for peripheral in self.adapter.peripherals().await? {
+                let Some(properties) = peripheral.properties().await? else {
+                    continue;
+                };
+                if !properties
+                    .address
+                    .to_string()
+                    .eq_ignore_ascii_case(mac_address)
+                {
+                    continue;
+                }
+                return match properties.rssi {
+                    Some(rssi) if self.in_range(mac_address, rssi) => {
+                        debug!("Device {mac_address} is present: advertising with RSSI {rssi}");
+                        Ok(true)
+                    }
+                    Some(rssi) => {
+                        debug!("Device {mac_address} is not present: RSSI {rssi} out of range");
+                        Ok(false)
+                    }
+                    None => {
+                        debug!("Device {mac_address} is not present: known but not advertising");
+                        Ok(false)
+                    }
+                };
+            }
+            debug!("Device {mac_address} is not present: not seen by adapter");
+            Ok(false)
+        })
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Beacon
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rssi_hysteresis() {
+        let cfg = RssiConfig {
+            enter: -70,
+            exit: Some(-80),
+            dwell_seconds: Some(10),
+        };
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let mut in_range = InRange::default();
+
+        assert!(!in_range.update(&cfg, -75, at(0)));
+        // Has to stay strong for the dwell time
+        assert!(!in_range.update(&cfg, -65, at(0)));
+        assert!(!in_range.update(&cfg, -65, at(5)));
+        assert!(in_range.update(&cfg, -65, at(10)));
+
+        // Between the thresholds stays in range
+        assert!(in_range.update(&cfg, -78, at(20)));
+        assert!(in_range.update(&cfg, -85, at(30)));
+        // Recovering restarts the dwell
+        assert!(in_range.update(&cfg, -75, at(35)));
+        assert!(in_range.update(&cfg, -85, at(40)));
+        assert!(!in_range.update(&cfg, -85, at(50)));
+    }
+}
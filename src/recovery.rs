@@ -0,0 +1,119 @@
+//! Escalating recovery from presence checks and scans that keep failing, usually a wedged
+//! Bluetooth stack: first a warning, then an adapter restart, then exiting for a supervisor like
+//! systemd to start the daemon afresh. Each step is reported on
+//! `<topic_path>/<publisher_id>/status`.
+use log::info;
+use serde::Serialize;
+
+use crate::config::{self, RecoveryConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Escalation {
+    Warning,
+    RestartAdapter,
+    Exit,
+}
+
+/// An escalation, as published on the status topic.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub escalation: Escalation,
+    /// Adapter the checks failed on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
+    pub consecutive_errors: u32,
+    /// The latest failure
+    pub error: String,
+}
+
+/// Consecutive failed checks and scans of one scanner, and the thresholds they escalate at.
+#[derive(Debug)]
+pub struct Recovery {
+    warn_after: u32,
+    restart_adapter_after: u32,
+    exit_after: u32,
+    consecutive_errors: u32,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self::new(&RecoveryConfig::default())
+    }
+}
+
+impl Recovery {
+    pub fn new(cfg: &RecoveryConfig) -> Self {
+        Recovery {
+            warn_after: cfg
+                .warn_after_errors
+                .unwrap_or(config::DEFAULT_RECOVERY_WARN_AFTER_ERRORS),
+            restart_adapter_after: cfg
+                .restart_adapter_after_errors
+                .unwrap_or(config::DEFAULT_RECOVERY_RESTART_ADAPTER_AFTER_ERRORS),
+            exit_after: cfg
+                .exit_after_errors
+                .unwrap_or(config::DEFAULT_RECOVERY_EXIT_AFTER_ERRORS),
+            consecutive_errors: 0,
+        }
+    }
+
+    pub fn consecutive_errors(&self) -> u32 {
+        self.consecutive_errors
+    }
+
+    pub fn succeeded(&mut self) {
+        if self.consecutive_errors > 0 {
+            info!(
+                "Presence checks recovered after {} consecutive error(s)",
+                self.consecutive_errors
+            );
+        }
+        self.consecutive_errors = 0;
+    }
+
+    /// Count a failed check or scan, returning the step to take if it reached a threshold. Each step is
+    /// only taken once until a check succeeds again, except exiting.
+    pub fn failed(&mut self) -> Option<Escalation> {
+        self.consecutive_errors += 1;
+        let errors = self.consecutive_errors;
+        if self.exit_after > 0 && errors >= self.exit_after {
+            Some(Escalation::Exit)
+        } else if errors == self.restart_adapter_after {
+            Some(Escalation::RestartAdapter)
+        } else if errors == self.warn_after {
+            Some(Escalation::Warning)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escalation() {
+        let mut recovery = Recovery::new(&RecoveryConfig {
+            warn_after_errors: Some(1),
+            restart_adapter_after_errors: Some(3),
+            exit_after_errors: Some(4),
+        });
+        assert_eq!(recovery.failed(), Some(Escalation::Warning));
+        assert_eq!(recovery.failed(), None);
+        // A success starts the count over
+        recovery.succeeded();
+        assert_eq!(recovery.failed(), Some(Escalation::Warning));
+        assert_eq!(recovery.failed(), None);
+        assert_eq!(recovery.failed(), Some(Escalation::RestartAdapter));
+        assert_eq!(recovery.failed(), Some(Escalation::Exit));
+
+        let mut recovery = Recovery::new(&RecoveryConfig {
+            warn_after_errors: Some(0),
+            restart_adapter_after_errors: Some(0),
+            exit_after_errors: Some(0),
+        });
+        assert!((0..100).all(|_| recovery.failed().is_none()));
+    }
+}
@@ -1,318 +1,2120 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 use anyhow::Context as _;
+use btleplug::api::AddressType;
 
-use log::{debug, error, info};
-use tokio::process::Command;
-use tokio::sync::broadcast;
+use futures::StreamExt as _;
+use futures::future::BoxFuture;
+use log::{debug, error, info, warn};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::time::{DelayQueue, delay_queue};
 
 use crate::{
-    config::{BleDevice, ScanConfig},
-    messages::{DeviceAnnouncement, StateAnnouncement},
+    advertisements::Advertisements,
+    bluez_cache::DeviceCache,
+    clock::Clock,
+    config::{
+        self, BleDevice, CheckMethod, ClearBluezCache, Detect, DeviceType, InterscanDelay,
+        NodeRole, ScanConfig, StartupState,
+    },
+    control::{Paused, Tuning},
+    fingerprint::Fingerprints,
+    messages::{DeviceAnnouncement, Reason, StateAnnouncement},
+    persist::{SavedDevice, StateFile},
+    presence::PresenceChecker,
+    recovery::{Escalation, Recovery, Report},
+    state::{PresencePolicy, PresenceState, Tracker, Transition},
+    stats::Stats,
 };
 
 pub struct Scanner {
-    rx: broadcast::Receiver<StateAnnouncement>,
-    tx: broadcast::Sender<StateAnnouncement>,
-    presence_timeout: std::time::Duration,
-    device_seen_debounce: std::time::Duration,
-    device_trigger_debounce: std::time::Duration,
-    interscan_delay: std::time::Duration,
-    announce_tx: broadcast::Sender<DeviceAnnouncement>,
+    rx: mpsc::Receiver<StateAnnouncement>,
     device_map: HashMap<String, DeviceState>,
+    defaults: DeviceDefaults,
+    state_file: Option<StateFile>,
+    role: NodeRole,
+    /// Messages received during an arrival pass, handled after it
+    pending: VecDeque<StateAnnouncement>,
+    /// Time the current arrival pass spent on departures, which doesn't count towards it
+    departure_time: std::time::Duration,
+    ctx: ScanContext,
+}
+
+/// Everything needed to scan a single device, kept separate from the device map so a device can
+/// be borrowed mutably while scanning it.
+struct ScanContext {
+    announce_tx: mpsc::Sender<DeviceAnnouncement>,
+    checker: Box<dyn PresenceChecker>,
+    /// Checkers of devices with a [CheckMethod] of their own
+    method_checkers: HashMap<CheckMethod, Box<dyn PresenceChecker>>,
+    /// Name of the presence backend checking devices, announced as their `method`
+    backend: &'static str,
+    clock: Arc<dyn Clock>,
+    stats: Arc<Stats>,
+    /// Adapter this scanner checks devices from, see [StateAnnouncement::DeviceChanged]
+    adapter_id: Option<String>,
+    recovery: Recovery,
+    /// Where escalations of failing checks are reported
+    escalations: Option<mpsc::UnboundedSender<Report>>,
+    advertisements: Advertisements,
+    presence_timeout: std::time::Duration,
+    check_timeout: std::time::Duration,
+    interscan_delay: InterscanDelay,
+    depart_scan_delay: std::time::Duration,
+    pass_budget: Option<std::time::Duration>,
+    pass_timeout: Option<std::time::Duration>,
+    /// Devices a timed out pass skipped, checked first by the next pass
+    skipped: HashSet<String>,
+    policy: PresencePolicy,
+    device_cache: Option<Arc<dyn DeviceCache>>,
+    clear_cache: ClearBluezCache,
+    fingerprints: Option<Fingerprints>,
+    beacon_expiration: Option<std::time::Duration>,
+    /// Pending presence checks, keyed by device name
+    timers: DelayQueue<String>,
+    paused: Option<Paused>,
+    /// Devices whose checks were skipped while paused, checked on resume
+    deferred: HashSet<String>,
 }
 
 #[derive(Debug)]
 struct DeviceState {
     mac_address: String,
-    seen: DeviceSeen,
+    device_type: Option<DeviceType>,
+    detect: Detect,
+    method: Option<CheckMethod>,
+    tracker: Tracker,
+    seen_debounce: std::time::Duration,
+    trigger_debounce: std::time::Duration,
+    /// The device's own debounces, which changing the defaults at runtime leaves alone
+    debounce_overrides: (Option<u64>, Option<u64>),
+    last_trigger: Option<std::time::Instant>,
+    /// Timer for the next presence check, replaced whenever a new check is scheduled
+    pending_check: Option<delay_queue::Key>,
+    /// Why the device is in its current state, announced along with it
+    reason: Option<Reason>,
+    /// When a check or advertisement last saw the device
+    last_seen: Option<std::time::Instant>,
 }
 
-#[derive(Debug)]
-enum DeviceSeen {
-    Seen(std::time::SystemTime),
-    NotSeen,
+/// Settings for devices that don't override them.
+#[derive(Debug, Clone, Copy)]
+struct DeviceDefaults {
+    seen_debounce: u64,
+    trigger_debounce: u64,
+    tracker: Tracker,
+}
+
+impl DeviceDefaults {
+    fn device_state(&self, device: &BleDevice) -> DeviceState {
+        let mut device_info = DeviceState {
+            mac_address: device.address.to_string(),
+            device_type: device.device_type,
+            detect: device.detect.unwrap_or_default(),
+            method: device.method,
+            tracker: self.tracker,
+            seen_debounce: std::time::Duration::ZERO,
+            trigger_debounce: std::time::Duration::ZERO,
+            debounce_overrides: (
+                device.device_seen_debounce_seconds,
+                device.device_trigger_debounce_seconds,
+            ),
+            last_trigger: None,
+            pending_check: None,
+            reason: (device.detect == Some(Detect::Static)).then_some(Reason::ManualOverride),
+            last_seen: None,
+        };
+        self.apply_debounces(&mut device_info);
+        device_info
+    }
+
+    fn apply_debounces(&self, device_info: &mut DeviceState) {
+        let (seen, trigger) = device_info.debounce_overrides;
+        device_info.seen_debounce =
+            std::time::Duration::from_secs(seen.unwrap_or(self.seen_debounce));
+        device_info.trigger_debounce =
+            std::time::Duration::from_secs(trigger.unwrap_or(self.trigger_debounce));
+    }
 }
 
 impl Scanner {
     pub fn new(
         cfg: &ScanConfig,
-        rx: broadcast::Receiver<StateAnnouncement>,
-        announce_tx: broadcast::Sender<DeviceAnnouncement>,
-        tx: broadcast::Sender<StateAnnouncement>,
+        rx: mpsc::Receiver<StateAnnouncement>,
+        announce_tx: mpsc::Sender<DeviceAnnouncement>,
         devices: &[BleDevice],
+        checker: Box<dyn PresenceChecker>,
+        clock: Arc<dyn Clock>,
     ) -> Self {
+        let defaults = DeviceDefaults {
+            seen_debounce: cfg
+                .device_seen_debounce_seconds
+                .unwrap_or(config::DEFAULT_DEVICE_SEEN_DEBOUNCE_SECONDS),
+            trigger_debounce: cfg
+                .device_trigger_debounce_seconds
+                .unwrap_or(config::DEFAULT_DEVICE_TRIGGER_DEBOUNCE_SECONDS),
+            tracker: match cfg.startup_state.unwrap_or_default() {
+                StartupState::Unknown => Tracker::unknown(),
+                // Restored separately with `restore_state`, devices missing from the file are
+                // absent
+                StartupState::Absent | StartupState::Restore => Tracker::default(),
+            },
+        };
+        let policy = PresencePolicy::new(cfg);
+        let now = clock.now();
         let device_map = devices
             .iter()
             .map(|device| {
-                (
-                    device.name.clone(),
-                    DeviceState {
-                        mac_address: device.address.to_string(),
-                        seen: DeviceSeen::NotSeen,
-                    },
-                )
+                let mut device_info = defaults.device_state(device);
+                fix_static(device, &mut device_info, now, &policy);
+                (device.name.clone(), device_info)
             })
             .collect::<HashMap<_, _>>();
 
         Scanner {
             rx,
-            tx,
-            announce_tx,
-            device_seen_debounce: std::time::Duration::from_secs(
-                cfg.device_seen_debounce_seconds.unwrap_or(60),
-            ),
-            device_trigger_debounce: std::time::Duration::from_secs(
-                cfg.device_trigger_debounce_seconds.unwrap_or(120),
-            ),
-            interscan_delay: std::time::Duration::from_secs(
-                cfg.interscan_delay_seconds.unwrap_or(5),
-            ),
-            presence_timeout: std::time::Duration::from_secs(
-                cfg.presence_timeout_seconds.unwrap_or(120),
-            ),
             device_map,
+            defaults,
+            state_file: cfg.state_file.clone().map(StateFile::new),
+            role: cfg.role.unwrap_or_default(),
+            pending: VecDeque::new(),
+            departure_time: std::time::Duration::ZERO,
+            ctx: ScanContext {
+                announce_tx,
+                checker,
+                method_checkers: HashMap::new(),
+                backend: cfg.backend.clone().unwrap_or_default().as_str(),
+                clock,
+                stats: Arc::new(Stats::default()),
+                adapter_id: None,
+                recovery: Recovery::new(&cfg.recovery.clone().unwrap_or_default()),
+                escalations: None,
+                advertisements: Advertisements::default(),
+                interscan_delay: cfg.interscan_delay_seconds.unwrap_or(InterscanDelay::fixed(
+                    config::DEFAULT_INTERSCAN_DELAY_SECONDS,
+                )),
+                depart_scan_delay: std::time::Duration::from_secs(
+                    cfg.depart_scan_delay_seconds.unwrap_or(0),
+                ),
+                presence_timeout: std::time::Duration::from_secs(
+                    cfg.presence_timeout_seconds
+                        .unwrap_or(config::DEFAULT_PRESENCE_TIMEOUT_SECONDS),
+                ),
+                check_timeout: std::time::Duration::from_secs(
+                    cfg.check_timeout_seconds
+                        .unwrap_or(config::DEFAULT_CHECK_TIMEOUT_SECONDS),
+                ),
+                pass_budget: cfg.pass_budget_seconds.map(std::time::Duration::from_secs),
+                pass_timeout: cfg.pass_timeout_seconds.map(std::time::Duration::from_secs),
+                skipped: HashSet::new(),
+                policy,
+                device_cache: None,
+                clear_cache: cfg.clear_bluez_cache.unwrap_or_default(),
+                fingerprints: None,
+                beacon_expiration: cfg
+                    .beacon_expiration_seconds
+                    .filter(|seconds| *seconds > 0)
+                    .map(std::time::Duration::from_secs),
+                timers: DelayQueue::new(),
+                paused: None,
+                deferred: HashSet::new(),
+            },
         }
     }
 
-    pub async fn run(&mut self) -> anyhow::Result<()> {
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.ctx.stats = stats;
+        self
+    }
+
+    /// Checker for the devices with `method` set.
+    pub fn with_method_checker(
+        mut self,
+        method: CheckMethod,
+        checker: Box<dyn PresenceChecker>,
+    ) -> Self {
+        self.ctx.method_checkers.insert(method, checker);
+        self
+    }
+
+    pub fn with_advertisements(mut self, advertisements: Advertisements) -> Self {
+        self.ctx.advertisements = advertisements;
+        self
+    }
+
+    /// Cache to clear according to `scan.clear_bluez_cache`.
+    pub fn with_device_cache(mut self, device_cache: Arc<dyn DeviceCache>) -> Self {
+        self.ctx.device_cache = Some(device_cache);
+        self
+    }
+
+    /// The adapter this scanner checks from, e.g. `hci0`, for device changes, scan failures and
+    /// escalations addressed to it.
+    pub fn with_adapter_id(mut self, adapter_id: &str) -> Self {
+        self.ctx.adapter_id = Some(adapter_id.to_string());
+        self
+    }
+
+    pub fn with_escalations(mut self, escalations: mpsc::UnboundedSender<Report>) -> Self {
+        self.ctx.escalations = Some(escalations);
+        self
+    }
+
+    pub fn with_fingerprints(mut self, fingerprints: Fingerprints) -> Self {
+        self.ctx.fingerprints = Some(fingerprints);
+        self
+    }
+
+    /// Share a state file between scanners, so their updates don't overwrite each other.
+    pub fn with_state_file(mut self, state_file: Option<StateFile>) -> Self {
+        self.state_file = state_file;
+        self
+    }
+
+    /// Restore device states saved in the state file by a previous run.
+    pub fn restore_state(&mut self) -> anyhow::Result<()> {
+        let Some(state_file) = &self.state_file else {
+            anyhow::bail!("scan.startup_state = \"restore\" requires scan.state_file");
+        };
+        let Some(saved) = state_file.load()? else {
+            info!(
+                "No saved device states in {}, starting absent",
+                state_file.path().display()
+            );
+            return Ok(());
+        };
+        let now = self.ctx.clock.now();
+        for (name, device_info) in self.device_map.iter_mut() {
+            // Static devices start out as configured
+            if device_info.detect == Detect::Static {
+                continue;
+            }
+            let Some(device) = saved.devices.get(&device_info.mac_address.to_uppercase()) else {
+                continue;
+            };
+            debug!("Restoring {name} as {device:?}");
+            if device.present {
+                device_info.tracker = Tracker {
+                    state: PresenceState::Present { last_seen: now },
+                    confidence: device.confidence,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    async fn save_state(&self) {
+        let Some(state_file) = &self.state_file else {
+            return;
+        };
+        let devices = self
+            .device_map
+            .values()
+            .filter_map(|device_info| {
+                let present = match device_info.tracker.state {
+                    PresenceState::Present { .. } | PresenceState::Departing { .. } => true,
+                    PresenceState::Absent | PresenceState::Arriving { .. } => false,
+                    PresenceState::Unknown => return None,
+                };
+                Some((
+                    device_info.mac_address.to_uppercase(),
+                    SavedDevice {
+                        present,
+                        confidence: device_info.tracker.confidence,
+                    },
+                ))
+            })
+            .collect();
+        if let Err(err) = state_file.update(devices).await {
+            error!("Error saving device states: {err:?}");
+        }
+    }
+
+    /// Handle requests and presence timeouts until the request queue closes or `shutdown` is
+    /// cancelled. A pass in progress is finished first.
+    pub async fn run(&mut self, shutdown: CancellationToken) -> anyhow::Result<()> {
         debug!("Start scan loop {:?}", self.device_map);
-        let mut last_trigger: Option<std::time::SystemTime> = None;
+        for (name, device_info) in self.device_map.iter() {
+            if device_info.detect == Detect::Static {
+                self.ctx.announce_fixed(name, device_info, None).await?;
+            }
+        }
+        // Restored devices were never checked by this process, make sure they're still around
+        for (name, device_info) in self.device_map.iter_mut() {
+            if matches!(device_info.tracker.state, PresenceState::Present { .. })
+                && device_info.detect != Detect::Static
+            {
+                self.ctx
+                    .schedule_check(name, device_info, self.ctx.presence_timeout);
+            }
+        }
+        let sweep_period = self
+            .ctx
+            .beacon_expiration
+            .map(|expiration| (expiration / 4).max(std::time::Duration::from_secs(1)));
+        let mut next_sweep = sweep_period.map(|_| self.ctx.clock.now());
         loop {
-            match self.rx.recv().await {
-                // Handle incoming MQTT messages (e.g. arrival scan requests)
-                Ok(msg) => match msg {
-                    StateAnnouncement::CheckStillPresent(device_name) => {
-                        info!("Received check presence request for {device_name}");
-                        self.check_still_present(&device_name)
-                            .await
-                            .context("Failed to check presence")?;
-                    }
-                    StateAnnouncement::ScanArrive => {
-                        info!("Received arrival scan request");
-                        last_trigger = Some(std::time::SystemTime::now());
-                        self.scan_arrival()
-                            .await
-                            .context("Failed to scan arrivals")?;
-                    }
-                    StateAnnouncement::ScanDepart => {
-                        info!("Received departure request");
-                        last_trigger = Some(std::time::SystemTime::now());
-                        self.scan_departure()
+            // Messages that came in during an arrival pass go first
+            let msg = if let Some(msg) = self.pending.pop_front() {
+                Some(msg)
+            } else {
+                let sweep_due = self.ctx.sleep_until(next_sweep);
+                tokio::select! {
+                msg = self.rx.recv() => msg,
+                _ = shutdown.cancelled() => {
+                    debug!("Scanner shutting down");
+                    break;
+                }
+                _ = sweep_due => {
+                    next_sweep = sweep_period.map(|period| self.ctx.clock.now() + period);
+                    if !self.ctx.triggers_paused() {
+                        self.expire_beacons()
                             .await
-                            .context("Failed to scan departure")?;
+                            .context("Failed to expire beacons")?;
                     }
-                    StateAnnouncement::DeviceTrigger => {
-                        let should_scan_devices = match last_trigger.map(|t| t.elapsed()) {
-                            Some(Ok(duration)) => {
-                                if duration > self.device_trigger_debounce {
-                                    debug!("Device trigger received after {duration:?}");
-                                    true
-                                } else {
-                                    debug!("Device trigger received too soon, ignoring");
-                                    false
-                                }
-                            }
-                            Some(Err(err)) => {
-                                error!("Unable to calculate duration since last trigger: {err:?}");
-                                true
+                    self.save_state().await;
+                    continue;
+                }
+                Some(expired) = self.ctx.timers.next() => {
+                    let device_name = expired.into_inner();
+                    info!("Presence timeout elapsed for {device_name}");
+                    self.check_still_present(&device_name)
+                        .await
+                        .context("Failed to check presence")?;
+                    self.save_state().await;
+                    continue;
+                }
+                }
+            };
+            match msg {
+                // Handle incoming MQTT messages (e.g. arrival scan requests)
+                Some(msg) => {
+                    match msg {
+                        msg if self.ignored_by_role(&msg) => {
+                            debug!("Ignoring {msg:?} as a {:?} node", self.role);
+                        }
+                        StateAnnouncement::ScanArrive { devices } => {
+                            info!("Received arrival scan request");
+                            let requested = requested(devices.as_deref());
+                            self.mark_triggered(&requested);
+                            let started = self.ctx.clock.now();
+                            self.scan_arrival(&requested)
+                                .await
+                                .context("Failed to scan arrivals")?;
+                            self.ctx
+                                .record_pass("Arrival", self.arrival_started(started));
+                        }
+                        StateAnnouncement::ScanDepart { devices } => {
+                            info!("Received departure request");
+                            self.depart(devices.as_deref()).await?;
+                        }
+                        StateAnnouncement::DeviceChanged {
+                            name,
+                            device,
+                            adapter,
+                        } => {
+                            self.change_device(&name, device.as_deref(), &adapter)
+                                .await
+                                .context("Failed to apply device change")?;
+                        }
+                        StateAnnouncement::ScanFailed { adapter, error } => {
+                            self.scan_failed(&adapter, error)?;
+                        }
+                        StateAnnouncement::Tune(tuning) => self.tune(&tuning),
+                        StateAnnouncement::Pause(paused) => {
+                            info!("Pausing scans: {paused:?}");
+                            self.ctx.paused = Some(paused);
+                        }
+                        StateAnnouncement::Resume => {
+                            self.resume().await.context("Failed to resume scans")?;
+                        }
+                        StateAnnouncement::SetPresence { device, present } => {
+                            self.set_presence(&device, present)
+                                .await
+                                .context("Failed to set device presence")?;
+                        }
+                        StateAnnouncement::DeviceSeen { .. } | StateAnnouncement::DeviceTrigger
+                            if self.ctx.triggers_paused() =>
+                        {
+                            debug!("Ignoring advertisement trigger while paused");
+                        }
+                        StateAnnouncement::Republish => {
+                            info!("Republishing device states");
+                            self.republish()
+                                .await
+                                .context("Failed to republish device states")?;
+                        }
+                        StateAnnouncement::DeviceSeen { mac_address } => {
+                            let now = self.ctx.clock.now();
+                            // Advertisement checks are free, so they aren't debounced
+                            let seen = self
+                                .device_map
+                                .iter()
+                                .find(|(_, device_info)| {
+                                    device_info.mac_address.eq_ignore_ascii_case(&mac_address)
+                                })
+                                .filter(|(name, device_info)| {
+                                    device_info.detect == Detect::Advertisement
+                                        || trigger_debounce_elapsed(now, name, device_info)
+                                })
+                                .map(|(name, _)| name.clone());
+                            if let Some(seen) = seen {
+                                debug!("Device {seen} was seen, checking for arrival");
+                                self.mark_triggered(|name| name == seen);
+                                self.scan_arrival(|name| name == seen)
+                                    .await
+                                    .context("Failed to scan seen device")?;
                             }
-                            None => {
-                                debug!("Device trigger received, no previous trigger time");
-                                true
+                        }
+                        StateAnnouncement::DeviceTrigger => {
+                            let now = self.ctx.clock.now();
+                            let triggered = self
+                                .device_map
+                                .iter()
+                                .filter(|(name, device_info)| {
+                                    trigger_debounce_elapsed(now, name, device_info)
+                                })
+                                .map(|(name, _)| name.clone())
+                                .collect::<HashSet<_>>();
+                            if !triggered.is_empty() {
+                                info!(
+                                    "Triggering scan due to new device matching manufacturer filter"
+                                );
+                                self.mark_triggered(|name| triggered.contains(name));
+                                let started = self.ctx.clock.now();
+                                self.scan_arrival(|name| triggered.contains(name))
+                                    .await
+                                    .context("Failed to scan for device trigger")?;
+                                self.ctx.record_pass(
+                                    "Triggered arrival",
+                                    self.arrival_started(started),
+                                );
                             }
-                        };
-                        if should_scan_devices {
-                            info!("Triggering scan due to new device matching manufacturer filter");
-                            last_trigger = Some(std::time::SystemTime::now());
-                            self.scan_arrival()
-                                .await
-                                .context("Failed to scan for device trigger")?;
                         }
                     }
-                },
-                Err(broadcast::error::RecvError::Closed) => {
+                    self.save_state().await;
+                }
+                None => {
                     debug!("Receiver closed");
                     break;
                 }
-                Err(broadcast::error::RecvError::Lagged(_)) => {
-                    debug!("Receiver lagged");
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether [ScanConfig::role] has this node skip a scan request or trigger.
+    fn ignored_by_role(&self, msg: &StateAnnouncement) -> bool {
+        match msg {
+            StateAnnouncement::ScanArrive { .. } | StateAnnouncement::DeviceTrigger => {
+                !self.role.arrives()
+            }
+            StateAnnouncement::ScanDepart { .. } => !self.role.departs(),
+            // Following a device's advertisements is passive, checking it isn't
+            StateAnnouncement::DeviceSeen { mac_address }
+                if self.role == NodeRole::PassiveListener =>
+            {
+                !self.device_map.values().any(|device_info| {
+                    device_info.detect == Detect::Advertisement
+                        && device_info.mac_address.eq_ignore_ascii_case(mac_address)
+                })
+            }
+            StateAnnouncement::DeviceSeen { .. } => !self.role.arrives(),
+            _ => false,
+        }
+    }
+
+    async fn depart(&mut self, devices: Option<&[String]>) -> anyhow::Result<()> {
+        let requested = requested(devices);
+        self.mark_triggered(&requested);
+        self.delay_departure().await;
+        let started = self.ctx.clock.now();
+        self.scan_departure(&requested)
+            .await
+            .context("Failed to scan departure")?;
+        self.ctx.record_pass("Departure", started);
+        Ok(())
+    }
+
+    /// Checkpoint of an arrival pass: handle departure requests that came in since it started
+    /// right away, since a departure is usually what's awaited, and queue everything else until
+    /// after the pass.
+    async fn depart_during_arrival(&mut self) -> anyhow::Result<()> {
+        while let Ok(msg) = self.rx.try_recv() {
+            match msg {
+                StateAnnouncement::ScanDepart { devices } if !self.ignored_by_role(&msg) => {
+                    info!("Received departure request, pausing arrival pass");
+                    let started = self.ctx.clock.now();
+                    self.depart(devices.as_deref()).await?;
+                    self.departure_time += self.ctx.clock.now().saturating_duration_since(started);
                 }
+                msg => self.pending.push_back(msg),
             }
         }
         Ok(())
     }
 
+    /// When an arrival pass that started at `started` would have started without the departures
+    /// handled during it.
+    fn arrival_started(&self, started: std::time::Instant) -> std::time::Instant {
+        started + self.departure_time
+    }
+
+    /// Count a scanning failure on this scanner's adapter towards its recovery.
+    fn scan_failed(&mut self, adapter: &str, error: String) -> anyhow::Result<()> {
+        if self.ctx.adapter_id.as_deref() != Some(adapter) {
+            return Ok(());
+        }
+        self.ctx.failed(
+            &format!("Scanning on adapter {adapter}"),
+            anyhow::anyhow!(error),
+        )
+    }
+
+    fn mark_triggered(&mut self, filter: impl Fn(&str) -> bool) {
+        let now = self.ctx.clock.now();
+        for (name, device_info) in self.device_map.iter_mut() {
+            if filter(name) {
+                device_info.last_trigger = Some(now);
+            }
+        }
+    }
+
+    /// Start, stop or update tracking of a device changed at runtime. A device that keeps its
+    /// address keeps its presence state.
+    async fn change_device(
+        &mut self,
+        name: &str,
+        device: Option<&BleDevice>,
+        adapter: &str,
+    ) -> anyhow::Result<()> {
+        let previous = self.device_map.remove(name);
+        self.ctx.skipped.remove(name);
+        if let Some(key) = previous
+            .as_ref()
+            .and_then(|device_info| device_info.pending_check)
+        {
+            self.ctx.timers.remove(&key);
+        }
+        let Some(device) = device.filter(|_| {
+            self.ctx
+                .adapter_id
+                .as_deref()
+                .is_none_or(|id| id == adapter)
+        }) else {
+            if previous.is_some() {
+                info!("Stopped tracking device {name}");
+            }
+            return Ok(());
+        };
+
+        let mut device_info = self.defaults.device_state(device);
+        if let Some(previous) = previous.filter(|p| p.mac_address == device_info.mac_address) {
+            device_info.tracker = previous.tracker;
+            device_info.last_trigger = previous.last_trigger;
+            device_info.reason = device_info.reason.or(previous.reason);
+            device_info.last_seen = previous.last_seen;
+        }
+        info!(
+            "Tracking device {} ({})",
+            device.name, device_info.mac_address
+        );
+        if device_info.detect == Detect::Static {
+            let transition = fix_static(
+                device,
+                &mut device_info,
+                self.ctx.clock.now(),
+                &self.ctx.policy,
+            );
+            self.ctx
+                .announce_fixed(&device.name, &device_info, transition)
+                .await?;
+            self.device_map.insert(device.name.clone(), device_info);
+            Ok(())
+        } else if matches!(
+            device_info.tracker.state,
+            PresenceState::Present { .. } | PresenceState::Departing { .. }
+        ) {
+            self.ctx
+                .schedule_check(&device.name, &mut device_info, self.ctx.presence_timeout);
+            self.device_map.insert(device.name.clone(), device_info);
+            Ok(())
+        } else {
+            self.device_map.insert(device.name.clone(), device_info);
+            self.scan_arrival(|candidate| candidate == device.name)
+                .await
+        }
+    }
+
+    /// Apply scan settings changed at runtime. A changed presence timeout applies from each
+    /// device's next check.
+    fn tune(&mut self, tuning: &Tuning) {
+        info!("Changing scan settings: {:?}", tuning.values());
+        let seconds = std::time::Duration::from_secs;
+        if let Some(delay) = tuning.interscan_delay_seconds {
+            self.ctx.interscan_delay = InterscanDelay::fixed(delay);
+        }
+        if let Some(timeout) = tuning.presence_timeout_seconds {
+            self.ctx.presence_timeout = seconds(timeout);
+        }
+        if let Some(debounce) = tuning.device_seen_debounce_seconds {
+            self.defaults.seen_debounce = debounce;
+        }
+        if let Some(debounce) = tuning.device_trigger_debounce_seconds {
+            self.defaults.trigger_debounce = debounce;
+        }
+        for device_info in self.device_map.values_mut() {
+            self.defaults.apply_debounces(device_info);
+        }
+    }
+
+    /// Check every device whose check was skipped while paused.
+    async fn resume(&mut self) -> anyhow::Result<()> {
+        if self.ctx.paused.take().is_none() {
+            return Ok(());
+        }
+        let deferred = std::mem::take(&mut self.ctx.deferred);
+        info!(
+            "Resuming scans, checking {} deferred device(s)",
+            deferred.len()
+        );
+        let mut scan_count = 0;
+        for name in deferred {
+            // Removed while paused
+            let Some(device_info) = self.device_map.get_mut(&name) else {
+                continue;
+            };
+            if scan_count > 0 {
+                self.ctx.clock.sleep(self.ctx.interscan_delay.pick()).await;
+            }
+            self.ctx.scan_device(&name, device_info).await?;
+            scan_count += 1;
+        }
+        Ok(())
+    }
+
+    /// Mark a static device tracked by this scanner present or absent.
+    async fn set_presence(&mut self, name: &str, present: bool) -> anyhow::Result<()> {
+        let Some(device_info) = self
+            .device_map
+            .get_mut(name)
+            .filter(|device_info| device_info.detect == Detect::Static)
+        else {
+            return Ok(());
+        };
+        info!(
+            "Setting {name} {}",
+            if present { "present" } else { "absent" }
+        );
+        let transition = device_info
+            .tracker
+            .set(present, self.ctx.clock.now(), &self.ctx.policy);
+        self.ctx.announce_fixed(name, device_info, transition).await
+    }
+
+    async fn republish(&self) -> anyhow::Result<()> {
+        for (name, device_info) in self.device_map.iter() {
+            let presence = match device_info.tracker.state {
+                PresenceState::Present { .. } | PresenceState::Departing { .. } => {
+                    crate::messages::DevicePresence::Present(device_info.tracker.confidence)
+                }
+                PresenceState::Absent => crate::messages::DevicePresence::Absent,
+                // Not announced yet, nothing to restore
+                PresenceState::Arriving { .. } | PresenceState::Unknown => continue,
+            };
+            self.ctx
+                .announce_device(name, device_info, presence, None)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark beacons absent once they haven't advertised for `scan.beacon_expiration_seconds`.
+    async fn expire_beacons(&mut self) -> anyhow::Result<()> {
+        let Some(expiration) = self.ctx.beacon_expiration else {
+            return Ok(());
+        };
+        let now = self.ctx.clock.now();
+        for (name, device_info) in self.device_map.iter_mut() {
+            let (PresenceState::Present { last_seen } | PresenceState::Departing { last_seen, .. }) =
+                device_info.tracker.state
+            else {
+                continue;
+            };
+            if self.ctx.device_type(device_info) != DeviceType::Beacon
+                || device_info.detect == Detect::Static
+            {
+                continue;
+            }
+            let last_seen = self
+                .ctx
+                .advertisements
+                .last_seen(&device_info.mac_address)
+                .map_or(last_seen, |advertised| advertised.max(last_seen));
+            let quiet = now.saturating_duration_since(last_seen);
+            if quiet <= expiration {
+                continue;
+            }
+
+            info!("Beacon {name} hasn't advertised in {quiet:?}, marking absent");
+            let transition = device_info.tracker.expire();
+            device_info.reason = Some(Reason::StaleExpired);
+            if let Some(key) = device_info.pending_check.take() {
+                self.ctx.timers.remove(&key);
+            }
+            if let Some(fingerprints) = &self.ctx.fingerprints {
+                fingerprints.set_present(&device_info.mac_address, false);
+            }
+            self.ctx
+                .announce_device(
+                    name,
+                    device_info,
+                    crate::messages::DevicePresence::Absent,
+                    transition,
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
     async fn check_still_present(&mut self, device_name: &str) -> anyhow::Result<()> {
         if let Some(device_info) = self.device_map.get_mut(device_name) {
             debug!("Checking if device {device_name} is still present");
-            scan_device(
-                device_name,
-                device_info,
-                self.tx.clone(),
-                &self.announce_tx,
-                self.presence_timeout,
-            )
-            .await
+            // The timer has already fired, its key may be reused by the next one
+            device_info.pending_check = None;
+            if !self.ctx.triggers_paused()
+                && self
+                    .ctx
+                    .refresh_from_advertisement(device_name, device_info)
+                    .await?
+            {
+                return Ok(());
+            }
+            self.ctx.scan_device(device_name, device_info).await
         } else {
-            error!("Device {device_name} not found in device map, can't check presence",);
-            // Not really OK, but don't want to abort event loop
+            warn!("Presence check requested for unknown device {device_name}");
             Ok(())
         }
     }
 
-    async fn scan_arrival(&mut self) -> anyhow::Result<()> {
+    async fn scan_arrival(&mut self, filter: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+        let started = self.ctx.clock.now();
+        self.departure_time = std::time::Duration::ZERO;
         let mut scan_count = 0;
-        for (name, device_info) in self.device_map.iter_mut() {
-            let now = std::time::SystemTime::now();
-            let should_scan = match device_info.seen {
-                DeviceSeen::Seen(at) => match now.duration_since(at) {
-                    Ok(duration) => {
-                        if duration > self.device_seen_debounce {
-                            debug!("Device {name} hasn't been seen in {duration:?}");
-                            true
-                        } else {
-                            debug!("Device {name} is seen recently ({at:?}), not scanning");
-                            false
-                        }
-                    }
-                    Err(err) => {
-                        error!("Unable to calculate duration since last seen: {name}, {err:?}");
+        let mut skipped = Vec::new();
+        let names = self
+            .ctx
+            .pass_order(self.device_map.keys().filter(|name| filter(name)));
+        for name in &names {
+            if scan_count > 0 {
+                self.depart_during_arrival().await?;
+            }
+            let Some(device_info) = self.device_map.get_mut(name) else {
+                continue;
+            };
+            if device_info.detect == Detect::Static {
+                continue;
+            }
+            let now = self.ctx.clock.now();
+            let should_scan = match device_info.tracker.state {
+                PresenceState::Present { last_seen } => {
+                    let duration = now.saturating_duration_since(last_seen);
+                    if duration > device_info.seen_debounce {
+                        debug!("Device {name} hasn't been seen in {duration:?}");
                         true
+                    } else {
+                        debug!("Device {name} is seen recently ({duration:?} ago), not scanning");
+                        false
                     }
-                },
-                DeviceSeen::NotSeen => {
+                }
+                PresenceState::Absent | PresenceState::Unknown => {
+                    debug!(
+                        "Device {name} currently marked as {:?}, is candidate for arrival scan",
+                        device_info.tracker.state
+                    );
+                    true
+                }
+                PresenceState::Arriving { .. } | PresenceState::Departing { .. } => {
                     debug!(
-                        "Device {name} currently marked as absent, is candidate for arrival scan"
+                        "Device {name} is {:?}, rescanning",
+                        device_info.tracker.state
                     );
                     true
                 }
             };
 
             if should_scan {
+                if self.ctx.defer(name, device_info) {
+                    continue;
+                }
+                let delay = if scan_count > 0 {
+                    self.ctx.interscan_delay.pick()
+                } else {
+                    std::time::Duration::ZERO
+                };
+                if self
+                    .ctx
+                    .pass_timed_out(started + self.departure_time, delay)
+                {
+                    skipped.push(name.as_str());
+                    continue;
+                }
                 if scan_count > 0 {
-                    tokio::time::sleep(self.interscan_delay).await;
+                    self.ctx.clock.sleep(delay).await;
                 }
-                scan_device(
-                    name,
-                    device_info,
-                    self.tx.clone(),
-                    &self.announce_tx,
-                    self.presence_timeout,
-                )
-                .await?;
+                self.ctx.scan_device(name, device_info).await?;
                 scan_count += 1;
             }
         }
+        self.ctx.report_skipped("Arrival", &skipped);
 
         Ok(())
     }
 
-    async fn scan_departure(&mut self) -> anyhow::Result<()> {
-        for (scan_count, (name, device_info)) in self.device_map.iter_mut().enumerate() {
+    async fn delay_departure(&self) {
+        if self.ctx.depart_scan_delay > std::time::Duration::ZERO {
+            info!(
+                "Waiting {:?} before scanning for departures",
+                self.ctx.depart_scan_delay
+            );
+            self.ctx.clock.sleep(self.ctx.depart_scan_delay).await;
+        }
+    }
+
+    async fn scan_departure(&mut self, filter: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+        let started = self.ctx.clock.now();
+        let mut scan_count = 0;
+        let mut skipped = Vec::new();
+        let names = self
+            .ctx
+            .pass_order(self.device_map.keys().filter(|name| filter(name)));
+        for name in &names {
+            let Some(device_info) = self.device_map.get_mut(name) else {
+                continue;
+            };
+            if device_info.detect == Detect::Static || self.ctx.defer(name, device_info) {
+                continue;
+            }
+            let delay = if scan_count > 0 {
+                self.ctx.interscan_delay.pick()
+            } else {
+                std::time::Duration::ZERO
+            };
+            if self.ctx.pass_timed_out(started, delay) {
+                skipped.push(name.as_str());
+                continue;
+            }
             if scan_count > 0 {
-                tokio::time::sleep(self.interscan_delay).await;
+                self.ctx.clock.sleep(delay).await;
             }
-            scan_device(
-                name,
-                device_info,
-                self.tx.clone(),
-                &self.announce_tx,
-                self.presence_timeout,
-            )
-            .await?;
+            scan_count += 1;
+            if self.ctx.clear_cache == ClearBluezCache::BeforeDepart
+                && device_info.detect == Detect::Check
+            {
+                self.ctx.forget(name, device_info).await;
+            }
+            self.ctx.scan_device(name, device_info).await?;
         }
+        self.ctx.report_skipped("Departure", &skipped);
 
         Ok(())
     }
 }
 
-async fn scan_device(
-    name: &str,
+/// Put a static device in its configured state, returning the transition if that changed
+/// whether it's present.
+fn fix_static(
+    device: &BleDevice,
     device_info: &mut DeviceState,
-    tx: broadcast::Sender<StateAnnouncement>,
-    announce_tx: &broadcast::Sender<DeviceAnnouncement>,
-    presence_timeout: std::time::Duration,
-) -> anyhow::Result<()> {
-    let now = std::time::SystemTime::now();
-    if is_device_present(device_info).await? {
-        device_info.seen = DeviceSeen::Seen(now);
-        let device_name = name.to_string();
-        tokio::task::spawn(async move {
-            tokio::time::sleep(presence_timeout).await;
-            if let Err(err) = tx
-                .send(StateAnnouncement::CheckStillPresent(device_name))
-                .context("Failed to send check presence request")
-            {
-                error!("Presence timeout elapsed for device {err}")
-            }
-        });
-        announce_device(
-            announce_tx,
-            name,
-            &device_info.mac_address,
-            crate::messages::DevicePresence::Present(100),
-        )
-    } else {
-        debug!("Device {name} is not present");
-        device_info.seen = DeviceSeen::NotSeen;
-        announce_device(
-            announce_tx,
-            name,
-            &device_info.mac_address,
-            crate::messages::DevicePresence::Absent,
-        )
+    now: std::time::Instant,
+    policy: &PresencePolicy,
+) -> Option<Transition> {
+    if device_info.detect != Detect::Static {
+        return None;
     }
+    device_info
+        .tracker
+        .set(device.present.unwrap_or(true), now, policy)
+}
+
+/// Filter for the devices a scan request names, every device if it names none.
+fn requested(devices: Option<&[String]>) -> impl Fn(&str) -> bool + '_ {
+    move |name| devices.is_none_or(|devices| devices.iter().any(|device| device == name))
 }
 
-fn announce_device(
-    announce_tx: &broadcast::Sender<DeviceAnnouncement>,
+fn trigger_debounce_elapsed(
+    now: std::time::Instant,
     name: &str,
-    mac_address: &str,
-    presence: crate::messages::DevicePresence,
-) -> anyhow::Result<()> {
-    announce_tx
-        .send(DeviceAnnouncement {
-            name: name.to_string(),
-            mac_address: mac_address.to_string(),
-            presence,
+    device_info: &DeviceState,
+) -> bool {
+    match device_info
+        .last_trigger
+        .map(|t| now.saturating_duration_since(t))
+    {
+        Some(duration) => {
+            if duration > device_info.trigger_debounce {
+                debug!("Device trigger for {name} received after {duration:?}");
+                true
+            } else {
+                debug!("Device trigger for {name} received too soon, ignoring");
+                false
+            }
+        }
+        None => {
+            debug!("Device trigger for {name} received, no previous trigger time");
+            true
+        }
+    }
+}
+
+impl ScanContext {
+    fn record_pass(&self, kind: &str, started: std::time::Instant) {
+        let duration = self.clock.now().saturating_duration_since(started);
+        let over_budget = self.pass_budget.is_some_and(|budget| duration > budget);
+        if over_budget {
+            warn!(
+                "{kind} pass took {duration:?}, over budget of {:?}",
+                self.pass_budget.unwrap_or_default()
+            );
+        } else {
+            debug!("{kind} pass took {duration:?}");
+        }
+        self.stats.record_pass(duration, over_budget);
+    }
+
+    /// Whether a pass started at `started` would be past [ScanConfig::pass_timeout_seconds] by
+    /// the time the next check starts, after `delay`.
+    fn pass_timed_out(&self, started: std::time::Instant, delay: std::time::Duration) -> bool {
+        self.pass_timeout.is_some_and(|timeout| {
+            self.clock.now().saturating_duration_since(started) + delay >= timeout
         })
-        .context("Failed to send device announcement")?;
+    }
 
-    Ok(())
-}
+    /// Devices keep their state until the next pass, which checks them first, or their presence
+    /// timeout checks them.
+    fn report_skipped(&mut self, kind: &str, skipped: &[&str]) {
+        if skipped.is_empty() {
+            return;
+        }
+        self.skipped
+            .extend(skipped.iter().map(|name| name.to_string()));
+        warn!(
+            "{kind} pass ran out of time after {:?}, skipped {}",
+            self.pass_timeout.unwrap_or_default(),
+            skipped.join(", ")
+        );
+        self.stats.record_skipped_checks(skipped.len());
+    }
 
-/// Shell out to `hcitool name <MAC>` like the Bash version of this utility does.
-/// Theoretically this is something that could be done in Rust, but `btleplug` only supports direct
-/// connecting via MAC address on Android, not Windows/Linux/macOS. That means this
-/// function only works on Linux, since `hcitool` is a `bluez` utility.
-async fn is_device_present(state: &DeviceState) -> anyhow::Result<bool> {
-    let output = Command::new("hcitool")
-        .arg("name")
-        .arg(&state.mac_address)
-        .output()
-        .await?;
+    /// Names of the devices in a pass in the order to check them, those a timed out pass skipped
+    /// first so the same devices can't be skipped every time.
+    fn pass_order<'a>(&self, names: impl Iterator<Item = &'a String>) -> Vec<String> {
+        let mut names = names.cloned().collect::<Vec<_>>();
+        names.sort_by_key(|name| !self.skipped.contains(name));
+        names
+    }
+
+    fn triggers_paused(&self) -> bool {
+        self.paused.is_some_and(|paused| paused.triggers)
+    }
+
+    /// Remember to check the device on resume instead of now, if paused. Advertisement-detected
+    /// devices cost nothing to check, so they're only held off when triggers are paused too.
+    fn defer(&mut self, name: &str, device_info: &DeviceState) -> bool {
+        let Some(paused) = self.paused else {
+            return false;
+        };
+        if device_info.detect == Detect::Advertisement && !paused.triggers {
+            return false;
+        }
+        debug!("Scans are paused, checking {name} on resume");
+        self.deferred.insert(name.to_string());
+        true
+    }
 
-    if output.status.success() {
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        if output_str.is_empty() {
-            debug!(
-                "Device {} is not present: empty reply from hcitool",
-                state.mac_address
+    async fn scan_device(
+        &mut self,
+        name: &str,
+        device_info: &mut DeviceState,
+    ) -> anyhow::Result<()> {
+        if self.defer(name, device_info) {
+            return Ok(());
+        }
+        self.skipped.remove(name);
+        let (seen, reason) = match device_info.detect {
+            Detect::Check => match self.check(name, device_info).await {
+                Ok(checked) => {
+                    self.recovery.succeeded();
+                    checked
+                }
+                Err(err) => return self.check_failed(name, device_info, err),
+            },
+            Detect::Advertisement => {
+                if self.advertised_recently(name, device_info) {
+                    (true, Reason::AdvertSeen)
+                } else {
+                    (false, Reason::StaleExpired)
+                }
+            }
+            Detect::Static => return self.announce_fixed(name, device_info, None).await,
+        };
+        device_info.reason = Some(reason);
+        if seen {
+            device_info.last_seen = Some(self.clock.now());
+        }
+        let transition = device_info
+            .tracker
+            .observe(seen, self.clock.now(), &self.policy);
+        if let Some(fingerprints) = &self.fingerprints {
+            fingerprints.set_present(
+                &device_info.mac_address,
+                matches!(
+                    device_info.tracker.state,
+                    PresenceState::Present { .. } | PresenceState::Departing { .. }
+                ),
             );
-            Ok(false)
+        }
+        if let Some(transition) = transition {
+            info!("Device {name} {}", transition.as_str());
+        }
+
+        let presence = match device_info.tracker.state {
+            PresenceState::Present { .. } => {
+                self.schedule_check(name, device_info, self.presence_timeout);
+                crate::messages::DevicePresence::Present(device_info.tracker.confidence)
+            }
+            PresenceState::Departing { .. } => {
+                debug!(
+                    "Device {name} is departing (confidence {}), checking again",
+                    device_info.tracker.confidence
+                );
+                self.schedule_check(name, device_info, self.interscan_delay.pick());
+                crate::messages::DevicePresence::Present(device_info.tracker.confidence)
+            }
+            PresenceState::Absent | PresenceState::Unknown => {
+                debug!("Device {name} is not present");
+                crate::messages::DevicePresence::Absent
+            }
+            PresenceState::Arriving { .. } => {
+                debug!(
+                    "Device {name} is arriving (confidence {}), checking again before announcing",
+                    device_info.tracker.confidence
+                );
+                self.schedule_check(name, device_info, self.interscan_delay.pick());
+                return Ok(());
+            }
+        };
+
+        self.announce_device(name, device_info, presence, transition)
+            .await
+    }
+
+    /// Check a device, returning whether it was seen and why.
+    async fn check(&self, name: &str, device_info: &DeviceState) -> anyhow::Result<(bool, Reason)> {
+        let checker = self.checker(device_info)?;
+        let started = self.clock.now();
+        let seen = tokio::select! {
+            result = checker.is_present(&device_info.mac_address) => result,
+            _ = self.clock.sleep(self.check_timeout) => Err(anyhow::anyhow!(
+                "Presence check for {name} timed out after {:?}",
+                self.check_timeout
+            )),
+        };
+        let duration = self.clock.now().saturating_duration_since(started);
+        debug!("Presence check for {name} took {duration:?}");
+        self.stats.record_check(duration);
+        self.stats
+            .record_device_check(name, seen.as_ref().ok().copied(), duration);
+        if self.clear_cache == ClearBluezCache::AfterCheck {
+            self.forget(name, device_info).await;
+        }
+        let seen = seen.inspect_err(|_| self.stats.record_check_failure())?;
+        Ok(if seen {
+            (true, Reason::NameRequestOk)
+        } else if self.fingerprint_matched(name, device_info) {
+            (true, Reason::AdvertSeen)
         } else {
-            debug!(
-                "Device {} is present: hcitool returned '{}'",
-                state.mac_address,
-                output_str.trim()
+            (false, Reason::NameRequestTimeout)
+        })
+    }
+
+    /// Escalate a failed check according to `scan.recovery`, leaving the device's state alone.
+    /// Fails once the daemon should exit.
+    fn check_failed(
+        &mut self,
+        name: &str,
+        device_info: &mut DeviceState,
+        err: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        // Its timer already fired, make sure a device being tracked gets checked again
+        if !matches!(
+            device_info.tracker.state,
+            PresenceState::Absent | PresenceState::Unknown
+        ) {
+            self.schedule_check(name, device_info, self.interscan_delay.pick());
+        }
+        self.failed(&format!("Presence check for {name}"), err)
+    }
+
+    /// Count a failed check or scan, escalating as `scan.recovery` says once enough failed in a
+    /// row. Only fails when it's time to exit.
+    fn failed(&mut self, what: &str, err: anyhow::Error) -> anyhow::Result<()> {
+        let errors = self.recovery.consecutive_errors() + 1;
+        let Some(escalation) = self.recovery.failed() else {
+            warn!("{what} failed ({errors} in a row): {err:#}");
+            return Ok(());
+        };
+        warn!("{what} failed ({errors} in a row), escalating to {escalation:?}: {err:#}");
+        if let Some(escalations) = &self.escalations {
+            let report = Report {
+                escalation,
+                adapter: self.adapter_id.clone(),
+                consecutive_errors: errors,
+                error: format!("{err:#}"),
+            };
+            if let Err(err) = escalations.send(report) {
+                error!("Error reporting escalation: {err:?}");
+            }
+        }
+        match escalation {
+            Escalation::Exit => {
+                Err(err.context(format!("{errors} presence checks or scans failed in a row")))
+            }
+            Escalation::Warning | Escalation::RestartAdapter => Ok(()),
+        }
+    }
+
+    /// Whether a device tracked by its advertisements advertised within the presence timeout.
+    fn advertised_recently(&self, name: &str, device_info: &DeviceState) -> bool {
+        let Some(advertised) = self.advertisements.last_seen(&device_info.mac_address) else {
+            debug!("Device {name} hasn't advertised");
+            return false;
+        };
+        let quiet = self.clock.now().saturating_duration_since(advertised);
+        debug!("Device {name} last advertised {quiet:?} ago");
+        quiet <= self.presence_timeout
+    }
+
+    /// Count an advertisement from a present public-address device since it was last seen as a
+    /// sighting, pushing its next check back instead of checking it now. Random addresses could
+    /// belong to anything, so they still get an active check.
+    async fn refresh_from_advertisement(
+        &mut self,
+        name: &str,
+        device_info: &mut DeviceState,
+    ) -> anyhow::Result<bool> {
+        let (PresenceState::Present { last_seen } | PresenceState::Departing { last_seen, .. }) =
+            device_info.tracker.state
+        else {
+            return Ok(false);
+        };
+        let public = self
+            .advertisements
+            .get(&device_info.mac_address)
+            .is_some_and(|advertisement| advertisement.address_type == Some(AddressType::Public));
+        let Some(advertised) = self
+            .advertisements
+            .last_seen(&device_info.mac_address)
+            .filter(|advertised| public && *advertised > last_seen)
+        else {
+            return Ok(false);
+        };
+        let remaining = self
+            .presence_timeout
+            .saturating_sub(self.clock.now().saturating_duration_since(advertised));
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+
+        debug!("Device {name} advertised since it was last seen, checking again in {remaining:?}");
+        device_info.tracker.observe(true, advertised, &self.policy);
+        device_info.reason = Some(Reason::AdvertSeen);
+        device_info.last_seen = Some(advertised);
+        self.schedule_check(name, device_info, remaining);
+        self.announce_device(
+            name,
+            device_info,
+            crate::messages::DevicePresence::Present(device_info.tracker.confidence),
+            None,
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// A device missed by its check still counts as seen if an advertisement matching its
+    /// fingerprint was heard since it was last due for a check.
+    fn fingerprint_matched(&self, name: &str, device_info: &DeviceState) -> bool {
+        let matched = self.fingerprints.as_ref().is_some_and(|fingerprints| {
+            fingerprints.matched_within(
+                &device_info.mac_address,
+                self.clock.now(),
+                self.presence_timeout,
+            )
+        });
+        if matched {
+            debug!("Device {name} missed its check but matched its advertisement fingerprint");
+        }
+        matched
+    }
+
+    async fn forget(&self, name: &str, device_info: &DeviceState) {
+        if let Some(device_cache) = &self.device_cache
+            && let Err(err) = device_cache.forget(&device_info.mac_address).await
+        {
+            warn!("Error clearing cached device {name}: {err:?}");
+        }
+    }
+
+    /// Ask for the device to be checked again after `delay`, cancelling any check scheduled by an
+    /// earlier sighting.
+    fn schedule_check(
+        &mut self,
+        name: &str,
+        device_info: &mut DeviceState,
+        delay: std::time::Duration,
+    ) {
+        debug!("Checking {name} again in {delay:?}");
+        match device_info.pending_check {
+            Some(key) => self.timers.reset(&key, delay),
+            None => {
+                device_info.pending_check = Some(self.timers.insert(name.to_string(), delay));
+            }
+        }
+    }
+
+    /// Sleep on the scanner's clock until `deadline`, or forever without one.
+    fn sleep_until(&self, deadline: Option<std::time::Instant>) -> BoxFuture<'static, ()> {
+        match deadline {
+            Some(deadline) => self
+                .clock
+                .sleep(deadline.saturating_duration_since(self.clock.now())),
+            None => Box::pin(std::future::pending()),
+        }
+    }
+
+    /// The checker for a device's `method`, or the backend's.
+    fn checker(&self, device_info: &DeviceState) -> anyhow::Result<&dyn PresenceChecker> {
+        match device_info.method {
+            Some(method) => self
+                .method_checkers
+                .get(&method)
+                .map(Box::as_ref)
+                .with_context(|| format!("No checker for method = {:?}", method.as_str())),
+            None => Ok(self.checker.as_ref()),
+        }
+    }
+
+    fn device_type(&self, device_info: &DeviceState) -> DeviceType {
+        device_info
+            .device_type
+            .unwrap_or_else(|| match device_info.detect {
+                Detect::Check => self
+                    .checker(device_info)
+                    .map_or(DeviceType::KnownMac, |checker| checker.device_type()),
+                Detect::Advertisement => DeviceType::Beacon,
+                Detect::Static => DeviceType::Static,
+            })
+    }
+
+    /// Announce a device whose state isn't decided by checks.
+    async fn announce_fixed(
+        &self,
+        name: &str,
+        device_info: &DeviceState,
+        transition: Option<Transition>,
+    ) -> anyhow::Result<()> {
+        let presence = match device_info.tracker.state {
+            PresenceState::Present { .. } | PresenceState::Departing { .. } => {
+                crate::messages::DevicePresence::Present(device_info.tracker.confidence)
+            }
+            _ => crate::messages::DevicePresence::Absent,
+        };
+        self.announce_device(name, device_info, presence, transition)
+            .await
+    }
+
+    async fn announce_device(
+        &self,
+        name: &str,
+        device_info: &DeviceState,
+        presence: crate::messages::DevicePresence,
+        transition: Option<Transition>,
+    ) -> anyhow::Result<()> {
+        let now = self.clock.now();
+        let last_seen = device_info
+            .last_seen
+            .max(self.advertisements.last_seen(&device_info.mac_address))
+            .and_then(|last_seen| {
+                chrono::TimeDelta::from_std(now.saturating_duration_since(last_seen)).ok()
+            })
+            .map(|ago| chrono::Utc::now() - ago);
+        self.announce_tx
+            .send(DeviceAnnouncement {
+                name: name.to_string(),
+                mac_address: device_info.mac_address.clone(),
+                device_type: self.device_type(device_info),
+                presence,
+                transition,
+                advertisement: self.advertisements.get(&device_info.mac_address),
+                reason: device_info.reason,
+                last_seen,
+                method: match device_info.detect {
+                    Detect::Check => device_info.method.map_or(self.backend, CheckMethod::as_str),
+                    Detect::Advertisement => "advertisement",
+                    Detect::Static => "static",
+                },
+            })
+            .await
+            .context("Failed to send device announcement")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::future::BoxFuture;
+
+    use super::*;
+    use crate::advertisements::Advertisement;
+    use crate::clock::MockClock;
+    #[cfg(feature = "bluez")]
+    use crate::command::ScriptedRunner;
+    use crate::config::AppConfig;
+    #[cfg(feature = "bluez")]
+    use crate::presence::HcitoolChecker;
+
+    struct FakeChecker {
+        present: bool,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl PresenceChecker for FakeChecker {
+        fn is_present<'a>(&'a self, _mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(std::future::ready(Ok(self.present)))
+        }
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::KnownMac
+        }
+    }
+
+    struct Harness {
+        scanner: Scanner,
+        tx: mpsc::Sender<StateAnnouncement>,
+        clock: Arc<MockClock>,
+        calls: Arc<AtomicUsize>,
+        announce_rx: mpsc::Receiver<DeviceAnnouncement>,
+    }
+
+    fn harness(config_str: &str, present: bool) -> Harness {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let checker = FakeChecker {
+            present,
+            calls: calls.clone(),
+        };
+        Harness {
+            calls,
+            ..harness_with_checker(config_str, Box::new(checker))
+        }
+    }
+
+    fn harness_with_checker(config_str: &str, checker: Box<dyn PresenceChecker>) -> Harness {
+        let config: AppConfig = toml::de::from_str(config_str).unwrap();
+        let (tx, rx) = mpsc::channel(64);
+        let (announce_tx, announce_rx) = mpsc::channel(64);
+        let clock = Arc::new(MockClock::new());
+        let scanner = Scanner::new(
+            &config.scan.unwrap_or_default(),
+            rx,
+            announce_tx,
+            &config.devices.unwrap_or_default(),
+            checker,
+            clock.clone(),
+        );
+        Harness {
+            scanner,
+            tx,
+            clock,
+            calls: Arc::new(AtomicUsize::new(0)),
+            announce_rx,
+        }
+    }
+
+    const CONFIG: &str = r#"
+        [mqtt]
+        host = "localhost"
+
+        [scan]
+        device_seen_debounce_seconds = 60
+        device_trigger_debounce_seconds = 120
+        interscan_delay_seconds = 0
+
+        [[devices]]
+        address = "00:11:22:33:44:55"
+        name = "Phone"
+
+        [[devices]]
+        address = "00:11:22:33:44:56"
+        name = "Car keys"
+        device_seen_debounce_seconds = 600
+    "#;
+
+    #[tokio::test]
+    async fn test_seen_debounce() {
+        let mut h = harness(CONFIG, true);
+
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+        let msg = h.announce_rx.recv().await.unwrap();
+        assert!(matches!(
+            msg.presence,
+            crate::messages::DevicePresence::Present(_)
+        ));
+        assert_eq!(msg.reason, Some(Reason::NameRequestOk));
+        assert_eq!(msg.method, config::PresenceBackend::default().as_str());
+        assert!(msg.last_seen.is_some());
+
+        // Both devices were just seen, nothing to scan
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+
+        // Only the phone's debounce has elapsed, the car keys override is longer
+        h.clock.advance(Duration::from_secs(61));
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_pause() {
+        let mut h = harness(CONFIG, true);
+        h.scanner.ctx.paused = Some(Paused { triggers: false });
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        h.scanner.scan_departure(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 0);
+        assert!(h.announce_rx.try_recv().is_err());
+
+        // Each skipped device is checked once on resume
+        h.scanner.resume().await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+        assert!(h.scanner.ctx.paused.is_none());
+        assert!(h.scanner.ctx.deferred.is_empty());
+        h.scanner.resume().await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_role() {
+        let arrive = StateAnnouncement::ScanArrive { devices: None };
+        let depart = StateAnnouncement::ScanDepart { devices: None };
+        let seen = StateAnnouncement::DeviceSeen {
+            mac_address: "00:11:22:33:44:55".to_string(),
+        };
+        let ignored = |role: &str| {
+            let h = harness(
+                &CONFIG.replace("[scan]", &format!("[scan]\nrole = {role:?}")),
+                true,
+            );
+            [&arrive, &depart, &seen, &StateAnnouncement::DeviceTrigger]
+                .map(|msg| h.scanner.ignored_by_role(msg))
+        };
+        assert_eq!(ignored("full"), [false; 4]);
+        assert_eq!(ignored("arrive_only"), [false, true, false, false]);
+        assert_eq!(ignored("depart_only"), [true, false, true, true]);
+        assert_eq!(ignored("passive_listener"), [true; 4]);
+        // Other messages aren't scan requests
+        let h = harness(
+            &CONFIG.replace("[scan]", "[scan]\nrole = \"passive_listener\""),
+            true,
+        );
+        assert!(!h.scanner.ignored_by_role(&StateAnnouncement::Republish));
+    }
+
+    #[tokio::test]
+    async fn test_tune() {
+        let mut h = harness(CONFIG, true);
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+
+        h.scanner.tune(&Tuning {
+            device_seen_debounce_seconds: Some(10),
+            interscan_delay_seconds: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(h.scanner.ctx.interscan_delay, InterscanDelay::fixed(3));
+        // The phone's debounce is now shorter, the car keys keep their own
+        h.clock.advance(Duration::from_secs(11));
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_absent_devices_always_scanned() {
+        let mut h = harness(CONFIG, false);
+
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 4);
+        let msg = h.announce_rx.recv().await.unwrap();
+        assert!(matches!(
+            msg.presence,
+            crate::messages::DevicePresence::Absent
+        ));
+        assert_eq!(msg.reason, Some(Reason::NameRequestTimeout));
+    }
+
+    struct FakeCache {
+        forgotten: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl DeviceCache for FakeCache {
+        fn forget<'a>(&'a self, mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            self.forgotten.lock().unwrap().push(mac_address.to_string());
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clear_cache_before_depart() {
+        let config = CONFIG.replace("[scan]", "[scan]\nclear_bluez_cache = \"before_depart\"");
+        let cache = Arc::new(FakeCache {
+            forgotten: std::sync::Mutex::new(Vec::new()),
+        });
+        let mut h = harness(&config, true);
+        h.scanner = h.scanner.with_device_cache(cache.clone());
+
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert!(cache.forgotten.lock().unwrap().is_empty());
+
+        h.scanner.scan_departure(|_| true).await.unwrap();
+        assert_eq!(cache.forgotten.lock().unwrap().len(), 2);
+
+        let phone = ["Phone".to_string()];
+        h.scanner
+            .scan_departure(requested(Some(&phone)))
+            .await
+            .unwrap();
+        assert_eq!(cache.forgotten.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_republish() {
+        let mut h = harness(CONFIG, true);
+
+        h.scanner
+            .scan_arrival(|name| name == "Phone")
+            .await
+            .unwrap();
+        assert!(h.announce_rx.recv().await.unwrap().transition.is_some());
+
+        h.scanner.republish().await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+        let mut announced = Vec::new();
+        for _ in 0..2 {
+            let msg = h.announce_rx.recv().await.unwrap();
+            assert!(msg.transition.is_none());
+            announced.push((msg.name, msg.presence));
+        }
+        announced.sort_by(|a, b| a.0.cmp(&b.0));
+        assert!(matches!(
+            announced[..],
+            [
+                (_, crate::messages::DevicePresence::Absent),
+                (_, crate::messages::DevicePresence::Present(100)),
+            ]
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reschedule_replaces_pending_check() {
+        let mut h = harness(CONFIG, true);
+        let device_info = h.scanner.device_map.get_mut("Phone").unwrap();
+        h.scanner
+            .ctx
+            .schedule_check("Phone", device_info, Duration::from_secs(10));
+        h.scanner
+            .ctx
+            .schedule_check("Phone", device_info, Duration::from_secs(20));
+        assert_eq!(h.scanner.ctx.timers.len(), 1);
+
+        let started = tokio::time::Instant::now();
+        let expired = h.scanner.ctx.timers.next().await.unwrap();
+        assert_eq!(expired.into_inner(), "Phone");
+        assert_eq!(started.elapsed(), Duration::from_secs(20));
+        assert!(h.scanner.ctx.timers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_change_device() {
+        let mut h = harness(CONFIG, true);
+        h.scanner = h.scanner.with_adapter_id("hci0");
+        h.scanner
+            .scan_arrival(|name| name == "Phone")
+            .await
+            .unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+
+        // Renamed, keeping its state and without another check
+        let mut phone: BleDevice =
+            toml::de::from_str("address = \"00:11:22:33:44:55\"\nname = \"Work phone\"").unwrap();
+        h.scanner
+            .change_device("Phone", Some(&phone), "hci0")
+            .await
+            .unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+        assert!(matches!(
+            h.scanner.device_map["Work phone"].tracker.state,
+            PresenceState::Present { .. }
+        ));
+
+        // New address, checked straight away
+        phone.address = "00:11:22:33:44:57".parse().unwrap();
+        h.scanner
+            .change_device("Work phone", Some(&phone), "hci0")
+            .await
+            .unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+
+        // Moved to another adapter's scanner
+        h.scanner
+            .change_device("Work phone", Some(&phone), "hci1")
+            .await
+            .unwrap();
+        assert!(!h.scanner.device_map.contains_key("Work phone"));
+
+        h.scanner.change_device("Car keys", None, "").await.unwrap();
+        assert!(h.scanner.device_map.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_trigger_debounce() {
+        let mut h = harness(CONFIG, true);
+        let now = h.clock.now();
+        assert!(trigger_debounce_elapsed(
+            now,
+            "Phone",
+            &h.scanner.device_map["Phone"]
+        ));
+
+        h.scanner.mark_triggered(|name| name == "Phone");
+        let now = h.clock.now();
+        assert!(!trigger_debounce_elapsed(
+            now,
+            "Phone",
+            &h.scanner.device_map["Phone"]
+        ));
+        assert!(trigger_debounce_elapsed(
+            now,
+            "Car keys",
+            &h.scanner.device_map["Car keys"]
+        ));
+
+        h.clock.advance(Duration::from_secs(121));
+        let now = h.clock.now();
+        assert!(trigger_debounce_elapsed(
+            now,
+            "Phone",
+            &h.scanner.device_map["Phone"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_interscan_delay() {
+        let config = CONFIG.replace("interscan_delay_seconds = 0", "interscan_delay_seconds = 5");
+        let mut h = harness(&config, false);
+
+        let clock = h.clock.clone();
+        let calls = h.calls.clone();
+        let (result, ()) = tokio::join!(h.scanner.scan_departure(|_| true), async move {
+            tokio::task::yield_now().await;
+            // The second device waits for the interscan delay
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            clock.advance(Duration::from_secs(5));
+        });
+        result.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_scan_failed() {
+        let config = CONFIG.replace("[scan]", "[scan]\nrecovery = { exit_after_errors = 2 }");
+        let mut h = harness(&config, true);
+        h.scanner = h.scanner.with_adapter_id("hci0");
+        // Another adapter's scanner handles its failures
+        h.scanner.scan_failed("hci1", "Failed".to_string()).unwrap();
+        assert_eq!(h.scanner.ctx.recovery.consecutive_errors(), 0);
+        h.scanner.scan_failed("hci0", "Failed".to_string()).unwrap();
+        assert_eq!(h.scanner.ctx.recovery.consecutive_errors(), 1);
+        assert!(h.scanner.scan_failed("hci0", "Failed".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_device_method() {
+        let config = CONFIG.replace("name = \"Phone\"", "name = \"Phone\"\nmethod = \"l2ping\"");
+        let mut h = harness(&config, false);
+        // Without its checker the check fails, rather than falling back to the backend
+        h.scanner
+            .scan_arrival(|name| name == "Phone")
+            .await
+            .unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(h.scanner.ctx.recovery.consecutive_errors(), 1);
+
+        let l2ping_calls = Arc::new(AtomicUsize::new(0));
+        h.scanner = h.scanner.with_method_checker(
+            CheckMethod::L2ping,
+            Box::new(FakeChecker {
+                present: true,
+                calls: l2ping_calls.clone(),
+            }),
+        );
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        // The car keys still use the backend
+        assert_eq!(l2ping_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+        let announced: Vec<_> = std::iter::from_fn(|| h.announce_rx.try_recv().ok()).collect();
+        let phone = announced.iter().find(|msg| msg.name == "Phone").unwrap();
+        assert_eq!(phone.method, "l2ping");
+        let keys = announced.iter().find(|msg| msg.name == "Car keys").unwrap();
+        assert_ne!(keys.method, "l2ping");
+    }
+
+    #[tokio::test]
+    async fn test_depart_during_arrival() {
+        let mut h = harness(CONFIG, false);
+        h.tx.send(StateAnnouncement::ScanDepart {
+            devices: Some(vec!["Phone".to_string()]),
+        })
+        .await
+        .unwrap();
+        h.tx.send(StateAnnouncement::Republish).await.unwrap();
+        // The phone's departure is checked between the two arrival checks
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 3);
+        assert!(matches!(
+            h.scanner.pending.pop_front(),
+            Some(StateAnnouncement::Republish)
+        ));
+        assert!(h.scanner.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_depart_during_arrival_time() {
+        let config = CONFIG.replace(
+            "interscan_delay_seconds = 0",
+            "interscan_delay_seconds = 0\ndepart_scan_delay_seconds = 10\npass_timeout_seconds = 5",
+        );
+        let mut h = harness(&config, false);
+        h.tx.send(StateAnnouncement::ScanDepart {
+            devices: Some(vec!["Phone".to_string()]),
+        })
+        .await
+        .unwrap();
+        let clock = h.clock.clone();
+        let (result, ()) = tokio::join!(h.scanner.scan_arrival(|_| true), async move {
+            tokio::task::yield_now().await;
+            clock.advance(Duration::from_secs(10));
+        });
+        result.unwrap();
+        // The departure's delay doesn't push the arrival pass past its timeout
+        assert_eq!(h.calls.load(Ordering::SeqCst), 3);
+        assert!(h.scanner.ctx.skipped.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pass_timeout() {
+        let config = CONFIG.replace(
+            "interscan_delay_seconds = 0",
+            "interscan_delay_seconds = 10\npass_timeout_seconds = 5",
+        );
+        let mut h = harness(&config, false);
+        // The second device would only be checked after the timeout
+        h.scanner.scan_departure(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+        let skipped = h.scanner.ctx.skipped.clone();
+        assert_eq!(skipped.len(), 1);
+        // The next pass starts with the skipped device
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(h.scanner.ctx.skipped.len(), 1);
+        assert!(h.scanner.ctx.skipped.is_disjoint(&skipped));
+        let stats = serde_json::to_value(h.scanner.ctx.stats.snapshot()).unwrap();
+        assert_eq!(stats["skipped_checks"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_beacon_expiration() {
+        let config = CONFIG
+            .replace("[scan]", "[scan]\nbeacon_expiration_seconds = 60")
+            .replace(
+                "name = \"Car keys\"",
+                "name = \"Car keys\"\ntype = \"BEACON\"",
             );
-            Ok(true)
+        let advertisements = Advertisements::default();
+        let mut h = harness(&config, true);
+        h.scanner = h.scanner.with_advertisements(advertisements.clone());
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        for _ in 0..2 {
+            h.announce_rx.recv().await.unwrap();
+        }
+
+        h.clock.advance(Duration::from_secs(50));
+        let advertisement = Advertisement {
+            rssi: None,
+            tx_power: None,
+            address_type: None,
+            service_count: 0,
+            manufacturer_ids: Vec::new(),
+            battery: None,
+        };
+        advertisements.record("00:11:22:33:44:56", advertisement, h.clock.now());
+        h.clock.advance(Duration::from_secs(50));
+        h.scanner.expire_beacons().await.unwrap();
+        assert!(h.announce_rx.try_recv().is_err());
+
+        // Quiet for too long by the next sweep, a quarter of the expiration later on the
+        // scanner's clock. The phone isn't a beacon, so it's left to its presence checks
+        let shutdown = CancellationToken::new();
+        let (result, msg) = tokio::join!(h.scanner.run(shutdown.clone()), async {
+            h.clock.advance(Duration::from_secs(15));
+            let msg = h.announce_rx.recv().await.unwrap();
+            shutdown.cancel();
+            msg
+        });
+        result.unwrap();
+        assert_eq!(msg.name, "Car keys");
+        assert_eq!(msg.transition, Some(Transition::Departed));
+        assert_eq!(msg.reason, Some(Reason::StaleExpired));
+        assert!(h.announce_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_advertisement_refreshes_presence() {
+        let advertisements = Advertisements::default();
+        let mut h = harness(CONFIG, true);
+        h.scanner = h.scanner.with_advertisements(advertisements.clone());
+        h.scanner
+            .scan_arrival(|name| name == "Phone")
+            .await
+            .unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+
+        let advertisement = |address_type| Advertisement {
+            rssi: None,
+            tx_power: None,
+            address_type: Some(address_type),
+            service_count: 0,
+            manufacturer_ids: Vec::new(),
+            battery: None,
+        };
+        h.clock.advance(Duration::from_secs(100));
+        advertisements.record(
+            "00:11:22:33:44:55",
+            advertisement(AddressType::Public),
+            h.clock.now(),
+        );
+        h.clock.advance(Duration::from_secs(20));
+        h.scanner.check_still_present("Phone").await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            h.scanner.device_map["Phone"].tracker.state,
+            PresenceState::Present {
+                last_seen: h.clock.now() - Duration::from_secs(20)
+            }
+        );
+
+        // No advertisement since, so it's checked
+        h.clock.advance(Duration::from_secs(100));
+        h.scanner.check_still_present("Phone").await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+
+        // Random addresses aren't trusted
+        h.clock.advance(Duration::from_secs(10));
+        advertisements.record(
+            "00:11:22:33:44:55",
+            advertisement(AddressType::Random),
+            h.clock.now(),
+        );
+        h.scanner.check_still_present("Phone").await.unwrap();
+        assert_eq!(h.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_detect_advertisement() {
+        let config = CONFIG.replace(
+            "name = \"Car keys\"",
+            "name = \"Car keys\"\ndetect = \"advertisement\"",
+        );
+        let advertisements = Advertisements::default();
+        let mut h = harness(&config, true);
+        h.scanner = h.scanner.with_advertisements(advertisements.clone());
+        h.scanner
+            .scan_arrival(|name| name == "Car keys")
+            .await
+            .unwrap();
+        let msg = h.announce_rx.try_recv().unwrap();
+        assert!(matches!(
+            msg.presence,
+            crate::messages::DevicePresence::Absent
+        ));
+
+        advertisements.record(
+            "00:11:22:33:44:56",
+            Advertisement {
+                rssi: None,
+                tx_power: None,
+                address_type: Some(AddressType::Public),
+                service_count: 0,
+                manufacturer_ids: Vec::new(),
+                battery: None,
+            },
+            h.clock.now(),
+        );
+        h.scanner
+            .scan_arrival(|name| name == "Car keys")
+            .await
+            .unwrap();
+        let msg = h.announce_rx.try_recv().unwrap();
+        assert_eq!(msg.transition, Some(Transition::Arrived));
+        assert_eq!(msg.device_type, DeviceType::Beacon);
+        assert_eq!(msg.reason, Some(Reason::AdvertSeen));
+
+        // Quiet for longer than the presence timeout
+        h.clock.advance(Duration::from_secs(
+            config::DEFAULT_PRESENCE_TIMEOUT_SECONDS + 1,
+        ));
+        h.scanner.scan_departure(|_| true).await.unwrap();
+        let msg = std::iter::from_fn(|| h.announce_rx.try_recv().ok())
+            .find(|msg| msg.name == "Car keys")
+            .unwrap();
+        assert_eq!(msg.transition, Some(Transition::Departed));
+        assert_eq!(msg.reason, Some(Reason::StaleExpired));
+        // Only the phone was actively checked
+        assert_eq!(h.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_detect_static() {
+        let config = CONFIG.replace(
+            "name = \"Car keys\"",
+            "name = \"Car keys\"\ndetect = \"static\"\npresent = false",
+        );
+        let mut h = harness(&config, true);
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        h.scanner.scan_departure(|_| true).await.unwrap();
+        // Only the phone was checked, the car keys stay as configured
+        assert_eq!(h.calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            h.scanner.device_map["Car keys"].tracker.state,
+            PresenceState::Absent
+        );
+
+        h.scanner.set_presence("Car keys", true).await.unwrap();
+        let msg = std::iter::from_fn(|| h.announce_rx.try_recv().ok())
+            .find(|msg| msg.name == "Car keys")
+            .unwrap();
+        assert_eq!(msg.transition, Some(Transition::Arrived));
+        assert_eq!(msg.device_type, DeviceType::Static);
+        assert_eq!(msg.reason, Some(Reason::ManualOverride));
+        assert_eq!(msg.method, "static");
+        assert!(msg.last_seen.is_none());
+        assert!(h.scanner.device_map["Car keys"].pending_check.is_none());
+
+        // Checked devices can't be overridden
+        h.scanner.set_presence("Phone", false).await.unwrap();
+        assert!(matches!(
+            h.scanner.device_map["Phone"].tracker.state,
+            PresenceState::Present { .. }
+        ));
+    }
+
+    struct FailingChecker;
+
+    impl PresenceChecker for FailingChecker {
+        fn is_present<'a>(&'a self, _mac_address: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+            Box::pin(std::future::ready(Err(anyhow::anyhow!("hcitool hung"))))
         }
-    } else {
-        Err(anyhow::anyhow!(
-            "Command exited non-zero {:?}",
-            output.stderr
-        ))
+
+        fn device_type(&self) -> DeviceType {
+            DeviceType::KnownMac
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recovery_escalation() {
+        let config = CONFIG.replace(
+            "interscan_delay_seconds = 0",
+            "interscan_delay_seconds = 0\nrecovery = { warn_after_errors = 1, restart_adapter_after_errors = 2, exit_after_errors = 3 }",
+        );
+        let (escalation_tx, mut escalation_rx) = mpsc::unbounded_channel();
+        let mut h = harness_with_checker(&config, Box::new(FailingChecker));
+        h.scanner = h
+            .scanner
+            .with_adapter_id("hci0")
+            .with_escalations(escalation_tx);
+
+        // Both devices fail, the second time is the restart
+        h.scanner.scan_arrival(|_| true).await.unwrap();
+        assert!(h.announce_rx.try_recv().is_err());
+        let report = escalation_rx.try_recv().unwrap();
+        assert_eq!(report.escalation, Escalation::Warning);
+        let report = escalation_rx.try_recv().unwrap();
+        assert_eq!(report.escalation, Escalation::RestartAdapter);
+        assert_eq!(report.adapter.as_deref(), Some("hci0"));
+        assert_eq!(report.consecutive_errors, 2);
+
+        let err = h.scanner.scan_arrival(|_| true).await.unwrap_err();
+        assert!(format!("{err:#}").contains("hcitool hung"), "{err:#}");
+        assert_eq!(
+            escalation_rx.try_recv().unwrap().escalation,
+            Escalation::Exit
+        );
+    }
+
+    #[tokio::test]
+    async fn test_depart_scan_delay() {
+        let config = CONFIG.replace("[scan]", "[scan]\ndepart_scan_delay_seconds = 30");
+        let h = harness(&config, false);
+
+        let clock = h.clock.clone();
+        let waited = AtomicUsize::new(0);
+        tokio::join!(
+            async {
+                h.scanner.delay_departure().await;
+                waited.fetch_add(1, Ordering::SeqCst);
+            },
+            async {
+                tokio::task::yield_now().await;
+                assert_eq!(waited.load(Ordering::SeqCst), 0);
+                clock.advance(Duration::from_secs(30));
+            }
+        );
+        assert_eq!(waited.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(feature = "bluez")]
+    #[tokio::test]
+    async fn test_hung_check_times_out() {
+        let config = CONFIG.replace(
+            "interscan_delay_seconds = 0",
+            "interscan_delay_seconds = 0\ncheck_timeout_seconds = 10\nrecovery = { exit_after_errors = 1 }",
+        );
+        let runner = Arc::new(ScriptedRunner::new(None));
+        let mut h = harness_with_checker(&config, Box::new(HcitoolChecker::new(runner)));
+
+        let clock = h.clock.clone();
+        let (result, ()) = tokio::join!(h.scanner.scan_departure(|_| true), async move {
+            tokio::task::yield_now().await;
+            clock.advance(Duration::from_secs(10));
+        });
+        assert!(format!("{:#}", result.unwrap_err()).contains("timed out"));
     }
 }
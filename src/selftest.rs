@@ -0,0 +1,219 @@
+//! `monitor-rs self-test`: exercise everything the daemon depends on (adapter, scanning, the
+//! presence backend and the MQTT broker) and report what works and what to fix.
+use std::time::{Duration, Instant};
+
+use anyhow::Context as _;
+use btleplug::api::{Central as _, ScanFilter};
+use mac_address::MacAddress;
+use rumqttc::{ConnectReturnCode, ConnectionError, Event, Packet, QoS};
+
+use crate::config::{self, AppConfig};
+
+/// How long to wait for an adapter, shorter than the daemon so a missing one is reported quickly.
+const ADAPTER_WAIT_SECONDS: u64 = 5;
+const SCAN_DURATION: Duration = Duration::from_secs(5);
+const MQTT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&self, step: &str, detail: impl std::fmt::Display) {
+        println!("PASS  {step}: {detail}");
+    }
+
+    fn warn(&self, step: &str, detail: impl std::fmt::Display) {
+        println!("WARN  {step}: {detail}");
+    }
+
+    fn fail(&mut self, step: &str, err: impl std::fmt::Display, hint: &str) {
+        self.failures += 1;
+        println!("FAIL  {step}: {err}\n      {hint}");
+    }
+}
+
+/// Run every check, failing if any of them did. Checks a device's presence with `address` if
+/// given, otherwise with the first configured device.
+pub async fn run(config: &AppConfig, address: Option<MacAddress>) -> anyhow::Result<()> {
+    let mut report = Report::default();
+
+    let mut adapter_config = config.adapter.clone().unwrap_or_default();
+    adapter_config.wait_seconds = Some(ADAPTER_WAIT_SECONDS);
+    let adapter = match crate::adapter::wait_for_adapters(&adapter_config).await {
+        Ok(adapters) => adapters.into_iter().next(),
+        Err(err) => {
+            report.fail(
+                "adapter",
+                format!("{err:#}"),
+                "Check that a Bluetooth adapter is attached and bluetoothd is running",
+            );
+            None
+        }
+    };
+
+    if let Some((id, adapter)) = &adapter {
+        match crate::adapter::ensure_powered(id, adapter, &adapter_config).await {
+            Ok(()) => report.pass("adapter", format!("{id} is powered on")),
+            Err(err) => report.fail(
+                "adapter",
+                format!("{err:#}"),
+                "Unblock the adapter (`rfkill unblock bluetooth`) and power it on",
+            ),
+        }
+        match scan(adapter).await {
+            Ok(0) => report.warn("scan", "No devices heard, is anything advertising nearby?"),
+            Ok(heard) => report.pass("scan", format!("Heard {heard} device(s)")),
+            Err(err) => report.fail(
+                "scan",
+                format!("{err:#}"),
+                "Make sure this user may scan, e.g. is in the `bluetooth` group",
+            ),
+        }
+        check_presence(config, adapter, address, &mut report).await;
+    }
+
+    match mqtt_loopback(&config.mqtt).await {
+        Ok(round_trip) => report.pass(
+            "mqtt",
+            format!(
+                "Connected to {} and received a loopback message after {round_trip:?}",
+                config.mqtt.host
+            ),
+        ),
+        Err(err) => {
+            let hint = match err.downcast_ref::<ConnectionError>() {
+                Some(ConnectionError::ConnectionRefused(
+                    ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized,
+                )) => "Check mqtt.username and mqtt.password",
+                Some(ConnectionError::ConnectionRefused(_)) => {
+                    "The broker refused the connection, check its logs"
+                }
+                Some(_) => "Check mqtt.host and mqtt.port, and that the broker is reachable",
+                None => "Check that this user may subscribe and publish under mqtt.topic_path",
+            };
+            report.fail("mqtt", format!("{err:#}"), hint);
+        }
+    }
+
+    if report.failures > 0 {
+        anyhow::bail!("{} self-test check(s) failed", report.failures);
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+/// Scan for a few seconds, returning how many devices were heard.
+async fn scan(adapter: &crate::adapter::Adapter) -> anyhow::Result<usize> {
+    adapter
+        .start_scan(ScanFilter::default())
+        .await
+        .context("Failed to start scan")?;
+    tokio::time::sleep(SCAN_DURATION).await;
+    adapter.stop_scan().await.context("Failed to stop scan")?;
+    Ok(adapter.peripherals().await?.len())
+}
+
+async fn check_presence(
+    config: &AppConfig,
+    adapter: &crate::adapter::Adapter,
+    address: Option<MacAddress>,
+    report: &mut Report,
+) {
+    let devices = config.devices.as_deref().unwrap_or_default();
+    let Some(address) = address.or_else(|| devices.first().map(|device| device.address)) else {
+        report.warn(
+            "presence",
+            "Skipped, no device configured. Pass a MAC address to check one",
+        );
+        return;
+    };
+    let scan_config = config.scan.clone().unwrap_or_default();
+    let checker = match crate::presence::new_checker(&scan_config, adapter, None) {
+        Ok(checker) => checker,
+        Err(err) => {
+            report.fail(
+                "presence",
+                format!("{err:#}"),
+                "Pick a scan.backend available on this system",
+            );
+            return;
+        }
+    };
+    let timeout = Duration::from_secs(
+        scan_config
+            .check_timeout_seconds
+            .unwrap_or(config::DEFAULT_CHECK_TIMEOUT_SECONDS),
+    );
+    let address = address.to_string();
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, checker.is_present(&address)).await {
+        Ok(Ok(true)) => report.pass(
+            "presence",
+            format!("{address} answered after {:?}", started.elapsed()),
+        ),
+        Ok(Ok(false)) => report.warn(
+            "presence",
+            format!("{address} didn't answer, is it nearby with Bluetooth on?"),
+        ),
+        Ok(Err(err)) => report.fail(
+            "presence",
+            format!("{err:#}"),
+            "Check that the scan.backend tools (e.g. hcitool) are installed and permitted",
+        ),
+        Err(_) => report.fail(
+            "presence",
+            format!("Checking {address} timed out after {timeout:?}"),
+            "The adapter may be busy or wedged, try `hciconfig hci0 reset`",
+        ),
+    }
+}
+
+/// Connect as a separate client (so a running daemon isn't kicked off), then publish a message
+/// and wait for it to come back. Returns the round trip time.
+async fn mqtt_loopback(cfg: &config::MqttConfig) -> anyhow::Result<Duration> {
+    let publisher_id = cfg.publisher_id.clone().unwrap_or_else(config::hostname);
+    let topic = format!(
+        "{}/{publisher_id}/self-test",
+        cfg.topic_path
+            .as_deref()
+            .unwrap_or(config::DEFAULT_TOPIC_PATH)
+    );
+    let options = crate::mqtt::mqtt_options(cfg, format!("{publisher_id}-self-test"));
+    let (client, mut eventloop) = rumqttc::AsyncClient::new(options, 10);
+    let payload = format!("self-test {}", std::process::id());
+
+    let loopback = async {
+        let mut sent = None;
+        loop {
+            match eventloop.poll().await? {
+                Event::Incoming(Packet::ConnAck(_)) => {
+                    client.subscribe(&topic, QoS::AtLeastOnce).await?;
+                }
+                Event::Incoming(Packet::SubAck(ack)) => {
+                    if ack
+                        .return_codes
+                        .iter()
+                        .any(|code| matches!(code, rumqttc::SubscribeReasonCode::Failure))
+                    {
+                        anyhow::bail!("Broker refused the subscription to {topic}");
+                    }
+                    sent = Some(Instant::now());
+                    client
+                        .publish(&topic, QoS::AtLeastOnce, false, payload.clone())
+                        .await?;
+                }
+                Event::Incoming(Packet::Publish(publish)) if publish.payload == payload => {
+                    return Ok(sent.map_or(Duration::ZERO, |sent| sent.elapsed()));
+                }
+                _ => {}
+            }
+        }
+    };
+    let result = tokio::time::timeout(MQTT_TIMEOUT, loopback)
+        .await
+        .with_context(|| format!("No loopback message on {topic} within {MQTT_TIMEOUT:?}"))?;
+    let _ = client.disconnect().await;
+    result
+}
@@ -0,0 +1,125 @@
+//! Windows service support. When started with `--service`, the process hands control to the
+//! Service Control Manager and logs to the Windows event log instead of stderr.
+//!
+//! The service is expected to be created with an absolute config path, since services start in
+//! `C:\Windows\System32`:
+//!
+//! ```text
+//! sc.exe create monitor-rs binPath= "C:\monitor-rs\monitor-rs.exe --service --config C:\monitor-rs\config.toml"
+//! ```
+use std::error::Error;
+use std::ffi::OsString;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::{error, info};
+use tokio_util::sync::CancellationToken;
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+};
+
+use crate::Args;
+
+const SERVICE_NAME: &str = "monitor-rs";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// The dispatcher invokes `service_main` on its own thread without our CLI arguments, so they
+/// are stashed here before handing over control.
+static SERVICE_ARGS: OnceLock<Args> = OnceLock::new();
+
+/// Start the service dispatcher, blocking until the service is stopped.
+pub fn start(args: Args) -> Result<(), Box<dyn Error>> {
+    let level = if args.verbose {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    // The event source has to be registered (once, as an administrator) with
+    // `eventlog::register` for messages to render correctly in Event Viewer.
+    eventlog::init(SERVICE_NAME, level)?;
+    crate::log_panics();
+
+    SERVICE_ARGS
+        .set(args)
+        .map_err(|_| "Service arguments already initialized")?;
+
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        error!("Service exited with error: {err}");
+    }
+}
+
+fn run_service() -> Result<(), Box<dyn Error>> {
+    let args = SERVICE_ARGS
+        .get()
+        .ok_or("Service arguments not initialized")?
+        .clone();
+
+    let shutdown = CancellationToken::new();
+    let stop = shutdown.clone();
+
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                info!("Service stop requested");
+                stop.cancel();
+                ServiceControlHandlerResult::NoError
+            }
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+
+    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+    status_handle.set_service_status(service_status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::Win32(0),
+    ))?;
+
+    info!("Service started");
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(crate::run(args, shutdown));
+    runtime.shutdown_timeout(Duration::from_secs(5));
+
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    status_handle.set_service_status(service_status(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        exit_code,
+    ))?;
+
+    result
+}
+
+fn service_status(
+    current_state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+    exit_code: ServiceExitCode,
+) -> ServiceStatus {
+    ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
@@ -0,0 +1,365 @@
+use std::time::Instant;
+
+use crate::config::{self, ScanConfig};
+
+/// Presence of a single device, driven by the results of presence checks.
+///
+/// ```text
+/// Absent -> Arriving -> Present -> Departing -> Absent
+/// ```
+///
+/// Devices may also start out `Unknown`, settling on `Present` or `Absent` after their first
+/// check without a transition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceState {
+    Unknown,
+    Absent,
+    /// Seen, but not yet confirmed enough times to be announced present
+    Arriving {
+        confirmations: u32,
+    },
+    Present {
+        last_seen: Instant,
+    },
+    /// Missed at least one check, but not enough to be announced absent
+    Departing {
+        last_seen: Instant,
+        failures: u32,
+    },
+}
+
+/// Edge emitted when a device crosses between absent and present.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Arrived,
+    Departed,
+}
+
+impl Transition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transition::Arrived => "arrived",
+            Transition::Departed => "departed",
+        }
+    }
+}
+
+/// Rules for moving a device between states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresencePolicy {
+    pub max_confidence: u8,
+    /// Confidence gained per successful check
+    pub step_up: u8,
+    /// Confidence lost per failed check
+    pub step_down: u8,
+    /// A device at or below this confidence is absent
+    pub absent_threshold: u8,
+    /// Consecutive successful checks needed before an absent device is present
+    pub arrival_confirmations: u32,
+    /// Consecutive failed checks needed before a present device is absent
+    pub depart_failures_required: u32,
+}
+
+impl PresencePolicy {
+    pub fn new(scan: &ScanConfig) -> Self {
+        let cfg = scan.confidence.clone().unwrap_or_default();
+        PresencePolicy {
+            max_confidence: cfg.max.unwrap_or(config::DEFAULT_CONFIDENCE_MAX),
+            step_up: cfg.step_up.unwrap_or(config::DEFAULT_CONFIDENCE_STEP_UP),
+            step_down: cfg
+                .step_down
+                .unwrap_or(config::DEFAULT_CONFIDENCE_STEP_DOWN),
+            absent_threshold: cfg
+                .absent_threshold
+                .unwrap_or(config::DEFAULT_CONFIDENCE_ABSENT_THRESHOLD),
+            arrival_confirmations: scan
+                .arrival_confirmations
+                .unwrap_or(config::DEFAULT_ARRIVAL_CONFIRMATIONS)
+                .max(1),
+            depart_failures_required: scan
+                .depart_failures_required
+                .unwrap_or(config::DEFAULT_DEPART_FAILURES_REQUIRED)
+                .max(1),
+        }
+    }
+}
+
+impl Default for PresencePolicy {
+    fn default() -> Self {
+        Self::new(&ScanConfig::default())
+    }
+}
+
+/// A device's [PresenceState] along with how confident we are that it's nearby.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tracker {
+    pub state: PresenceState,
+    pub confidence: u8,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Tracker {
+            state: PresenceState::Absent,
+            confidence: 0,
+        }
+    }
+}
+
+impl Tracker {
+    pub fn unknown() -> Self {
+        Tracker {
+            state: PresenceState::Unknown,
+            confidence: 0,
+        }
+    }
+
+    /// Feed the result of a presence check into the state machine, returning the transition if
+    /// the device arrived or departed as a result.
+    pub fn observe(
+        &mut self,
+        seen: bool,
+        now: Instant,
+        policy: &PresencePolicy,
+    ) -> Option<Transition> {
+        self.confidence = if seen {
+            self.confidence
+                .saturating_add(policy.step_up)
+                .min(policy.max_confidence)
+        } else {
+            self.confidence.saturating_sub(policy.step_down)
+        };
+        let failures = match (self.state, seen) {
+            (PresenceState::Present { .. }, false) => 1,
+            (PresenceState::Departing { failures, .. }, false) => failures + 1,
+            _ => 0,
+        };
+        if failures > 0 && failures < policy.depart_failures_required {
+            // Not enough misses to leave yet, however far confidence has fallen
+            let floor = policy
+                .absent_threshold
+                .saturating_add(1)
+                .min(policy.max_confidence);
+            self.confidence = self.confidence.max(floor);
+        }
+        let above_threshold = self.confidence > policy.absent_threshold;
+        let arriving = |confirmations: u32| {
+            if above_threshold && confirmations >= policy.arrival_confirmations {
+                (
+                    PresenceState::Present { last_seen: now },
+                    Some(Transition::Arrived),
+                )
+            } else {
+                (PresenceState::Arriving { confirmations }, None)
+            }
+        };
+
+        let (next, transition) = match (self.state, seen) {
+            (PresenceState::Unknown, true) => {
+                let (next, _) = arriving(1);
+                (next, None)
+            }
+            (PresenceState::Unknown, false) => (PresenceState::Absent, None),
+            (PresenceState::Absent, true) => arriving(1),
+            (PresenceState::Arriving { confirmations }, true) => arriving(confirmations + 1),
+            (PresenceState::Absent | PresenceState::Arriving { .. }, false) => {
+                (PresenceState::Absent, None)
+            }
+            (PresenceState::Present { .. } | PresenceState::Departing { .. }, true) => {
+                (PresenceState::Present { last_seen: now }, None)
+            }
+            (
+                PresenceState::Present { last_seen } | PresenceState::Departing { last_seen, .. },
+                false,
+            ) => departing(last_seen, failures, above_threshold),
+        };
+        self.state = next;
+        transition
+    }
+
+    /// Put the device in a fixed state without a check, e.g. a static device, returning the
+    /// transition if that changed whether it's present.
+    pub fn set(
+        &mut self,
+        present: bool,
+        now: Instant,
+        policy: &PresencePolicy,
+    ) -> Option<Transition> {
+        let was_present = matches!(
+            self.state,
+            PresenceState::Present { .. } | PresenceState::Departing { .. }
+        );
+        if !present {
+            return self.expire();
+        }
+        self.state = PresenceState::Present { last_seen: now };
+        self.confidence = policy.max_confidence;
+        (!was_present).then_some(Transition::Arrived)
+    }
+
+    /// Mark the device absent without a check, e.g. because it stopped advertising, returning
+    /// the departure if it was present.
+    pub fn expire(&mut self) -> Option<Transition> {
+        let was_present = matches!(
+            self.state,
+            PresenceState::Present { .. } | PresenceState::Departing { .. }
+        );
+        self.state = PresenceState::Absent;
+        self.confidence = 0;
+        was_present.then_some(Transition::Departed)
+    }
+}
+
+fn departing(
+    last_seen: Instant,
+    failures: u32,
+    above_threshold: bool,
+) -> (PresenceState, Option<Transition>) {
+    if above_threshold {
+        (
+            PresenceState::Departing {
+                last_seen,
+                failures,
+            },
+            None,
+        )
+    } else {
+        (PresenceState::Absent, Some(Transition::Departed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        let now = Instant::now();
+        let policy = PresencePolicy::default();
+        let mut tracker = Tracker::default();
+
+        assert_eq!(tracker.observe(false, now, &policy), None);
+        assert_eq!(tracker.state, PresenceState::Absent);
+
+        assert_eq!(
+            tracker.observe(true, now, &policy),
+            Some(Transition::Arrived)
+        );
+        assert_eq!(tracker.confidence, 100);
+        assert_eq!(tracker.observe(true, now, &policy), None);
+
+        assert_eq!(
+            tracker.observe(false, now, &policy),
+            Some(Transition::Departed)
+        );
+        assert_eq!(tracker.state, PresenceState::Absent);
+        assert_eq!(tracker.confidence, 0);
+    }
+
+    #[test]
+    fn test_unknown_settles_without_transition() {
+        let now = Instant::now();
+        let policy = PresencePolicy::default();
+
+        let mut tracker = Tracker::unknown();
+        assert_eq!(tracker.observe(true, now, &policy), None);
+        assert_eq!(tracker.state, PresenceState::Present { last_seen: now });
+
+        let mut tracker = Tracker::unknown();
+        assert_eq!(tracker.observe(false, now, &policy), None);
+        assert_eq!(tracker.state, PresenceState::Absent);
+    }
+
+    #[test]
+    fn test_confidence_steps() {
+        let now = Instant::now();
+        let policy = PresencePolicy {
+            max_confidence: 100,
+            step_up: 50,
+            step_down: 30,
+            absent_threshold: 50,
+            arrival_confirmations: 1,
+            depart_failures_required: 1,
+        };
+        let mut tracker = Tracker::default();
+
+        assert_eq!(tracker.observe(true, now, &policy), None);
+        assert_eq!(tracker.state, PresenceState::Arriving { confirmations: 1 });
+        assert_eq!(
+            tracker.observe(true, now, &policy),
+            Some(Transition::Arrived)
+        );
+        assert_eq!(tracker.confidence, 100);
+
+        assert_eq!(tracker.observe(false, now, &policy), None);
+        assert_eq!(tracker.confidence, 70);
+        assert!(matches!(
+            tracker.state,
+            PresenceState::Departing { failures: 1, .. }
+        ));
+        assert_eq!(
+            tracker.observe(false, now, &policy),
+            Some(Transition::Departed)
+        );
+        assert_eq!(tracker.confidence, 40);
+    }
+
+    #[test]
+    fn test_arrival_confirmations() {
+        let now = Instant::now();
+        let policy = PresencePolicy {
+            arrival_confirmations: 3,
+            ..PresencePolicy::default()
+        };
+        let mut tracker = Tracker::default();
+
+        assert_eq!(tracker.observe(true, now, &policy), None);
+        assert_eq!(tracker.observe(true, now, &policy), None);
+        assert_eq!(tracker.state, PresenceState::Arriving { confirmations: 2 });
+        // A miss starts the count over
+        assert_eq!(tracker.observe(false, now, &policy), None);
+        assert_eq!(tracker.state, PresenceState::Absent);
+
+        for _ in 0..2 {
+            assert_eq!(tracker.observe(true, now, &policy), None);
+        }
+        assert_eq!(
+            tracker.observe(true, now, &policy),
+            Some(Transition::Arrived)
+        );
+    }
+
+    #[test]
+    fn test_depart_failures_required() {
+        let now = Instant::now();
+        let policy = PresencePolicy {
+            depart_failures_required: 3,
+            ..PresencePolicy::default()
+        };
+        let mut tracker = Tracker::default();
+        tracker.observe(true, now, &policy);
+
+        for failures in 1..3 {
+            assert_eq!(tracker.observe(false, now, &policy), None);
+            assert_eq!(
+                tracker.state,
+                PresenceState::Departing {
+                    last_seen: now,
+                    failures
+                }
+            );
+            assert_eq!(tracker.confidence, 1);
+        }
+        assert_eq!(
+            tracker.observe(false, now, &policy),
+            Some(Transition::Departed)
+        );
+        assert_eq!(tracker.confidence, 0);
+
+        // A sighting in between starts the count over
+        tracker.observe(true, now, &policy);
+        tracker.observe(false, now, &policy);
+        tracker.observe(true, now, &policy);
+        assert_eq!(tracker.observe(false, now, &policy), None);
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Most recent checks of each device its reliability is worked out from.
+const DEVICE_CHECK_WINDOW: usize = 50;
+
+/// Counters describing what this node has been doing, shared between subsystems and
+/// periodically published to `<topic_path>/<publisher_id>/stats`.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    checks: AtomicU64,
+    check_millis: AtomicU64,
+    check_failures: AtomicU64,
+    passes: AtomicU64,
+    pass_millis: AtomicU64,
+    last_pass_millis: AtomicU64,
+    passes_over_budget: AtomicU64,
+    skipped_checks: AtomicU64,
+    arrive_requests: AtomicU64,
+    depart_requests: AtomicU64,
+    device_triggers: AtomicU64,
+    mqtt_reconnects: AtomicU64,
+    mqtt_network_errors: AtomicU64,
+    mqtt_protocol_errors: AtomicU64,
+    mqtt_rejections: AtomicU64,
+    mqtt_acks: AtomicU64,
+    mqtt_ack_millis: AtomicU64,
+    mqtt_ack_timeouts: AtomicU64,
+    /// When each QoS 1+ publish still waiting for the broker's acknowledgement was sent, by
+    /// packet ID
+    pending_acks: Mutex<HashMap<u16, Instant>>,
+    /// Recent checks of each device, newest last
+    device_checks: Mutex<HashMap<String, VecDeque<DeviceCheck>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DeviceCheck {
+    /// `None` if the check failed
+    present: Option<bool>,
+    duration: Duration,
+}
+
+/// How a device fared in its recent checks, for spotting the flaky radio behind a device that
+/// keeps flapping between present and absent.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct DeviceReliability {
+    checks: usize,
+    /// Share of checks the device answered
+    success_rate: f64,
+    /// Share of checks that failed outright (timeouts, backend errors)
+    failure_rate: f64,
+    average_check_millis: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    uptime_seconds: u64,
+    checks: u64,
+    average_check_millis: u64,
+    check_failures: u64,
+    passes: u64,
+    average_pass_millis: u64,
+    last_pass_millis: u64,
+    passes_over_budget: u64,
+    /// Devices left unchecked because their pass ran out of time
+    skipped_checks: u64,
+    arrive_requests: u64,
+    depart_requests: u64,
+    device_triggers: u64,
+    mqtt_reconnects: u64,
+    mqtt_network_errors: u64,
+    mqtt_protocol_errors: u64,
+    mqtt_rejections: u64,
+    /// QoS 1+ publishes the broker acknowledged
+    mqtt_acks: u64,
+    average_ack_millis: u64,
+    /// QoS 1+ publishes still waiting for an acknowledgement
+    mqtt_pending_acks: usize,
+    /// QoS 1+ publishes given up on, never acknowledged in time
+    mqtt_ack_timeouts: u64,
+    /// Over each device's last checks
+    devices: BTreeMap<String, DeviceReliability>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            started: Instant::now(),
+            checks: AtomicU64::default(),
+            check_millis: AtomicU64::default(),
+            check_failures: AtomicU64::default(),
+            passes: AtomicU64::default(),
+            pass_millis: AtomicU64::default(),
+            last_pass_millis: AtomicU64::default(),
+            passes_over_budget: AtomicU64::default(),
+            skipped_checks: AtomicU64::default(),
+            arrive_requests: AtomicU64::default(),
+            depart_requests: AtomicU64::default(),
+            device_triggers: AtomicU64::default(),
+            mqtt_reconnects: AtomicU64::default(),
+            mqtt_network_errors: AtomicU64::default(),
+            mqtt_protocol_errors: AtomicU64::default(),
+            mqtt_rejections: AtomicU64::default(),
+            mqtt_acks: AtomicU64::default(),
+            mqtt_ack_millis: AtomicU64::default(),
+            mqtt_ack_timeouts: AtomicU64::default(),
+            pending_acks: Mutex::default(),
+            device_checks: Mutex::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn record_check(&self, duration: Duration) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        self.check_millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_check_failure(&self) {
+        self.check_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of checking a device, `None` if the check failed.
+    pub fn record_device_check(&self, name: &str, present: Option<bool>, duration: Duration) {
+        let mut device_checks = self.device_checks.lock().expect("stats lock poisoned");
+        let checks = device_checks.entry(name.to_string()).or_default();
+        if checks.len() == DEVICE_CHECK_WINDOW {
+            checks.pop_front();
+        }
+        checks.push_back(DeviceCheck { present, duration });
+    }
+
+    fn device_reliability(&self) -> BTreeMap<String, DeviceReliability> {
+        let device_checks = self.device_checks.lock().expect("stats lock poisoned");
+        device_checks
+            .iter()
+            .map(|(name, checks)| {
+                let count = checks.len().max(1);
+                let share = |matches: fn(&DeviceCheck) -> bool| {
+                    checks.iter().filter(|check| matches(check)).count() as f64 / count as f64
+                };
+                let total: Duration = checks.iter().map(|check| check.duration).sum();
+                let reliability = DeviceReliability {
+                    checks: checks.len(),
+                    success_rate: share(|check| check.present == Some(true)),
+                    failure_rate: share(|check| check.present.is_none()),
+                    average_check_millis: (total / count as u32).as_millis() as u64,
+                };
+                (name.clone(), reliability)
+            })
+            .collect()
+    }
+
+    /// Record a full arrival or departure pass over the device list.
+    pub fn record_pass(&self, duration: Duration, over_budget: bool) {
+        let millis = duration.as_millis() as u64;
+        self.passes.fetch_add(1, Ordering::Relaxed);
+        self.pass_millis.fetch_add(millis, Ordering::Relaxed);
+        self.last_pass_millis.store(millis, Ordering::Relaxed);
+        if over_budget {
+            self.passes_over_budget.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_skipped_checks(&self, skipped: usize) {
+        self.skipped_checks
+            .fetch_add(skipped as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_arrive_request(&self) {
+        self.arrive_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_depart_request(&self) {
+        self.depart_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_device_trigger(&self) {
+        self.device_triggers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_mqtt_reconnect(&self) {
+        self.mqtt_reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_mqtt_network_error(&self) {
+        self.mqtt_network_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_mqtt_protocol_error(&self) {
+        self.mqtt_protocol_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The broker refused the connection.
+    pub fn record_mqtt_rejection(&self) {
+        self.mqtt_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A QoS 1+ publish left for the broker, QoS 0 ones (packet ID 0) aren't acknowledged.
+    pub fn record_publish_sent(&self, pkid: u16, now: Instant) {
+        if pkid == 0 {
+            return;
+        }
+        let mut pending = self.pending_acks.lock().expect("stats lock poisoned");
+        // Resent after a reconnect, still counts from the first attempt
+        pending.entry(pkid).or_insert(now);
+    }
+
+    pub fn record_publish_acked(&self, pkid: u16, now: Instant) {
+        let mut pending = self.pending_acks.lock().expect("stats lock poisoned");
+        if let Some(sent) = pending.remove(&pkid) {
+            self.mqtt_acks.fetch_add(1, Ordering::Relaxed);
+            self.mqtt_ack_millis.fetch_add(
+                now.saturating_duration_since(sent).as_millis() as u64,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Give up on publishes unacknowledged for longer than `timeout`, returning their packet IDs.
+    pub fn expire_unacked(&self, now: Instant, timeout: Duration) -> Vec<u16> {
+        let mut pending = self.pending_acks.lock().expect("stats lock poisoned");
+        let mut expired = Vec::new();
+        pending.retain(|pkid, sent| {
+            let aged_out = now.saturating_duration_since(*sent) > timeout;
+            if aged_out {
+                expired.push(*pkid);
+            }
+            !aged_out
+        });
+        self.mqtt_ack_timeouts
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        expired
+    }
+
+    /// Presence checks run and failed so far.
+    pub fn check_counts(&self) -> (u64, u64) {
+        (
+            self.checks.load(Ordering::Relaxed),
+            self.check_failures.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let checks = self.checks.load(Ordering::Relaxed);
+        let passes = self.passes.load(Ordering::Relaxed);
+        let acks = self.mqtt_acks.load(Ordering::Relaxed);
+        StatsSnapshot {
+            uptime_seconds: self.started.elapsed().as_secs(),
+            checks,
+            average_check_millis: self
+                .check_millis
+                .load(Ordering::Relaxed)
+                .checked_div(checks)
+                .unwrap_or_default(),
+            check_failures: self.check_failures.load(Ordering::Relaxed),
+            passes,
+            average_pass_millis: self
+                .pass_millis
+                .load(Ordering::Relaxed)
+                .checked_div(passes)
+                .unwrap_or_default(),
+            last_pass_millis: self.last_pass_millis.load(Ordering::Relaxed),
+            passes_over_budget: self.passes_over_budget.load(Ordering::Relaxed),
+            skipped_checks: self.skipped_checks.load(Ordering::Relaxed),
+            arrive_requests: self.arrive_requests.load(Ordering::Relaxed),
+            depart_requests: self.depart_requests.load(Ordering::Relaxed),
+            device_triggers: self.device_triggers.load(Ordering::Relaxed),
+            mqtt_reconnects: self.mqtt_reconnects.load(Ordering::Relaxed),
+            mqtt_network_errors: self.mqtt_network_errors.load(Ordering::Relaxed),
+            mqtt_protocol_errors: self.mqtt_protocol_errors.load(Ordering::Relaxed),
+            mqtt_rejections: self.mqtt_rejections.load(Ordering::Relaxed),
+            mqtt_acks: acks,
+            average_ack_millis: self
+                .mqtt_ack_millis
+                .load(Ordering::Relaxed)
+                .checked_div(acks)
+                .unwrap_or_default(),
+            mqtt_pending_acks: self.pending_acks.lock().expect("stats lock poisoned").len(),
+            mqtt_ack_timeouts: self.mqtt_ack_timeouts.load(Ordering::Relaxed),
+            devices: self.device_reliability(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_reliability() {
+        let stats = Stats::default();
+        let millis = Duration::from_millis;
+        stats.record_device_check("Phone", Some(true), millis(100));
+        stats.record_device_check("Phone", Some(false), millis(300));
+        stats.record_device_check("Phone", None, millis(200));
+        stats.record_device_check("Phone", Some(true), millis(200));
+        for _ in 0..DEVICE_CHECK_WINDOW {
+            stats.record_device_check("Watch", Some(false), millis(10));
+        }
+        stats.record_device_check("Watch", Some(true), millis(10));
+
+        let devices = stats.snapshot().devices;
+        assert_eq!(
+            devices["Phone"],
+            DeviceReliability {
+                checks: 4,
+                success_rate: 0.5,
+                failure_rate: 0.25,
+                average_check_millis: 200,
+            }
+        );
+        assert_eq!(devices["Watch"].checks, DEVICE_CHECK_WINDOW);
+        assert_eq!(
+            devices["Watch"].success_rate,
+            1.0 / DEVICE_CHECK_WINDOW as f64
+        );
+    }
+
+    #[test]
+    fn test_publish_acks() {
+        let stats = Stats::default();
+        let start = Instant::now();
+        let timeout = Duration::from_secs(30);
+        stats.record_publish_sent(0, start);
+        stats.record_publish_sent(1, start);
+        stats.record_publish_sent(2, start);
+        stats.record_publish_acked(1, start + Duration::from_millis(40));
+        // Unknown or already acknowledged
+        stats.record_publish_acked(1, start + Duration::from_millis(50));
+        stats.record_publish_acked(7, start);
+        assert!(stats.expire_unacked(start + timeout, timeout).is_empty());
+        // Resent, but still waiting since the first attempt
+        stats.record_publish_sent(2, start + timeout);
+
+        assert_eq!(stats.expire_unacked(start + timeout * 2, timeout), vec![2]);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.mqtt_acks, 1);
+        assert_eq!(snapshot.average_ack_millis, 40);
+        assert_eq!(snapshot.mqtt_pending_acks, 0);
+        assert_eq!(snapshot.mqtt_ack_timeouts, 1);
+    }
+}